@@ -0,0 +1,121 @@
+#![no_std]
+#[macro_use] extern crate terminal_print;
+
+#[macro_use] extern crate alloc;
+extern crate task;
+extern crate getopts;
+extern crate path;
+extern crate fs_node;
+extern crate fs_copy;
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::string::ToString;
+use getopts::Options;
+use path::Path;
+use fs_node::{DirRef, FileOrDir, FsNode};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    match copy_nodes(args) {
+        Ok(_) => 0,
+        Err(err) => {
+            println!("{}", err);
+            -1
+        }
+    }
+}
+
+pub fn copy_nodes(args: Vec<String>) -> Result<(), String> {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("r", "recursive", "recursively copy directories and their contents");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            print_usage(opts);
+            return Err(e.to_string());
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return Ok(());
+    }
+
+    if matches.free.len() < 2 {
+        print_usage(opts);
+        return Err("cp: missing source or destination".into());
+    }
+
+    let taskref = match task::get_my_current_task() {
+        Some(t) => t,
+        None => {
+            return Err("failed to get current task".into());
+        }
+    };
+
+    let working_dir = {
+        let locked_task = taskref.lock();
+        let curr_env = locked_task.env.lock();
+        Arc::clone(&curr_env.working_dir)
+    };
+
+    let recursive = matches.opt_present("r");
+    let (sources, destination) = matches.free.split_at(matches.free.len() - 1);
+    let destination = Path::new(destination[0].clone());
+    let destination_node = destination.get(&working_dir);
+    if sources.len() > 1 {
+        match destination_node {
+            Some(FileOrDir::Dir(_)) => { }
+            _ => return Err(format!("cp: destination {} is not a directory", destination)),
+        }
+    }
+
+    for source_string in sources {
+        let source = Path::new(source_string.clone());
+        let node = match source.get(&working_dir) {
+            Some(node) => node,
+            None => return Err(format!("cp: couldn't find path {}", source)),
+        };
+
+        // Only copy directories if the user specified "-r".
+        if let FileOrDir::Dir(_) = node {
+            if !recursive {
+                println!("Skipping the copy of directory '{}', try specifying the \"-r\" flag", node.get_name());
+                continue;
+            }
+        }
+
+        // Copy the node into the destination directory if it exists, otherwise copy it to the destination path.
+        let (destination_dir, new_name) = match destination_node {
+            Some(FileOrDir::Dir(ref d)) => (Arc::clone(d), node.get_name()),
+            _ => (parent_dir(&destination, &working_dir)?, destination.basename().to_string()),
+        };
+
+        fs_copy::copy(&node, &destination_dir, &new_name, recursive)
+            .map_err(|e| format!("cp: cannot copy {} to {}: {}", source, destination, e))?;
+    }
+
+    Ok(())
+}
+
+/// Returns the directory that contains the trailing component of the given `path`.
+fn parent_dir(path: &Path, working_dir: &DirRef) -> Result<DirRef, String> {
+    let parent = path.parent().ok_or_else(|| format!("cp: invalid destination {}", path))?;
+    match parent.get(working_dir) {
+        Some(FileOrDir::Dir(d)) => Ok(d),
+        _ => Err(format!("cp: directory {} does not exist", parent)),
+    }
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: cp [OPTION]... SOURCE DEST
+   or: cp [OPTION]... SOURCE... DIRECTORY
+Copy SOURCE to DEST, or copy SOURCE(s) into DIRECTORY";
@@ -0,0 +1,204 @@
+//! Displays the amount of used and free memory in the system, similar to `free` in Unix-like systems.
+//!
+//! Optionally, it also displays the per-core heaps,
+//! the memory allocated by each task, and the memory used by each loaded crate.
+
+#![no_std]
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate memory;
+extern crate multiple_heaps;
+extern crate kernel_config;
+extern crate task;
+extern crate mod_mgmt;
+
+use core::fmt::Write;
+use alloc::{
+    vec::Vec,
+    string::String,
+};
+use getopts::{Options, Matches};
+use kernel_config::memory::{PAGE_SIZE, KERNEL_HEAP_INITIAL_SIZE};
+use task::TASKLIST;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("b", "bytes", "show sizes in bytes");
+    opts.optflag("m", "mebi", "show sizes in MiB");
+    opts.optflag("p", "per-core", "show the size and usage of each per-core heap");
+    opts.optflag("t", "tasks", "show the frames and heap memory allocated by each task");
+    opts.optflag("c", "crates", "show the memory used by each crate loaded in the current namespace");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    let unit = if matches.opt_present("b") {
+        Unit::Bytes
+    } else if matches.opt_present("m") {
+        Unit::MiB
+    } else {
+        Unit::KiB
+    };
+
+    let mut output = String::new();
+    print_system(&mut output, unit, matches.opt_present("p"))
+        .map_err(|_e| String::from("String formatting error"))?;
+    if matches.opt_present("t") {
+        print_tasks(&mut output, unit)
+            .map_err(|_e| String::from("String formatting error"))?;
+    }
+    if matches.opt_present("c") {
+        print_crates(&mut output, unit)?;
+    }
+
+    print!("{}", output);
+    Ok(())
+}
+
+
+/// The unit in which sizes are displayed.
+#[derive(Clone, Copy)]
+enum Unit {
+    Bytes,
+    KiB,
+    MiB,
+}
+
+impl Unit {
+    /// Converts the given number of bytes into this unit.
+    fn convert(&self, bytes: usize) -> usize {
+        match self {
+            Unit::Bytes => bytes,
+            Unit::KiB => bytes / 1024,
+            Unit::MiB => bytes / (1024 * 1024),
+        }
+    }
+}
+
+
+/// Prints the system-wide physical memory, heap, and mapping usage.
+fn print_system(output: &mut String, unit: Unit, per_core: bool) -> core::fmt::Result {
+    writeln!(output, "{0:<10} {1:>12} {2:>12} {3:>12}", "", "total", "used", "free")?;
+
+    match memory::frame_stats() {
+        Some(frames) => writeln!(output, "{0:<10} {1:>12} {2:>12} {3:>12}", "Mem:",
+            unit.convert(frames.total_frames * PAGE_SIZE),
+            unit.convert(frames.used_frames() * PAGE_SIZE),
+            unit.convert(frames.free_frames * PAGE_SIZE),
+        )?,
+        None => writeln!(output, "{0:<10} {1:>12}", "Mem:", "unknown")?,
+    }
+
+    let heaps = multiple_heaps::stats();
+    let heap_size = KERNEL_HEAP_INITIAL_SIZE + heaps.as_ref().map(|h| h.size_in_bytes()).unwrap_or(0);
+    let heap_used = memory::system_memory_usage().heap_bytes_in_use();
+    writeln!(output, "{0:<10} {1:>12} {2:>12} {3:>12}", "Heap:",
+        unit.convert(heap_size),
+        unit.convert(heap_used),
+        unit.convert(heap_size.saturating_sub(heap_used)),
+    )?;
+
+    if per_core {
+        match heaps {
+            Some(heaps) => {
+                for heap in &heaps.per_core {
+                    writeln!(output, "{0:<10} {1:>12} {2:>12} {3:>12}", format!("  core {}:", heap.heap_id),
+                        unit.convert(heap.size_in_bytes),
+                        unit.convert(heap.allocated_bytes),
+                        unit.convert(heap.size_in_bytes.saturating_sub(heap.allocated_bytes)),
+                    )?;
+                }
+                writeln!(output, "{0:<10} {1:>12} {2:>12}", "  large:",
+                    unit.convert(heaps.large_allocation_bytes),
+                    unit.convert(heaps.large_allocation_bytes),
+                )?;
+            }
+            None => writeln!(output, "  (per-core heaps are not in use)")?,
+        }
+    }
+
+    let mapped_pages = memory::mapped_pages_stats();
+    writeln!(output, "{0:<10} {1:>12} {2:>12}  ({3} MappedPages)", "Mapped:",
+        unit.convert(mapped_pages.pages * PAGE_SIZE),
+        "", mapped_pages.count,
+    )
+}
+
+
+/// Prints the frames and heap memory that each task has allocated and not yet deallocated.
+fn print_tasks(output: &mut String, unit: Unit) -> core::fmt::Result {
+    writeln!(output, "\n{0:<5} {1:>12} {2:>12}  {3}", "ID", "FRAMES", "HEAP", "NAME")?;
+    for (id, taskref) in TASKLIST.lock().iter() {
+        // only hold the task's lock for a short time
+        let (name, usage) = {
+            let task = taskref.lock();
+            (task.name.clone(), task.memory_usage.stats())
+        };
+        writeln!(output, "{0:<5} {1:>12} {2:>12}  {3}", id,
+            unit.convert(usage.frames_in_use() * PAGE_SIZE),
+            unit.convert(usage.heap_bytes_in_use()),
+            name,
+        )?;
+    }
+    Ok(())
+}
+
+
+/// Prints the memory used by the sections of each crate in the current task's namespace.
+fn print_crates(output: &mut String, unit: Unit) -> Result<(), String> {
+    let curr_task = task::get_my_current_task().ok_or_else(|| format!("unable to get current task"))?;
+    let namespace = curr_task.get_namespace();
+
+    let mut crates = Vec::new();
+    namespace.for_each_crate(true, |crate_name, crate_ref| {
+        crates.push((String::from(crate_name), crate_ref.lock_as_ref().memory_usage()));
+        true // keep going
+    });
+
+    let mut result = writeln!(output, "\n{0:>12} {1:>12} {2:>12} {3:>12}  {4}", "TEXT", "RODATA", "DATA", "MAPPED", "CRATE");
+    for (crate_name, usage) in crates {
+        result = result.and_then(|_| writeln!(output, "{0:>12} {1:>12} {2:>12} {3:>12}  {4}",
+            unit.convert(usage.text_bytes),
+            unit.convert(usage.rodata_bytes),
+            unit.convert(usage.data_bytes),
+            unit.convert(usage.mapped_pages * PAGE_SIZE),
+            crate_name,
+        ));
+    }
+    result.map_err(|_e| String::from("String formatting error"))
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &'static str = "Usage: free [OPTION]
+Displays the amount of used and free physical memory and heap memory, in KiB by default.
+The per-task numbers are the memory each task allocated and didn't deallocate itself.";
@@ -12,6 +12,7 @@ extern crate libtest;
 extern crate spawn;
 extern crate getopts;
 extern crate heap;
+extern crate multiple_heaps;
 
 use alloc::{
     string::{String, ToString},
@@ -26,6 +27,7 @@ use qp_trie::{Trie, wrapper::BString};
 use getopts::{Matches, Options};
 use libtest::*;
 use core::sync::atomic::{AtomicUsize, Ordering};
+use multiple_heaps::MultipleHeapsStats;
 
 #[cfg(not(direct_access_to_multiple_heaps))]
 use heap::GLOBAL_ALLOCATOR as ALLOCATOR;
@@ -60,6 +62,9 @@ pub fn main(args: Vec<String>) -> isize {
     opts.optflag("", "threadtest", "run the threadtest heap benchmark");
     opts.optflag("", "shbench", "run the shbench heap benchmark");
     opts.optflag("", "large", "run threadtest or shbench for large allocations");
+    opts.optflag("", "heapstats", "print the size, fragmentation, and page reclamation of the per-core heaps after the test");
+    opts.optflag("", "reclaim", "reclaim the empty pages of the per-core heaps after the test");
+    opts.optflag("", "burst", "run a burst of allocations on this core, then measure moving its empty pages to another heap and reclaiming them");
 
 
     
@@ -97,6 +102,8 @@ pub fn main(args: Vec<String>) -> isize {
 }
 
 fn rmain(matches: Matches) -> Result<(), &'static str> {
+    let stats_before = multiple_heaps::stats();
+
     if matches.opt_present("vector") {
         do_vec();
     }
@@ -137,10 +144,82 @@ fn rmain(matches: Matches) -> Result<(), &'static str> {
         }
         do_shbench()?;
     }
+    else if matches.opt_present("burst") {
+        do_burst()?;
+    }
     else {
         return Err("Unknown command")
     }
 
+    if matches.opt_present("reclaim") {
+        let reclaimed = multiple_heaps::reclaim_empty_pages()?;
+        println!("Reclaimed {} empty pages", reclaimed);
+    }
+    if matches.opt_present("heapstats") {
+        let stats_after = multiple_heaps::stats().ok_or("multiple heaps are not in use")?;
+        print_heap_stats(stats_before.as_ref(), &stats_after);
+    }
+
+    Ok(())
+}
+
+/// Prints the memory used by each per-core heap, and how many heap pages were mapped, reclaimed,
+/// and moved between heaps since `before` was taken.
+fn print_heap_stats(before: Option<&MultipleHeapsStats>, after: &MultipleHeapsStats) {
+    println!("{:<6} {:>12} {:>12} {:>12} {:>12}", "HEAP", "SIZE", "ALLOCATED", "EMPTY", "FRAGMENTED");
+    for heap in &after.per_core {
+        println!("{:<6} {:>12} {:>12} {:>12} {:>12}", heap.heap_id, heap.size_in_bytes, heap.allocated_bytes, heap.empty_bytes, heap.fragmented_bytes());
+    }
+    println!("{:<6} {:>12} {:>12}", "large", after.large_allocation_bytes, after.large_allocation_bytes);
+
+    let (mapped, reclaimed, moved) = before.map_or((0, 0, 0), |b| (b.pages_mapped, b.pages_reclaimed, b.pages_moved));
+    println!("Heap pages mapped: {}, reclaimed: {}, moved between heaps: {}",
+        after.pages_mapped - mapped,
+        after.pages_reclaimed - reclaimed,
+        after.pages_moved - moved,
+    );
+}
+
+/// The number of objects of each size in `CAPACITY` that the burst test allocates and then deallocates all at once.
+const BURST_OBJECTS: usize = 512;
+
+/// Allocates a burst of objects of every size class on this core's heap and then deallocates them,
+/// which leaves the heap with many empty pages. 
+/// Then measures moving some of those empty pages to another core's heap and back,
+/// and reclaiming the empty pages beyond the watermark of each size class.
+fn do_burst() -> Result<(), &'static str> {
+    let hpet = get_hpet();
+    let hpet = hpet.as_ref().ok_or("couldn't get HPET timer")?;
+    let heap_id = CPU_ID!() as usize;
+
+    {
+        let mut objects: Vec<Vec<u8>> = Vec::with_capacity(CAPACITY.len() * BURST_OBJECTS);
+        for size in CAPACITY.iter() {
+            for _ in 0..BURST_OBJECTS {
+                let mut object = Vec::with_capacity(*size);
+                object.push(*size as u8);
+                objects.push(object);
+            }
+        }
+    }
+    let after_burst = multiple_heaps::stats().ok_or("multiple heaps are not in use")?;
+    let empty_pages = after_burst.per_core.iter().find(|h| h.heap_id == heap_id)
+        .map_or(0, |h| h.empty_bytes / multiple_heaps::HEAP_MAPPED_PAGES_SIZE_IN_BYTES);
+    println!("Burst of {} objects on heap {} left {} empty pages", CAPACITY.len() * BURST_OBJECTS, heap_id, empty_pages);
+
+    // move half of the empty pages to another heap and back again
+    if let Some(other_heap_id) = after_burst.per_core.iter().map(|h| h.heap_id).find(|id| *id != heap_id) {
+        let start = hpet.get_counter();
+        let moved = multiple_heaps::move_empty_pages(heap_id, other_heap_id, empty_pages / 2)?;
+        let end = hpet.get_counter();
+        let moved_back = multiple_heaps::move_empty_pages(other_heap_id, heap_id, moved)?;
+        println!("Moved {} empty pages to heap {} in {} ns, and {} back", moved, other_heap_id, hpet_2_ns(end - start), moved_back);
+    }
+
+    let start = hpet.get_counter();
+    let reclaimed = multiple_heaps::reclaim_empty_pages()?;
+    let end = hpet.get_counter();
+    println!("Reclaimed {} empty pages in {} ns", reclaimed, hpet_2_ns(end - start));
     Ok(())
 }
 
@@ -0,0 +1,107 @@
+//! Dumps the live heap allocations and compares snapshots of them, to find memory leaks in long-running applications.
+//!
+//! This requires the heap allocations to be traced, i.e., the `multiple_heaps` crate must be built with its `tracing_allocator` feature,
+//! e.g., with `make trace_heap=yes`, which also enables the frame pointers that are needed to record call stacks.
+
+#![no_std]
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate task;
+extern crate tracing_allocator;
+
+use core::fmt::Write;
+use alloc::{
+    vec::Vec,
+    string::String,
+};
+use getopts::{Options, Matches};
+use tracing_allocator::AllocationRecord;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("d", "dump", "print every live allocation and its call stack");
+    opts.optflag("s", "save", "save a snapshot of the live allocations and print its id");
+    opts.optopt("c", "compare", "print the allocations made since the saved snapshot with the given id that are still live", "ID");
+    opts.optopt("t", "task", "only print the allocations made by the task with the given id", "TASK_ID");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    let task_id = match matches.opt_str("t") {
+        Some(id) => Some(id.parse::<usize>().map_err(|_e| format!("invalid task id {:?}", id))?),
+        None => None,
+    };
+    let selected = |record: &&AllocationRecord| task_id.map_or(true, |id| record.task_id == Some(id));
+
+    let curr_task = task::get_my_current_task().ok_or_else(|| format!("unable to get current task"))?;
+    let namespace = curr_task.get_namespace();
+    let current = tracing_allocator::take_snapshot()?;
+    let live: Vec<&AllocationRecord> = current.records().iter().filter(selected).collect();
+    let mut output = String::new();
+
+    if let Some(id) = matches.opt_str("c") {
+        let id = id.parse::<usize>().map_err(|_e| format!("invalid snapshot id {:?}", id))?;
+        let earlier = tracing_allocator::get_saved_snapshot(id).ok_or_else(|| format!("there is no saved snapshot {}", id))?;
+        let allocated: Vec<&AllocationRecord> = current.allocated_since(&earlier).iter().filter(selected).collect();
+        let deallocated: Vec<&AllocationRecord> = current.deallocated_since(&earlier).into_iter().filter(selected).collect();
+        tracing_allocator::write_allocations(&mut output, allocated.iter().cloned(), &namespace)
+            .and_then(|_| writeln!(output, "Since snapshot {}: {} allocations ({} bytes) are still live, {} earlier allocations ({} bytes) were deallocated.",
+                id,
+                allocated.len(), allocated.iter().map(|r| r.size).sum::<usize>(),
+                deallocated.len(), deallocated.iter().map(|r| r.size).sum::<usize>(),
+            ))
+            .map_err(|_e| String::from("String formatting error"))?;
+    }
+    else if matches.opt_present("d") {
+        tracing_allocator::write_allocations(&mut output, live.iter().cloned(), &namespace)
+            .map_err(|_e| String::from("String formatting error"))?;
+    }
+
+    writeln!(output, "{} live allocations, {} bytes in total.", live.len(), live.iter().map(|r| r.size).sum::<usize>())
+        .map_err(|_e| String::from("String formatting error"))?;
+
+    // the snapshot is saved after the comparison above, such that it can be compared against in a later run
+    if matches.opt_present("s") {
+        let id = tracing_allocator::save_snapshot()?;
+        writeln!(output, "Saved snapshot {}.", id).map_err(|_e| String::from("String formatting error"))?;
+    }
+
+    print!("{}", output);
+    Ok(())
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &'static str = "Usage: heap_trace [OPTION]
+Prints the number of live heap allocations, or, with the options below, the allocations themselves.
+To find leaks in an application, save a snapshot, run the application, and then compare against that snapshot.
+Heap allocations are only traced if the multiple_heaps crate was built with its tracing_allocator feature, e.g., with 'make trace_heap=yes'.";
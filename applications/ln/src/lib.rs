@@ -0,0 +1,113 @@
+#![no_std]
+#[macro_use] extern crate terminal_print;
+
+#[macro_use] extern crate alloc;
+extern crate task;
+extern crate getopts;
+extern crate path;
+extern crate fs_node;
+extern crate symlink;
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::string::ToString;
+use getopts::Options;
+use path::Path;
+use fs_node::{DirRef, FileOrDir};
+use symlink::Symlink;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    match link(args) {
+        Ok(_) => 0,
+        Err(err) => {
+            println!("{}", err);
+            -1
+        }
+    }
+}
+
+pub fn link(args: Vec<String>) -> Result<(), String> {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("s", "symbolic", "make a symbolic link instead of a hard link");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            print_usage(opts);
+            return Err(e.to_string());
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return Ok(());
+    }
+
+    if matches.free.len() != 2 {
+        print_usage(opts);
+        return Err("ln: expected a target and a link name".into());
+    }
+
+    let taskref = match task::get_my_current_task() {
+        Some(t) => t,
+        None => {
+            return Err("failed to get current task".into());
+        }
+    };
+
+    let working_dir = {
+        let locked_task = taskref.lock();
+        let curr_env = locked_task.env.lock();
+        Arc::clone(&curr_env.working_dir)
+    };
+
+    let target = Path::new(matches.free[0].clone());
+    let link_path = Path::new(matches.free[1].clone());
+
+    // If the link name is an existing directory, the link is created within it, named after the target.
+    let (link_dir, link_name) = match link_path.get(&working_dir) {
+        Some(FileOrDir::Dir(d)) => (d, target.basename().to_string()),
+        _ => (parent_dir(&link_path, &working_dir)?, link_path.basename().to_string()),
+    };
+    if link_dir.lock().get(&link_name).is_some() {
+        return Err(format!("ln: {} already exists", link_name));
+    }
+
+    if matches.opt_present("s") {
+        // The target is stored as given, and is resolved relative to the link's directory when the link is followed.
+        Symlink::new(link_name, target.to_string(), &link_dir)
+            .map_err(|e| format!("ln: cannot create symbolic link {}: {}", link_path, e))?;
+    } else {
+        let file = match target.get(&working_dir) {
+            Some(FileOrDir::File(f)) => f,
+            Some(FileOrDir::Dir(_)) => return Err(format!("ln: {}: hard links to directories are not allowed", target)),
+            None => return Err(format!("ln: couldn't find path {}", target)),
+        };
+        link_dir.lock().insert_link(&link_name, file)
+            .map_err(|e| format!("ln: cannot create hard link {}: {}", link_path, e))?;
+    }
+    Ok(())
+}
+
+/// Returns the directory that contains the trailing component of the given `path`.
+fn parent_dir(path: &Path, working_dir: &DirRef) -> Result<DirRef, String> {
+    let parent = path.parent().ok_or_else(|| format!("ln: invalid link name {}", path))?;
+    match parent.get(working_dir) {
+        Some(FileOrDir::Dir(d)) => Ok(d),
+        _ => Err(format!("ln: directory {} does not exist", parent)),
+    }
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: ln [-s] TARGET LINK_NAME
+   or: ln [-s] TARGET DIRECTORY
+Create a link called LINK_NAME (or a link within DIRECTORY) that refers to TARGET.
+By default, a hard link to the file TARGET is created; 
+with -s, a symbolic link is created instead, whose TARGET need not exist.";
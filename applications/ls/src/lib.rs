@@ -11,7 +11,7 @@ extern crate path;
 use alloc::vec::Vec;
 use alloc::string::String;
 use alloc::string::ToString;
-use fs_node::{FileOrDir, DirRef};
+use fs_node::{FileOrDir, FsNode, DirRef, Timestamp};
 use getopts::Options;
 use path::Path;
 use alloc::sync::Arc;
@@ -19,6 +19,7 @@ use alloc::sync::Arc;
 pub fn main(args: Vec<String>) -> isize {
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this help menu");
+    opts.optflag("l", "long", "use a long listing format that shows each node's kind, permissions, size and modification time");
 
     let matches = match opts.parse(&args) {
         Ok(m) => m,
@@ -50,8 +51,9 @@ pub fn main(args: Vec<String>) -> isize {
     };
     
     // print children of working directory if no child is specified
+    let long = matches.opt_present("l");
     if matches.free.is_empty() {
-        print_children(&curr_wd);
+        print_children(&curr_wd, long);
         return 0;
     }
 
@@ -60,12 +62,16 @@ pub fn main(args: Vec<String>) -> isize {
     // navigate to the path specified by first argument
     match path.get(&curr_wd) {
         Some(FileOrDir::Dir(dir)) => {
-            print_children(&dir);
+            print_children(&dir, long);
             return 0;
         }
-        Some(FileOrDir::File(file)) => {
-            println!("'{}' is not a directory; `ls` currently only supports listing directory contents.", file.lock().get_name());
-            return -1;
+        Some(file) => {
+            if long {
+                println!("{}", long_entry(&file));
+            } else {
+                println!("{}", file.get_name());
+            }
+            return 0;
         }
         _ => {
             println!("Couldn't find path: {}", path); 
@@ -74,21 +80,60 @@ pub fn main(args: Vec<String>) -> isize {
     };
 }
 
-fn print_children(dir: &DirRef) {
+fn print_children(dir: &DirRef, long: bool) {
     let mut child_string = String::new();
     let mut child_list = dir.lock().list(); 
     child_list.reverse();
-    for child in child_list.iter() {
-        child_string.push_str(&format!("{}\n", child));
+    if long {
+        // Obtain the child nodes first so that we don't hold the directory's lock while locking each child.
+        let children: Vec<FileOrDir> = {
+            let locked_dir = dir.lock();
+            child_list.iter().filter_map(|name| locked_dir.get(name)).collect()
+        };
+        for child in children.iter() {
+            child_string.push_str(&format!("{}\n", long_entry(child)));
+        }
+    } else {
+        for child in child_list.iter() {
+            child_string.push_str(&format!("{}\n", child));
+        }
     }
     println!("{}", child_string);
 }
 
+/// Returns the long listing of the given node, e.g., `-rw-     1234  2019-10-17 12:34  file.txt`.
+/// Symbolic links are followed by their target, e.g., `lrwx       12  2019-10-17 12:34  link -> /path/to/file`.
+fn long_entry(node: &FileOrDir) -> String {
+    let metadata = node.metadata();
+    let target = match node {
+        FileOrDir::File(file) => file.lock().symlink_target().map(|t| format!(" -> {}", t)).unwrap_or_default(),
+        FileOrDir::Dir(_) => String::new(),
+    };
+    format!("{}{}  {:>8}  {}  {}{}",
+        metadata.kind.as_char(),
+        metadata.permissions,
+        metadata.size,
+        format_time(metadata.modified),
+        node.get_name(),
+        target,
+    )
+}
+
+/// Formats the given time as `YYYY-MM-DD HH:MM`, or as a placeholder of the same width if there is no time.
+fn format_time(time: Option<Timestamp>) -> String {
+    match time {
+        // The RTC only provides a two-digit year.
+        Some(t) => format!("20{:02}-{:02}-{:02} {:02}:{:02}", t.years, t.months, t.days, t.hours, t.minutes),
+        None => format!("{:16}", "-"),
+    }
+}
+
 fn print_usage(opts: Options) {
     println!("{}", opts.usage(USAGE));
 }
 
 
-const USAGE: &'static str = "Usage: ls [DIR | FILE]
+const USAGE: &'static str = "Usage: ls [-l] [DIR | FILE]
 List the contents of the given directory or info about the given file.
-If no arguments are provided, it lists the contents of the current directory.";
\ No newline at end of file
+If no arguments are provided, it lists the contents of the current directory.
+With -l, each node is listed along with its kind, permissions, size and modification time.";
\ No newline at end of file
@@ -0,0 +1,103 @@
+#![no_std]
+#[macro_use] extern crate terminal_print;
+
+#[macro_use] extern crate alloc;
+extern crate task;
+extern crate getopts;
+extern crate path;
+extern crate fs_node;
+extern crate ramdisk;
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::string::ToString;
+use getopts::Options;
+use path::Path;
+use fs_node::FileOrDir;
+use ramdisk::RamDisk;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    match mkramdisk(args) {
+        Ok(_) => 0,
+        Err(err) => {
+            println!("{}", err);
+            -1
+        }
+    }
+}
+
+pub fn mkramdisk(args: Vec<String>) -> Result<(), String> {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("s", "sector-size", "the size of each sector in bytes (default 512)", "BYTES");
+    opts.optopt("c", "count", "the number of sectors in a new empty RAM disk", "SECTORS");
+    opts.optopt("f", "file", "copy the RAM disk's contents from the given file, e.g., a disk image", "PATH");
+    opts.optopt("m", "module", "copy the RAM disk's contents from the given bootloader data module", "NAME");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            print_usage(opts);
+            return Err(e.to_string());
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return Ok(());
+    }
+
+    let sector_size = match matches.opt_str("s") {
+        Some(s) => s.parse::<usize>().map_err(|_| format!("mkramdisk: invalid sector size {:?}", s))?,
+        None => ramdisk::DEFAULT_SECTOR_SIZE_IN_BYTES,
+    };
+
+    let disk = match (matches.opt_str("c"), matches.opt_str("f"), matches.opt_str("m")) {
+        (Some(count), None, None) => {
+            let count = count.parse::<usize>().map_err(|_| format!("mkramdisk: invalid sector count {:?}", count))?;
+            RamDisk::new(sector_size, count)
+        }
+        (None, Some(path_string), None) => {
+            let taskref = match task::get_my_current_task() {
+                Some(t) => t,
+                None => {
+                    return Err("failed to get current task".into());
+                }
+            };
+            let working_dir = {
+                let locked_task = taskref.lock();
+                let curr_env = locked_task.env.lock();
+                Arc::clone(&curr_env.working_dir)
+            };
+            let path = Path::new(path_string);
+            let file = match path.get(&working_dir) {
+                Some(FileOrDir::File(f)) => f,
+                Some(FileOrDir::Dir(_)) => return Err(format!("mkramdisk: {} is a directory", path)),
+                None => return Err(format!("mkramdisk: couldn't find file {}", path)),
+            };
+            RamDisk::from_file(sector_size, &file)
+        }
+        (None, None, Some(module_name)) => RamDisk::from_module(sector_size, &module_name),
+        _ => {
+            print_usage(opts);
+            return Err("mkramdisk: exactly one of -c, -f, or -m must be given".into());
+        }
+    }.map_err(|e| format!("mkramdisk: {}", e))?;
+
+    let (_disk, name) = ramdisk::register(disk);
+    println!("Created RAM disk {}", name);
+    Ok(())
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: mkramdisk [-s BYTES] -c SECTORS
+       mkramdisk [-s BYTES] -f PATH
+       mkramdisk [-s BYTES] -m MODULE
+Create a RAM disk and register it as a storage device, e.g., sd2, which can then be used like any other drive.
+The RAM disk is either empty, or a copy of a file or a bootloader data module (loaded as d#MODULE).";
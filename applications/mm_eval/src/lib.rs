@@ -16,6 +16,7 @@ extern crate tsc;
 extern crate memory_structs;
 extern crate apic;
 extern crate runqueue;
+extern crate multiple_heaps;
 
 use alloc::string::String;
 use alloc::vec::Vec;
@@ -31,7 +32,7 @@ use getopts::{Matches, Options};
 use kernel_config::memory::PAGE_SIZE;
 use memory_structs::PageRange;
 use libtest::{hpet_timing_overhead, hpet_2_ns, calculate_stats, check_myrq};
-use memory::{get_frame_allocator_ref, VirtualAddress, Mapper, MappedPages, EntryFlags, mapped_pages_unmap};
+use memory::{get_frame_allocator_ref, frame_stats, VirtualAddress, Mapper, MappedPages, EntryFlags, mapped_pages_unmap};
 use mapper_spillful::MapperSpillful;
 use hpet::get_hpet;
 
@@ -181,6 +182,36 @@ fn unmap_spillful(
 }
 
 
+/// The size of the heap objects allocated by the heap reclamation evaluation.
+const HEAP_OBJECT_SIZE: usize = 1024;
+
+/// Allocates and then deallocates enough heap objects to fill `size_in_bytes` bytes, 
+/// which leaves empty pages in this core's heap, and then reclaims them.
+/// Returns the time taken to reclaim the empty pages and the number of frames that were returned to the frame allocator.
+fn heap_reclaim(size_in_bytes: usize, hpet_overhead: u64) -> Result<(u64, usize), &'static str> {
+    {
+        let num_objects = size_in_bytes / HEAP_OBJECT_SIZE;
+        let mut objects: Vec<Vec<u8>> = Vec::with_capacity(num_objects);
+        for _ in 0..num_objects {
+            let mut object = Vec::with_capacity(HEAP_OBJECT_SIZE);
+            object.push(0u8);
+            objects.push(object);
+        }
+    }
+
+    let hpet = get_hpet().ok_or("couldn't get HPET timer")?;
+    let free_frames_before = frame_stats().ok_or("Couldn't get frame allocator")?.free_frames;
+    let start_time = hpet.get_counter();
+
+    let _reclaimed = multiple_heaps::reclaim_empty_pages()?;
+
+    let end_time = hpet.get_counter() - hpet_overhead;
+    let free_frames_after = frame_stats().ok_or("Couldn't get frame allocator")?.free_frames;
+
+    Ok((hpet_2_ns(end_time - start_time), free_frames_after.saturating_sub(free_frames_before)))
+}
+
+
 pub fn main(args: Vec<String>) -> isize {
 
     let mut opts = Options::new();
@@ -188,6 +219,7 @@ pub fn main(args: Vec<String>) -> isize {
     opts.optflag("p", "spillful", "run the state spillful memory mapping evaluation");
     opts.optopt("n", "", "create 'N' mappings ", "NUM");
     opts.optopt("s", "--size", "specify the size (in pages) for each mapping", "SIZE");
+    opts.optflag("r", "reclaim", "evaluate reclaiming the empty heap pages left by deallocating as much heap memory as the mappings would use");
 
     let matches = match opts.parse(&args) {
         Ok(m) => m,
@@ -247,6 +279,25 @@ pub fn rmain(matches: &Matches, _opts: &Options) -> Result<(), &'static str> {
     // calculate overhead of reading hpet counter
     let overhead = hpet_timing_overhead()?;
 
+    if matches.opt_present("r") {
+        let mut reclaim_times: Vec<u64> = Vec::with_capacity(TRIES);
+        let mut reclaimed_frames: Vec<u64> = Vec::with_capacity(TRIES);
+        for _ in 0..TRIES {
+            let (time, frames) = heap_reclaim(num_mappings * size_in_pages * PAGE_SIZE, overhead)?;
+            reclaim_times.push(time);
+            reclaimed_frames.push(frames as u64);
+        }
+
+        println!("Reclaim Heap Pages (ns)");
+        let stats_reclaim = calculate_stats(&mut reclaim_times).ok_or("Could not calculate stats for heap reclamation")?;
+        println!("{:?}", stats_reclaim);
+
+        println!("Reclaimed Frames");
+        let stats_frames = calculate_stats(&mut reclaimed_frames).ok_or("Could not calculate stats for reclaimed frames")?;
+        println!("{:?}", stats_frames);
+        return Ok(());
+    }
+
     for _ in 0..TRIES 
     {
         // (1) create mappings
@@ -313,7 +364,8 @@ fn print_usage(opts: &Options) {
 
 const USAGE: &'static str = "Usage: mm_eval [ARGS]
 Evaluates two different memory mapping implementations.
-The normal spill-free MappedPages approach is evaluated by default.";
+The normal spill-free MappedPages approach is evaluated by default.
+With -r, evaluates how quickly the per-core heaps return empty pages to the frame allocator instead.";
 
 } // end of cfg_if
 else {
@@ -0,0 +1,128 @@
+#![no_std]
+#[macro_use] extern crate terminal_print;
+
+#[macro_use] extern crate alloc;
+extern crate spin;
+extern crate task;
+extern crate getopts;
+extern crate path;
+extern crate fs_node;
+extern crate vfs_node;
+extern crate mount_table;
+extern crate storage_manager;
+extern crate fat32;
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::string::ToString;
+use alloc::collections::{BTreeMap, BTreeSet};
+use spin::Mutex;
+use getopts::Options;
+use path::Path;
+use fs_node::{DirRef, FileOrDir, FsNode, NodeAttributes, Permissions, Watchers};
+use vfs_node::VFSDirectory;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    match mount(args) {
+        Ok(_) => 0,
+        Err(err) => {
+            println!("{}", err);
+            -1
+        }
+    }
+}
+
+pub fn mount(args: Vec<String>) -> Result<(), String> {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("t", "type", "the type of filesystem to mount, either \"memfs\" or \"fat32\"", "TYPE");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            print_usage(opts);
+            return Err(e.to_string());
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return Ok(());
+    }
+
+    // With no arguments, list all of the mounted filesystems.
+    if matches.free.is_empty() {
+        for info in mount_table::mounts() {
+            println!("{} on {} type {}", info.source, info.path, info.fs_type);
+        }
+        return Ok(());
+    }
+
+    let taskref = match task::get_my_current_task() {
+        Some(t) => t,
+        None => {
+            return Err("failed to get current task".into());
+        }
+    };
+
+    let working_dir = {
+        let locked_task = taskref.lock();
+        let curr_env = locked_task.env.lock();
+        Arc::clone(&curr_env.working_dir)
+    };
+
+    let fs_type = matches.opt_str("t").unwrap_or_else(|| String::from("memfs"));
+    let (source, path_string) = match (fs_type.as_str(), matches.free.len()) {
+        ("memfs", 1) => (String::from("none"), &matches.free[0]),
+        ("fat32", 2) => (matches.free[0].clone(), &matches.free[1]),
+        ("memfs", _) | ("fat32", _) => {
+            print_usage(opts);
+            return Err("mount: wrong number of arguments".into());
+        }
+        _ => return Err(format!("mount: unsupported filesystem type {:?}", fs_type)),
+    };
+
+    let path = Path::new(path_string.clone());
+    let mount_point = match path.get(&working_dir) {
+        Some(FileOrDir::Dir(d)) => d,
+        Some(FileOrDir::File(_)) => return Err(format!("mount: {} is not a directory", path)),
+        None => return Err(format!("mount: couldn't find directory {}", path)),
+    };
+    // The mounted filesystem's root must have the same name as its mount point.
+    let name = mount_point.lock().get_name();
+
+    let fs_root: DirRef = match fs_type.as_str() {
+        "fat32" => {
+            let device = storage_manager::storage_device_by_name(&source)
+                .ok_or_else(|| format!("mount: couldn't find storage device {:?}", source))?;
+            fat32::open(device, name).map_err(|e| format!("mount: {}", e))?
+        }
+        _ => {
+            let root = VFSDirectory {
+                name,
+                children: BTreeMap::new(),
+                links: BTreeSet::new(),
+                parent: Weak::<Mutex<VFSDirectory>>::new(),
+                attributes: NodeAttributes::new(Permissions::all()),
+                watchers: Watchers::new(),
+            };
+            Arc::new(Mutex::new(root)) as DirRef
+        }
+    };
+
+    mount_table::mount(&mount_point, fs_root, &fs_type, &source).map_err(|e| format!("mount: {}", e))
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: mount [-t memfs] PATH
+       mount -t fat32 DEVICE PATH
+       mount
+Mount a new filesystem onto the directory at PATH, hiding its current contents until it is unmounted.
+A memfs filesystem is an empty in-memory filesystem; a fat32 filesystem is read from a storage device or partition, e.g., sd0 or sd0p1.
+With no arguments, list all mounted filesystems.";
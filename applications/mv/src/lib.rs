@@ -0,0 +1,125 @@
+#![no_std]
+#[macro_use] extern crate terminal_print;
+
+#[macro_use] extern crate alloc;
+extern crate task;
+extern crate getopts;
+extern crate path;
+extern crate fs_node;
+extern crate fs_copy;
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::string::ToString;
+use getopts::Options;
+use path::Path;
+use fs_node::{DirRef, FileOrDir, FsNode};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    match move_nodes(args) {
+        Ok(_) => 0,
+        Err(err) => {
+            println!("{}", err);
+            -1
+        }
+    }
+}
+
+pub fn move_nodes(args: Vec<String>) -> Result<(), String> {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            print_usage(opts);
+            return Err(e.to_string());
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return Ok(());
+    }
+
+    if matches.free.len() < 2 {
+        print_usage(opts);
+        return Err("mv: missing source or destination".into());
+    }
+
+    let taskref = match task::get_my_current_task() {
+        Some(t) => t,
+        None => {
+            return Err("failed to get current task".into());
+        }
+    };
+
+    let working_dir = {
+        let locked_task = taskref.lock();
+        let curr_env = locked_task.env.lock();
+        Arc::clone(&curr_env.working_dir)
+    };
+
+    let (sources, destination) = matches.free.split_at(matches.free.len() - 1);
+    let destination = Path::new(destination[0].clone());
+    let destination_node = destination.get(&working_dir);
+    if sources.len() > 1 {
+        match destination_node {
+            Some(FileOrDir::Dir(_)) => { }
+            _ => return Err(format!("mv: destination {} is not a directory", destination)),
+        }
+    }
+
+    for source_string in sources {
+        let source = Path::new(source_string.clone());
+        // Move a symbolic link itself, not the node it refers to.
+        let node = match source.get_no_follow(&working_dir) {
+            Some(node) => node,
+            None => return Err(format!("mv: couldn't find path {}", source)),
+        };
+        let name = node.get_name();
+        let source_dir = node.get_parent_dir().ok_or_else(|| format!("mv: cannot move {}", source))?;
+
+        // Move the node into the destination directory if it exists, otherwise move it to the destination path.
+        let (destination_dir, new_name) = match destination_node {
+            Some(FileOrDir::Dir(ref d)) => (Arc::clone(d), name.clone()),
+            _ => (parent_dir(&destination, &working_dir)?, destination.basename().to_string()),
+        };
+
+        // Don't hold both locks at once, since the source and destination may be the same directory.
+        let source_fs = source_dir.lock().filesystem_id();
+        let destination_fs = destination_dir.lock().filesystem_id();
+        if source_fs == destination_fs {
+            fs_node::move_node(&source_dir, &name, &destination_dir, &new_name)
+                .map_err(|e| format!("mv: cannot move {} to {}: {}", source, destination, e))?;
+        } else {
+            // A node can't be moved between filesystems, so copy it and then remove the original.
+            fs_copy::copy(&node, &destination_dir, &new_name, true)
+                .map_err(|e| format!("mv: cannot copy {} to {}: {}", source, destination, e))?;
+            source_dir.lock().remove(&node)
+                .ok_or_else(|| format!("mv: copied {} to {}, but couldn't remove the original", source, destination))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the directory that contains the trailing component of the given `path`.
+fn parent_dir(path: &Path, working_dir: &DirRef) -> Result<DirRef, String> {
+    let parent = path.parent().ok_or_else(|| format!("mv: invalid destination {}", path))?;
+    match parent.get(working_dir) {
+        Some(FileOrDir::Dir(d)) => Ok(d),
+        _ => Err(format!("mv: directory {} does not exist", parent)),
+    }
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: mv SOURCE DEST
+   or: mv SOURCE... DIRECTORY
+Rename SOURCE to DEST, or move SOURCE(s) into DIRECTORY";
@@ -66,7 +66,8 @@ pub fn remove_node(args: Vec<String>) -> Result<(), String> {
 
     for path_string in &matches.free {
         let path = Path::new(path_string.clone());
-        let node_to_delete = match path.get(&working_dir) {
+        // Remove a symbolic link itself, not the node it refers to.
+        let node_to_delete = match path.get_no_follow(&working_dir) {
             Some(node) => node,
             _ => return Err(format!("Couldn't find path {}", path)),
         };
@@ -74,14 +75,19 @@ pub fn remove_node(args: Vec<String>) -> Result<(), String> {
         // Only remove directories if the user specified "-r". 
         let can_remove_dirs = matches.opt_present("r");
         let path_error = || { format!("Couldn't remove {} from its parent directory.", &path) };
-        let parent = node_to_delete.get_parent_dir().ok_or_else(path_error)?;
 
         match node_to_delete {
             FileOrDir::File(_) => {
-                parent.lock().remove(&node_to_delete).ok_or_else(path_error)?;
+                // Remove the entry with the given name, which may be a hard link in a different directory than the file's parent.
+                let parent = match path.parent().and_then(|p| p.get(&working_dir)) {
+                    Some(FileOrDir::Dir(dir)) => dir,
+                    _ => node_to_delete.get_parent_dir().ok_or_else(path_error)?,
+                };
+                parent.lock().unlink(path.basename()).ok_or_else(path_error)?;
             } 
             FileOrDir::Dir(_) => {
                 if can_remove_dirs {
+                    let parent = node_to_delete.get_parent_dir().ok_or_else(path_error)?;
                     parent.lock().remove(&node_to_delete).ok_or_else(path_error)?;
                 } else {
                     println!("Skipping the removal of directory '{}', try specifying the \"-r\" flag", 
@@ -0,0 +1,132 @@
+//! Tests spawning an application in many namespaces that are cloned from one namespace,
+//! in which the application crate is copied on write rather than loaded again from its object file.
+//!
+//! The application crate is first loaded into a template namespace without being run.
+//! Each namespace cloned from it with `CrateNamespace::clone_on_write()` shares that crate,
+//! so spawning the application in a cloned namespace gives it a copy-on-write copy of the crate,
+//! which only duplicates the pages that are written by relocations or by the new instance of the application.
+
+#![no_std]
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate memory;
+extern crate mod_mgmt;
+extern crate path;
+extern crate spawn;
+extern crate task;
+
+use alloc::{
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use getopts::{Matches, Options};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("n", "namespaces", "the number of cloned namespaces to spawn the application in (default 4)", "NUM");
+    opts.optopt("a", "app", "the name of the application to spawn (default hello)", "APP");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+#[cfg(internal_deps)]
+fn rmain(matches: Matches) -> Result<(), String> {
+    use mod_mgmt::CrateNamespace;
+    use path::Path;
+    use task::ExitValue;
+
+    let num_namespaces = match matches.opt_str("n") {
+        Some(n) => n.parse::<usize>().map_err(|_e| format!("invalid number of namespaces {:?}", n))?,
+        None => 4,
+    };
+    let app_name = matches.opt_str("a").unwrap_or_else(|| String::from("hello"));
+
+    let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or_else(|| format!("couldn't get kernel MMI"))?;
+    let template = mod_mgmt::create_application_namespace(None)?;
+    let (app_file, _ns) = CrateNamespace::get_crate_object_file_starting_with(&template, &format!("{}-", app_name))
+        .ok_or_else(|| format!("couldn't find a single application crate object file for {:?}", app_name))?;
+    let app_path = Path::new(app_file.lock().get_absolute_path());
+
+    // the application crate is loaded into the template namespace, but never run there
+    let template_app = CrateNamespace::load_crate_as_application(&template, &app_file, &kernel_mmi_ref, false)?;
+    let free_frames_before = memory::frame_stats().ok_or_else(|| format!("couldn't get frame allocator"))?.free_frames;
+
+    let mut tasks = Vec::with_capacity(num_namespaces);
+    for i in 0..num_namespaces {
+        let namespace = Arc::new(template.clone_on_write());
+        // the task starts blocked, because its application crate is removed from the namespace once it exits
+        let taskref = spawn::new_application_task_builder(app_path.clone(), Some(Arc::clone(&namespace)))?
+            .name(format!("{}_cow_{}", app_name, i))
+            .block()
+            .spawn()?;
+
+        // the new instance must have its own crate, not the one it shares with the template namespace
+        let app_crate = namespace.get_crate(&template_app.lock_as_ref().crate_name)
+            .ok_or_else(|| format!("the application crate is missing from cloned namespace {}", i))?;
+        if app_crate.ptr_eq(&template_app) || app_crate.is_shared() {
+            return Err(format!("cloned namespace {} still shares the application crate with the template namespace", i));
+        }
+        tasks.push(taskref);
+    }
+    let free_frames_after = memory::frame_stats().ok_or_else(|| format!("couldn't get frame allocator"))?.free_frames;
+    println!("Spawned {} in {} cloned namespaces, which used {} frames.", 
+        app_name, num_namespaces, free_frames_before.saturating_sub(free_frames_after)
+    );
+
+    for (i, taskref) in tasks.iter().enumerate() {
+        taskref.unblock();
+        taskref.join()?;
+        match taskref.take_exit_value() {
+            Some(ExitValue::Completed(exit_status)) => match exit_status.downcast_ref::<isize>() {
+                Some(0) => { }
+                Some(status) => return Err(format!("the application in cloned namespace {} exited with status {}", i, status)),
+                None => return Err(format!("the application in cloned namespace {} returned an unexpected value", i)),
+            },
+            Some(ExitValue::Killed(reason)) => return Err(format!("the application in cloned namespace {} was killed: {:?}", i, reason)),
+            None => return Err(format!("couldn't get the exit value of the application in cloned namespace {}", i)),
+        }
+    }
+
+    println!("Test passed: each cloned namespace ran its own copy of {}.", app_name);
+    Ok(())
+}
+
+#[cfg(not(internal_deps))]
+fn rmain(_matches: Matches) -> Result<(), String> {
+    Err(String::from("Theseus was not compiled with the 'internal_deps' config enabled, which is required to copy crates on write."))
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &'static str = "Usage: test_cow_namespace [OPTION]
+Loads an application into a namespace without running it, then spawns it in several namespaces cloned from that one,
+each of which gets a copy-on-write copy of the application crate.";
@@ -0,0 +1,149 @@
+//! Tests `Private` mappings of a memory-backed file, which are copy-on-write mappings
+//! whose pages are only copied from the file when they're first written.
+//!
+//! This checks that only the written pages of the mapping get their own frames,
+//! that their contents match the file, that writes to the mapping never reach the file,
+//! and that writes to the file are visible in the pages of the mapping that weren't yet written.
+
+#![no_std]
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate fs_node;
+extern crate kernel_config;
+extern crate memfs;
+extern crate memory;
+extern crate mmap;
+extern crate root;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Matches, Options};
+use fs_node::{FileOrDir, FileRef};
+use kernel_config::memory::PAGE_SIZE;
+use memfs::MemFile;
+use mmap::{FileMapping, MapMode};
+
+
+const FILE_NAME: &'static str = "test_mmap_file";
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("p", "pages", "the size in pages of the file to map (default 16, at least 3)", "NUM");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    let num_pages = match matches.opt_str("p") {
+        Some(n) => n.parse::<usize>().map_err(|_e| format!("invalid number of pages {:?}", n))?,
+        None => 16,
+    };
+    if num_pages < 3 {
+        return Err(format!("the file must be at least 3 pages large"));
+    }
+
+    // the file ends partway through its last page
+    let length = num_pages * PAGE_SIZE - PAGE_SIZE / 2;
+    let contents: Vec<u8> = (0..length).map(|i| (i % 251) as u8).collect();
+    let parent = root::get_root();
+    let file = MemFile::new(String::from(FILE_NAME), parent)?;
+    let result = file.lock().write(&contents, 0)
+        .map_err(String::from)
+        .and_then(|_| test_private_mapping(&file, &contents));
+    parent.lock().remove(&FileOrDir::File(file));
+    result?;
+
+    println!("Test passed: the private mapping of {} pages was copied on write.", num_pages);
+    Ok(())
+}
+
+
+fn test_private_mapping(file: &FileRef, contents: &[u8]) -> Result<(), String> {
+    let length = contents.len();
+    let mut mapping = mmap::map_file(file, 0, length, MapMode::Private)?;
+    if mapping.is_shared() {
+        return Err(format!("the private mapping of a memory-backed file should be a copy-on-write copy"));
+    }
+    check_copied_pages(file, &mapping, 0)?;
+
+    // reading doesn't copy any pages
+    let mapped = mapping.as_slice()?;
+    if let Some(i) = (0..length).find(|&i| mapped[i] != contents[i]) {
+        return Err(format!("the mapping's byte at offset {:#X} ({}) differs from the file's ({})", i, mapped[i], contents[i]));
+    }
+    check_copied_pages(file, &mapping, 0)?;
+
+    // writing a page copies only that page, and the write must not reach the file
+    mapping.as_slice_mut()?[PAGE_SIZE] = !contents[PAGE_SIZE];
+    check_copied_pages(file, &mapping, 1)?;
+    let mut file_byte = [0u8; 1];
+    file.lock().read(&mut file_byte, PAGE_SIZE)?;
+    if file_byte[0] != contents[PAGE_SIZE] {
+        return Err(format!("a write to the private mapping was written to the file"));
+    }
+
+    // writes to the file are visible in the pages that weren't yet copied, but not in the copied page
+    file.lock().write(&[!contents[2 * PAGE_SIZE]], 2 * PAGE_SIZE)?;
+    file.lock().write(&[!contents[PAGE_SIZE + 1]], PAGE_SIZE + 1)?;
+    let mapped = mapping.as_slice()?;
+    if mapped[2 * PAGE_SIZE] != !contents[2 * PAGE_SIZE] {
+        return Err(format!("a write to the file wasn't visible in a page of the private mapping that wasn't yet written"));
+    }
+    if mapped[PAGE_SIZE + 1] != contents[PAGE_SIZE + 1] {
+        return Err(format!("a write to the file was visible in a page of the private mapping that was already written"));
+    }
+    check_copied_pages(file, &mapping, 1)
+}
+
+
+/// Returns an error unless exactly `expected` pages of the given `mapping` are mapped to frames
+/// other than those that hold the file's contents, i.e., have been copied.
+fn check_copied_pages(file: &FileRef, mapping: &FileMapping, expected: usize) -> Result<(), String> {
+    let f = file.lock();
+    let file_mp = f.as_mapping()?;
+    let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or_else(|| format!("couldn't get kernel MMI"))?;
+    let kernel_mmi = kernel_mmi_ref.lock();
+    let mp = mapping.mapped_pages();
+    let copied = (0..mp.size_in_pages())
+        .filter(|&i| kernel_mmi.page_table.translate_page(*mp.start() + i) != kernel_mmi.page_table.translate_page(*file_mp.start() + i))
+        .count();
+    if copied != expected {
+        return Err(format!("{} pages of the private mapping were copied, expected {}", copied, expected));
+    }
+    Ok(())
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+const USAGE: &'static str = "Usage: test_mmap [OPTION]
+Creates a memory-backed file, maps it privately, and checks that only the pages of the mapping that are written
+are copied from the file, and that writes to the mapping don't change the file.";
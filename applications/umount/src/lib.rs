@@ -0,0 +1,84 @@
+#![no_std]
+#[macro_use] extern crate terminal_print;
+
+#[macro_use] extern crate alloc;
+extern crate task;
+extern crate getopts;
+extern crate path;
+extern crate fs_node;
+extern crate mount_table;
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::string::ToString;
+use getopts::Options;
+use path::Path;
+use fs_node::FileOrDir;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    match unmount(args) {
+        Ok(_) => 0,
+        Err(err) => {
+            println!("{}", err);
+            -1
+        }
+    }
+}
+
+pub fn unmount(args: Vec<String>) -> Result<(), String> {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(e) => {
+            print_usage(opts);
+            return Err(e.to_string());
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return Ok(());
+    }
+
+    if matches.free.is_empty() {
+        return Err("umount: missing argument".into());
+    }
+
+    let taskref = match task::get_my_current_task() {
+        Some(t) => t,
+        None => {
+            return Err("failed to get current task".into());
+        }
+    };
+
+    let working_dir = {
+        let locked_task = taskref.lock();
+        let curr_env = locked_task.env.lock();
+        Arc::clone(&curr_env.working_dir)
+    };
+
+    for path_string in &matches.free {
+        let path = Path::new(path_string.clone());
+        // Path resolution yields the root directory of the topmost filesystem mounted at this path.
+        let dir = match path.get(&working_dir) {
+            Some(FileOrDir::Dir(d)) => d,
+            Some(FileOrDir::File(_)) => return Err(format!("umount: {} is not a directory", path)),
+            None => return Err(format!("umount: couldn't find directory {}", path)),
+        };
+        mount_table::unmount(&dir).map_err(|e| format!("umount: {}: {}", path, e))?;
+    }
+
+    Ok(())
+}
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: umount PATH
+Unmount the filesystem that is mounted on the directory at PATH, revealing the directory's original contents.";
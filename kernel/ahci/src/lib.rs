@@ -0,0 +1,811 @@
+//! Support for accessing SATA drives through an AHCI (Advanced Host Controller Interface) controller,
+//! which is the standard, native interface for SATA, unlike the legacy IDE emulation mode supported by the `ata` crate.
+//!
+//! The primary structs of interest are [`AhciController`](struct.AhciController.html),
+//! which discovers the drives attached to each of its ports,
+//! and [`AhciDrive`](struct.AhciDrive.html), which implements the `StorageDevice` trait.
+//!
+//! All transfers use DMA. Each port has a command list with one command header per command slot,
+//! a receive area for the FISes (Frame Information Structures) that the drive sends back,
+//! and one command table and DMA buffer for each of the command slots that we use.
+//! Large transfers are split across multiple command slots that are issued together;
+//! if both the controller and the drive support Native Command Queuing (NCQ),
+//! those commands are issued as queued commands that the drive may complete in any order.
+//!
+//! Command completion is detected by polling the port's registers, so interrupts from the controller are disabled.
+//!
+//! The AHCI specification is available here:
+//! <https://www.intel.com/content/www/us/en/io/serial-ata/serial-ata-ahci-spec-rev1-3-1.html>
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate volatile;
+extern crate owning_ref;
+extern crate kernel_config;
+extern crate memory;
+extern crate pci;
+extern crate pit_clock;
+extern crate ata;
+extern crate storage_device;
+
+use core::{
+	cmp::min,
+	mem::size_of,
+	ops::DerefMut,
+	sync::atomic::{fence, Ordering},
+};
+use alloc::{
+	boxed::Box,
+	sync::Arc,
+	vec::Vec,
+};
+use spin::Mutex;
+use volatile::{Volatile, ReadOnly};
+use owning_ref::BoxRefMut;
+use kernel_config::memory::PAGE_SIZE;
+use memory::{EntryFlags, FrameRange, MappedPages, PhysicalAddress, PhysicalMemoryArea,
+	allocate_pages_by_bytes, create_contiguous_mapping, get_frame_allocator_ref, get_kernel_mmi_ref};
+use pci::PciDevice;
+use ata::AtaIdentifyData;
+use storage_device::{StorageDevice, StorageDeviceRef, StorageController};
+
+
+/// The mapping flags used for the HBA's registers and for the memory that the HBA accesses via DMA.
+const AHCI_MAPPING_FLAGS: EntryFlags = EntryFlags::from_bits_truncate(
+	EntryFlags::PRESENT.bits() |
+	EntryFlags::WRITABLE.bits() |
+	EntryFlags::NO_CACHE.bits() |
+	EntryFlags::NO_EXECUTE.bits()
+);
+
+/// The sector size of drives that don't report a larger logical sector size.
+const DEFAULT_SECTOR_SIZE_IN_BYTES: usize = 512;
+/// The size of the response to an identify device command.
+const IDENTIFY_DATA_SIZE_IN_BYTES: usize = 512;
+
+/// An AHCI controller can have at most 32 ports.
+const MAX_PORTS: usize = 32;
+/// A port's command list can have at most 32 command slots.
+const MAX_COMMAND_SLOTS: usize = 32;
+/// The maximum number of command slots that we use on each port,
+/// which bounds the number of commands that are issued to a port at once.
+const MAX_QUEUED_COMMANDS: usize = 8;
+/// The number of bytes transferred by a single command, which is the size of each command slot's DMA buffer.
+const BYTES_PER_COMMAND: usize = 32 * 1024;
+
+/// The size of a port's command list, which must be 1024-byte aligned.
+const COMMAND_LIST_SIZE_IN_BYTES: usize = MAX_COMMAND_SLOTS * size_of::<CommandHeader>();
+/// The offset of the received FIS area, which must be 256-byte aligned, in the same allocation as the command list.
+const RECEIVED_FIS_OFFSET: usize = COMMAND_LIST_SIZE_IN_BYTES;
+/// The size of a port's received FIS area.
+const RECEIVED_FIS_SIZE_IN_BYTES: usize = 256;
+/// The space reserved for each command table, which must be 128-byte aligned.
+const COMMAND_TABLE_SIZE_IN_BYTES: usize = 256;
+
+/// The size of a Register Host-to-Device FIS, in bytes.
+const H2D_FIS_SIZE_IN_BYTES: usize = 20;
+/// The FIS type of a Register Host-to-Device FIS.
+const FIS_TYPE_REGISTER_H2D: u8 = 0x27;
+/// Set in a Register Host-to-Device FIS to indicate that it carries a command.
+const FIS_H2D_COMMAND: u8 = 0x80;
+/// Set in the device field of a command FIS to use LBA addressing.
+const DEVICE_LBA_MODE: u8 = 1 << 6;
+
+/// Set in a command header when the command writes to the drive.
+const COMMAND_HEADER_WRITE: u16 = 1 << 6;
+/// The mask of the byte count field in a PRDT entry, which holds the byte count minus one.
+const PRD_BYTE_COUNT_MASK: u32 = (1 << 22) - 1;
+
+// Bits in the HBA's `cap` register.
+const CAP_64_BIT_ADDRESSING: u32 = 1 << 31;
+const CAP_NATIVE_COMMAND_QUEUING: u32 = 1 << 30;
+// Bits in the HBA's `ghc` register.
+const GHC_AHCI_ENABLE: u32 = 1 << 31;
+const GHC_INTERRUPT_ENABLE: u32 = 1 << 1;
+// Bits in the HBA's `cap2` and `bohc` registers, for the BIOS/OS handoff.
+const CAP2_BIOS_HANDOFF: u32 = 1 << 0;
+const BOHC_BIOS_BUSY: u32 = 1 << 4;
+const BOHC_OS_OWNED: u32 = 1 << 1;
+const BOHC_BIOS_OWNED: u32 = 1 << 0;
+
+// Bits in a port's `cmd` register.
+const PORT_CMD_START: u32 = 1 << 0;
+const PORT_CMD_FIS_RECEIVE_ENABLE: u32 = 1 << 4;
+const PORT_CMD_FIS_RECEIVE_RUNNING: u32 = 1 << 14;
+const PORT_CMD_LIST_RUNNING: u32 = 1 << 15;
+// Bits in a port's `is` register.
+const PORT_IS_TASK_FILE_ERROR: u32 = 1 << 30;
+// Bits in a port's `tfd` register, which mirror the ATA status register.
+const TFD_ERROR: u32 = 0x01;
+const TFD_DATA_REQUEST: u32 = 0x08;
+const TFD_BUSY: u32 = 0x80;
+// Values of the device detection and interface power management fields in a port's `ssts` register.
+const SSTS_DEVICE_PRESENT: u32 = 0x3;
+const SSTS_INTERFACE_ACTIVE: u32 = 0x1;
+
+/// The value of a port's `sig` register when an ATA drive (a disk) is attached.
+const SATA_SIGNATURE_ATA: u32 = 0x0000_0101;
+/// The value of a port's `sig` register when an ATAPI drive (e.g., an optical drive) is attached.
+const SATA_SIGNATURE_ATAPI: u32 = 0xEB14_0101;
+
+/// How long to wait between polls of a register.
+const POLL_INTERVAL_MICROSECONDS: u32 = 10;
+/// How long to wait for a port to start or stop.
+const PORT_TIMEOUT_MICROSECONDS: u32 = 500_000;
+/// How long to wait for a command to complete.
+const COMMAND_TIMEOUT_MICROSECONDS: u32 = 5_000_000;
+/// How long the BIOS may take to finish its outstanding commands after relinquishing ownership of the HBA.
+const BIOS_HANDOFF_TIMEOUT_MICROSECONDS: u32 = 2_000_000;
+
+
+/// The ATA commands that we issue to an AHCI drive.
+/// Only 48-bit LBA commands are used, as all SATA drives support them.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug)]
+enum AhciCommand {
+	/// Read sectors using DMA (48-bit LBA)
+	ReadDmaExt       = 0x25,
+	/// Write sectors using DMA (48-bit LBA)
+	WriteDmaExt      = 0x35,
+	/// Read sectors using NCQ (48-bit LBA)
+	ReadFpdmaQueued  = 0x60,
+	/// Write sectors using NCQ (48-bit LBA)
+	WriteFpdmaQueued = 0x61,
+	/// Flush the drive's cache (48-bit LBA).
+	CacheFlushExt    = 0xEA,
+	/// Get identifying details of an ATA drive.
+	IdentifyDevice   = 0xEC,
+}
+
+
+/// The generic host control registers of an AHCI controller (HBA),
+/// followed by the registers of each of its ports.
+/// These are memory-mapped at the address in the controller's PCI BAR5, called the ABAR.
+#[repr(C)]
+struct HbaRegisters {
+	/// Host capabilities
+	cap:        ReadOnly<u32>,       // 0x00
+	/// Global host control
+	ghc:        Volatile<u32>,       // 0x04
+	/// Interrupt status, one bit per port
+	_is:        Volatile<u32>,       // 0x08
+	/// Ports implemented, one bit per port
+	pi:         ReadOnly<u32>,       // 0x0C
+	/// Version
+	vs:         ReadOnly<u32>,       // 0x10
+	_ccc_ctl:   Volatile<u32>,       // 0x14
+	_ccc_ports: Volatile<u32>,       // 0x18
+	_em_loc:    Volatile<u32>,       // 0x1C
+	_em_ctl:    Volatile<u32>,       // 0x20
+	/// Extended host capabilities
+	cap2:       ReadOnly<u32>,       // 0x24
+	/// BIOS/OS handoff control and status
+	bohc:       Volatile<u32>,       // 0x28
+	_padding0:  [u8; 212],           // 0x2C - 0xFF
+	ports:      [PortRegisters; MAX_PORTS], // 0x100 - 0x10FF
+}
+
+/// The registers of a single port on an AHCI controller.
+#[repr(C)]
+struct PortRegisters {
+	/// Command list base address, lower 32 bits, 1024-byte aligned
+	clb:        Volatile<u32>,       // 0x00
+	/// Command list base address, upper 32 bits
+	clbu:       Volatile<u32>,       // 0x04
+	/// Received FIS base address, lower 32 bits, 256-byte aligned
+	fb:         Volatile<u32>,       // 0x08
+	/// Received FIS base address, upper 32 bits
+	fbu:        Volatile<u32>,       // 0x0C
+	/// Interrupt status
+	is:         Volatile<u32>,       // 0x10
+	/// Interrupt enable
+	ie:         Volatile<u32>,       // 0x14
+	/// Command and status
+	cmd:        Volatile<u32>,       // 0x18
+	_reserved0: u32,                 // 0x1C
+	/// Task file data, i.e., the drive's status and error registers
+	tfd:        ReadOnly<u32>,       // 0x20
+	/// Signature of the attached device
+	sig:        ReadOnly<u32>,       // 0x24
+	/// SATA status
+	ssts:       ReadOnly<u32>,       // 0x28
+	/// SATA control
+	_sctl:      Volatile<u32>,       // 0x2C
+	/// SATA error
+	serr:       Volatile<u32>,       // 0x30
+	/// SATA active, one bit per command slot with an outstanding queued command
+	sact:       Volatile<u32>,       // 0x34
+	/// Command issue, one bit per command slot with an outstanding command
+	ci:         Volatile<u32>,       // 0x38
+	_sntf:      Volatile<u32>,       // 0x3C
+	_fbs:       Volatile<u32>,       // 0x40
+	_reserved1: [u32; 11],           // 0x44 - 0x6F
+	_vendor:    [u32; 4],            // 0x70 - 0x7F
+}
+
+impl PortRegisters {
+	/// Stops this port from processing its command list and receiving FISes,
+	/// which must be done before changing the command list or received FIS addresses.
+	fn stop(&mut self) -> Result<(), &'static str> {
+		let cmd = self.cmd.read();
+		self.cmd.write(cmd & !PORT_CMD_START);
+		wait_until(PORT_TIMEOUT_MICROSECONDS, || self.cmd.read() & PORT_CMD_LIST_RUNNING == 0)
+			.map_err(|_| "AHCI: timed out waiting for port's command list to stop")?;
+		let cmd = self.cmd.read();
+		self.cmd.write(cmd & !PORT_CMD_FIS_RECEIVE_ENABLE);
+		wait_until(PORT_TIMEOUT_MICROSECONDS, || self.cmd.read() & PORT_CMD_FIS_RECEIVE_RUNNING == 0)
+			.map_err(|_| "AHCI: timed out waiting for port's FIS receive to stop")
+	}
+
+	/// Starts this port receiving FISes and processing its command list.
+	fn start(&mut self) -> Result<(), &'static str> {
+		wait_until(PORT_TIMEOUT_MICROSECONDS, || self.tfd.read() & (TFD_BUSY | TFD_DATA_REQUEST) == 0)
+			.map_err(|_| "AHCI: timed out waiting for drive to become idle")?;
+		let cmd = self.cmd.read();
+		self.cmd.write(cmd | PORT_CMD_FIS_RECEIVE_ENABLE);
+		let cmd = self.cmd.read();
+		self.cmd.write(cmd | PORT_CMD_START);
+		Ok(())
+	}
+
+	/// Clears any errors on this port and restarts it, which aborts all outstanding commands.
+	fn recover(&mut self) -> Result<(), &'static str> {
+		self.stop()?;
+		self.serr.write(0xFFFF_FFFF);
+		self.is.write(0xFFFF_FFFF);
+		self.start()
+	}
+}
+
+
+/// An entry in a port's command list, which describes the command in the corresponding command slot.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct CommandHeader {
+	/// Bits `[0:5)` hold the length of the command FIS in dwords, and bit 6 is set for writes.
+	flags: u16,
+	/// The number of entries in the command table's PRDT.
+	prdt_length: u16,
+	/// The number of bytes transferred so far, which is updated by the HBA.
+	prd_byte_count: u32,
+	/// The physical address of the command table, lower 32 bits, 128-byte aligned
+	command_table_base: u32,
+	/// The physical address of the command table, upper 32 bits
+	command_table_base_upper: u32,
+	_reserved: [u32; 4],
+}
+
+/// The command table for a single command slot, which holds the command FIS
+/// and the Physical Region Descriptor Table (PRDT) that describes where to transfer data to or from.
+#[repr(C)]
+struct CommandTable {
+	command_fis: [u8; 64],
+	atapi_command: [u8; 16],
+	_reserved: [u8; 48],
+	/// Each command slot's DMA buffer is physically contiguous, so only one PRDT entry is needed.
+	prdt: [PrdtEntry; 1],
+}
+
+/// An entry in a Physical Region Descriptor Table, which describes one physically-contiguous data buffer.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct PrdtEntry {
+	/// The physical address of the data buffer, lower 32 bits
+	data_base: u32,
+	/// The physical address of the data buffer, upper 32 bits
+	data_base_upper: u32,
+	_reserved: u32,
+	/// Bits `[0:22)` hold the number of bytes in the buffer minus one, bit 31 requests an interrupt on completion.
+	byte_count: u32,
+}
+
+
+/// A shared reference to the memory-mapped registers of an AHCI controller,
+/// which is shared between the controller and all of its drives.
+type HbaRegistersRef = Arc<Mutex<BoxRefMut<MappedPages, HbaRegisters>>>;
+
+
+/// A SATA drive attached to a port of an AHCI controller.
+pub struct AhciDrive {
+	/// The registers of the controller that this drive is attached to.
+	hba: HbaRegistersRef,
+	/// The index of the port that this drive is attached to.
+	port: usize,
+	/// The pages holding this port's command list, followed by its received FIS area.
+	command_list: MappedPages,
+	command_list_phys: PhysicalAddress,
+	/// The pages holding the command table for each command slot that we use.
+	command_tables: MappedPages,
+	command_tables_phys: PhysicalAddress,
+	/// The pages holding the DMA buffer for each command slot that we use, in order of command slot.
+	dma_buffers: MappedPages,
+	dma_buffers_phys: PhysicalAddress,
+	/// The number of command slots that we use, at most `MAX_QUEUED_COMMANDS`.
+	num_slots: usize,
+	/// Whether transfers are issued as NCQ commands.
+	ncq: bool,
+	/// The size of a logical sector on this drive.
+	sector_size: usize,
+	identify_data: AtaIdentifyData,
+}
+
+impl AhciDrive {
+	/// Sets up the given `port` of the given HBA and identifies the drive attached to it.
+	///
+	/// # Arguments
+	/// * `hba`: the registers of the controller.
+	/// * `port`: the index of the port that the drive is attached to.
+	/// * `num_command_slots`: the number of command slots per port supported by the controller.
+	/// * `hba_supports_ncq`: whether the controller supports Native Command Queuing.
+	/// * `hba_supports_64_bit`: whether the controller supports 64-bit physical addresses.
+	fn new(
+		hba: HbaRegistersRef,
+		port: usize,
+		num_command_slots: usize,
+		hba_supports_ncq: bool,
+		hba_supports_64_bit: bool,
+	) -> Result<AhciDrive, &'static str> {
+		let num_slots = min(num_command_slots, MAX_QUEUED_COMMANDS);
+		let (mut command_list, command_list_phys) = create_contiguous_mapping(COMMAND_LIST_SIZE_IN_BYTES + RECEIVED_FIS_SIZE_IN_BYTES, AHCI_MAPPING_FLAGS)?;
+		let (command_tables, command_tables_phys) = create_contiguous_mapping(num_slots * COMMAND_TABLE_SIZE_IN_BYTES, AHCI_MAPPING_FLAGS)?;
+		let (dma_buffers, dma_buffers_phys) = create_contiguous_mapping(num_slots * BYTES_PER_COMMAND, AHCI_MAPPING_FLAGS)?;
+
+		if !hba_supports_64_bit {
+			let highest_address = (dma_buffers_phys + (num_slots * BYTES_PER_COMMAND - 1)).value()
+				.max((command_tables_phys + (num_slots * COMMAND_TABLE_SIZE_IN_BYTES - 1)).value())
+				.max((command_list_phys + (COMMAND_LIST_SIZE_IN_BYTES + RECEIVED_FIS_SIZE_IN_BYTES - 1)).value());
+			if highest_address > u32::max_value() as usize {
+				return Err("AHCI: controller only supports 32-bit addresses, but DMA memory was allocated above 4GiB");
+			}
+		}
+
+		for byte in command_list.as_slice_mut::<u8>(0, COMMAND_LIST_SIZE_IN_BYTES + RECEIVED_FIS_SIZE_IN_BYTES)?.iter_mut() {
+			*byte = 0;
+		}
+
+		{
+			let mut hba_regs = hba.lock();
+			let port_regs = &mut hba_regs.ports[port];
+			port_regs.stop()?;
+			let received_fis_phys = command_list_phys + RECEIVED_FIS_OFFSET;
+			port_regs.clb.write(lower_32_bits(command_list_phys));
+			port_regs.clbu.write(upper_32_bits(command_list_phys));
+			port_regs.fb.write(lower_32_bits(received_fis_phys));
+			port_regs.fbu.write(upper_32_bits(received_fis_phys));
+			// We poll for command completion, so disable this port's interrupts and clear any stale status.
+			port_regs.ie.write(0);
+			port_regs.serr.write(0xFFFF_FFFF);
+			port_regs.is.write(0xFFFF_FFFF);
+			port_regs.start()?;
+		}
+
+		let mut drive = AhciDrive {
+			hba,
+			port,
+			command_list,
+			command_list_phys,
+			command_tables,
+			command_tables_phys,
+			dma_buffers,
+			dma_buffers_phys,
+			num_slots,
+			ncq: false,
+			sector_size: DEFAULT_SECTOR_SIZE_IN_BYTES,
+			identify_data: AtaIdentifyData::default(),
+		};
+
+		let identify_data = drive.identify_drive()?;
+		// Check to see that the drive supports LBA, as with ATA drives.
+		if identify_data.capabilities & 0x200 == 0 {
+			return Err("AHCI: drive doesn't support LBA addressing mode");
+		}
+		// Word 83 bit 10 indicates support for the 48-bit LBA commands that we use.
+		let command_set_support = identify_data.command_set_support;
+		if command_set_support[1] & (1 << 10) == 0 {
+			return Err("AHCI: drive doesn't support 48-bit LBA addressing mode");
+		}
+
+		// Word 76 bit 8 indicates NCQ support, and word 75 holds the drive's maximum queue depth minus one.
+		let drive_supports_ncq = identify_data.serial_ata_capabilities & (1 << 8) != 0;
+		if hba_supports_ncq && drive_supports_ncq {
+			let queue_depth = (identify_data.queue_depth & 0x1F) as usize + 1;
+			drive.num_slots = min(drive.num_slots, queue_depth);
+			drive.ncq = true;
+		}
+
+		// Word 106 indicates whether the logical sector size is larger than 256 words,
+		// in which case words 117-118 hold the logical sector size in words.
+		let sector_size_info = identify_data.physical_logical_sector_size;
+		if sector_size_info & 0xC000 == 0x4000 && sector_size_info & (1 << 12) != 0 {
+			let sector_size = identify_data.words_per_logical_sector as usize * 2;
+			if sector_size == 0 || BYTES_PER_COMMAND % sector_size != 0 {
+				return Err("AHCI: drive has an unsupported logical sector size");
+			}
+			drive.sector_size = sector_size;
+		}
+
+		drive.identify_data = identify_data;
+		Ok(drive)
+	}
+
+	/// Issues an identify device command to query the drive's characteristics.
+	fn identify_drive(&mut self) -> Result<AtaIdentifyData, &'static str> {
+		let fis = register_h2d_fis(AhciCommand::IdentifyDevice, 0, 0, 0, 0);
+		self.prepare_command(0, &fis, IDENTIFY_DATA_SIZE_IN_BYTES, false)?;
+		self.issue_and_wait(1, false)?;
+		let mut arr = [0u8; IDENTIFY_DATA_SIZE_IN_BYTES];
+		arr.copy_from_slice(self.dma_buffers.as_slice(0, IDENTIFY_DATA_SIZE_IN_BYTES)?);
+		Ok(AtaIdentifyData::new(arr))
+	}
+
+	/// Reads data from this drive starting at the given `offset_in_sectors` into the provided `buffer`.
+	/// The length of the given `buffer` determines the number of bytes to be read,
+	/// and must be a multiple of the sector size.
+	///
+	/// Returns the number of sectors (*not bytes*) that were successfully read from the drive.
+	pub fn read_dma(&mut self, buffer: &mut [u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+		let sector_count = self.check_transfer(buffer.len(), offset_in_sectors)?;
+		let mut lba = offset_in_sectors;
+		for chunk in buffer.chunks_mut(self.num_slots * BYTES_PER_COMMAND) {
+			let chunk_sectors = chunk.len() / self.sector_size;
+			self.transfer(lba, chunk_sectors, false)?;
+			chunk.copy_from_slice(self.dma_buffers.as_slice(0, chunk.len())?);
+			lba += chunk_sectors;
+		}
+		Ok(sector_count)
+	}
+
+	/// Writes data from the provided `buffer` to this drive, starting at the given `offset_in_sectors` into the drive.
+	/// The length of the given `buffer` determines the number of bytes to be written,
+	/// and must be a multiple of the sector size.
+	///
+	/// The drive's cache is flushed after the data is written.
+	///
+	/// Returns the number of sectors (*not bytes*) that were successfully written to the drive.
+	pub fn write_dma(&mut self, buffer: &[u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+		let sector_count = self.check_transfer(buffer.len(), offset_in_sectors)?;
+		let mut lba = offset_in_sectors;
+		for chunk in buffer.chunks(self.num_slots * BYTES_PER_COMMAND) {
+			let chunk_sectors = chunk.len() / self.sector_size;
+			self.dma_buffers.as_slice_mut(0, chunk.len())?.copy_from_slice(chunk);
+			self.transfer(lba, chunk_sectors, true)?;
+			lba += chunk_sectors;
+		}
+		self.flush()?;
+		Ok(sector_count)
+	}
+
+	/// Flushes the drive's write cache.
+	pub fn flush(&mut self) -> Result<(), &'static str> {
+		let fis = register_h2d_fis(AhciCommand::CacheFlushExt, 0, 0, 0, DEVICE_LBA_MODE);
+		self.prepare_command(0, &fis, 0, false)?;
+		self.issue_and_wait(1, false)
+	}
+
+	/// Returns the index of the controller port that this drive is attached to.
+	pub fn port(&self) -> usize {
+		self.port
+	}
+
+	/// Returns `true` if transfers to and from this drive use Native Command Queuing (NCQ).
+	pub fn is_ncq_enabled(&self) -> bool {
+		self.ncq
+	}
+
+	/// Returns the information obtained from this drive's response to the identify device command.
+	pub fn identify_data(&self) -> &AtaIdentifyData {
+		&self.identify_data
+	}
+
+	/// Checks that a transfer of `length_in_bytes` starting at `offset_in_sectors` is valid for this drive,
+	/// and returns the number of sectors it covers.
+	fn check_transfer(&self, length_in_bytes: usize, offset_in_sectors: usize) -> Result<usize, &'static str> {
+		if length_in_bytes % self.sector_size != 0 {
+			return Err("AHCI: the buffer length must be a multiple of the drive's sector size");
+		}
+		let sector_count = length_in_bytes / self.sector_size;
+		if offset_in_sectors + sector_count > self.size_in_sectors() {
+			return Err("AHCI: transfer extends past the end of the drive");
+		}
+		Ok(sector_count)
+	}
+
+	/// Transfers `sector_count` sectors starting at `lba` between the drive and the DMA buffers,
+	/// splitting the transfer into one command per command slot.
+	/// The `sector_count` must fit within the DMA buffers of all of the command slots that we use.
+	fn transfer(&mut self, lba: usize, sector_count: usize, write: bool) -> Result<(), &'static str> {
+		let sectors_per_command = BYTES_PER_COMMAND / self.sector_size;
+		let mut slots_mask = 0u32;
+		let mut slot = 0;
+		let mut next_lba = lba;
+		let mut remaining = sector_count;
+		while remaining > 0 {
+			if slot >= self.num_slots {
+				return Err("BUG: AHCI transfer was too large for the DMA buffers");
+			}
+			let count = min(remaining, sectors_per_command);
+			let fis = if self.ncq {
+				// For queued commands, the sector count goes in the features field,
+				// and the command's tag (its command slot) goes in bits [3:8) of the count field.
+				let command = if write { AhciCommand::WriteFpdmaQueued } else { AhciCommand::ReadFpdmaQueued };
+				register_h2d_fis(command, next_lba, (slot << 3) as u16, count as u16, DEVICE_LBA_MODE)
+			} else {
+				let command = if write { AhciCommand::WriteDmaExt } else { AhciCommand::ReadDmaExt };
+				register_h2d_fis(command, next_lba, count as u16, 0, DEVICE_LBA_MODE)
+			};
+			self.prepare_command(slot, &fis, count * self.sector_size, write)?;
+			slots_mask |= 1 << slot;
+			slot += 1;
+			next_lba += count;
+			remaining -= count;
+		}
+		self.issue_and_wait(slots_mask, self.ncq)
+	}
+
+	/// Fills in the command header and command table of the given command `slot`
+	/// such that it will issue the given `fis` and transfer `byte_count` bytes to or from that slot's DMA buffer.
+	fn prepare_command(&mut self, slot: usize, fis: &[u8; H2D_FIS_SIZE_IN_BYTES], byte_count: usize, write: bool) -> Result<(), &'static str> {
+		let command_table_phys = self.command_tables_phys + slot * COMMAND_TABLE_SIZE_IN_BYTES;
+		let dma_buffer_phys = self.dma_buffers_phys + slot * BYTES_PER_COMMAND;
+		{
+			let command_table: &mut CommandTable = self.command_tables.as_type_mut(slot * COMMAND_TABLE_SIZE_IN_BYTES)?;
+			command_table.command_fis = [0; 64];
+			command_table.command_fis[.. H2D_FIS_SIZE_IN_BYTES].copy_from_slice(fis);
+			command_table.atapi_command = [0; 16];
+			command_table.prdt[0] = PrdtEntry {
+				data_base: lower_32_bits(dma_buffer_phys),
+				data_base_upper: upper_32_bits(dma_buffer_phys),
+				_reserved: 0,
+				byte_count: (byte_count.saturating_sub(1) as u32) & PRD_BYTE_COUNT_MASK,
+			};
+		}
+		let command_header: &mut CommandHeader = self.command_list.as_type_mut(slot * size_of::<CommandHeader>())?;
+		*command_header = CommandHeader {
+			flags: (H2D_FIS_SIZE_IN_BYTES / 4) as u16 | if write { COMMAND_HEADER_WRITE } else { 0 },
+			prdt_length: if byte_count > 0 { 1 } else { 0 },
+			prd_byte_count: 0,
+			command_table_base: lower_32_bits(command_table_phys),
+			command_table_base_upper: upper_32_bits(command_table_phys),
+			_reserved: [0; 4],
+		};
+		Ok(())
+	}
+
+	/// Issues the commands in the command slots given by `slots_mask` and waits for all of them to complete.
+	/// If `queued` is true, the commands are NCQ commands.
+	fn issue_and_wait(&mut self, slots_mask: u32, queued: bool) -> Result<(), &'static str> {
+		// Ensure the command headers and tables are written before the HBA is told to fetch them.
+		fence(Ordering::SeqCst);
+		{
+			let mut hba_regs = self.hba.lock();
+			let port_regs = &mut hba_regs.ports[self.port];
+			if queued {
+				port_regs.sact.write(slots_mask);
+			}
+			port_regs.ci.write(slots_mask);
+		}
+
+		// The HBA lock is only held briefly during each poll so that other ports can be used in the meantime.
+		let mut waited = 0;
+		loop {
+			{
+				let mut hba_regs = self.hba.lock();
+				let port_regs = &mut hba_regs.ports[self.port];
+				let interrupt_status = port_regs.is.read();
+				if interrupt_status & PORT_IS_TASK_FILE_ERROR != 0 || port_regs.tfd.read() & TFD_ERROR != 0 {
+					error!("AHCI: command on port {} failed, task file data: {:#X}, SATA error: {:#X}",
+						self.port, port_regs.tfd.read(), port_regs.serr.read()
+					);
+					port_regs.recover()?;
+					return Err("AHCI: the drive reported an error");
+				}
+				let outstanding = port_regs.ci.read() | if queued { port_regs.sact.read() } else { 0 };
+				if outstanding & slots_mask == 0 {
+					port_regs.is.write(interrupt_status);
+					break;
+				}
+			}
+			if waited >= COMMAND_TIMEOUT_MICROSECONDS {
+				error!("AHCI: command on port {} timed out", self.port);
+				self.hba.lock().ports[self.port].recover()?;
+				return Err("AHCI: command timed out");
+			}
+			pit_clock::pit_wait(POLL_INTERVAL_MICROSECONDS)?;
+			waited += POLL_INTERVAL_MICROSECONDS;
+		}
+
+		// Ensure the DMA buffers are not read before the HBA has finished with them.
+		fence(Ordering::SeqCst);
+		Ok(())
+	}
+}
+
+impl StorageDevice for AhciDrive {
+	fn read_sectors(&mut self, buffer: &mut [u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+		self.read_dma(buffer, offset_in_sectors)
+	}
+
+	fn write_sectors(&mut self, buffer: &[u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+		self.write_dma(buffer, offset_in_sectors)
+	}
+
+	/// Returns the number of sectors in this drive.
+	fn size_in_sectors(&self) -> usize {
+		if self.identify_data.user_addressable_sectors != 0 {
+			self.identify_data.user_addressable_sectors as usize
+		} else {
+			self.identify_data.max_48_bit_lba as usize
+		}
+	}
+
+	fn sector_size_in_bytes(&self) -> usize {
+		self.sector_size
+	}
+}
+
+
+pub type AhciDriveRef = Arc<Mutex<AhciDrive>>;
+
+
+/// An AHCI controller, also known as a Host Bus Adapter (HBA),
+/// which has up to 32 ports that each have up to one SATA drive attached.
+pub struct AhciController {
+	/// The drives attached to this controller, in order of port number.
+	drives: Vec<AhciDriveRef>,
+}
+
+impl AhciController {
+	/// Creates a new instance of an AHCI controller based on the given PCI device,
+	/// and sets up every SATA drive attached to its ports.
+	pub fn new(pci_device: &PciDevice) -> Result<AhciController, &'static str> {
+		// The AHCI base address (ABAR) is in BAR5, and is always a 32-bit memory address.
+		let abar = PhysicalAddress::new((pci_device.bars[5] & 0xFFFF_FFF0) as usize)?;
+		if abar.value() == 0 {
+			return Err("AHCI: controller's BAR5 was not configured");
+		}
+		// The HBA fetches commands and transfers data via DMA.
+		pci_device.pci_set_command_bus_master_bit();
+
+		let hba = Arc::new(Mutex::new(map_hba_registers(abar)?));
+		let (num_command_slots, supports_ncq, supports_64_bit, ports_implemented) = {
+			let mut hba_regs = hba.lock();
+			take_ownership_from_bios(&mut hba_regs)?;
+			let ghc = hba_regs.ghc.read();
+			hba_regs.ghc.write((ghc | GHC_AHCI_ENABLE) & !GHC_INTERRUPT_ENABLE);
+			let cap = hba_regs.cap.read();
+			debug!("AHCI controller version {:#X}, capabilities {:#X}", hba_regs.vs.read(), cap);
+			(
+				((cap >> 8) & 0x1F) as usize + 1,
+				cap & CAP_NATIVE_COMMAND_QUEUING != 0,
+				cap & CAP_64_BIT_ADDRESSING != 0,
+				hba_regs.pi.read(),
+			)
+		};
+
+		let mut drives = Vec::new();
+		for port in (0 .. MAX_PORTS).filter(|p| ports_implemented & (1 << p) != 0) {
+			let (status, signature) = {
+				let hba_regs = hba.lock();
+				(hba_regs.ports[port].ssts.read(), hba_regs.ports[port].sig.read())
+			};
+			let device_detection = status & 0xF;
+			let power_management = (status >> 8) & 0xF;
+			if device_detection != SSTS_DEVICE_PRESENT || power_management != SSTS_INTERFACE_ACTIVE {
+				continue;
+			}
+			match signature {
+				SATA_SIGNATURE_ATA => { }
+				SATA_SIGNATURE_ATAPI => {
+					info!("AHCI port {}: skipping ATAPI device, which is unsupported", port);
+					continue;
+				}
+				other => {
+					info!("AHCI port {}: skipping device with unsupported signature {:#X}", port, other);
+					continue;
+				}
+			}
+
+			match AhciDrive::new(Arc::clone(&hba), port, num_command_slots, supports_ncq, supports_64_bit) {
+				Ok(drive) => {
+					info!("AHCI port {}: drive initialized, size: {} sectors of {} bytes, NCQ: {}",
+						port, drive.size_in_sectors(), drive.sector_size_in_bytes(), drive.is_ncq_enabled()
+					);
+					drives.push(Arc::new(Mutex::new(drive)));
+				}
+				Err(e) => warn!("AHCI port {}: failed to initialize drive: {}", port, e),
+			}
+		}
+
+		info!("AHCI controller at {}: {} drive(s) found", pci_device.location, drives.len());
+		Ok(AhciController { drives })
+	}
+
+	/// Returns an `Iterator` over all of the `AhciDrive`s attached to this controller,
+	/// in order of port number.
+	pub fn iter(&self) -> impl Iterator<Item = &AhciDriveRef> {
+		self.drives.iter()
+	}
+}
+
+impl StorageController for AhciController {
+	fn devices<'c>(&'c self) -> Box<(dyn Iterator<Item = StorageDeviceRef> + 'c)> {
+		Box::new(
+			self.iter().map(|ahci_drive_ref| Arc::clone(ahci_drive_ref) as StorageDeviceRef)
+		)
+	}
+}
+
+
+/// Maps the HBA's memory-mapped registers at the given physical address.
+fn map_hba_registers(abar: PhysicalAddress) -> Result<BoxRefMut<MappedPages, HbaRegisters>, &'static str> {
+	let offset_in_page = abar.value() % PAGE_SIZE;
+	let size_in_bytes = offset_in_page + size_of::<HbaRegisters>();
+
+	// Inform the frame allocator that the frames of the HBA registers are off-limits.
+	let frame_allocator = get_frame_allocator_ref().ok_or("AHCI: couldn't get the frame allocator")?;
+	let hba_area = PhysicalMemoryArea::new(abar, size_of::<HbaRegisters>(), 1, 0);
+	frame_allocator.lock().add_area(hba_area, false)?;
+
+	let pages = allocate_pages_by_bytes(size_in_bytes).ok_or("AHCI: couldn't allocate pages for the HBA registers")?;
+	let frames = FrameRange::from_phys_addr(abar, size_of::<HbaRegisters>());
+	let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("AHCI: KERNEL_MMI was not yet initialized!")?;
+	let mapped_pages = kernel_mmi_ref.lock().page_table.map_allocated_pages_to(pages, frames, AHCI_MAPPING_FLAGS, frame_allocator.lock().deref_mut())?;
+	BoxRefMut::new(Box::new(mapped_pages)).try_map_mut(|mp| mp.as_type_mut::<HbaRegisters>(offset_in_page))
+}
+
+/// Requests ownership of the HBA from the BIOS, if the HBA supports the BIOS/OS handoff mechanism.
+fn take_ownership_from_bios(hba_regs: &mut HbaRegisters) -> Result<(), &'static str> {
+	if hba_regs.cap2.read() & CAP2_BIOS_HANDOFF == 0 {
+		return Ok(());
+	}
+	let bohc = hba_regs.bohc.read();
+	hba_regs.bohc.write(bohc | BOHC_OS_OWNED);
+	wait_until(BIOS_HANDOFF_TIMEOUT_MICROSECONDS, || {
+		let bohc = hba_regs.bohc.read();
+		bohc & BOHC_BIOS_OWNED == 0 && bohc & BOHC_BIOS_BUSY == 0
+	}).map_err(|_| "AHCI: timed out waiting for the BIOS to relinquish ownership of the controller")
+}
+
+/// Polls the given `condition` until it is true, or until `timeout_microseconds` have elapsed.
+fn wait_until<F: FnMut() -> bool>(timeout_microseconds: u32, mut condition: F) -> Result<(), ()> {
+	let mut waited = 0;
+	while !condition() {
+		if waited >= timeout_microseconds {
+			return Err(());
+		}
+		pit_clock::pit_wait(POLL_INTERVAL_MICROSECONDS).map_err(|_| ())?;
+		waited += POLL_INTERVAL_MICROSECONDS;
+	}
+	Ok(())
+}
+
+/// Creates a Register Host-to-Device FIS that issues the given `command`.
+fn register_h2d_fis(command: AhciCommand, lba: usize, count: u16, features: u16, device: u8) -> [u8; H2D_FIS_SIZE_IN_BYTES] {
+	let mut fis = [0u8; H2D_FIS_SIZE_IN_BYTES];
+	fis[0]  = FIS_TYPE_REGISTER_H2D;
+	fis[1]  = FIS_H2D_COMMAND;
+	fis[2]  = command as u8;
+	fis[3]  = features as u8;
+	fis[4]  = lba as u8;
+	fis[5]  = (lba >> 8) as u8;
+	fis[6]  = (lba >> 16) as u8;
+	fis[7]  = device;
+	fis[8]  = (lba >> 24) as u8;
+	fis[9]  = (lba >> 32) as u8;
+	fis[10] = (lba >> 40) as u8;
+	fis[11] = (features >> 8) as u8;
+	fis[12] = count as u8;
+	fis[13] = (count >> 8) as u8;
+	fis
+}
+
+fn lower_32_bits(addr: PhysicalAddress) -> u32 {
+	addr.value() as u32
+}
+
+fn upper_32_bits(addr: PhysicalAddress) -> u32 {
+	(addr.value() >> 32) as u32
+}
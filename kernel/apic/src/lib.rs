@@ -88,6 +88,8 @@ pub fn core_count() -> usize {
 
 
 /// Returns the APIC ID of the currently executing processor core.
+/// 
+/// This must not lock or allocate anything, because the `tracing_allocator` calls it for every heap allocation.
 pub fn get_my_apic_id() -> u8 {
     rdmsr(IA32_TSC_AUX) as u8
 }
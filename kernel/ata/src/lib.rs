@@ -1,26 +1,47 @@
 //! Support for accessing ATA drives (IDE).
 //! 
 //! The primary struct of interest is [`AtaDrive`](struct.AtaDrive.html).
+//! 
+//! Drives are accessed using bus-master DMA if the IDE controller and the drive both support it,
+//! otherwise they fall back to PIO. 
+//! DMA transfers on an IDE controller in compatibility mode are completed by an interrupt,
+//! which the `interrupts` crate forwards to [`handle_primary_interrupt()`](fn.handle_primary_interrupt.html)
+//! or [`handle_secondary_interrupt()`](fn.handle_secondary_interrupt.html),
+//! such that the task that issued the transfer is blocked until the transfer completes rather than spinning.
 
 #![no_std]
 
 #[macro_use] extern crate alloc;
 #[macro_use] extern crate log;
+#[macro_use] extern crate lazy_static;
 extern crate spin;
+extern crate irq_safety;
 extern crate port_io;
 extern crate pci;
 #[macro_use] extern crate bitflags;
+extern crate memory;
+extern crate wait_queue;
+extern crate mutex_sleep;
 extern crate storage_device;
 
-use core::fmt;
+use core::{
+	cmp::min,
+	fmt,
+	mem::size_of,
+	sync::atomic::{AtomicBool, AtomicU8, AtomicU16, Ordering},
+};
 use spin::Mutex;
 use alloc::{
 	string::String,
 	boxed::Box,
 	sync::Arc,
 };
+use irq_safety::interrupts_enabled;
 use port_io::{Port, PortReadOnly, PortWriteOnly};
 use pci::PciDevice;
+use memory::{EntryFlags, MappedPages, PhysicalAddress, create_contiguous_mapping};
+use wait_queue::WaitQueue;
+use mutex_sleep::MutexSleep;
 use storage_device::{StorageDevice, StorageDeviceRef, StorageController};
 
 
@@ -36,6 +57,25 @@ const MAX_LBA_28_VALUE: usize = (1 << 28) - 1;
 /// To use a BAR as a Port address, you must mask out the lowest 2 bits.
 const PCI_BAR_PORT_MASK: u16 = 0xFFFC;
 
+/// The offset of the secondary channel's bus master registers from the start of BAR4.
+/// The primary channel's bus master registers are at the start of BAR4.
+const BUS_MASTER_SECONDARY_CHANNEL_OFFSET: u16 = 8;
+/// The size of each bus's DMA buffer, which is the maximum amount of data transferred by a single DMA command.
+const DMA_BUFFER_SIZE_IN_BYTES: usize = 64 * 1024;
+/// The memory region described by a single PRD must not cross a 64KiB boundary.
+const PRD_BOUNDARY: usize = 64 * 1024;
+/// Set in the last PRD of a PRDT.
+const PRD_END_OF_TABLE: u16 = 0x8000;
+/// The maximum number of PRDs needed to describe a DMA buffer, 
+/// which may be split at most once by a 64KiB boundary since it is no larger than 64KiB. 
+const MAX_PRDS: usize = 2;
+/// Bit 0 of an IDE controller's programming interface is set if the primary channel is in PCI native mode,
+/// and bit 2 is set if the secondary channel is. Otherwise, the channel is in compatibility mode.
+const PROG_IF_PRIMARY_NATIVE_MODE: u8 = 0x01;
+const PROG_IF_SECONDARY_NATIVE_MODE: u8 = 0x04;
+/// Bit 7 of an IDE controller's programming interface is set if it supports bus mastering (DMA).
+const PROG_IF_BUS_MASTER: u8 = 0x80;
+
 
 bitflags! {
 	/// The possible error values found in an ATA drive's error port.
@@ -82,6 +122,33 @@ bitflags! {
     }
 }
 
+bitflags! {
+	/// The possible values found in an IDE channel's bus master status register.
+	struct BusMasterStatus: u8 {
+		/// Set by the BIOS if the slave drive supports DMA.
+		const SLAVE_DMA_CAPABLE  = 0x40;
+		/// Set by the BIOS if the master drive supports DMA.
+		const MASTER_DMA_CAPABLE = 0x20;
+		/// Set when the drive raises an interrupt. Cleared by writing a 1 to it.
+		const INTERRUPT          = 0x04;
+		/// Set when a DMA transfer fails. Cleared by writing a 1 to it.
+		const ERROR              = 0x02;
+		/// Set while a DMA transfer is in progress.
+		const ACTIVE             = 0x01;
+	}
+}
+
+bitflags! {
+	/// The possible values used in an IDE channel's bus master command register.
+	struct BusMasterCommand: u8 {
+		/// Set to transfer data from the drive into memory (a read), 
+		/// clear to transfer data from memory to the drive (a write).
+		const READ  = 0x08;
+		/// Set to start the DMA transfer, clear to stop it.
+		const START = 0x01;
+	}
+}
+
 #[allow(dead_code)]
 /// The possible commands that can be issued to an ATA drive's command port. 
 /// More esoteric commands (nearly a full list) are here: <https://wiki.osdev.org/ATA_Command_Matrix>.
@@ -158,6 +225,177 @@ enum BusDriveSelect {
 	Slave  = 1 << 4,
 }
 
+/// The two channels of an IDE controller, each of which has its own ATA bus.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum IdeChannel {
+	Primary   = 0,
+	Secondary = 1,
+}
+
+
+/// The state shared between a task waiting for a DMA transfer to complete on an IDE channel
+/// and that channel's interrupt handler.
+struct ChannelInterruptState {
+	/// Whether the channel's interrupt signals the completion of DMA transfers. 
+	/// This is only true for channels in compatibility mode, which use the legacy IRQs 14 and 15. 
+	enabled: AtomicBool,
+	/// Set while a DMA transfer is in progress.
+	dma_in_progress: AtomicBool,
+	/// The value of the bus master status register when the interrupt that completed the DMA transfer was received,
+	/// or `0` if it has not yet completed. This is never `0` once set, since the `INTERRUPT` bit is always set.
+	completion_status: AtomicU8,
+	/// The port of the channel's bus master status register.
+	bus_master_status_port: AtomicU16,
+	/// The port of the channel's ATA status register, which must be read to acknowledge the drive's interrupt.
+	ata_status_port: AtomicU16,
+	/// The task waiting for the DMA transfer to complete.
+	wait_queue: WaitQueue,
+}
+impl ChannelInterruptState {
+	fn new() -> ChannelInterruptState {
+		ChannelInterruptState {
+			enabled: AtomicBool::new(false),
+			dma_in_progress: AtomicBool::new(false),
+			completion_status: AtomicU8::new(0),
+			bus_master_status_port: AtomicU16::new(0),
+			ata_status_port: AtomicU16::new(0),
+			wait_queue: WaitQueue::new(),
+		}
+	}
+}
+
+lazy_static! {
+	/// The interrupt state of the primary and secondary channels of IDE controllers in compatibility mode,
+	/// indexed by `IdeChannel`. Only one IDE controller can use the legacy IRQs at a time.
+	static ref CHANNEL_INTERRUPT_STATE: [ChannelInterruptState; 2] = [ChannelInterruptState::new(), ChannelInterruptState::new()];
+}
+
+/// Handles an interrupt from the primary channel of an IDE controller in compatibility mode (IRQ 14).
+/// 
+/// This is invoked by the interrupt handler in the `interrupts` crate, which is responsible for sending the EOI.
+pub fn handle_primary_interrupt() {
+	handle_interrupt(IdeChannel::Primary);
+}
+
+/// Handles an interrupt from the secondary channel of an IDE controller in compatibility mode (IRQ 15).
+/// 
+/// This is invoked by the interrupt handler in the `interrupts` crate, which is responsible for sending the EOI.
+pub fn handle_secondary_interrupt() {
+	handle_interrupt(IdeChannel::Secondary);
+}
+
+/// Wakes up the task waiting for a DMA transfer on the given `channel`, if that transfer has completed.
+/// Interrupts raised by PIO transfers are ignored, as PIO transfers poll the drive's status.
+fn handle_interrupt(channel: IdeChannel) {
+	let state = &CHANNEL_INTERRUPT_STATE[channel as usize];
+	if !state.enabled.load(Ordering::Acquire) || !state.dma_in_progress.load(Ordering::Acquire) {
+		return;
+	}
+	let bus_master_status: PortReadOnly<u8> = PortReadOnly::new(state.bus_master_status_port.load(Ordering::Acquire));
+	let status = bus_master_status.read();
+	if status & BusMasterStatus::INTERRUPT.bits() == 0 {
+		return;
+	}
+	// Reading the regular status register acknowledges the drive's interrupt.
+	let ata_status: PortReadOnly<u8> = PortReadOnly::new(state.ata_status_port.load(Ordering::Acquire));
+	ata_status.read();
+
+	state.dma_in_progress.store(false, Ordering::Release);
+	state.completion_status.store(status, Ordering::Release);
+	state.wait_queue.notify_one();
+}
+
+
+/// An entry in a Physical Region Descriptor Table (PRDT), 
+/// which describes one physically-contiguous memory region of a DMA transfer.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct PhysicalRegionDescriptor {
+	/// The physical address of the memory region.
+	base: u32,
+	/// The size of the memory region in bytes, in which `0` means 64KiB.
+	byte_count: u16,
+	/// Only the highest bit is used, which marks the last entry in the PRDT.
+	flags: u16,
+}
+
+/// The bus master registers and memory used for DMA transfers on a single ATA bus.
+#[derive(Debug)]
+struct BusMasterDma {
+	/// The bus master command register.
+	/// Located at `BAR4 + 0` for the primary channel and `BAR4 + 8` for the secondary channel.
+	command: Port<u8>,
+	/// The bus master status register.
+	/// Located at `BAR4 + 2` for the primary channel and `BAR4 + 10` for the secondary channel.
+	status: Port<u8>,
+	/// The physical address of the PRDT.
+	/// Located at `BAR4 + 4` for the primary channel and `BAR4 + 12` for the secondary channel.
+	prdt_address: Port<u32>,
+	/// The memory holding the PRDT, which must be 4-byte aligned and must not cross a 64KiB boundary.
+	prdt: MappedPages,
+	prdt_phys: PhysicalAddress,
+	/// The memory that data is transferred to or from via DMA.
+	buffer: MappedPages,
+	buffer_phys: PhysicalAddress,
+	/// Which channel of the IDE controller this bus is.
+	channel: IdeChannel,
+}
+
+impl BusMasterDma {
+	/// Sets up DMA for the given `channel`, whose bus master registers are at the given `bus_master_base` port.
+	fn new(bus_master_base: u16, channel: IdeChannel) -> Result<BusMasterDma, &'static str> {
+		// The memory accessed by the IDE controller via DMA must not be cached.
+		let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE;
+		let (prdt, prdt_phys) = create_contiguous_mapping(MAX_PRDS * size_of::<PhysicalRegionDescriptor>(), flags)?;
+		let (buffer, buffer_phys) = create_contiguous_mapping(DMA_BUFFER_SIZE_IN_BYTES, flags)?;
+		// Bus-master IDE DMA only supports 32-bit physical addresses.
+		if (buffer_phys + (DMA_BUFFER_SIZE_IN_BYTES - 1)).value() > u32::max_value() as usize
+			|| prdt_phys.value() > u32::max_value() as usize
+		{
+			return Err("DMA memory was allocated above 4GiB, which IDE controllers cannot access");
+		}
+		Ok(BusMasterDma {
+			command: Port::new(bus_master_base + 0),
+			status: Port::new(bus_master_base + 2),
+			prdt_address: Port::new(bus_master_base + 4),
+			prdt,
+			prdt_phys,
+			buffer,
+			buffer_phys,
+			channel,
+		})
+	}
+
+	/// Fills in the PRDT to describe a transfer of `length_in_bytes` bytes to or from the start of the DMA buffer.
+	fn prepare_prdt(&mut self, length_in_bytes: usize) -> Result<(), &'static str> {
+		let prds = self.prdt.as_slice_mut::<PhysicalRegionDescriptor>(0, MAX_PRDS)?;
+		let mut phys_addr = self.buffer_phys.value();
+		let mut remaining = length_in_bytes;
+		let mut count = 0;
+		while remaining > 0 {
+			if count >= MAX_PRDS {
+				return Err("BUG: DMA transfer needed too many PRDs");
+			}
+			let bytes_until_boundary = PRD_BOUNDARY - (phys_addr % PRD_BOUNDARY);
+			let length = min(remaining, bytes_until_boundary);
+			prds[count] = PhysicalRegionDescriptor {
+				base: phys_addr as u32,
+				// A 64KiB region is represented by a byte count of 0.
+				byte_count: length as u16,
+				flags: 0,
+			};
+			phys_addr += length;
+			remaining -= length;
+			count += 1;
+		}
+		if count == 0 {
+			return Err("BUG: DMA transfer was empty");
+		}
+		prds[count - 1].flags = PRD_END_OF_TABLE;
+		Ok(())
+	}
+}
+
 
 /// There are two ATA buses on an IDE controller,
 /// and each one can have two drives attached to it:
@@ -215,6 +453,10 @@ struct AtaBus {
 	/// `DEVADDRESS`, located at `BAR1 + 3`. 
 	/// Not sure what this is used for.
 	drive_address: Port<u8>,
+
+	/// The bus master registers used for DMA transfers, 
+	/// or `None` if DMA is not supported on this bus.
+	dma: Option<BusMasterDma>,
 }
 
 impl AtaBus {
@@ -237,6 +479,8 @@ impl AtaBus {
 			alternate_status: PortReadOnly::new(control_bar + 2),
 			control: PortWriteOnly::new(control_bar + 2),
 			drive_address: Port::new(control_bar + 3),
+
+			dma: None,
 		}
 	}
 
@@ -379,6 +623,208 @@ impl AtaBus {
 		Ok(sector_count)
 	}
 
+	/// Selects the given drive, sets up the LBA and sector count registers, and issues the given command,
+	/// using `command_lba_28` if the LBA fits in 28 bits, or `command_lba_48` otherwise. 
+	/// 
+	/// Returns `true` if the 28-bit LBA command was issued.
+	fn issue_command(&mut self,
+		which: BusDriveSelect,
+		lba_start: usize,
+		sector_count: usize,
+		command_lba_28: AtaCommand,
+		command_lba_48: AtaCommand,
+	) -> bool {
+		let using_lba_28 = lba_start <= MAX_LBA_28_VALUE;
+		if using_lba_28 {
+			unsafe {
+				// bits [24:28] of the LBA need to go into the lower 4 bits of the `drive_select` port.
+				self.drive_select.write(0xE0 | (which as u8) | ((lba_start >> 24) as u8 & 0x0F));
+				self.sector_count.write(sector_count as u8);
+				self.lba_high.write((lba_start >> 16) as u8);
+				self.lba_mid.write( (lba_start >>  8) as u8);
+				self.lba_low.write( (lba_start >>  0) as u8);
+				self.command.write(command_lba_28 as u8);
+			}
+		} else {
+			// When using 48-bit LBAs, the high bytes of the sector_count and LBA must be written *before* the low bytes.
+			unsafe {
+				self.drive_select.write(0x40 | (which as u8));
+				// write the high bytes
+				self.sector_count.write((sector_count >> 8) as u8);
+				self.lba_high.write((lba_start >> 40) as u8);
+				self.lba_mid.write( (lba_start >> 32) as u8);
+				self.lba_low.write( (lba_start >> 24) as u8);
+				// write the low bytes
+				self.sector_count.write(sector_count as u8);
+				self.lba_high.write((lba_start >> 16) as u8);
+				self.lba_mid.write( (lba_start >>  8) as u8);
+				self.lba_low.write( (lba_start >>  0) as u8);
+				self.command.write(command_lba_48 as u8);
+			}
+		}
+		using_lba_28
+	}
+
+	/// Returns `true` if this bus supports bus-master DMA transfers.
+	fn supports_dma(&self) -> bool {
+		self.dma.is_some()
+	}
+
+	/// Issues the actual read DMA command on the ATA Bus without performing any bounds checks.
+	/// Large reads are split into multiple DMA transfers, each of which is no larger than the bus's DMA buffer.
+	/// 
+	/// See `AtaDrive::read_dma()` (the caller of this function) for more documentation.
+	fn read_dma(&mut self, 
+		buffer: &mut [u8],
+		which: BusDriveSelect,
+		lba_start: usize,
+		sector_count: usize
+	) -> Result<usize, &'static str> {
+		let max_sectors_per_transfer = DMA_BUFFER_SIZE_IN_BYTES / SECTOR_SIZE_IN_BYTES;
+		let mut sectors_done = 0;
+		while sectors_done < sector_count {
+			let count = min(sector_count - sectors_done, max_sectors_per_transfer);
+			let length_in_bytes = count * SECTOR_SIZE_IN_BYTES;
+			self.dma_transfer(which, lba_start + sectors_done, count, true)?;
+
+			let dma = self.dma.as_ref().ok_or("ATA bus does not support DMA")?;
+			let offset_in_bytes = sectors_done * SECTOR_SIZE_IN_BYTES;
+			buffer[offset_in_bytes .. (offset_in_bytes + length_in_bytes)]
+				.copy_from_slice(dma.buffer.as_slice(0, length_in_bytes)?);
+			sectors_done += count;
+		}
+		Ok(sector_count)
+	}
+
+	/// Issues the actual write DMA command on the ATA Bus without performing any bounds checks.
+	/// Large writes are split into multiple DMA transfers, each of which is no larger than the bus's DMA buffer.
+	/// 
+	/// See `AtaDrive::write_dma()` (the caller of this function) for more documentation.
+	fn write_dma(&mut self, 
+		buffer: &[u8],
+		which: BusDriveSelect,
+		lba_start: usize,
+		sector_count: usize
+	) -> Result<usize, &'static str> {
+		let max_sectors_per_transfer = DMA_BUFFER_SIZE_IN_BYTES / SECTOR_SIZE_IN_BYTES;
+		let mut sectors_done = 0;
+		while sectors_done < sector_count {
+			let count = min(sector_count - sectors_done, max_sectors_per_transfer);
+			let length_in_bytes = count * SECTOR_SIZE_IN_BYTES;
+			{
+				let dma = self.dma.as_mut().ok_or("ATA bus does not support DMA")?;
+				let offset_in_bytes = sectors_done * SECTOR_SIZE_IN_BYTES;
+				dma.buffer.as_slice_mut(0, length_in_bytes)?
+					.copy_from_slice(&buffer[offset_in_bytes .. (offset_in_bytes + length_in_bytes)]);
+			}
+			self.dma_transfer(which, lba_start + sectors_done, count, false)?;
+			sectors_done += count;
+		}
+		Ok(sector_count)
+	}
+
+	/// Performs a single DMA transfer of `sector_count` sectors between the drive and the start of the bus's DMA buffer,
+	/// in which `read` specifies the direction: drive to memory if `true`, memory to drive if `false`.
+	/// 
+	/// If this bus's interrupts are enabled, the current task blocks until the transfer completes;
+	/// otherwise, the bus master status register is polled until the transfer completes. 
+	fn dma_transfer(&mut self,
+		which: BusDriveSelect,
+		lba_start: usize,
+		sector_count: usize,
+		read: bool,
+	) -> Result<(), &'static str> {
+		if sector_count == 0 {
+			return Ok(());
+		}
+		self.wait_for_data_done().map_err(|_| "error before issuing DMA command")?;
+
+		let channel = {
+			let dma = self.dma.as_mut().ok_or("ATA bus does not support DMA")?;
+			dma.prepare_prdt(sector_count * SECTOR_SIZE_IN_BYTES)?;
+			let direction = if read { BusMasterCommand::READ } else { BusMasterCommand::empty() };
+			unsafe {
+				dma.prdt_address.write(dma.prdt_phys.value() as u32);
+				// Set the transfer direction with the START bit cleared, 
+				// and clear any previous interrupt and error status by writing 1s to those bits.
+				dma.command.write(direction.bits());
+				dma.status.write((BusMasterStatus::INTERRUPT | BusMasterStatus::ERROR).bits());
+			}
+			dma.channel
+		};
+
+		let state = &CHANNEL_INTERRUPT_STATE[channel as usize];
+		let use_interrupts = state.enabled.load(Ordering::Acquire) && interrupts_enabled();
+		state.completion_status.store(0, Ordering::Release);
+		state.dma_in_progress.store(true, Ordering::Release);
+
+		let (command_lba_28, command_lba_48) = if read {
+			(AtaCommand::ReadDma, AtaCommand::ReadDmaExt)
+		} else {
+			(AtaCommand::WriteDma, AtaCommand::WriteDmaExt)
+		};
+		let using_lba_28 = self.issue_command(which, lba_start, sector_count, command_lba_28, command_lba_48);
+
+		let dma = self.dma.as_mut().ok_or("ATA bus does not support DMA")?;
+		unsafe { dma.command.write(dma.command.read() | BusMasterCommand::START.bits()); }
+
+		// Wait for the transfer to complete, either by blocking until the interrupt handler wakes us up, or by polling.
+		let mut completion_status = None;
+		if use_interrupts {
+			let wait_result = state.wait_queue.wait_until(&|| {
+				match state.completion_status.load(Ordering::Acquire) {
+					0 => None,
+					status => Some(status),
+				}
+			});
+			match wait_result {
+				Ok(status) => completion_status = Some(status),
+				Err(e) => warn!("AtaBus::dma_transfer(): couldn't block waiting for DMA completion ({:?}), polling instead.", e),
+			}
+		}
+		let completion_status = match completion_status {
+			Some(status) => BusMasterStatus::from_bits_truncate(status),
+			None => {
+				let mut _loop_counter = 0;
+				loop {
+					let status = BusMasterStatus::from_bits_truncate(dma.status.read());
+					if status.intersects(BusMasterStatus::INTERRUPT | BusMasterStatus::ERROR) {
+						break status;
+					}
+					// The interrupt handler may have observed (and cleared) the interrupt before we did.
+					let handled_status = state.completion_status.load(Ordering::Acquire);
+					if handled_status != 0 {
+						break BusMasterStatus::from_bits_truncate(handled_status);
+					}
+					_loop_counter += 1;
+					if _loop_counter % 1_000_000 == 0 {
+						warn!("AtaBus::dma_transfer() has been busy waiting for a long time... is there a device/driver problem? (status: {:?})", status);
+					}
+				}
+			}
+		};
+		state.dma_in_progress.store(false, Ordering::Release);
+
+		// Stop the transfer and clear the interrupt and error status.
+		unsafe { 
+			dma.command.write(dma.command.read() & !BusMasterCommand::START.bits());
+			dma.status.write((BusMasterStatus::INTERRUPT | BusMasterStatus::ERROR).bits());
+		}
+
+		if completion_status.intersects(BusMasterStatus::ERROR) {
+			return Err("DMA transfer failed: bus master status indicated an error");
+		}
+		self.wait_for_data_done().map_err(|_| "error after DMA transfer")?;
+
+		if !read {
+			// Flush the drive's cache after each write command
+			let cache_flush_cmd = if using_lba_28 { AtaCommand::CacheFlush } else { AtaCommand::CacheFlushExt };
+			unsafe { self.command.write(cache_flush_cmd as u8) };
+			self.wait_for_data_done().map_err(|_| "error after cache flush after DMA write")?;
+		}
+		Ok(())
+	}
+
 	/// Issues an ATA identify command to probe the drive
 	/// and query its characteristics. 
 	/// 
@@ -538,11 +984,16 @@ impl AtaBus {
 pub struct AtaDrive {
 	/// A reference to the bus that this drive sits on,
 	/// shared with the other AtaDrive that also sits on this bus.
-	bus: Arc<Mutex<AtaBus>>,
+	/// This is a sleeping lock because a DMA transfer may block while holding it,
+	/// such that a task waiting to use the other drive on this bus sleeps instead of spinning.
+	bus: Arc<MutexSleep<AtaBus>>,
 	/// Data that represents the characteristics of the drive. 
 	identify_data: AtaIdentifyData,
 	/// Whether this drive is a master or slave on the bus.
 	master_slave: BusDriveSelect,
+	/// Whether this drive can be accessed using DMA, 
+	/// which requires both the drive and its bus to support DMA.
+	dma_supported: bool,
 }
 
 impl AtaDrive {
@@ -552,25 +1003,83 @@ impl AtaDrive {
 	/// Since two drives (one master and one slave) may exist on one IDE bus (sharing the same data and control BAR),
 	/// the caller must specify *which* one to search for. 
 	/// The caller can look for both by calling this twice: once with `which = Master` and once with `which = Slave`.
-	fn new(bus: Arc<Mutex<AtaBus>>, which: BusDriveSelect) -> Result<AtaDrive, &'static str> {
+	fn new(bus: Arc<MutexSleep<AtaBus>>, which: BusDriveSelect) -> Result<AtaDrive, &'static str> {
 		// Issue a preliminary software reset of the bus to clear out lingering errors.
-		bus.lock().software_reset(); 
+		bus.lock()?.software_reset(); 
 		// Then use an identify command to see if the drive exists.
-		let identify_data = bus.lock().identify_drive(which)?;
+		let identify_data = bus.lock()?.identify_drive(which)?;
 
 		// Check to see that the drive supports LBA,
 		// because we don't support the ancient CHS (cylinder-head-sector) addressing scheme.
 		if identify_data.capabilities & 0x200 == 0 {
 			return Err("drive is an ancient CHS device that doesn't support LBA addressing mode, but we don't support CHS.");
 		}
+		let dma_supported = bus.lock()?.supports_dma() && (identify_data.capabilities & 0x100 != 0);
 
 		Ok(AtaDrive {
 			bus, 
 			identify_data,
 			master_slave: which,
+			dma_supported,
 		})
 	}
 
+	/// Checks that a transfer of the given `buffer_length` bytes starting at `offset_in_sectors` is valid for this drive,
+	/// i.e., that it lies entirely within this drive, and returns the number of sectors to be transferred.
+	fn check_transfer(&self, buffer_length: usize, offset_in_sectors: usize) -> Result<usize, &'static str> {
+		if buffer_length % SECTOR_SIZE_IN_BYTES != 0 {
+			return Err("The buffer length must be a multiple of sector size (512) bytes. ATA drives can only transfer at sector granularity.");
+		}
+		let sector_count = buffer_length / SECTOR_SIZE_IN_BYTES;
+		match offset_in_sectors.checked_add(sector_count) {
+			Some(end) if end <= self.size_in_sectors() => Ok(sector_count),
+			_ => Err("the transfer extends beyond the end of the drive"),
+		}
+	}
+
+	/// Reads data from this drive starting at the given `offset_in_sectors` into the provided `buffer`
+	/// using bus-master DMA. 
+	/// The length of the given `buffer` determines the number of bytes to be read.
+	/// 
+	/// As content is read from the drive at sector granularity, 
+	/// the buffer length must be a multiple of the sector size (512 bytes),
+	/// and the offset is specified in number of sectors (not number of bytes) from the beginning of the drive.
+	/// 
+	/// If the IDE controller is in compatibility mode, the current task blocks until each DMA transfer completes.
+	/// 
+	/// Returns the number of sectors (*not bytes*) that were successfully read from the drive.
+	pub fn read_dma(&mut self, buffer: &mut [u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+		if !self.dma_supported {
+			return Err("AtaDrive::read_dma(): this drive does not support DMA");
+		}
+		let sector_count = self.check_transfer(buffer.len(), offset_in_sectors)?;
+		self.bus.lock()?.read_dma(buffer, self.master_slave, offset_in_sectors, sector_count)
+	}
+
+	/// Writes data from the provided `buffer` to this drive, starting at the given `offset_in_sectors` into the drive,
+	/// using bus-master DMA.
+	/// The length of the given `buffer` determines the number of bytes to be written.
+	/// 
+	/// As content is written to the drive at sector granularity, 
+	/// the buffer length must be a multiple of the sector size (512 bytes),
+	/// and the offset is specified in number of sectors (not number of bytes) from the beginning of the drive.
+	/// 
+	/// If the IDE controller is in compatibility mode, the current task blocks until each DMA transfer completes.
+	/// 
+	/// Returns the number of sectors (*not bytes*) that were successfully written to the drive.
+	pub fn write_dma(&mut self, buffer: &[u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+		if !self.dma_supported {
+			return Err("AtaDrive::write_dma(): this drive does not support DMA");
+		}
+		let sector_count = self.check_transfer(buffer.len(), offset_in_sectors)?;
+		self.bus.lock()?.write_dma(buffer, self.master_slave, offset_in_sectors, sector_count)
+	}
+
+	/// Returns `true` if this drive can be accessed using DMA.
+	pub fn is_dma_supported(&self) -> bool {
+		self.dma_supported
+	}
+
 	/// Reads data from this drive starting at the given `offset_in_sectors` into the provided `buffer`.
 	/// The length of the given `buffer` determines the number of bytes to be written.
 	/// 
@@ -582,6 +1091,7 @@ impl AtaDrive {
 	/// 
 	/// # Note
 	/// This is slow, as it uses blocking port I/O instead of DMA. 
+	/// See [`read_dma()`](#method.read_dma) and [`write_dma()`](#method.write_dma).
 	pub fn read_pio(&mut self, buffer: &mut [u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
 		if offset_in_sectors > self.size_in_bytes() {
 			return Err("offset_in_sectors was out of bounds");
@@ -604,7 +1114,7 @@ impl AtaDrive {
 			return Err("AtaDrive::read_pio(): cannot read more sectors than the drive's max");
 		}
 		
-		self.bus.lock().read_pio(buffer, self.master_slave, lba_start, sector_count)
+		self.bus.lock()?.read_pio(buffer, self.master_slave, lba_start, sector_count)
 	}
 
 	/// Writes data from the provided `buffer` to this drive, starting at the given `offset_in_sectors` into the drive.
@@ -618,6 +1128,7 @@ impl AtaDrive {
 	/// 
 	/// # Note
 	/// This is slow, as it uses blocking port I/O instead of DMA. 
+	/// See [`read_dma()`](#method.read_dma) and [`write_dma()`](#method.write_dma).
 	pub fn write_pio(&mut self, buffer: &[u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
 		if offset_in_sectors > self.size_in_bytes() {
 			return Err("offset_in_sectors was out of bounds");
@@ -640,7 +1151,7 @@ impl AtaDrive {
 			return Err("AtaDrive::write_pio(): cannot write more sectors than the drive's max");
 		}
 
-		self.bus.lock().write_pio(buffer, self.master_slave, lba_start, sector_count)
+		self.bus.lock()?.write_pio(buffer, self.master_slave, lba_start, sector_count)
 	}
 
 
@@ -656,10 +1167,22 @@ impl AtaDrive {
 
 impl StorageDevice for AtaDrive {
 	fn read_sectors(&mut self, buffer: &mut [u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+		if self.dma_supported {
+			match self.read_dma(buffer, offset_in_sectors) {
+				Ok(sectors) => return Ok(sectors),
+				Err(e) => warn!("AtaDrive::read_sectors(): DMA read failed ({}), falling back to PIO.", e),
+			}
+		}
 		self.read_pio(buffer, offset_in_sectors)
 	}
 
     fn write_sectors(&mut self, buffer: &[u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+		if self.dma_supported {
+			match self.write_dma(buffer, offset_in_sectors) {
+				Ok(sectors) => return Ok(sectors),
+				Err(e) => warn!("AtaDrive::write_sectors(): DMA write failed ({}), falling back to PIO.", e),
+			}
+		}
 		self.write_pio(buffer, offset_in_sectors)
 	}
 
@@ -724,11 +1247,36 @@ impl IdeController {
 			}
 		};
 
-		// TODO: use the BAR4 for DMA in the future
-		let _bus_master_base = pci_device.bars[4]; 
+		let mut primary_bus = AtaBus::new(primary_bus_data_port, primary_bus_control_port);
+		let mut secondary_bus = AtaBus::new(secondary_bus_data_port, secondary_bus_control_port);
 
-		let primary_bus = Arc::new(Mutex::new(AtaBus::new(primary_bus_data_port, primary_bus_control_port)));
-		let secondary_bus = Arc::new(Mutex::new(AtaBus::new(secondary_bus_data_port, secondary_bus_control_port)));
+		// BAR4 holds the I/O port base of the bus master registers, which are used for DMA.
+		let bus_master_bar = pci_device.bars[4];
+		if pci_device.prog_if & PROG_IF_BUS_MASTER == 0 || bus_master_bar & 0x1 == 0 || bus_master_bar & !0x3 == 0 {
+			info!("IDE controller at {} does not support bus-master DMA (prog_if: {:#X}, BAR4: {:#X}), using PIO only.", 
+				pci_device.location, pci_device.prog_if, bus_master_bar
+			);
+		} else {
+			let bus_master_base = bus_master_bar as u16 & PCI_BAR_PORT_MASK;
+			setup_dma(
+				&mut primary_bus,
+				IdeChannel::Primary,
+				bus_master_base,
+				primary_bus_data_port + 7,
+				pci_device.prog_if & PROG_IF_PRIMARY_NATIVE_MODE == 0,
+			);
+			setup_dma(
+				&mut secondary_bus,
+				IdeChannel::Secondary,
+				bus_master_base + BUS_MASTER_SECONDARY_CHANNEL_OFFSET,
+				secondary_bus_data_port + 7,
+				pci_device.prog_if & PROG_IF_SECONDARY_NATIVE_MODE == 0,
+			);
+			pci_device.pci_set_command_bus_master_bit();
+		}
+
+		let primary_bus = Arc::new(MutexSleep::new(primary_bus));
+		let secondary_bus = Arc::new(MutexSleep::new(secondary_bus));
 
 		let primary_master   = AtaDrive::new(Arc::clone(&primary_bus), BusDriveSelect::Master);
 		let primary_slave    = AtaDrive::new(primary_bus, BusDriveSelect::Slave);
@@ -775,6 +1323,27 @@ impl IdeController {
 	}
 }
 
+/// Sets up bus-master DMA on the given `bus`, which is the given `channel` of an IDE controller
+/// whose bus master registers for that channel start at the given `bus_master_base` port.
+/// 
+/// If `compatibility_mode` is `true`, the channel uses the legacy IRQ 14 or 15 to signal DMA completion,
+/// so the channel's interrupt state is set up such that tasks can block while waiting for DMA transfers.
+/// Channels in PCI native mode share a PCI interrupt instead, so their DMA transfers are polled for completion.
+fn setup_dma(bus: &mut AtaBus, channel: IdeChannel, bus_master_base: u16, ata_status_port: u16, compatibility_mode: bool) {
+	match BusMasterDma::new(bus_master_base, channel) {
+		Ok(dma) => {
+			if compatibility_mode {
+				let state = &CHANNEL_INTERRUPT_STATE[channel as usize];
+				state.bus_master_status_port.store(bus_master_base + 2, Ordering::Release);
+				state.ata_status_port.store(ata_status_port, Ordering::Release);
+				state.enabled.store(true, Ordering::Release);
+			}
+			bus.dma = Some(dma);
+		}
+		Err(e) => warn!("Couldn't set up DMA for IDE {:?} channel, using PIO only. Error: {}", channel, e),
+	}
+}
+
 impl StorageController for IdeController {
     fn devices<'c>(&'c self) -> Box<(dyn Iterator<Item = StorageDeviceRef> + 'c)> {
 		Box::new(
@@ -976,7 +1545,7 @@ pub struct AtaIdentifyData {
 impl AtaIdentifyData {
 	/// Converts the given byte array, which should be the result of an ATA identify command,
 	/// into a struct that contains the identified details of an ATA drive.
-	fn new(arr: [u8; SECTOR_SIZE_IN_BYTES])-> AtaIdentifyData {
+	pub fn new(arr: [u8; SECTOR_SIZE_IN_BYTES])-> AtaIdentifyData {
 		let mut identify_data: AtaIdentifyData = unsafe { core::mem::transmute(arr) };
 		Self::flip_bytes(&mut identify_data.serial_number.0);
 		Self::flip_bytes(&mut identify_data.firmware_version.0);
@@ -2,6 +2,16 @@
 //! 
 //! For example, these wrappers can expose a storage device that transfers 512-byte blocks at a time
 //! as a device that can transfer arbitrary bytes at a time (as little as one byte). 
+//!
+//! # Caching
+//! Blocks read from or written to the storage device are kept in a bounded cache,
+//! whose capacity and write policy are specified by a [`CacheConfig`](struct.CacheConfig.html).
+//! When the cache is full, the least recently used block is evicted to make room for a new one,
+//! first writing it back to the storage device if it is dirty.
+//!
+//! With the [`WriteBack`](enum.WritePolicy.html#variant.WriteBack) policy, written blocks are only marked as dirty,
+//! and are written back to the storage device upon eviction, upon an explicit [`sync()`](struct.BlockIo.html#method.sync),
+//! when the `BlockIo` is dropped, or periodically by a background flush task.
 //! 
 //! # Limitations
 //! Currently, the `BlockIo` struct is hardcoded to use a `StorageDevice` reference,
@@ -13,22 +23,102 @@
 //! 
 //! Cached blocks are stored as vectors of bytes on the heap, 
 //! we should do something else such as separate mapped regions. 
-//! Cached blocks cannot yet be dropped to relieve memory pressure. 
 
 #![no_std]
 
 #[macro_use] extern crate alloc;
 #[macro_use] extern crate log;
+extern crate spin;
 extern crate hashbrown;
+extern crate sleep;
+extern crate spawn;
 extern crate storage_device;
 
-use alloc::vec::Vec;
-use hashbrown::{
-    HashMap,
-    hash_map::Entry,
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
 };
+use spin::Mutex;
+use hashbrown::HashMap;
 use storage_device::{StorageDevice, StorageDeviceRef, BlockBounds};
 
+
+/// The default number of blocks that a `BlockIo` cache can hold.
+/// For 512-byte sectors, this is 2 MiB of cached data.
+pub const DEFAULT_CACHE_CAPACITY_IN_BLOCKS: usize = 4096;
+
+/// The default interval between periodic flushes of dirty blocks in a write-back cache.
+pub const DEFAULT_FLUSH_INTERVAL_MS: u64 = 5000;
+
+
+/// The policy that determines when written blocks are written to the backing storage device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Written blocks are written to the cache and the backing storage device immediately.
+    WriteThrough,
+    /// Written blocks are only written to the cache and marked as dirty.
+    /// Dirty blocks are written back to the storage device when they are evicted from the cache,
+    /// when the cache is synced, or periodically by a background flush task.
+    WriteBack,
+}
+
+/// The configuration of a `BlockIo`'s cache.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheConfig {
+    /// The maximum number of blocks held in the cache. Must be at least one.
+    pub capacity_in_blocks: usize,
+    /// When written blocks are written to the backing storage device.
+    pub write_policy: WritePolicy,
+    /// If `Some`, a background task is spawned that writes back the cache's dirty blocks at this interval.
+    /// This is only used with the `WriteBack` policy.
+    pub flush_interval_ms: Option<u64>,
+}
+
+impl Default for CacheConfig {
+    /// Returns a write-through cache configuration with the default capacity.
+    fn default() -> CacheConfig {
+        CacheConfig {
+            capacity_in_blocks: DEFAULT_CACHE_CAPACITY_IN_BLOCKS,
+            write_policy: WritePolicy::WriteThrough,
+            flush_interval_ms: None,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Returns a write-back cache configuration with the default capacity and flush interval.
+    pub fn write_back() -> CacheConfig {
+        CacheConfig {
+            capacity_in_blocks: DEFAULT_CACHE_CAPACITY_IN_BLOCKS,
+            write_policy: WritePolicy::WriteBack,
+            flush_interval_ms: Some(DEFAULT_FLUSH_INTERVAL_MS),
+        }
+    }
+}
+
+
+/// Statistics about the usage of a `BlockIo`'s cache, as returned by [`BlockIo::stats()`](struct.BlockIo.html#method.stats).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    /// The number of block accesses that were served from the cache.
+    pub hits: u64,
+    /// The number of block accesses that required reading the block from the storage device.
+    pub misses: u64,
+    /// The number of blocks evicted from the cache to make room for other blocks.
+    pub evictions: u64,
+    /// The number of blocks written to the storage device.
+    pub writebacks: u64,
+    /// The number of blocks currently in the cache.
+    pub cached_blocks: usize,
+    /// The number of blocks currently in the cache that have not yet been written to the storage device.
+    pub dirty_blocks: usize,
+    /// The maximum number of blocks that the cache can hold.
+    pub capacity_in_blocks: usize,
+}
+
+
 /// A wrapper around a `StorageDevice` that supports reads and writes of arbitrary byte lengths
 /// (down to a single byte) by issuing commands to the underlying storage device.
 /// This is needed because most storage devices only allow reads/writes of larger blocks, 
@@ -36,18 +126,38 @@ use storage_device::{StorageDevice, StorageDeviceRef, BlockBounds};
 /// 
 /// It also contains a cache for the blocks in the backing storage device,
 /// in order to improve performance by avoiding actual storage device access.
+///
+/// When a `BlockIo` is dropped, all of its dirty blocks are written back to the storage device.
 pub struct BlockIo {
     /// The cache of blocks (sectors) read from the storage device,
-    /// a map from sector number to data byte array.
-    cache: BlockCache, 
+    /// which is shared with the background flush task, if there is one.
+    cache: Arc<Mutex<BlockCache>>,
     /// The underlying storage device from where the blocks are read/written.
     device: StorageDeviceRef,
 }
 impl BlockIo {
-    /// Creates a new `BlockIo` device 
+    /// Creates a new `BlockIo` device with the default (write-through) cache configuration.
     pub fn new(storage_device: StorageDeviceRef) -> BlockIo {
+        Self::with_config(storage_device, CacheConfig::default())
+    }
+
+    /// Creates a new `BlockIo` device whose cache uses the given `config`.
+    ///
+    /// If the `config` specifies a write-back policy with a flush interval,
+    /// a background task is spawned that periodically writes back dirty blocks.
+    /// That task exits once this `BlockIo` is dropped.
+    pub fn with_config(storage_device: StorageDeviceRef, config: CacheConfig) -> BlockIo {
+        let cache = Arc::new(Mutex::new(BlockCache::new(&config)));
+        if let (WritePolicy::WriteBack, Some(interval_ms)) = (config.write_policy, config.flush_interval_ms) {
+            let spawn_result = spawn::new_task_builder(flush_task, (Arc::downgrade(&cache), Arc::clone(&storage_device), interval_ms))
+                .name(String::from("block_io_flush"))
+                .spawn();
+            if let Err(e) = spawn_result {
+                warn!("BlockIo: couldn't spawn background flush task, dirty blocks will only be written back upon sync or eviction. Error: {}", e);
+            }
+        }
         BlockIo {
-            cache: HashMap::new(),
+            cache,
             device: storage_device, 
         }
     }
@@ -60,8 +170,9 @@ impl BlockIo {
     /// 
     /// The read blocks will be cached in this `BlockIo` struct to accelerate future storage device access.
     pub fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, &'static str> {
+        let mut cache = self.cache.lock();
         let mut locked_device = self.device.lock();
-        let BlockBounds { range, first_block_offset, .. } = locked_device.block_bounds(buffer.len(), offset)?;
+        let BlockBounds { range, first_block_offset, .. } = locked_device.block_bounds(offset, buffer.len())?;
         let block_size_in_bytes = locked_device.sector_size_in_bytes();
 
         // Read the actual data, one block at a time.
@@ -70,7 +181,7 @@ impl BlockIo {
 		for block_num in range {
 			// don't copy past the end of `buffer`
 			let num_bytes_to_copy = core::cmp::min(block_size_in_bytes - src_offset, buffer.len() - dest_offset);
-            let block_bytes = Self::read_block(&mut self.cache, &mut *locked_device, block_num)?;
+            let block_bytes = cache.read_block(&mut *locked_device, block_num)?;
 			buffer[dest_offset .. (dest_offset + num_bytes_to_copy)].copy_from_slice(&block_bytes[src_offset .. (src_offset + num_bytes_to_copy)]);
 			trace!("BlockIo::read(): for block {}, copied bytes into buffer[{}..{}] from block[{}..{}]",
 				block_num, dest_offset, dest_offset + num_bytes_to_copy, src_offset, src_offset + num_bytes_to_copy,
@@ -88,11 +199,13 @@ impl BlockIo {
 	/// Returns the number of bytes that were successfully written to the storage device.
     /// 
     /// The written blocks will be cached in this `BlockIo` struct to accelerate future storage device access.
-    /// Currently, we use a *write-through* cache policy,
-    /// in which the blocks are written directly to the cache and the backing storage device immediately.
+    /// With a write-through policy, the blocks are written to the cache and the backing storage device immediately;
+    /// with a write-back policy, they are only written to the cache and marked dirty.
+    /// See [`WritePolicy`](enum.WritePolicy.html).
     pub fn write(&mut self, buffer: &[u8], offset: usize) -> Result<usize, &'static str> {
+        let mut cache = self.cache.lock();
         let mut locked_device = self.device.lock();
-        let block_bounds = locked_device.block_bounds(buffer.len(), offset)?;
+        let block_bounds = locked_device.block_bounds(offset, buffer.len())?;
         let block_size_in_bytes = locked_device.sector_size_in_bytes();
 
         // // A write transfer (and a read too) can be broken down into three parts: 
@@ -112,7 +225,7 @@ impl BlockIo {
                 (&buffer[src_offset .. (src_offset + num_bytes_to_copy)]).to_vec()
             } else {
                 // We're only partially writing to this block, so we need to read the old block first.
-                let old_block = Self::read_block(&mut self.cache, &mut *locked_device, block_num)?;
+                let old_block = cache.read_block(&mut *locked_device, block_num)?;
                 let mut new_block_contents = old_block.to_vec();
                 let overwrite_offset = dest_offset % block_size_in_bytes;
                 new_block_contents[overwrite_offset .. (overwrite_offset + num_bytes_to_copy)]
@@ -120,13 +233,7 @@ impl BlockIo {
                 new_block_contents
             };
 
-            let mut new_cached_block = CachedBlock {
-                block: buffer_to_write,
-                state: CacheState::Modified,
-            };
-            // Currently using a write-through policy right now, so flush the block immediately
-            Self::flush_block(&mut *locked_device, block_num, &mut new_cached_block)?;
-            self.cache.insert(block_num, new_cached_block);
+            cache.write_block(&mut *locked_device, block_num, buffer_to_write)?;
 			trace!("BlockIo::write(): for block {}, copied bytes from buffer[{}..{}] to block[{}..{}]",
 				block_num, src_offset, src_offset + num_bytes_to_copy, dest_offset, dest_offset + num_bytes_to_copy,
 			);
@@ -141,70 +248,62 @@ impl BlockIo {
     /// If the `block_to_flush` is None, all blocks in the entire cache
     /// will be written back to the storage device.
     pub fn flush(&mut self, block_num: Option<usize>) -> Result<(), &'static str> {
+        let mut cache = self.cache.lock();
         let mut locked_device = self.device.lock();
         if let Some(bn) = block_num {
-            // Flush just one block
-            if let Some(cached_block) = self.cache.get_mut(&bn) {
-                Self::flush_block(&mut *locked_device, bn, cached_block)?;
-            }
-            // If the block wasn't in the cache, do nothing.
+            // Flush just one block. If the block wasn't in the cache, do nothing.
+            cache.flush_block(&mut *locked_device, bn)
         }
         else {
-            // Flush all blocks
-            for (bn, cached_block) in self.cache.iter_mut() {
-                Self::flush_block(&mut *locked_device, *bn, cached_block)?;
-            }
+            cache.flush_all(&mut *locked_device)
         }
-        Ok(())
     }
 
-    /// An internal function that first checks the cache for a specific block
-    /// in order to avoid reading from the storage device.
-    /// If that block exists in the cache, it is copied into the buffer. 
-    /// If not, it is read from the storage device into the cache, and then copied into the buffer.
-    fn read_block<'c>(cache: &'c mut BlockCache, locked_device: &mut dyn StorageDevice, block: usize) -> Result<&'c [u8], &'static str> {
-        match cache.entry(block) {
-            Entry::Occupied(occ) => {
-                // An existing entry in the cache can be used directly (without going to the backing store)
-                // if it's in the `Modified` or `Shared` state.
-                // But if it's in the `Invalid` state, we have to re-read the block from the storage device.
-                let cached_block = occ.into_mut();
-                match cached_block.state {
-                    CacheState::Modified | CacheState::Shared => Ok(&cached_block.block),
-                    CacheState::Invalid => {
-                        locked_device.read_sectors(&mut cached_block.block, block)?;
-                        cached_block.state = CacheState::Shared;
-                        Ok(&cached_block.block)
-                    }
-                }
-            }
-            Entry::Vacant(vacant) => {
-                // A vacant entry will be read from the backing storage device,
-                // so it will always start out in the `Shared` state.
-                let mut v = vec![0; locked_device.sector_size_in_bytes()];
-                locked_device.read_sectors(&mut v, block)?;
-                let cb = CachedBlock {
-                    block: v,
-                    state: CacheState::Shared,
-                };
-                let cached_block = vacant.insert(cb);
-                Ok(&cached_block.block)
-            }
+    /// Writes all dirty blocks in the cache back to the storage device.
+    ///
+    /// Once this returns successfully, every write made through this `BlockIo` before this call
+    /// has reached the storage device, regardless of the cache's write policy.
+    pub fn sync(&mut self) -> Result<(), &'static str> {
+        self.flush(None)
+    }
+
+    /// Returns statistics about the usage of this `BlockIo`'s cache.
+    pub fn stats(&self) -> CacheStats {
+        self.cache.lock().stats()
+    }
+
+    /// Returns the configuration of this `BlockIo`'s cache.
+    pub fn config(&self) -> CacheConfig {
+        self.cache.lock().config
+    }
+}
+
+impl Drop for BlockIo {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            error!("BlockIo: failed to write back dirty blocks when dropped: {}", e);
         }
     }
+}
 
-    /// An internal function that writes out the given `cached_block`
-    /// to the given locked `StorageDevice` if the cached block is in the `Modified` state.
-    fn flush_block(locked_device: &mut dyn StorageDevice, block_num: usize, cached_block: &mut CachedBlock) -> Result<(), &'static str> {
-        // we only need to actually write blocks in the `Modified` state.
-        match cached_block.state {
-            CacheState::Shared | CacheState::Invalid => { },
-            CacheState::Modified => {
-                locked_device.write_sectors(&cached_block.block, block_num)?;
-                cached_block.state = CacheState::Shared;
-            }
+
+/// The entry point of the background task that periodically writes back the dirty blocks in a write-back cache.
+/// The task exits once the cache has been dropped.
+fn flush_task((cache, device, interval_ms): (Weak<Mutex<BlockCache>>, StorageDeviceRef, u64)) -> Result<(), &'static str> {
+    loop {
+        sleep::sleep_ms(interval_ms)?;
+        let cache_ref = match cache.upgrade() {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let mut locked_cache = cache_ref.lock();
+        if locked_cache.dirty_blocks == 0 {
+            continue;
+        }
+        let mut locked_device = device.lock();
+        if let Err(e) = locked_cache.flush_all(&mut *locked_device) {
+            error!("BlockIo flush task: failed to write back dirty blocks: {}", e);
         }
-        Ok(())
     }
 }
 
@@ -213,19 +312,193 @@ impl BlockIo {
 /// A block from a storage device stored in a cache.
 /// This currently includes the actual owned cached content as a vector of bytes on the heap,
 /// in addition to the `CacheState` of the cached item.
-/// 
-/// TODO: allow non-dirty blocks to be freed (reclaimed) upon memory pressure. 
 #[derive(Debug)]
 struct CachedBlock {
     block: Vec<u8>,
     state: CacheState,
+    /// The time (in cache accesses) at which this block was last accessed, which is its key in the LRU list.
+    last_access: u64,
+}
+
+
+/// A bounded cache of blocks with least-recently-used (LRU) eviction.
+struct BlockCache {
+    /// The cached blocks, a map from block (sector) number to the cached block.
+    blocks: HashMap<usize, CachedBlock>,
+    /// The cached block numbers ordered from least to most recently used,
+    /// as a map from the time of the last access to the block number.
+    lru: BTreeMap<u64, usize>,
+    /// A counter that is incremented upon every block access, used to order the LRU list.
+    access_counter: u64,
+    /// The number of blocks in the `Modified` state.
+    dirty_blocks: usize,
+    config: CacheConfig,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    writebacks: u64,
 }
 
-type BlockCache = HashMap<usize, CachedBlock>;
+impl BlockCache {
+    fn new(config: &CacheConfig) -> BlockCache {
+        let mut config = *config;
+        if config.capacity_in_blocks == 0 {
+            warn!("BlockIo: cache capacity must be at least one block, using one block.");
+            config.capacity_in_blocks = 1;
+        }
+        BlockCache {
+            blocks: HashMap::new(),
+            lru: BTreeMap::new(),
+            access_counter: 0,
+            dirty_blocks: 0,
+            config,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+            writebacks: 0,
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            writebacks: self.writebacks,
+            cached_blocks: self.blocks.len(),
+            dirty_blocks: self.dirty_blocks,
+            capacity_in_blocks: self.config.capacity_in_blocks,
+        }
+    }
+
+    /// Marks the given cached block as the most recently used one.
+    fn touch(&mut self, block_num: usize) {
+        self.access_counter += 1;
+        let now = self.access_counter;
+        if let Some(cached_block) = self.blocks.get_mut(&block_num) {
+            self.lru.remove(&cached_block.last_access);
+            cached_block.last_access = now;
+            self.lru.insert(now, block_num);
+        }
+    }
+
+    /// Checks the cache for a specific block in order to avoid reading from the storage device.
+    /// If that block exists in the cache, a reference to its contents is returned.
+    /// If not, it is read from the storage device into the cache first, evicting another block if the cache is full.
+    fn read_block(&mut self, locked_device: &mut dyn StorageDevice, block_num: usize) -> Result<&[u8], &'static str> {
+        let cached = self.blocks.get(&block_num).map(|cb| cb.state);
+        match cached {
+            // An existing entry in the cache can be used directly (without going to the backing store)
+            // if it's in the `Modified` or `Shared` state.
+            Some(CacheState::Modified) | Some(CacheState::Shared) => {
+                self.hits += 1;
+            }
+            // But if it's in the `Invalid` state, we have to re-read the block from the storage device.
+            Some(CacheState::Invalid) => {
+                self.misses += 1;
+                let cached_block = self.blocks.get_mut(&block_num).ok_or("BUG: BlockCache lost a block")?;
+                locked_device.read_sectors(&mut cached_block.block, block_num)?;
+                cached_block.state = CacheState::Shared;
+            }
+            // A block that isn't cached will be read from the backing storage device,
+            // so it will always start out in the `Shared` state.
+            None => {
+                self.misses += 1;
+                let mut v = vec![0; locked_device.sector_size_in_bytes()];
+                locked_device.read_sectors(&mut v, block_num)?;
+                self.insert(locked_device, block_num, v, CacheState::Shared)?;
+            }
+        }
+        self.touch(block_num);
+        self.blocks.get(&block_num)
+            .map(|cb| &cb.block[..])
+            .ok_or("BUG: BlockCache lost a block")
+    }
+
+    /// Writes the given `contents` of a whole block into the cache,
+    /// and also to the storage device if this cache uses the write-through policy.
+    fn write_block(&mut self, locked_device: &mut dyn StorageDevice, block_num: usize, contents: Vec<u8>) -> Result<(), &'static str> {
+        match self.blocks.get_mut(&block_num) {
+            Some(cached_block) => {
+                cached_block.block = contents;
+                if cached_block.state != CacheState::Modified {
+                    cached_block.state = CacheState::Modified;
+                    self.dirty_blocks += 1;
+                }
+            }
+            None => self.insert(locked_device, block_num, contents, CacheState::Modified)?,
+        }
+        self.touch(block_num);
+        if self.config.write_policy == WritePolicy::WriteThrough {
+            self.flush_block(locked_device, block_num)?;
+        }
+        Ok(())
+    }
+
+    /// Inserts a block that is not yet in the cache, evicting the least recently used block if the cache is full.
+    fn insert(&mut self, locked_device: &mut dyn StorageDevice, block_num: usize, block: Vec<u8>, state: CacheState) -> Result<(), &'static str> {
+        while self.blocks.len() >= self.config.capacity_in_blocks {
+            self.evict_one(locked_device)?;
+        }
+        if state == CacheState::Modified {
+            self.dirty_blocks += 1;
+        }
+        self.access_counter += 1;
+        let now = self.access_counter;
+        self.blocks.insert(block_num, CachedBlock { block, state, last_access: now });
+        self.lru.insert(now, block_num);
+        Ok(())
+    }
+
+    /// Evicts the least recently used block, writing it back to the storage device first if it is dirty.
+    fn evict_one(&mut self, locked_device: &mut dyn StorageDevice) -> Result<(), &'static str> {
+        let (&last_access, &block_num) = self.lru.iter().next().ok_or("BUG: BlockCache was full but had no LRU entries")?;
+        // A dirty block cannot be dropped until it has been written back.
+        self.flush_block(locked_device, block_num)?;
+        self.lru.remove(&last_access);
+        self.blocks.remove(&block_num);
+        self.evictions += 1;
+        Ok(())
+    }
+
+    /// Writes out the given block to the given locked `StorageDevice` if the cached block is in the `Modified` state.
+    /// If the block isn't in the cache, this does nothing.
+    fn flush_block(&mut self, locked_device: &mut dyn StorageDevice, block_num: usize) -> Result<(), &'static str> {
+        if let Some(cached_block) = self.blocks.get_mut(&block_num) {
+            // we only need to actually write blocks in the `Modified` state.
+            match cached_block.state {
+                CacheState::Shared | CacheState::Invalid => { },
+                CacheState::Modified => {
+                    locked_device.write_sectors(&cached_block.block, block_num)?;
+                    cached_block.state = CacheState::Shared;
+                    self.dirty_blocks -= 1;
+                    self.writebacks += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out all dirty blocks to the given locked `StorageDevice`, in ascending block order.
+    fn flush_all(&mut self, locked_device: &mut dyn StorageDevice) -> Result<(), &'static str> {
+        if self.dirty_blocks == 0 {
+            return Ok(());
+        }
+        let mut dirty: Vec<usize> = self.blocks.iter()
+            .filter(|(_, cb)| cb.state == CacheState::Modified)
+            .map(|(bn, _)| *bn)
+            .collect();
+        dirty.sort_unstable();
+        for block_num in dirty {
+            self.flush_block(locked_device, block_num)?;
+        }
+        Ok(())
+    }
+}
 
 
 /// The states of an item in the cache, following the MSI cache coherence protocol.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
 enum CacheState {
     /// Dirty: the cached item has been modified more recently than the backing store,
@@ -45,6 +45,9 @@ extern crate exceptions_full;
 extern crate network_manager;
 extern crate window_manager;
 extern crate multiple_heaps;
+extern crate sleep;
+extern crate fat32;
+extern crate initrd;
 #[cfg(simd_personality)] extern crate simd_personality;
 
 
@@ -121,6 +124,10 @@ pub fn init(
     // //initialize the per core heaps
     multiple_heaps::switch_to_multiple_heaps()?;
     info!("Initialized per-core heaps");
+    #[cfg(not(unsafe_heap))]
+    spawn::new_task_builder(heap_reclaim_task, ())
+        .name(alloc::string::String::from("heap_reclaim"))
+        .spawn()?;
 
     // initialize window manager.
     let (key_producer, mouse_producer) = window_manager::init()?;
@@ -128,6 +135,8 @@ pub fn init(
     // initialize the rest of our drivers
     device_manager::init(key_producer, mouse_producer)?;
     task_fs::init()?;
+    fat32::init()?;
+    initrd::init()?;
 
 
     // Before we start running applications, we need to unmap the identity-mapped section of the kernel's page tables, at PML4[0].
@@ -174,3 +183,25 @@ pub fn init(
         error!("BUG: captain::init(): captain's bootstrap task was rescheduled after being dead!");
     }
 }
+
+
+/// The interval at which the heap reclaim task checks whether the per-core heaps have empty pages to reclaim.
+#[cfg(not(unsafe_heap))]
+const HEAP_RECLAIM_INTERVAL_MS: u64 = 1000;
+
+/// The entry point of the background task that returns the empty pages of the per-core heaps to the frame allocator.
+/// 
+/// The heaps only note that they have too many empty pages when memory is deallocated,
+/// since unmapping those pages within the deallocation could deadlock, so this task does the unmapping for them.
+#[cfg(not(unsafe_heap))]
+fn heap_reclaim_task(_: ()) -> Result<(), &'static str> {
+    loop {
+        sleep::sleep_ms(HEAP_RECLAIM_INTERVAL_MS)?;
+        if multiple_heaps::has_reclaimable_pages() {
+            match multiple_heaps::reclaim_empty_pages() {
+                Ok(reclaimed) => debug!("heap_reclaim_task: reclaimed {} empty heap pages", reclaimed),
+                Err(e) => error!("heap_reclaim_task: failed to reclaim empty heap pages: {}", e),
+            }
+        }
+    }
+}
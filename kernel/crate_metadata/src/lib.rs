@@ -61,7 +61,8 @@ use alloc::{
     vec::Vec,
 };
 use memory::{MappedPages, VirtualAddress, EntryFlags};
-#[cfg(internal_deps)] use memory::{PageTable, FrameAllocator};
+#[cfg(internal_deps)] use core::ops::DerefMut;
+#[cfg(internal_deps)] use memory::{MmiRef, get_frame_allocator_ref};
 use cow_arc::{CowArc, CowWeak};
 use fs_node::{FileRef, WeakFileRef};
 use hashbrown::HashMap;
@@ -356,13 +357,36 @@ impl LoadedCrate {
         results
     }
 
+    /// Returns the amount of memory used by this crate's sections and by the pages they are loaded into.
+    pub fn memory_usage(&self) -> CrateMemoryUsage {
+        let mut usage = CrateMemoryUsage::default();
+        for sec in self.sections.values() {
+            match sec.typ {
+                SectionType::Text => usage.text_bytes += sec.size(),
+                SectionType::Rodata | SectionType::GccExceptTable | SectionType::EhFrame => usage.rodata_bytes += sec.size(),
+                SectionType::Data | SectionType::Bss => usage.data_bytes += sec.size(),
+            }
+        }
+        usage.mapped_pages = [&self.text_pages, &self.rodata_pages, &self.data_pages].iter()
+            .filter_map(|pages| pages.as_ref())
+            .map(|(mp, _)| mp.lock().size_in_pages())
+            .sum();
+        usage
+    }
+
     /// Creates a new copy of this `LoadedCrate`, which is a relatively slow process
     /// because it must do the following:    
-    /// * Deep copy all of the MappedPages into completely new memory regions.
+    /// * Copy all of the MappedPages into completely new memory regions.
+    ///   These are copy-on-write copies that share the original crate's memory,
+    ///   so only the pages that are written afterwards, e.g., by relocations or to data/bss sections, are actually duplicated.
     /// * Duplicate every section within this crate.
     /// * Recalculate every relocation entry to point to the newly-copied sections,
     ///   which is the most time-consuming component of this function.
     /// 
+    /// # Locking / Deadlock
+    /// Writing relocations into the copied pages may need to allocate frames from within the page fault handler, 
+    /// so the caller must not hold the lock on the frame allocator or on `kernel_mmi_ref`.
+    /// 
     /// # Notes
     /// This is obviously different from cloning a shared Arc reference to this `LoadedCrate`,
     /// i.e., a `StrongCrateRef`, which is an instant and cheap operation that does not duplicate the underlying `LoadedCrate`.
@@ -375,28 +399,41 @@ impl LoadedCrate {
     /// 
     /// This is only available when the `internal_deps` cfg option is set.
     #[cfg(internal_deps)]
-    pub fn deep_copy<A: FrameAllocator>(
+    pub fn deep_copy(
         &self, 
-        page_table: &mut PageTable, 
-        allocator: &mut A
+        kernel_mmi_ref: &MmiRef,
     ) -> Result<StrongCrateRef, &'static str> {
 
-        // This closure deep copies the given mapped_pages (mapping them as WRITABLE)
+        // This closure copies the given mapped_pages on write (mapping them as WRITABLE)
         // and recalculates the the range of addresses covered by the new mapping.
-        let mut deep_copy_mp = |old_mp_range: &(Arc<Mutex<MappedPages>>, Range<VirtualAddress>), flags: EntryFlags|
+        let deep_copy_mp = |old_mp_range: &(Arc<Mutex<MappedPages>>, Range<VirtualAddress>), flags: EntryFlags|
             -> Result<(Arc<Mutex<MappedPages>>, Range<VirtualAddress>), &'static str> 
         {
-            let old_mp_locked = old_mp_range.0.lock();
+            let mut old_mp_locked = old_mp_range.0.lock();
             let old_start_address = old_mp_range.1.start.value();
             let size = old_mp_range.1.end.value() - old_start_address;
             let offset = old_start_address - old_mp_locked.start_address().value();
-            let new_mp = old_mp_range.0.lock().deep_copy(Some(flags | EntryFlags::WRITABLE), page_table, allocator)?;
+            let allocator = get_frame_allocator_ref().ok_or("couldn't get frame allocator")?;
+            let cow_result = old_mp_locked.copy_on_write(
+                Some(flags | EntryFlags::WRITABLE),
+                &mut kernel_mmi_ref.lock().page_table,
+                allocator.lock().deref_mut(),
+            );
+            let new_mp = match cow_result {
+                Ok(mp) => mp,
+                // Some memory can't be shared, e.g., the sections of the nano_core, so it must be duplicated right away.
+                Err(_e) => old_mp_locked.deep_copy(
+                    Some(flags | EntryFlags::WRITABLE),
+                    &mut kernel_mmi_ref.lock().page_table,
+                    allocator.lock().deref_mut(),
+                )?,
+            };
             let new_start_address = new_mp.start_address() + offset;
             Ok((Arc::new(Mutex::new(new_mp)), new_start_address .. (new_start_address + size)))
         };
 
-        // First, deep copy all of the memory regions.
-        // We initially map the as writable because we'll have to copy things into them
+        // First, copy all of the memory regions.
+        // We initially map them as writable because we'll have to write relocations into them
         let (new_text_pages_range, new_rodata_pages_range, new_data_pages_range) = {
             let new_text_pages = match self.text_pages {
                 Some(ref tp) => Some(deep_copy_mp(tp, TEXT_SECTION_FLAGS)?),
@@ -550,10 +587,10 @@ impl LoadedCrate {
 
         // since we mapped all the new MappedPages as writable, we need to properly remap them.
         if let Some(ref mut tp) = new_text_pages_locked { 
-            tp.remap(page_table, TEXT_SECTION_FLAGS)?;
+            tp.remap(&mut kernel_mmi_ref.lock().page_table, TEXT_SECTION_FLAGS)?;
         }
         if let Some(ref mut rp) = new_rodata_pages_locked { 
-            rp.remap(page_table, RODATA_SECTION_FLAGS)?;
+            rp.remap(&mut kernel_mmi_ref.lock().page_table, RODATA_SECTION_FLAGS)?;
         }
         // data/bss sections are already mapped properly, since they're writable
 
@@ -569,6 +606,29 @@ impl LoadedCrate {
 }
 
 
+/// The memory used by a `LoadedCrate`, see [`LoadedCrate::memory_usage()`](struct.LoadedCrate.html#method.memory_usage).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CrateMemoryUsage {
+    /// The total size in bytes of the crate's `.text` sections.
+    pub text_bytes: usize,
+    /// The total size in bytes of the crate's read-only sections,
+    /// i.e., its `.rodata`, `.eh_frame`, and `.gcc_except_table` sections.
+    pub rodata_bytes: usize,
+    /// The total size in bytes of the crate's `.data` and `.bss` sections.
+    pub data_bytes: usize,
+    /// The number of pages that the crate's sections are loaded into, 
+    /// which includes any unused space at the end of each mapping.
+    pub mapped_pages: usize,
+}
+
+impl CrateMemoryUsage {
+    /// The total size in bytes of all of the crate's sections.
+    pub fn section_bytes(&self) -> usize {
+        self.text_bytes + self.rodata_bytes + self.data_bytes
+    }
+}
+
+
 /// The possible types of sections that can be loaded from a crate object file.
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum SectionType {
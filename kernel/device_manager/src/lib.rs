@@ -63,7 +63,7 @@ pub fn init(key_producer: Queue<Event>, mouse_producer: Queue<Event>) -> Result<
             continue;
         }
 
-        // If this is a storage device, initialize it as such.
+        // If this is a storage device (e.g., an IDE, AHCI, or virtio block device), initialize it as such.
         match storage_manager::init_device(dev) {
             // finished with this device, proceed to the next one.
             Ok(true)  => continue,
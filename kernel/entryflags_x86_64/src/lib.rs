@@ -21,6 +21,8 @@ bitflags! {
         const HUGE_PAGE         = 1 << 7;
         // const GLOBAL            = 1 << 8;
         const GLOBAL            = 0; // disabling because VirtualBox doesn't like it
+        // bits 9 to 11 are ignored by the hardware and available for use by the OS
+        const COPY_ON_WRITE     = 1 << 9;
         const NO_EXECUTE        = 1 << 63;
     }
 
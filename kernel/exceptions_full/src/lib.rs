@@ -16,12 +16,14 @@ extern crate debug_info;
 extern crate gimli;
 
 extern crate memory;
+extern crate tss;
 extern crate stack_trace;
 extern crate fault_log;
 
+use core::mem;
 use x86_64::structures::idt::{LockedIdt, ExceptionStackFrame, PageFaultErrorCode};
 use x86_64::registers::msr::*;
-use fault_log::log_exception;
+use fault_log::{log_exception, log_stack_overflow};
 
 pub fn init(idt_ref: &'static LockedIdt) {
     { 
@@ -36,12 +38,18 @@ pub fn init(idt_ref: &'static LockedIdt) {
         idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
         idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
         idt.device_not_available.set_handler_fn(device_not_available_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
+        unsafe {
+            // setting the handler resets the stack index, so the double fault stack must be set again
+            idt.double_fault.set_handler_fn(double_fault_handler)
+                            .set_stack_index(tss::DOUBLE_FAULT_IST_INDEX as u16);
+        }
         // reserved: 0x09 coprocessor segment overrun exception
         idt.invalid_tss.set_handler_fn(invalid_tss_handler);
         idt.segment_not_present.set_handler_fn(segment_not_present_handler);
         // missing: 0x0c stack segment exception
         idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        // page faults are handled on the faulting task's own stack, since populating a lazy mapping
+        // or copying a copy-on-write page may block, and an IST stack can't be reentered.
         idt.page_fault.set_handler_fn(page_fault_handler);
         // reserved: 0x0f vector 15
         // missing: 0x10 floating point exception
@@ -201,6 +209,65 @@ fn kill_and_halt(exception_number: u8, stack_frame: &ExceptionStackFrame) {
 
 
 
+/// Returns the ID of the current task if the given `address` lies within the unmapped guard page below its stack,
+/// i.e., if accessing that address means that the current task has overflowed its stack.
+/// 
+/// This doesn't lock the current task, since the overflow may have occurred while the task held its own lock.
+fn task_overflowing_stack(address: memory::VirtualAddress) -> Option<usize> {
+    let stack_bottom = memory::Page::containing_address(task::get_my_current_task_stack_bottom()?);
+    if memory::Page::containing_address(address) + 1 == stack_bottom {
+        task::get_my_current_task_id()
+    } else {
+        None
+    }
+}
+
+/// Grows the current task's growable stack by populating the pages that contain the addresses from `lowest` up to `highest`.
+/// Returns `true` if all of those pages lie within that stack and are now populated, such that the faulting access can be retried.
+/// 
+/// This never blocks, since the interrupted code may have held the locks that populating a page normally requires,
+/// see [`memory::handle_lazy_stack_fault()`].
+fn grow_task_stack(lowest: memory::VirtualAddress, highest: memory::VirtualAddress) -> bool {
+    let stack_bottom = match task::get_my_current_task_stack_bottom() {
+        Some(bottom) => memory::Page::containing_address(bottom),
+        None => return false,
+    };
+    let start = memory::Page::containing_address(lowest);
+    let end = memory::Page::containing_address(highest);
+    if start < stack_bottom || start > end {
+        return false;
+    }
+    for page in memory::PageRange::new(start, end) {
+        match memory::handle_lazy_stack_fault(page.start_address()) {
+            Ok(true) => { }
+            Ok(false) => return false,
+            Err(e) => {
+                error!("couldn't grow the stack of the current task at {:#X}: {}", page.start_address(), e);
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Reports that the task with the given `task_id` overflowed its stack by accessing the given `address`,
+/// which caused the given exception, and then kills that task.
+/// 
+/// Both the double fault handler and the page fault handler report stack overflows in the same way.
+fn stack_overflow(task_id: usize, exception_number: u8, stack_frame: &ExceptionStackFrame, error_code: Option<u64>, address: memory::VirtualAddress) {
+    let task_name = task::get_my_current_task_name().unwrap_or("<unknown>");
+    println_both!("\nEXCEPTION {:#X}: stack overflow in task {} ({}) while accessing {:#X}\n{:#?}\n",
+             exception_number,
+             task_id,
+             task_name,
+             address,
+             stack_frame);
+
+    log_stack_overflow(task_id, task_name, stack_frame.instruction_pointer.0, error_code, address.value());
+    kill_and_halt(exception_number, stack_frame)
+}
+
+
 /// exception 0x00
 pub extern "x86-interrupt" fn divide_by_zero_handler(stack_frame: &mut ExceptionStackFrame) {
     println_both!("\nEXCEPTION: DIVIDE BY ZERO\n{:#?}\n", stack_frame);
@@ -304,7 +371,30 @@ pub extern "x86-interrupt" fn device_not_available_handler(stack_frame: &mut Exc
 }
 
 /// exception 0x08
+/// 
+/// A double fault whose stack pointer lies within the guard page below the current task's stack
+/// is reported as a stack overflow in that task.
+/// This is how most stack overflows are caught: page faults are handled on the faulting task's own stack,
+/// so once its stack pointer has moved into the guard page, the CPU can't push the page fault's exception frame,
+/// which causes a double fault that is handled on its own stack.
+/// 
+/// For the same reason, a growable stack is grown here when its stack pointer moves into a page that isn't yet populated.
+/// The faulting instruction is then retried by returning from this handler, which relies upon the saved instruction pointer
+/// being that of the faulting instruction, even though the architecture doesn't guarantee that for double faults.
 pub extern "x86-interrupt" fn double_fault_handler(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
+    // the double fault was caused by pushing onto the stack right below the stack pointer
+    let next_push_address = memory::VirtualAddress::new_canonical(stack_frame.stack_pointer.0.wrapping_sub(mem::size_of::<usize>()));
+    // the page fault's exception frame (up to six words) is pushed onto the stack after aligning it to 16 bytes
+    let lowest_push_address = memory::VirtualAddress::new_canonical(
+        (stack_frame.stack_pointer.0 & !0xF).wrapping_sub(6 * mem::size_of::<usize>())
+    );
+    if grow_task_stack(lowest_push_address, next_push_address) {
+        return;
+    }
+    if let Some(task_id) = task_overflowing_stack(next_push_address) {
+        return stack_overflow(task_id, 0x8, stack_frame, Some(error_code), next_push_address);
+    }
+
     println_both!("\nEXCEPTION: DOUBLE FAULT\n{:#?}\n", stack_frame);
     
     log_exception(0x8, stack_frame.instruction_pointer.0, Some(error_code), None);
@@ -345,9 +435,53 @@ pub extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: &mut
 }
 
 /// exception 0x0e
+/// 
+/// Faults on pages of lazy mappings that weren't yet populated are handled by populating those pages,
+/// and the first write to a copy-on-write page is handled by giving that page its own copy of the frame,
+/// after which the faulting access is retried; all other page faults kill the current task.
+/// A fault on a page of the current task's growable stack that lies above its stack pointer is handled without blocking.
+/// An access to the guard page below the current task's stack that happens before its stack pointer has moved into it,
+/// e.g., when a large local variable is written, is reported as a stack overflow in that task;
+/// see the double fault handler for overflows that move the stack pointer into the guard page.
 pub extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut ExceptionStackFrame, error_code: PageFaultErrorCode) {
     use x86_64::registers::control_regs;
 
+    let accessed_address = memory::VirtualAddress::new_canonical(control_regs::cr2().0);
+    let is_write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    // the current task's stack is grown without blocking, because this fault may have occurred
+    // while the page fault handler itself held the locks needed to populate a lazy page.
+    let stack_pointer = memory::VirtualAddress::new_canonical(stack_frame.stack_pointer.0);
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) && grow_task_stack(accessed_address, stack_pointer) {
+        return;
+    }
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        match memory::handle_lazy_page_fault(
+            accessed_address,
+            is_write,
+            error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH),
+        ) {
+            Ok(true) => return,
+            Ok(false) => { }
+            Err(e) => {
+                error!("page_fault_handler: couldn't populate lazily-mapped page at {:#X}: {}", accessed_address, e);
+            }
+        }
+    }
+    // a write to a present page may be the first write to a copy-on-write page
+    else if is_write && !error_code.contains(PageFaultErrorCode::USER_MODE) {
+        match memory::handle_cow_page_fault(accessed_address) {
+            Ok(true) => return,
+            Ok(false) => { }
+            Err(e) => {
+                error!("page_fault_handler: couldn't copy copy-on-write page at {:#X}: {}", accessed_address, e);
+            }
+        }
+    }
+
+    if let Some(task_id) = task_overflowing_stack(accessed_address) {
+        return stack_overflow(task_id, 0xE, stack_frame, None, accessed_address);
+    }
+
     #[cfg(not(downtime_eval))]
     println_both!("\nEXCEPTION: PAGE FAULT while accessing {:#X}\nerror code: \
                                   {:?}\n{:#?}\n",
@@ -0,0 +1,366 @@
+//! The `FatDirectory` type, which implements the `Directory` trait for directories on a FAT32 volume.
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use spin::{Mutex, MutexGuard};
+use fs_node::{DirRef, WeakDirRef, Directory, FileOrDir, FilesystemId, FsNode, Metadata, NodeKind, Permissions};
+use entry::{DirEntry, ATTR_READ_ONLY};
+use file::FatFile;
+use {FatFilesystem, FatFsRef};
+
+
+/// A directory on a FAT32 volume.
+///
+/// # Inserting nodes
+/// Inserting a node into a `FatDirectory` creates a new entry on disk with the node's name and a copy of its contents
+/// (recursively, for a directory), and the new on-disk entry is then represented by a new `FatDirectory` or `FatFile`.
+/// The inserted node itself is not retained, so later changes to it will not be reflected on disk.
+/// Thus, existing code that creates a new node in its parent directory, e.g., `VFSDirectory::new()`,
+/// works as expected, but the returned node should be re-obtained from this directory before it is modified.
+///
+/// # Renaming and moving nodes
+/// Renaming a node, or moving it to another directory on the same volume, only rewrites its directory entry;
+/// its contents stay where they are on disk. As with insertion, the node is represented by a new `FatFile` or `FatDirectory`
+/// afterwards, so it should be re-obtained from its new directory.
+///
+/// FAT32 has no symbolic or hard links, so inserting a symbolic link or creating a hard link fails.
+///
+/// # Permissions
+/// A directory whose entry is marked read-only rejects insertions. The root directory of a volume has no entry,
+/// so it is always writable and has no timestamps.
+pub struct FatDirectory {
+    /// The name of this directory.
+    name: String,
+    /// The volume that this directory is on.
+    fs: FatFsRef,
+    /// The first cluster of this directory's list of entries.
+    cluster: u32,
+    /// The on-disk directory entry for this directory, or `None` if it's the root directory of the volume.
+    entry: Option<DirEntry>,
+    /// The child nodes of this directory, which are lazily read from disk upon first access.
+    children: Mutex<Option<BTreeMap<String, FileOrDir>>>,
+    /// The parent directory that contains this directory.
+    parent: WeakDirRef,
+    /// A weak reference to this directory itself, which is given to its child nodes as their parent.
+    self_ref: WeakDirRef,
+}
+
+impl FatDirectory {
+    /// Creates the node that represents the root directory of a mounted FAT32 volume.
+    /// The caller is responsible for mounting it into the filesystem tree.
+    pub(crate) fn new_root(name: String, fs: FatFsRef, root_cluster: u32, parent: WeakDirRef) -> DirRef {
+        Self::new_internal(name, fs, root_cluster, None, parent)
+    }
+
+    /// Creates the node that represents the subdirectory described by the given on-disk `entry`.
+    fn from_entry(entry: DirEntry, fs: FatFsRef, parent: WeakDirRef) -> DirRef {
+        Self::new_internal(entry.name.clone(), fs, entry.first_cluster, Some(entry), parent)
+    }
+
+    fn new_internal(name: String, fs: FatFsRef, cluster: u32, entry: Option<DirEntry>, parent: WeakDirRef) -> DirRef {
+        let dir = FatDirectory {
+            name,
+            fs,
+            cluster,
+            entry,
+            children: Mutex::new(None),
+            parent,
+            self_ref: Weak::<Mutex<FatDirectory>>::new(),
+        };
+        let dir_ref = Arc::new(Mutex::new(dir));
+        dir_ref.lock().self_ref = Arc::downgrade(&dir_ref) as WeakDirRef;
+        dir_ref as DirRef
+    }
+
+    /// Returns the locked map of this directory's children, first reading them from disk if necessary.
+    fn children(&self) -> Result<MutexGuard<Option<BTreeMap<String, FileOrDir>>>, &'static str> {
+        let mut children = self.children.lock();
+        if children.is_none() {
+            let entries = self.fs.lock().read_dir(self.cluster)?;
+            let mut map = BTreeMap::new();
+            for entry in entries {
+                map.insert(entry.name.clone(), self.node_from_entry(entry));
+            }
+            *children = Some(map);
+        }
+        Ok(children)
+    }
+
+    /// Creates the in-memory node that represents the given on-disk `entry`, a child of this directory.
+    fn node_from_entry(&self, entry: DirEntry) -> FileOrDir {
+        if entry.is_dir() {
+            FileOrDir::Dir(FatDirectory::from_entry(entry, self.fs.clone(), self.self_ref.clone()))
+        } else {
+            FileOrDir::File(FatFile::from_entry(entry, self.fs.clone(), self.self_ref.clone()))
+        }
+    }
+
+    /// Finds the name of the child with the given `name`, which is matched case-insensitively like FAT does.
+    fn find_child_name(children: &BTreeMap<String, FileOrDir>, name: &str) -> Option<String> {
+        children.keys().find(|k| k.eq_ignore_ascii_case(name)).cloned()
+    }
+
+    /// Writes a copy of the given `node` into this directory on disk, recursively if it is a directory,
+    /// and returns the new node that represents it.
+    fn copy_to_disk(&self, name: &str, node: &FileOrDir) -> Result<FileOrDir, &'static str> {
+        match node {
+            FileOrDir::File(file) => {
+                // Read the file's contents before locking the volume, in case it's a file on this same volume.
+                let contents = {
+                    let locked_file = file.lock();
+                    let mut contents = vec![0u8; locked_file.size()];
+                    let bytes_read = locked_file.read(&mut contents, 0)?;
+                    contents.truncate(bytes_read);
+                    contents
+                };
+                let entry = self.fs.lock().create_file(self.cluster, name, &contents)?;
+                Ok(self.node_from_entry(entry))
+            }
+            FileOrDir::Dir(dir) => {
+                let entry = self.fs.lock().create_dir(self.cluster, name)?;
+                let new_node = self.node_from_entry(entry.clone());
+                if let FileOrDir::Dir(ref new_dir) = new_node {
+                    let grandchildren: Vec<FileOrDir> = {
+                        let locked_dir = dir.lock();
+                        locked_dir.list().iter().filter_map(|n| locked_dir.get(n)).collect()
+                    };
+                    let mut locked_new_dir = new_dir.lock();
+                    for grandchild in grandchildren {
+                        if let Err(e) = locked_new_dir.insert(grandchild) {
+                            // Don't leave a partial copy of the directory behind.
+                            if let Err(remove_error) = self.fs.lock().remove_dir_entry(&entry) {
+                                error!("FAT32: failed to remove partial copy of directory {:?}: {}", name, remove_error);
+                            }
+                            return Err(e);
+                        }
+                    }
+                }
+                Ok(new_node)
+            }
+        }
+    }
+
+    /// Removes the on-disk `entry` of a child that was replaced by a newly-written entry with its name,
+    /// along with the node that represented it, which is returned.
+    ///
+    /// If the entry couldn't be removed, this directory then holds both the replaced and the new entry on disk,
+    /// so its children are read from disk again upon their next access.
+    fn remove_replaced(&mut self, entry: &DirEntry) -> Result<FileOrDir, &'static str> {
+        if let Err(e) = self.fs.lock().remove_dir_entry(entry) {
+            error!("FAT32: failed to remove replaced entry {:?} from disk: {}", entry.name, e);
+            *self.children.lock() = None;
+            return Err(e);
+        }
+        let mut old_node = self.children()?
+            .as_mut()
+            .and_then(|map| map.remove(&entry.name))
+            .ok_or("BUG: FatDirectory children didn't contain the replaced node")?;
+        old_node.set_parent_dir(Weak::<Mutex<FatDirectory>>::new());
+        self.mark_modified();
+        Ok(old_node)
+    }
+
+    /// Records on disk that the contents of this directory were just modified.
+    fn mark_modified(&mut self) {
+        if let Some(ref mut entry) = self.entry {
+            let time = fs_node::now();
+            entry.modified = Some(time);
+            entry.accessed = Some(time);
+            if let Err(e) = self.fs.lock().update_dir_entry(entry) {
+                warn!("FAT32: failed to update the modification time of directory {:?}: {}", self.name, e);
+            }
+        }
+    }
+
+    /// Reads the on-disk entry of the child with the given exact `name`.
+    ///
+    /// The entry is re-read from disk rather than taken from the child node,
+    /// since a file's first cluster may have changed since its node was created.
+    fn disk_entry(&self, name: &str) -> Result<DirEntry, &'static str> {
+        self.fs.lock().read_dir(self.cluster)?
+            .into_iter()
+            .find(|e| e.name == name)
+            .ok_or("FAT32: entry was missing on disk")
+    }
+
+    /// Moves the child called `name` into the `destination` directory (or within this directory, if `None`),
+    /// where it will be called `new_name`, by moving its on-disk directory entry.
+    fn move_entry(&mut self, name: &str, destination: Option<&mut FatDirectory>, new_name: &str) -> Result<(), &'static str> {
+        let node = self.get(name).ok_or("source node does not exist")?;
+        let name = node.get_name();
+        let existing = match destination {
+            Some(ref d) => d.get(new_name),
+            None => self.get(new_name).filter(|e| !e.get_name().eq_ignore_ascii_case(&name)),
+        };
+        // Replace an existing file with the new name, but never an existing directory.
+        // The existing file's entry is only removed after the moved entry was written, so it's kept if the move fails.
+        let existing_entry = match existing {
+            Some(FileOrDir::Dir(_)) => return Err("a directory with the new name already exists"),
+            Some(existing_file) => Some(match destination {
+                Some(ref d) => d.disk_entry(&existing_file.get_name())?,
+                None => self.disk_entry(&existing_file.get_name())?,
+            }),
+            None => None,
+        };
+
+        let entry = self.disk_entry(&name)?;
+        let destination_cluster = destination.as_ref().map_or(self.cluster, |d| d.cluster);
+        let new_entry = self.fs.lock().move_entry(&entry, self.cluster, destination_cluster, new_name)?;
+
+        if let Some(mut old_node) = self.children()?.as_mut().and_then(|map| map.remove(&name)) {
+            old_node.set_parent_dir(Weak::<Mutex<FatDirectory>>::new());
+        }
+        let target = match destination {
+            Some(d) => d,
+            None => self,
+        };
+        if let Some(existing_entry) = existing_entry {
+            target.remove_replaced(&existing_entry)?;
+        }
+        let new_node = target.node_from_entry(new_entry);
+        target.children()?
+            .as_mut()
+            .ok_or("BUG: FatDirectory children weren't loaded")?
+            .insert(new_node.get_name(), new_node);
+        Ok(())
+    }
+}
+
+impl Directory for FatDirectory {
+    fn insert(&mut self, node: FileOrDir) -> Result<Option<FileOrDir>, &'static str> {
+        if !self.metadata().permissions.contains(Permissions::WRITE) {
+            return Err("permission denied: FAT32 directory is read-only");
+        }
+        if let FileOrDir::File(ref file) = node {
+            if file.lock().symlink_target().is_some() {
+                return Err("FAT32: symbolic links are not supported");
+            }
+        }
+        let name = node.get_name();
+        // Replace an existing node with the same name, as required by the `Directory` trait.
+        // Its entry is only removed after the new node was written, so it's kept if writing the new node fails.
+        let existing_entry = match self.get(&name) {
+            Some(existing) => Some(self.disk_entry(&existing.get_name())?),
+            None => None,
+        };
+        let new_node = self.copy_to_disk(&name, &node)?;
+        let old_node = match existing_entry {
+            Some(entry) => Some(self.remove_replaced(&entry)?),
+            None => None,
+        };
+        self.children()?
+            .as_mut()
+            .ok_or("BUG: FatDirectory children weren't loaded")?
+            .insert(new_node.get_name(), new_node);
+        self.mark_modified();
+        Ok(old_node)
+    }
+
+    fn get(&self, name: &str) -> Option<FileOrDir> {
+        let children = match self.children() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("FatDirectory::get(): failed to read directory {:?}: {}", self.name, e);
+                return None;
+            }
+        };
+        let map = children.as_ref()?;
+        Self::find_child_name(map, name).and_then(|n| map.get(&n).cloned())
+    }
+
+    fn list(&self) -> Vec<String> {
+        match self.children() {
+            Ok(children) => children.as_ref().map(|map| map.keys().cloned().collect()).unwrap_or_default(),
+            Err(e) => {
+                error!("FatDirectory::list(): failed to read directory {:?}: {}", self.name, e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn remove(&mut self, node: &FileOrDir) -> Option<FileOrDir> {
+        let node_name = node.get_name();
+        let mut children = self.children().ok()?;
+        let map = children.as_mut()?;
+        let name = Self::find_child_name(map, &node_name)?;
+
+        let removal = self.disk_entry(&name).and_then(|entry| self.fs.lock().remove_dir_entry(&entry));
+        if let Err(e) = removal {
+            error!("FatDirectory::remove(): failed to remove {:?} from disk: {}", name, e);
+            return None;
+        }
+
+        let mut old_node = map.remove(&name)?;
+        old_node.set_parent_dir(Weak::<Mutex<FatDirectory>>::new());
+        drop(children);
+        self.mark_modified();
+        Some(old_node)
+    }
+
+    /// All directories on the same volume share a filesystem ID, which is derived from the volume's address.
+    fn filesystem_id(&self) -> FilesystemId {
+        FilesystemId(&*self.fs as *const Mutex<FatFilesystem> as usize)
+    }
+
+    fn rename(&mut self, old_name: &str, new_name: &str) -> Result<(), &'static str> {
+        self.move_entry(old_name, None, new_name)
+    }
+
+    fn move_child(
+        &mut self,
+        name: &str,
+        destination: &mut dyn Directory,
+        _new_parent: WeakDirRef,
+        new_name: &str,
+    ) -> Result<(), &'static str> {
+        let destination = destination.downcast_mut::<FatDirectory>()
+            .ok_or("FAT32: cannot move a node to a directory that is not on a FAT32 volume")?;
+        if !Arc::ptr_eq(&self.fs, &destination.fs) {
+            return Err("FAT32: cannot move a node to a different volume");
+        }
+        self.move_entry(name, Some(destination), new_name)
+    }
+}
+
+impl FsNode for FatDirectory {
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_parent_dir(&self) -> Option<DirRef> {
+        self.parent.upgrade()
+    }
+
+    fn set_parent_dir(&mut self, new_parent: WeakDirRef) {
+        self.parent = new_parent;
+    }
+
+    fn metadata(&self) -> Metadata {
+        match self.entry {
+            Some(ref entry) => entry.metadata(),
+            None => Metadata {
+                kind: NodeKind::Directory,
+                size: 0,
+                permissions: Permissions::all(),
+                created: None,
+                modified: None,
+                accessed: None,
+            },
+        }
+    }
+
+    /// FAT only supports marking a directory as read-only, so only the `WRITE` permission can be changed.
+    fn set_permissions(&mut self, permissions: Permissions) -> Result<(), &'static str> {
+        let entry = self.entry.as_mut().ok_or("FAT32: cannot change the permissions of a volume's root directory")?;
+        if permissions.contains(Permissions::WRITE) {
+            entry.attributes &= !ATTR_READ_ONLY;
+        } else {
+            entry.attributes |= ATTR_READ_ONLY;
+        }
+        self.fs.lock().update_dir_entry(entry)
+    }
+}
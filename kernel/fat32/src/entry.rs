@@ -0,0 +1,431 @@
+//! Parsing and encoding of the 32-byte on-disk directory entries of a FAT volume,
+//! including the VFAT long file name (LFN) entries that precede a short (8.3) entry.
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use fs_node::{Metadata, NodeKind, Permissions, Timestamp};
+
+/// The size in bytes of a single directory entry slot.
+pub const DIR_ENTRY_SIZE: usize = 32;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN:    u8 = 0x02;
+pub const ATTR_SYSTEM:    u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE:   u8 = 0x20;
+/// A long file name entry is marked with this exact combination of attributes.
+pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+const ATTR_LONG_NAME_MASK: u8 = 0x3F;
+
+/// The first byte of a slot that marks the end of a directory; all following slots are free too.
+pub const END_OF_DIRECTORY: u8 = 0x00;
+/// The first byte of a slot that held an entry that has since been deleted.
+pub const DELETED_ENTRY: u8 = 0xE5;
+
+/// The sequence number of the last (logically) long name entry has this bit set.
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// The number of UTF-16 code units stored in each long name entry.
+const CHARS_PER_LONG_ENTRY: usize = 13;
+/// The maximum length of a long file name, in UTF-16 code units.
+const MAX_LONG_NAME_LENGTH: usize = 255;
+/// The byte offsets within a long name entry of each of its 13 UTF-16 code units.
+const LONG_ENTRY_CHAR_OFFSETS: [usize; CHARS_PER_LONG_ENTRY] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// In the (Windows NT-defined) reserved byte of a short entry,
+/// indicates that the base name should be displayed in lowercase.
+const NT_LOWERCASE_BASE: u8 = 0x08;
+/// In the (Windows NT-defined) reserved byte of a short entry,
+/// indicates that the extension should be displayed in lowercase.
+const NT_LOWERCASE_EXT:  u8 = 0x10;
+
+/// Characters that are not permitted anywhere in a FAT file name.
+const INVALID_NAME_CHARS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+/// Characters that are permitted in long names but not in short (8.3) names.
+const INVALID_SHORT_NAME_CHARS: &[char] = &['+', ',', ';', '=', '[', ']', '.', ' '];
+
+/// The byte offsets within a short entry of its timestamp fields.
+const CREATE_TIME_OFFSET: usize = 14;
+const CREATE_DATE_OFFSET: usize = 16;
+const ACCESS_DATE_OFFSET: usize = 18;
+const WRITE_TIME_OFFSET:  usize = 22;
+const WRITE_DATE_OFFSET:  usize = 24;
+/// FAT dates count years from this year.
+const FAT_EPOCH_YEAR: u16 = 1980;
+
+/// The short name of the `.` entry at the start of every non-root directory.
+pub const DOT_SHORT_NAME: [u8; 11] = *b".          ";
+/// The short name of the `..` entry at the start of every non-root directory.
+pub const DOT_DOT_SHORT_NAME: [u8; 11] = *b"..         ";
+
+
+/// A parsed directory entry, which describes a single file or subdirectory.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    /// The name of this entry, which is its long name if it has one.
+    pub name: String,
+    /// The raw 8.3 short name of this entry.
+    pub short_name: [u8; 11],
+    /// The attribute flags of this entry, e.g., `ATTR_DIRECTORY`.
+    pub attributes: u8,
+    /// The first cluster of this entry's contents, or `0` if it has no contents yet.
+    pub first_cluster: u32,
+    /// The size in bytes of this entry's contents, which is always `0` for directories.
+    pub size: u32,
+    /// The times at which this entry was created, last written, and last accessed,
+    /// which are `None` if they were never set on disk. FAT only stores the date of the last access.
+    pub created: Option<Timestamp>,
+    pub modified: Option<Timestamp>,
+    pub accessed: Option<Timestamp>,
+    /// The absolute byte offset on disk of every slot occupied by this entry:
+    /// its long name slots (if any), followed by its short name slot.
+    pub slots: Vec<usize>,
+}
+
+impl DirEntry {
+    /// Returns `true` if this entry is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    /// Returns the absolute byte offset on disk of the short name slot of this entry,
+    /// which holds its first cluster and size.
+    pub fn short_entry_offset(&self) -> usize {
+        *self.slots.last().expect("BUG: FAT32 DirEntry had no slots")
+    }
+
+    /// Returns the permissions of this entry, which lacks the `WRITE` permission if it is marked as read-only.
+    pub fn permissions(&self) -> Permissions {
+        let mut permissions = Permissions::READ;
+        if self.attributes & ATTR_READ_ONLY == 0 {
+            permissions |= Permissions::WRITE;
+        }
+        if self.is_dir() {
+            permissions |= Permissions::EXECUTE;
+        }
+        permissions
+    }
+
+    /// Returns the metadata of the node that this entry describes.
+    pub fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: if self.is_dir() { NodeKind::Directory } else { NodeKind::File },
+            size: self.size as usize,
+            permissions: self.permissions(),
+            created: self.created,
+            modified: self.modified,
+            accessed: self.accessed,
+        }
+    }
+}
+
+
+/// Returns `true` if the given raw slot is a long name entry.
+pub fn is_long_name_entry(raw: &[u8]) -> bool {
+    raw[11] & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME
+}
+
+/// Returns `true` if the given raw slot is a volume label entry (and not a long name entry).
+pub fn is_volume_label(raw: &[u8]) -> bool {
+    !is_long_name_entry(raw) && (raw[11] & ATTR_VOLUME_ID != 0)
+}
+
+/// Parses the short name, attributes, first cluster and size from the given raw short entry.
+/// The returned `DirEntry` is named by its short name and has no slots.
+pub fn parse_short_entry(raw: &[u8]) -> DirEntry {
+    let mut short_name = [0u8; 11];
+    short_name.copy_from_slice(&raw[0..11]);
+    let cluster_hi = super::read_u16(raw, 20) as u32;
+    let cluster_lo = super::read_u16(raw, 26) as u32;
+    DirEntry {
+        name: short_name_to_string(&short_name, raw[12]),
+        short_name,
+        attributes: raw[11],
+        first_cluster: (cluster_hi << 16) | cluster_lo,
+        size: super::read_u32(raw, 28),
+        created: decode_timestamp(super::read_u16(raw, CREATE_DATE_OFFSET), super::read_u16(raw, CREATE_TIME_OFFSET)),
+        modified: decode_timestamp(super::read_u16(raw, WRITE_DATE_OFFSET), super::read_u16(raw, WRITE_TIME_OFFSET)),
+        accessed: decode_timestamp(super::read_u16(raw, ACCESS_DATE_OFFSET), 0),
+        slots: Vec::new(),
+    }
+}
+
+/// Converts a raw 8.3 short name into a displayable name, e.g., `"README  TXT"` into `"README.TXT"`.
+fn short_name_to_string(short_name: &[u8; 11], nt_flags: u8) -> String {
+    let convert = |bytes: &[u8], lowercase: bool| -> String {
+        bytes.iter()
+            .take_while(|&&b| b != b' ')
+            .map(|&b| if lowercase { b.to_ascii_lowercase() as char } else { b as char })
+            .collect()
+    };
+    // A first byte of 0x05 stands in for a real 0xE5 character, which would otherwise mark a deleted entry.
+    let mut base_bytes = [0u8; 8];
+    base_bytes.copy_from_slice(&short_name[0..8]);
+    if base_bytes[0] == 0x05 {
+        base_bytes[0] = DELETED_ENTRY;
+    }
+    let mut name = convert(&base_bytes, nt_flags & NT_LOWERCASE_BASE != 0);
+    let ext = convert(&short_name[8..11], nt_flags & NT_LOWERCASE_EXT != 0);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+/// Encodes a short directory entry with the given fields,
+/// whose creation, write, and access times are all set to the given `time`.
+pub fn encode_short_entry(short_name: &[u8; 11], attributes: u8, nt_flags: u8, first_cluster: u32, size: u32, time: &Timestamp) -> [u8; DIR_ENTRY_SIZE] {
+    let mut raw = [0u8; DIR_ENTRY_SIZE];
+    raw[0..11].copy_from_slice(short_name);
+    raw[11] = attributes;
+    raw[12] = nt_flags;
+    super::write_u16(&mut raw, 20, (first_cluster >> 16) as u16);
+    super::write_u16(&mut raw, 26, first_cluster as u16);
+    super::write_u32(&mut raw, 28, size);
+    write_timestamps(&mut raw, Some(time), Some(time), Some(time));
+    raw
+}
+
+/// Writes the given timestamps into the given raw short entry, leaving the fields of any `None` timestamps untouched.
+pub fn write_timestamps(raw: &mut [u8], created: Option<&Timestamp>, modified: Option<&Timestamp>, accessed: Option<&Timestamp>) {
+    if let Some(t) = created {
+        let (date, time) = encode_timestamp(t);
+        super::write_u16(raw, CREATE_DATE_OFFSET, date);
+        super::write_u16(raw, CREATE_TIME_OFFSET, time);
+    }
+    if let Some(t) = modified {
+        let (date, time) = encode_timestamp(t);
+        super::write_u16(raw, WRITE_DATE_OFFSET, date);
+        super::write_u16(raw, WRITE_TIME_OFFSET, time);
+    }
+    if let Some(t) = accessed {
+        let (date, _) = encode_timestamp(t);
+        super::write_u16(raw, ACCESS_DATE_OFFSET, date);
+    }
+}
+
+/// Converts the given timestamp into a FAT date and time, in that order.
+///
+/// The RTC only provides a two-digit year, which is assumed to be in the 21st century.
+/// FAT times have a resolution of two seconds.
+fn encode_timestamp(timestamp: &Timestamp) -> (u16, u16) {
+    let year = 2000 + timestamp.years as u16 - FAT_EPOCH_YEAR;
+    let date = (year << 9) | ((timestamp.months as u16 & 0x0F) << 5) | (timestamp.days as u16 & 0x1F);
+    let time = ((timestamp.hours as u16 & 0x1F) << 11) | ((timestamp.minutes as u16 & 0x3F) << 5) | (timestamp.seconds as u16 / 2);
+    (date, time)
+}
+
+/// Converts the given FAT date and time into a timestamp, or returns `None` if the date was never set.
+fn decode_timestamp(date: u16, time: u16) -> Option<Timestamp> {
+    if date == 0 {
+        return None;
+    }
+    let year = FAT_EPOCH_YEAR + (date >> 9);
+    Some(Timestamp {
+        seconds: ((time & 0x1F) * 2) as u8,
+        minutes: ((time >> 5) & 0x3F) as u8,
+        hours: (time >> 11) as u8,
+        days: (date & 0x1F) as u8,
+        months: ((date >> 5) & 0x0F) as u8,
+        years: (year % 100) as u8,
+    })
+}
+
+/// Calculates the checksum of a short name that each of its long name entries must carry.
+pub fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &b| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b)
+    })
+}
+
+/// Encodes the given `name` into a series of long name entries, in the order they must appear on disk,
+/// i.e., starting with the entry that holds the last characters of the name.
+pub fn encode_long_name(name: &str, checksum: u8) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let num_entries = (units.len() + CHARS_PER_LONG_ENTRY - 1) / CHARS_PER_LONG_ENTRY;
+    // The name is null-terminated (unless it exactly fills the last entry), then padded with 0xFFFF.
+    if units.len() % CHARS_PER_LONG_ENTRY != 0 {
+        units.push(0x0000);
+    }
+    while units.len() < num_entries * CHARS_PER_LONG_ENTRY {
+        units.push(0xFFFF);
+    }
+
+    let mut entries = Vec::with_capacity(num_entries);
+    for ordinal in (1 ..= num_entries).rev() {
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw[0] = ordinal as u8 | if ordinal == num_entries { LAST_LONG_ENTRY } else { 0 };
+        raw[11] = ATTR_LONG_NAME;
+        raw[13] = checksum;
+        let chunk = &units[(ordinal - 1) * CHARS_PER_LONG_ENTRY .. ordinal * CHARS_PER_LONG_ENTRY];
+        for (&unit, &offset) in chunk.iter().zip(LONG_ENTRY_CHAR_OFFSETS.iter()) {
+            super::write_u16(&mut raw, offset, unit);
+        }
+        entries.push(raw);
+    }
+    entries
+}
+
+/// Returns an error if the given `name` cannot be used as a FAT file name.
+pub fn validate_name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err("FAT32: invalid file name");
+    }
+    if name.encode_utf16().count() > MAX_LONG_NAME_LENGTH {
+        return Err("FAT32: file name is longer than 255 characters");
+    }
+    if name.chars().any(|c| (c as u32) < 0x20 || INVALID_NAME_CHARS.contains(&c)) {
+        return Err("FAT32: file name contains an invalid character");
+    }
+    Ok(())
+}
+
+/// If the given `name` can be represented exactly as an 8.3 short name (ignoring case),
+/// this returns that short name along with the NT flags that preserve the case of its base and extension.
+///
+/// Returns `None` if the `name` requires a long name entry,
+/// e.g., because it is too long, has mixed case, or contains characters not permitted in short names.
+pub fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.rfind('.') {
+        Some(idx) => (&name[..idx], &name[idx + 1 ..]),
+        None => (name, ""),
+    };
+    let valid_part = |part: &str, max_len: usize| {
+        part.len() <= max_len
+            && part.chars().all(|c| c.is_ascii() && !INVALID_SHORT_NAME_CHARS.contains(&c))
+    };
+    if base.is_empty() || !valid_part(base, 8) || !valid_part(ext, 3) {
+        return None;
+    }
+    // Each part must be entirely uppercase or entirely lowercase to be representable with the NT flags.
+    let case_flag = |part: &str, flag: u8| -> Option<u8> {
+        let has_lower = part.chars().any(|c| c.is_ascii_lowercase());
+        let has_upper = part.chars().any(|c| c.is_ascii_uppercase());
+        match (has_lower, has_upper) {
+            (true, true)  => None,
+            (true, false) => Some(flag),
+            _             => Some(0),
+        }
+    };
+    let nt_flags = case_flag(base, NT_LOWERCASE_BASE)? | case_flag(ext, NT_LOWERCASE_EXT)?;
+
+    let mut short_name = [b' '; 11];
+    for (i, b) in base.bytes().enumerate() {
+        short_name[i] = b.to_ascii_uppercase();
+    }
+    for (i, b) in ext.bytes().enumerate() {
+        short_name[8 + i] = b.to_ascii_uppercase();
+    }
+    if short_name[0] == DELETED_ENTRY {
+        short_name[0] = 0x05;
+    }
+    Some((short_name, nt_flags))
+}
+
+/// Generates a unique short name alias for a `name` that requires a long name entry,
+/// in the form of `BASENA~N.EXT`, which does not collide with any of the `existing` short names.
+pub fn generate_alias(name: &str, existing: &[[u8; 11]]) -> Result<[u8; 11], &'static str> {
+    let simplify = |part: &str, max_len: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != '.' && c != ' ')
+            .map(|c| if c.is_ascii() && !INVALID_SHORT_NAME_CHARS.contains(&c) { c.to_ascii_uppercase() as u8 } else { b'_' })
+            .take(max_len)
+            .collect()
+    };
+    let (base, ext) = match name.rfind('.') {
+        Some(idx) if idx > 0 => (simplify(&name[..idx], 8), simplify(&name[idx + 1 ..], 3)),
+        _ => (simplify(name, 8), Vec::new()),
+    };
+
+    for n in 1 .. 1_000_000usize {
+        let suffix = format!("~{}", n);
+        let base_len = core::cmp::min(base.len(), 8 - suffix.len());
+        let mut alias = [b' '; 11];
+        alias[..base_len].copy_from_slice(&base[..base_len]);
+        alias[base_len .. base_len + suffix.len()].copy_from_slice(suffix.as_bytes());
+        alias[8 .. 8 + ext.len()].copy_from_slice(&ext);
+        if !existing.contains(&alias) {
+            return Ok(alias);
+        }
+    }
+    Err("FAT32: couldn't generate a unique short name")
+}
+
+
+/// Accumulates the long name entries that precede a short entry,
+/// such that the long name can be assembled once that short entry is reached.
+pub struct LongNameBuilder {
+    units: Vec<u16>,
+    checksum: u8,
+    /// The sequence number of the long name entry expected next,
+    /// or `0` if the next entry should be the short entry.
+    next_ordinal: u8,
+    slots: Vec<usize>,
+}
+
+impl LongNameBuilder {
+    pub fn new() -> LongNameBuilder {
+        LongNameBuilder {
+            units: Vec::new(),
+            checksum: 0,
+            next_ordinal: 0,
+            slots: Vec::new(),
+        }
+    }
+
+    /// Discards any partially-accumulated long name, e.g., because an unexpected entry was found.
+    pub fn reset(&mut self) {
+        self.units.clear();
+        self.next_ordinal = 0;
+        self.slots.clear();
+    }
+
+    /// Adds the given raw long name entry, located at `offset` on disk, to this builder.
+    pub fn push(&mut self, raw: &[u8], offset: usize) {
+        let ordinal = raw[0] & !LAST_LONG_ENTRY;
+        if raw[0] & LAST_LONG_ENTRY != 0 {
+            // This is the first long name entry on disk, which starts a new name.
+            self.reset();
+            self.units = vec![0xFFFF; ordinal as usize * CHARS_PER_LONG_ENTRY];
+            self.checksum = raw[13];
+        } else if ordinal != self.next_ordinal || raw[13] != self.checksum {
+            // An orphaned or out-of-order long name entry, which we must ignore.
+            self.reset();
+            return;
+        }
+        if ordinal == 0 || (ordinal as usize) * CHARS_PER_LONG_ENTRY > self.units.len() {
+            self.reset();
+            return;
+        }
+
+        let start = (ordinal as usize - 1) * CHARS_PER_LONG_ENTRY;
+        for (i, &char_offset) in LONG_ENTRY_CHAR_OFFSETS.iter().enumerate() {
+            self.units[start + i] = super::read_u16(raw, char_offset);
+        }
+        self.slots.push(offset);
+        self.next_ordinal = ordinal - 1;
+    }
+
+    /// Completes the long name for the given short entry, returning that name and the slots it occupied.
+    ///
+    /// Returns `None` if there was no valid long name for the given short entry,
+    /// in which case the short name should be used.
+    /// Either way, this builder is reset.
+    pub fn finish(&mut self, short_name: &[u8; 11]) -> Option<(String, Vec<usize>)> {
+        let complete = !self.slots.is_empty()
+            && self.next_ordinal == 0
+            && self.checksum == short_name_checksum(short_name);
+        let result = if complete {
+            let len = self.units.iter().position(|&u| u == 0x0000 || u == 0xFFFF).unwrap_or(self.units.len());
+            String::from_utf16(&self.units[..len]).ok()
+                .map(|name| (name, core::mem::replace(&mut self.slots, Vec::new())))
+        } else {
+            None
+        };
+        self.reset();
+        result
+    }
+}
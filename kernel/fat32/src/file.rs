@@ -0,0 +1,115 @@
+//! The `FatFile` type, which implements the `File` trait for files on a FAT32 volume.
+
+use alloc::{
+    string::String,
+    sync::Arc,
+};
+use spin::Mutex;
+use fs_node::{DirRef, WeakDirRef, File, FileRef, FsNode, Metadata, Permissions};
+use memory::MappedPages;
+use entry::{DirEntry, ATTR_READ_ONLY};
+use FatFsRef;
+
+
+/// A file on a FAT32 volume.
+///
+/// Reads and writes go directly to the volume (through its block cache),
+/// and writes that grow the file allocate new clusters and update the file's directory entry on disk.
+/// Files whose directory entry is marked read-only cannot be written.
+pub struct FatFile {
+    /// The name of this file.
+    name: String,
+    /// The volume that this file is on.
+    fs: FatFsRef,
+    /// The on-disk directory entry for this file, which holds its first cluster and size.
+    entry: DirEntry,
+    /// The parent directory that contains this file.
+    parent: WeakDirRef,
+}
+
+impl FatFile {
+    /// Creates the node that represents the file described by the given on-disk `entry`.
+    pub(crate) fn from_entry(entry: DirEntry, fs: FatFsRef, parent: WeakDirRef) -> FileRef {
+        let file = FatFile {
+            name: entry.name.clone(),
+            fs,
+            entry,
+            parent,
+        };
+        Arc::new(Mutex::new(file)) as FileRef
+    }
+}
+
+impl File for FatFile {
+    fn read(&self, buffer: &mut [u8], offset: usize) -> Result<usize, &'static str> {
+        self.fs.lock().read_file(self.entry.first_cluster, self.entry.size, buffer, offset)
+    }
+
+    fn write(&mut self, buffer: &[u8], offset: usize) -> Result<usize, &'static str> {
+        if !self.entry.permissions().contains(Permissions::WRITE) {
+            return Err("permission denied: FAT32 file is read-only");
+        }
+        let mut fs = self.fs.lock();
+        let (first_cluster, size) = fs.write_file(self.entry.first_cluster, self.entry.size, buffer, offset)?;
+        let time = fs_node::now();
+        self.entry.first_cluster = first_cluster;
+        self.entry.size = size;
+        self.entry.modified = Some(time);
+        self.entry.accessed = Some(time);
+        fs.update_dir_entry(&self.entry)?;
+        Ok(buffer.len())
+    }
+
+    fn size(&self) -> usize {
+        self.entry.size as usize
+    }
+
+    fn truncate(&mut self, size: usize) -> Result<(), &'static str> {
+        if !self.entry.permissions().contains(Permissions::WRITE) {
+            return Err("permission denied: FAT32 file is read-only");
+        }
+        if size > self.entry.size as usize {
+            return Err("cannot truncate a file to a larger size");
+        }
+        let mut fs = self.fs.lock();
+        let first_cluster = fs.truncate_file(self.entry.first_cluster, size)?;
+        let time = fs_node::now();
+        self.entry.first_cluster = first_cluster;
+        self.entry.size = size as u32;
+        self.entry.modified = Some(time);
+        self.entry.accessed = Some(time);
+        fs.update_dir_entry(&self.entry)
+    }
+
+    fn as_mapping(&self) -> Result<&MappedPages, &'static str> {
+        Err("Mapping a FatFile as a MappedPages object is unimplemented")
+    }
+}
+
+impl FsNode for FatFile {
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_parent_dir(&self) -> Option<DirRef> {
+        self.parent.upgrade()
+    }
+
+    fn set_parent_dir(&mut self, new_parent: WeakDirRef) {
+        self.parent = new_parent;
+    }
+
+    fn metadata(&self) -> Metadata {
+        self.entry.metadata()
+    }
+
+    /// FAT only supports marking a file as read-only, so only the `WRITE` permission can be changed.
+    fn set_permissions(&mut self, permissions: Permissions) -> Result<(), &'static str> {
+        if permissions.contains(Permissions::WRITE) {
+            self.entry.attributes &= !ATTR_READ_ONLY;
+        } else {
+            self.entry.attributes |= ATTR_READ_ONLY;
+        }
+        self.fs.lock().update_dir_entry(&self.entry)
+    }
+}
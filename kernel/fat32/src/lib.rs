@@ -0,0 +1,696 @@
+//! A FAT32 filesystem driver that exposes a FAT32 volume as `fs_node` directories and files.
+//!
+//! The volume is accessed through a [`BlockIo`] wrapper around its `StorageDevice`,
+//! which allows the FAT tables, directory entries and file contents to be read and written at byte granularity.
+//! The `BlockIo` uses a write-back cache, so modified data reaches the disk periodically,
+//! upon [`FatFilesystem::sync()`](struct.FatFilesystem.html#method.sync), or when the volume is dropped.
+//!
+//! The root directory of a volume is a [`FatDirectory`] obtained from [`open()`](fn.open.html),
+//! which can then be mounted anywhere in the filesystem tree using the `mount_table` crate.
+//! At boot, [`init()`](fn.init.html) mounts every FAT32 volume it finds onto a new directory in the root directory.
+//!
+//! Subdirectories and files are lazily read from disk the first time their parent directory is accessed,
+//! and are then cached in that parent, such that each entry on disk is represented by exactly one node in memory.
+//! Thus, this driver assumes that it has exclusive access to the volume while it is mounted.
+//!
+//! # Limitations
+//! * Only FAT32 volumes are supported, not FAT12 or FAT16 volumes.
+//! * The RTC provides only a two-digit year, so timestamps written to disk assume the 21st century.
+//! * The free cluster count in the FSInfo sector is invalidated once the volume is modified
+//!   rather than being kept up to date, which is permitted by the FAT specification.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate fs_node;
+extern crate memory;
+extern crate root;
+extern crate vfs_node;
+extern crate mount_table;
+extern crate block_io;
+extern crate storage_device;
+extern crate storage_manager;
+
+mod entry;
+mod directory;
+mod file;
+
+pub use directory::FatDirectory;
+pub use file::FatFile;
+
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use spin::Mutex;
+use block_io::{BlockIo, CacheConfig, CacheStats};
+use fs_node::DirRef;
+use vfs_node::VFSDirectory;
+use storage_device::StorageDeviceRef;
+use entry::*;
+
+
+/// The prefix of the names given to the directories that FAT32 volumes are mounted as by [`init()`](fn.init.html),
+/// e.g., `/disk0`, `/disk1`, etc.
+pub const DISK_MOUNT_PREFIX: &str = "disk";
+
+/// The two-byte signature at the end of a FAT boot sector.
+const BOOT_SECTOR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+/// The signature at the start of the FSInfo sector.
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+/// The offset within the FSInfo sector of the free cluster count.
+const FS_INFO_FREE_COUNT_OFFSET: usize = 488;
+/// A free cluster count that tells others the count is unknown and must be recomputed.
+const FS_INFO_UNKNOWN_FREE_COUNT: u32 = 0xFFFF_FFFF;
+/// A FAT volume with fewer clusters than this is a FAT12 or FAT16 volume.
+const MIN_FAT32_CLUSTER_COUNT: u32 = 65525;
+
+/// The number of the first cluster in the data region, as clusters 0 and 1 are reserved.
+const FIRST_DATA_CLUSTER: u32 = 2;
+/// FAT32 entries only use the lower 28 bits; the upper 4 bits are reserved and must be preserved.
+const FAT_ENTRY_MASK: u32 = 0x0FFF_FFFF;
+/// A FAT entry with this value represents a free cluster.
+const FREE_CLUSTER: u32 = 0;
+/// A FAT entry with a value greater than or equal to this marks the last cluster in a chain.
+const END_OF_CHAIN_MIN: u32 = 0x0FFF_FFF8;
+/// The value we write into a FAT entry to mark the last cluster in a chain.
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+
+
+/// A shareable reference to a mounted `FatFilesystem`,
+/// which is shared by all of the directories and files on that volume.
+pub type FatFsRef = Arc<Mutex<FatFilesystem>>;
+
+
+/// Initializes the FAT32 driver by mounting every FAT32 volume found on the system's storage devices,
+/// i.e., on their partitions or on unpartitioned devices, onto a new directory in the root directory, 
+/// named `disk0`, `disk1`, and so on.
+///
+/// Volumes that do not contain a FAT32 filesystem are skipped.
+pub fn init() -> Result<(), &'static str> {
+    let mut mounted = 0;
+    for (source, device) in storage_manager::volumes() {
+        let name = format!("{}{}", DISK_MOUNT_PREFIX, mounted);
+        let root_dir = match open(device, name.clone()) {
+            Ok(dir) => dir,
+            Err(e) => {
+                debug!("fat32::init(): skipping volume {}: {}", source, e);
+                continue;
+            }
+        };
+        let mount_point = VFSDirectory::new(name.clone(), root::get_root())?;
+        mount_table::mount(&mount_point, root_dir, "fat32", &source)?;
+        info!("Mounted FAT32 volume {} at /{}", source, name);
+        mounted += 1;
+    }
+    Ok(())
+}
+
+/// Opens the FAT32 volume on the given `device` and returns its root directory, which will have the given `name`.
+///
+/// The returned directory is not yet part of the filesystem tree;
+/// it should be mounted onto a directory with the same `name` using `mount_table::mount()`.
+pub fn open(device: StorageDeviceRef, name: String) -> Result<DirRef, &'static str> {
+    let fs = FatFilesystem::new(device)?;
+    let root_cluster = fs.root_cluster;
+    let fs_ref = Arc::new(Mutex::new(fs));
+    Ok(FatDirectory::new_root(name, fs_ref, root_cluster, Weak::<Mutex<VFSDirectory>>::new()))
+}
+
+
+/// A mounted FAT32 volume, which contains the volume's layout information from its BIOS parameter block
+/// and handles all accesses to the underlying storage device.
+pub struct FatFilesystem {
+    /// The byte-granular wrapper around the storage device that holds this volume.
+    io: BlockIo,
+    bytes_per_sector: usize,
+    sectors_per_cluster: usize,
+    /// The number of copies of the FAT, all of which are kept in sync.
+    num_fats: usize,
+    /// The first sector of the first FAT, which comes right after the reserved sectors.
+    fat_start_sector: usize,
+    sectors_per_fat: usize,
+    /// The first sector of the data region, which holds cluster number 2.
+    data_start_sector: usize,
+    /// The number of clusters in the data region.
+    cluster_count: u32,
+    /// The first cluster of the root directory.
+    root_cluster: u32,
+    /// The sector number of the FSInfo sector, if the volume has one.
+    fs_info_sector: Option<usize>,
+    /// Where to start searching for the next free cluster.
+    next_free_hint: u32,
+    /// Whether the free cluster count in the FSInfo sector has already been marked as unknown.
+    fs_info_invalidated: bool,
+}
+
+impl FatFilesystem {
+    /// Reads and validates the boot sector of the FAT32 volume on the given `device`.
+    fn new(device: StorageDeviceRef) -> Result<FatFilesystem, &'static str> {
+        let device_sector_size = device.lock().sector_size_in_bytes();
+        // Probe the volume using a plain write-through cache, which doesn't require a background flush task.
+        let mut io = BlockIo::new(Arc::clone(&device));
+        let mut boot_sector = vec![0u8; core::cmp::max(device_sector_size, 512)];
+        if io.read(&mut boot_sector, 0)? != boot_sector.len() {
+            return Err("FAT32: couldn't read the boot sector");
+        }
+
+        if boot_sector[510..512] != BOOT_SECTOR_SIGNATURE {
+            return Err("FAT32: boot sector signature was missing");
+        }
+        let bytes_per_sector    = read_u16(&boot_sector, 11) as usize;
+        let sectors_per_cluster = boot_sector[13] as usize;
+        let reserved_sectors    = read_u16(&boot_sector, 14) as usize;
+        let num_fats            = boot_sector[16] as usize;
+        let root_entry_count    = read_u16(&boot_sector, 17);
+        let total_sectors_16    = read_u16(&boot_sector, 19) as usize;
+        let sectors_per_fat_16  = read_u16(&boot_sector, 22);
+        let total_sectors_32    = read_u32(&boot_sector, 32) as usize;
+        let sectors_per_fat     = read_u32(&boot_sector, 36) as usize;
+        let root_cluster        = read_u32(&boot_sector, 44);
+        let fs_info_sector      = read_u16(&boot_sector, 48) as usize;
+
+        match bytes_per_sector {
+            512 | 1024 | 2048 | 4096 => { }
+            _ => return Err("FAT32: invalid bytes per sector"),
+        }
+        if !sectors_per_cluster.is_power_of_two() || reserved_sectors == 0 || num_fats == 0 {
+            return Err("FAT32: invalid BIOS parameter block");
+        }
+        // FAT12 and FAT16 volumes have a fixed-size root directory and a 16-bit FAT size.
+        if root_entry_count != 0 || sectors_per_fat_16 != 0 || sectors_per_fat == 0 {
+            return Err("FAT32: volume is not a FAT32 volume");
+        }
+
+        let total_sectors = if total_sectors_16 != 0 { total_sectors_16 } else { total_sectors_32 };
+        let data_start_sector = reserved_sectors + num_fats * sectors_per_fat;
+        if total_sectors <= data_start_sector {
+            return Err("FAT32: invalid total sector count");
+        }
+        let cluster_count = ((total_sectors - data_start_sector) / sectors_per_cluster) as u32;
+        if cluster_count < MIN_FAT32_CLUSTER_COUNT {
+            return Err("FAT32: volume is too small to be a FAT32 volume (it's FAT12 or FAT16)");
+        }
+        if root_cluster < FIRST_DATA_CLUSTER || root_cluster >= cluster_count + FIRST_DATA_CLUSTER {
+            return Err("FAT32: invalid root directory cluster");
+        }
+
+        // Writes to a FAT volume are small and frequent (FAT entries and directory entries),
+        // so we use a write-back cache that is periodically flushed to the storage device.
+        drop(io);
+        let io = BlockIo::with_config(device, CacheConfig::write_back());
+
+        let mut fs = FatFilesystem {
+            io,
+            bytes_per_sector,
+            sectors_per_cluster,
+            num_fats,
+            fat_start_sector: reserved_sectors,
+            sectors_per_fat,
+            data_start_sector,
+            cluster_count,
+            root_cluster,
+            fs_info_sector: None,
+            next_free_hint: FIRST_DATA_CLUSTER,
+            fs_info_invalidated: false,
+        };
+
+        // The FSInfo sector is optional, so we only use it if its signature is valid.
+        if fs_info_sector != 0 && fs_info_sector != 0xFFFF && fs_info_sector < reserved_sectors {
+            let mut signature = [0u8; 4];
+            fs.read_bytes(&mut signature, fs_info_sector * bytes_per_sector)?;
+            if read_u32(&signature, 0) == FS_INFO_LEAD_SIGNATURE {
+                fs.fs_info_sector = Some(fs_info_sector);
+            }
+        }
+
+        debug!("FAT32: mounted volume with {} clusters of {} bytes, root cluster {}",
+            cluster_count, fs.cluster_size(), root_cluster
+        );
+        Ok(fs)
+    }
+
+    /// Returns the size of a single cluster in bytes.
+    pub fn cluster_size(&self) -> usize {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+
+    /// Returns the first cluster of the root directory.
+    pub fn root_cluster(&self) -> u32 {
+        self.root_cluster
+    }
+
+    /// Writes all modified data that is still cached in memory back to the storage device.
+    ///
+    /// Modified data is otherwise written back periodically, and when the volume is dropped after being unmounted.
+    pub fn sync(&mut self) -> Result<(), &'static str> {
+        self.io.sync()
+    }
+
+    /// Returns statistics about the usage of this volume's block cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.io.stats()
+    }
+
+    /// Returns the absolute byte offset on disk of the start of the given cluster.
+    fn cluster_offset(&self, cluster: u32) -> usize {
+        (self.data_start_sector + (cluster - FIRST_DATA_CLUSTER) as usize * self.sectors_per_cluster) * self.bytes_per_sector
+    }
+
+    /// Reads exactly `buffer.len()` bytes from the volume, starting at the given absolute byte `offset`.
+    fn read_bytes(&mut self, buffer: &mut [u8], offset: usize) -> Result<(), &'static str> {
+        if self.io.read(buffer, offset)? != buffer.len() {
+            return Err("FAT32: read past the end of the storage device");
+        }
+        Ok(())
+    }
+
+    /// Writes all of the given `buffer` to the volume, starting at the given absolute byte `offset`.
+    fn write_bytes(&mut self, buffer: &[u8], offset: usize) -> Result<(), &'static str> {
+        if self.io.write(buffer, offset)? != buffer.len() {
+            return Err("FAT32: write past the end of the storage device");
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the given cluster number refers to a cluster in the data region.
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= FIRST_DATA_CLUSTER && cluster < self.cluster_count + FIRST_DATA_CLUSTER
+    }
+
+    /// Reads the FAT entry for the given `cluster`.
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32, &'static str> {
+        let mut raw = [0u8; 4];
+        let offset = self.fat_start_sector * self.bytes_per_sector + cluster as usize * 4;
+        self.read_bytes(&mut raw, offset)?;
+        Ok(read_u32(&raw, 0) & FAT_ENTRY_MASK)
+    }
+
+    /// Sets the FAT entry for the given `cluster` to `value` in every copy of the FAT.
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), &'static str> {
+        let mut raw = [0u8; 4];
+        let offset = self.fat_start_sector * self.bytes_per_sector + cluster as usize * 4;
+        self.read_bytes(&mut raw, offset)?;
+        let reserved_bits = read_u32(&raw, 0) & !FAT_ENTRY_MASK;
+        write_u32(&mut raw, 0, reserved_bits | (value & FAT_ENTRY_MASK));
+        for fat in 0 .. self.num_fats {
+            self.write_bytes(&raw, offset + fat * self.sectors_per_fat * self.bytes_per_sector)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the list of clusters in the chain that begins at the given `first_cluster`.
+    /// A `first_cluster` of `0` represents an empty chain.
+    fn cluster_chain(&mut self, first_cluster: u32) -> Result<Vec<u32>, &'static str> {
+        let mut chain = Vec::new();
+        if first_cluster == FREE_CLUSTER {
+            return Ok(chain);
+        }
+        let mut cluster = first_cluster;
+        loop {
+            if !self.is_valid_cluster(cluster) || chain.len() > self.cluster_count as usize {
+                error!("FAT32: corrupt cluster chain starting at {}: invalid cluster {}", first_cluster, cluster);
+                return Err("FAT32: found a corrupt cluster chain");
+            }
+            chain.push(cluster);
+            cluster = self.fat_entry(cluster)?;
+            if cluster >= END_OF_CHAIN_MIN {
+                return Ok(chain);
+            }
+        }
+    }
+
+    /// Allocates a free cluster, zeroes it, and marks it as the end of a cluster chain.
+    /// If `previous` is given, the new cluster is appended to the chain that `previous` ends.
+    fn allocate_cluster(&mut self, previous: Option<u32>) -> Result<u32, &'static str> {
+        let end = self.cluster_count + FIRST_DATA_CLUSTER;
+        let start = if self.is_valid_cluster(self.next_free_hint) { self.next_free_hint } else { FIRST_DATA_CLUSTER };
+        let mut cluster = start;
+        while self.fat_entry(cluster)? != FREE_CLUSTER {
+            cluster += 1;
+            if cluster >= end {
+                cluster = FIRST_DATA_CLUSTER;
+            }
+            if cluster == start {
+                return Err("FAT32: no free space left on the volume");
+            }
+        }
+
+        self.set_fat_entry(cluster, END_OF_CHAIN)?;
+        if let Some(prev) = previous {
+            self.set_fat_entry(prev, cluster)?;
+        }
+        let zeroes = vec![0u8; self.cluster_size()];
+        let offset = self.cluster_offset(cluster);
+        self.write_bytes(&zeroes, offset)?;
+
+        self.next_free_hint = cluster + 1;
+        self.invalidate_fs_info()?;
+        Ok(cluster)
+    }
+
+    /// Marks every cluster in the chain that begins at `first_cluster` as free.
+    fn free_chain(&mut self, first_cluster: u32) -> Result<(), &'static str> {
+        for cluster in self.cluster_chain(first_cluster)? {
+            self.set_fat_entry(cluster, FREE_CLUSTER)?;
+        }
+        if self.is_valid_cluster(first_cluster) && first_cluster < self.next_free_hint {
+            self.next_free_hint = first_cluster;
+        }
+        self.invalidate_fs_info()
+    }
+
+    /// Marks the free cluster count in the FSInfo sector as unknown,
+    /// because we do not keep it up to date as clusters are allocated and freed.
+    fn invalidate_fs_info(&mut self) -> Result<(), &'static str> {
+        if self.fs_info_invalidated {
+            return Ok(());
+        }
+        if let Some(sector) = self.fs_info_sector {
+            let mut raw = [0u8; 4];
+            write_u32(&mut raw, 0, FS_INFO_UNKNOWN_FREE_COUNT);
+            self.write_bytes(&raw, sector * self.bytes_per_sector + FS_INFO_FREE_COUNT_OFFSET)?;
+        }
+        self.fs_info_invalidated = true;
+        Ok(())
+    }
+
+    /// Reads the contents of the file whose data begins at `first_cluster` and is `size` bytes long,
+    /// starting at the given `offset` into the file.
+    ///
+    /// Returns the number of bytes read into the given `buffer`.
+    fn read_file(&mut self, first_cluster: u32, size: u32, buffer: &mut [u8], offset: usize) -> Result<usize, &'static str> {
+        let size = size as usize;
+        if offset > size {
+            return Err("read offset exceeds file size");
+        }
+        let read_bytes = core::cmp::min(size - offset, buffer.len());
+        if read_bytes == 0 {
+            return Ok(0);
+        }
+
+        let cluster_size = self.cluster_size();
+        let chain = self.cluster_chain(first_cluster)?;
+        let mut done = 0;
+        while done < read_bytes {
+            let position = offset + done;
+            let cluster = *chain.get(position / cluster_size).ok_or("FAT32: file's cluster chain is shorter than its size")?;
+            let offset_in_cluster = position % cluster_size;
+            let count = core::cmp::min(cluster_size - offset_in_cluster, read_bytes - done);
+            let disk_offset = self.cluster_offset(cluster) + offset_in_cluster;
+            self.read_bytes(&mut buffer[done .. done + count], disk_offset)?;
+            done += count;
+        }
+        Ok(done)
+    }
+
+    /// Writes the given `buffer` into the file whose data begins at `first_cluster` and is `size` bytes long,
+    /// starting at the given `offset` into the file. Clusters are appended to the file as needed.
+    ///
+    /// Returns the file's (possibly new) first cluster and its (possibly new) size.
+    fn write_file(&mut self, first_cluster: u32, size: u32, buffer: &[u8], offset: usize) -> Result<(u32, u32), &'static str> {
+        if offset > size as usize {
+            return Err("offset out of bounds");
+        }
+        let end = offset + buffer.len();
+        if end > core::u32::MAX as usize {
+            return Err("FAT32: files cannot be larger than 4 GiB");
+        }
+
+        let cluster_size = self.cluster_size();
+        let mut chain = self.cluster_chain(first_cluster)?;
+        let clusters_needed = (end + cluster_size - 1) / cluster_size;
+        while chain.len() < clusters_needed {
+            let new_cluster = self.allocate_cluster(chain.last().cloned())?;
+            chain.push(new_cluster);
+        }
+
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done;
+            let cluster = chain[position / cluster_size];
+            let offset_in_cluster = position % cluster_size;
+            let count = core::cmp::min(cluster_size - offset_in_cluster, buffer.len() - done);
+            let disk_offset = self.cluster_offset(cluster) + offset_in_cluster;
+            self.write_bytes(&buffer[done .. done + count], disk_offset)?;
+            done += count;
+        }
+
+        let first_cluster = chain.first().cloned().unwrap_or(FREE_CLUSTER);
+        Ok((first_cluster, core::cmp::max(size as usize, end) as u32))
+    }
+
+    /// Shrinks the file whose data begins at `first_cluster` to `new_size` bytes,
+    /// freeing the clusters at the end of its chain that it no longer needs.
+    ///
+    /// Returns the file's (possibly new) first cluster, which is `0` if the file is now empty.
+    fn truncate_file(&mut self, first_cluster: u32, new_size: usize) -> Result<u32, &'static str> {
+        let cluster_size = self.cluster_size();
+        let chain = self.cluster_chain(first_cluster)?;
+        let clusters_needed = (new_size + cluster_size - 1) / cluster_size;
+        if clusters_needed >= chain.len() {
+            return Ok(first_cluster);
+        }
+        if clusters_needed == 0 {
+            self.free_chain(first_cluster)?;
+            return Ok(FREE_CLUSTER);
+        }
+        // Cut the chain after its last needed cluster, then free the rest of it.
+        self.set_fat_entry(chain[clusters_needed - 1], END_OF_CHAIN)?;
+        self.free_chain(chain[clusters_needed])?;
+        Ok(first_cluster)
+    }
+
+    /// Reads and parses all entries in the directory that begins at the given `dir_cluster`,
+    /// excluding the `.` and `..` entries and the volume label.
+    fn read_dir(&mut self, dir_cluster: u32) -> Result<Vec<DirEntry>, &'static str> {
+        let mut entries = Vec::new();
+        let mut long_name = LongNameBuilder::new();
+        let mut buf = vec![0u8; self.cluster_size()];
+
+        for cluster in self.cluster_chain(dir_cluster)? {
+            let cluster_offset = self.cluster_offset(cluster);
+            self.read_bytes(&mut buf, cluster_offset)?;
+            for (i, raw) in buf.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                let slot_offset = cluster_offset + i * DIR_ENTRY_SIZE;
+                match raw[0] {
+                    END_OF_DIRECTORY => return Ok(entries),
+                    DELETED_ENTRY => {
+                        long_name.reset();
+                        continue;
+                    }
+                    _ => { }
+                }
+                if is_long_name_entry(raw) {
+                    long_name.push(raw, slot_offset);
+                    continue;
+                }
+                let mut entry = parse_short_entry(raw);
+                if is_volume_label(raw) || entry.short_name == DOT_SHORT_NAME || entry.short_name == DOT_DOT_SHORT_NAME {
+                    long_name.reset();
+                    continue;
+                }
+                if let Some((name, slots)) = long_name.finish(&entry.short_name) {
+                    entry.name = name;
+                    entry.slots = slots;
+                }
+                entry.slots.push(slot_offset);
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Finds `count` consecutive free slots in the directory that begins at `dir_cluster`,
+    /// extending the directory with new clusters if necessary.
+    ///
+    /// Returns the absolute byte offsets on disk of those slots.
+    fn find_free_slots(&mut self, dir_cluster: u32, count: usize) -> Result<Vec<usize>, &'static str> {
+        let cluster_size = self.cluster_size();
+        let mut buf = vec![0u8; cluster_size];
+        let mut run = Vec::with_capacity(count);
+
+        let chain = self.cluster_chain(dir_cluster)?;
+        for &cluster in chain.iter() {
+            let cluster_offset = self.cluster_offset(cluster);
+            self.read_bytes(&mut buf, cluster_offset)?;
+            for (i, raw) in buf.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                if raw[0] == END_OF_DIRECTORY || raw[0] == DELETED_ENTRY {
+                    run.push(cluster_offset + i * DIR_ENTRY_SIZE);
+                    if run.len() == count {
+                        return Ok(run);
+                    }
+                } else {
+                    run.clear();
+                }
+            }
+        }
+
+        // Not enough free slots, so the directory must grow. New clusters are already zeroed.
+        let mut last_cluster = *chain.last().ok_or("FAT32: directory had no clusters")?;
+        while run.len() < count {
+            last_cluster = self.allocate_cluster(Some(last_cluster))?;
+            let cluster_offset = self.cluster_offset(last_cluster);
+            let needed = core::cmp::min(count - run.len(), cluster_size / DIR_ENTRY_SIZE);
+            run.extend((0 .. needed).map(|i| cluster_offset + i * DIR_ENTRY_SIZE));
+        }
+        Ok(run)
+    }
+
+    /// Adds a new entry with the given `name` and fields to the directory that begins at `dir_cluster`,
+    /// using long name entries if the `name` cannot be represented as a short 8.3 name.
+    ///
+    /// If an entry with the same `name` already exists, e.g., one that the new entry replaces,
+    /// the caller must remove it afterwards.
+    fn add_dir_entry(&mut self, dir_cluster: u32, name: &str, attributes: u8, first_cluster: u32, size: u32) -> Result<DirEntry, &'static str> {
+        validate_name(name)?;
+        let existing: Vec<[u8; 11]> = self.read_dir(dir_cluster)?.into_iter().map(|e| e.short_name).collect();
+
+        let (short_name, nt_flags, mut raw_slots) = match exact_short_name(name) {
+            Some((short_name, nt_flags)) if !existing.contains(&short_name) => (short_name, nt_flags, Vec::new()),
+            _ => {
+                let alias = generate_alias(name, &existing)?;
+                (alias, 0, encode_long_name(name, short_name_checksum(&alias)))
+            }
+        };
+        let time = fs_node::now();
+        raw_slots.push(encode_short_entry(&short_name, attributes, nt_flags, first_cluster, size, &time));
+
+        let slots = self.find_free_slots(dir_cluster, raw_slots.len())?;
+        for (raw, &offset) in raw_slots.iter().zip(slots.iter()) {
+            self.write_bytes(raw, offset)?;
+        }
+        Ok(DirEntry {
+            name: String::from(name),
+            short_name,
+            attributes,
+            first_cluster,
+            size,
+            created: Some(time),
+            modified: Some(time),
+            accessed: Some(time),
+            slots,
+        })
+    }
+
+    /// Updates the attributes, first cluster, size and timestamp fields of the given on-disk `entry`.
+    fn update_dir_entry(&mut self, entry: &DirEntry) -> Result<(), &'static str> {
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        let offset = entry.short_entry_offset();
+        self.read_bytes(&mut raw, offset)?;
+        raw[11] = entry.attributes;
+        write_u16(&mut raw, 20, (entry.first_cluster >> 16) as u16);
+        write_u16(&mut raw, 26, entry.first_cluster as u16);
+        write_u32(&mut raw, 28, entry.size);
+        write_timestamps(&mut raw, entry.created.as_ref(), entry.modified.as_ref(), entry.accessed.as_ref());
+        self.write_bytes(&raw, offset)
+    }
+
+    /// Creates a new, empty subdirectory named `name` within the directory that begins at `parent_cluster`.
+    fn create_dir(&mut self, parent_cluster: u32, name: &str) -> Result<DirEntry, &'static str> {
+        validate_name(name)?;
+        let cluster = self.allocate_cluster(None)?;
+        // A `..` entry that refers to the root directory must use cluster 0.
+        let parent_ref = if parent_cluster == self.root_cluster { FREE_CLUSTER } else { parent_cluster };
+        let time = fs_node::now();
+        let dot     = encode_short_entry(&DOT_SHORT_NAME,     ATTR_DIRECTORY, 0, cluster, 0, &time);
+        let dot_dot = encode_short_entry(&DOT_DOT_SHORT_NAME, ATTR_DIRECTORY, 0, parent_ref, 0, &time);
+        let offset = self.cluster_offset(cluster);
+        let result = self.write_bytes(&dot, offset)
+            .and_then(|_| self.write_bytes(&dot_dot, offset + DIR_ENTRY_SIZE))
+            .and_then(|_| self.add_dir_entry(parent_cluster, name, ATTR_DIRECTORY, cluster, 0));
+        if result.is_err() {
+            self.free_chain(cluster)?;
+        }
+        result
+    }
+
+    /// Creates a new file named `name` with the given `contents` within the directory that begins at `parent_cluster`.
+    fn create_file(&mut self, parent_cluster: u32, name: &str, contents: &[u8]) -> Result<DirEntry, &'static str> {
+        validate_name(name)?;
+        let (first_cluster, size) = self.write_file(FREE_CLUSTER, 0, contents, 0)?;
+        let result = self.add_dir_entry(parent_cluster, name, ATTR_ARCHIVE, first_cluster, size);
+        if result.is_err() {
+            self.free_chain(first_cluster)?;
+        }
+        result
+    }
+
+    /// Removes the given `entry` from its directory and frees all of the clusters it occupies,
+    /// including (recursively) the clusters of everything within it if it is a directory.
+    fn remove_dir_entry(&mut self, entry: &DirEntry) -> Result<(), &'static str> {
+        for &slot in entry.slots.iter() {
+            self.write_bytes(&[DELETED_ENTRY], slot)?;
+        }
+        self.free_entry_contents(entry)
+    }
+
+    /// Moves the given `entry` from the directory that begins at `old_parent_cluster`
+    /// into the directory that begins at `new_parent_cluster` (which may be the same directory) with the new `name`,
+    /// without touching the clusters that hold its contents.
+    ///
+    /// The new entry is written before the old one is deleted, so an interrupted move never loses the entry.
+    /// If an entry with the new `name` already exists in the destination directory, the caller must remove it afterwards.
+    fn move_entry(&mut self, entry: &DirEntry, old_parent_cluster: u32, new_parent_cluster: u32, name: &str) -> Result<DirEntry, &'static str> {
+        let mut new_entry = self.add_dir_entry(new_parent_cluster, name, entry.attributes, entry.first_cluster, entry.size)?;
+        // Moving an entry doesn't change when it was created or last modified.
+        new_entry.created = entry.created.or(new_entry.created);
+        new_entry.modified = entry.modified.or(new_entry.modified);
+        self.update_dir_entry(&new_entry)?;
+        for &slot in entry.slots.iter() {
+            self.write_bytes(&[DELETED_ENTRY], slot)?;
+        }
+
+        // A moved directory's `..` entry must refer to its new parent.
+        if entry.is_dir() && old_parent_cluster != new_parent_cluster {
+            let parent_ref = if new_parent_cluster == self.root_cluster { FREE_CLUSTER } else { new_parent_cluster };
+            let offset = self.cluster_offset(entry.first_cluster) + DIR_ENTRY_SIZE;
+            let mut raw = [0u8; DIR_ENTRY_SIZE];
+            self.read_bytes(&mut raw, offset)?;
+            if raw[0 .. 11] == DOT_DOT_SHORT_NAME {
+                write_u16(&mut raw, 20, (parent_ref >> 16) as u16);
+                write_u16(&mut raw, 26, parent_ref as u16);
+                self.write_bytes(&raw, offset)?;
+            } else {
+                warn!("FAT32: moved directory {:?} had no `..` entry", name);
+            }
+        }
+        Ok(new_entry)
+    }
+
+    /// Frees the clusters occupied by the given `entry`, recursively if it is a directory.
+    fn free_entry_contents(&mut self, entry: &DirEntry) -> Result<(), &'static str> {
+        if entry.is_dir() {
+            for child in self.read_dir(entry.first_cluster)? {
+                self.free_entry_contents(&child)?;
+            }
+        }
+        self.free_chain(entry.first_cluster)
+    }
+}
+
+
+/// Reads a little-endian `u16` from the given `buf` at the given byte `offset`.
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    (buf[offset] as u16) | ((buf[offset + 1] as u16) << 8)
+}
+
+/// Reads a little-endian `u32` from the given `buf` at the given byte `offset`.
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    (read_u16(buf, offset) as u32) | ((read_u16(buf, offset + 2) as u32) << 16)
+}
+
+/// Writes the given `value` as a little-endian `u16` into the given `buf` at the given byte `offset`.
+fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset] = value as u8;
+    buf[offset + 1] = (value >> 8) as u8;
+}
+
+/// Writes the given `value` as a little-endian `u32` into the given `buf` at the given byte `offset`.
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    write_u16(buf, offset, value as u16);
+    write_u16(buf, offset + 2, (value >> 16) as u16);
+}
@@ -41,6 +41,8 @@ pub enum FaultType {
     NMI,
     DivideByZero,
     Panic,
+    /// A task overflowed its stack, i.e., it accessed the guard page below its stack.
+    StackOverflow,
     UnknownException(u8)
 }
 
@@ -92,6 +94,8 @@ pub struct FaultEntry {
     pub core: Option<u8>,
     /// Task runnning immediately before the Exception
     pub running_task: Option<String>,
+    /// The id of the task running immediately before the Exception
+    pub running_task_id: Option<usize>,
     /// If available the application crate that spawned the task
     pub running_app_crate: Option<String>,
     /// For page faults the address the program attempted to access. None for other faults
@@ -116,6 +120,7 @@ impl FaultEntry {
             error_code: None,
             core: None,
             running_task: None,
+            running_task_id: None,
             running_app_crate: None,
             address_accessed: None,
             instruction_pointer: None,
@@ -159,10 +164,11 @@ fn update_and_insert_fault_entry_internal(
 
     let namespace = curr_task.get_namespace();
 
-    // Add name of current task
+    // Add name and id of current task
     fe.running_task = {
         Some(curr_task.lock().name.clone())
     };
+    fe.running_task_id = task::get_my_current_task_id();
 
     // If task is from an application add application crate name. `None` if not 
     fe.running_app_crate = {
@@ -199,6 +205,30 @@ pub fn log_exception (
     update_and_insert_fault_entry_internal(fe, Some(instruction_pointer));
 }
 
+/// Add a new stack overflow instance to the fault log,
+/// which was detected because the current task, with the given `task_id` and `task_name`, 
+/// accessed the guard page below its stack at `address_accessed`.
+/// 
+/// Unlike the other functions that log faults, this doesn't lock the current task, 
+/// since it may have overflowed its stack while holding its own lock. 
+/// Thus, the entry doesn't record the task's application crate or the crate in which the overflow occurred.
+pub fn log_stack_overflow (
+    task_id: usize,
+    task_name: &str,
+    instruction_pointer: usize,
+    error_code: Option<u64>,
+    address_accessed: usize
+) {
+    let mut fe = FaultEntry::new(FaultType::StackOverflow);
+    fe.error_code = error_code;
+    fe.core = Some(get_my_apic_id());
+    fe.running_task = Some(String::from(task_name));
+    fe.running_task_id = Some(task_id);
+    fe.address_accessed = Some(VirtualAddress::new_canonical(address_accessed));
+    fe.instruction_pointer = Some(VirtualAddress::new_canonical(instruction_pointer));
+    FAULT_LIST.lock().push(fe);
+}
+
 /// Add a new panic instance to the fault log. 
 pub fn log_panic_entry(panic_info: &PanicInfo) {
     let mut fe = FaultEntry::new(FaultType::Panic);
@@ -0,0 +1,281 @@
+//! Open file handles and per-task file descriptor tables.
+//!
+//! A [`FileHandle`](struct.FileHandle.html) is obtained by opening a file with [`open()`](fn.open.html).
+//! It remembers the [`OpenFlags`](struct.OpenFlags.html) that the file was opened with and a cursor position,
+//! so reads and writes through it don't need an explicit offset like the `fs_node::File` methods do.
+//! File handles implement the `Read`, `Write`, and `Seek` traits from `core_io`.
+//!
+//! Each task has an [`FdTable`](struct.FdTable.html) that maps small integer file descriptors to its open file handles.
+//! A task's descriptors are closed automatically when it exits.
+//!
+//! # Example
+//! ```
+//! let handle = file_handle::open(&Path::new("notes.txt".into()), &working_dir, OpenFlags::WRITE | OpenFlags::CREATE)?;
+//! let fd = taskref.get_fd_table().lock().insert(handle);
+//! ```
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate bitflags;
+extern crate spin;
+extern crate core_io;
+extern crate fs_node;
+extern crate heapfile;
+extern crate path;
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::Arc,
+};
+use spin::Mutex;
+use core_io::{Read, Write, Seek, SeekFrom};
+use fs_node::{DirRef, FileOrDir, FileRef, FsNode, Permissions};
+use heapfile::HeapFile;
+use path::Path;
+
+
+bitflags! {
+    /// The modes in which a file can be opened, which can be combined.
+    pub struct OpenFlags: u8 {
+        /// The file can be read through the handle.
+        const READ     = 1 << 0;
+        /// The file can be written through the handle.
+        const WRITE    = 1 << 1;
+        /// Every write goes to the end of the file, regardless of the cursor position. Implies `WRITE`.
+        const APPEND   = 1 << 2;
+        /// The file is created (as an empty `HeapFile`) if it doesn't exist.
+        const CREATE   = 1 << 3;
+        /// The file's existing contents are discarded when it is opened. Requires `WRITE`.
+        const TRUNCATE = 1 << 4;
+    }
+}
+
+impl OpenFlags {
+    /// Returns `true` if these flags allow writing.
+    pub fn is_writable(&self) -> bool {
+        self.intersects(OpenFlags::WRITE | OpenFlags::APPEND)
+    }
+}
+
+
+/// Opens the file at the given `path`, which is either absolute or relative to the given `working_dir`.
+///
+/// If the file doesn't exist and `flags` includes `CREATE`, a new empty file is created in its parent directory.
+/// Opening a directory is an error.
+///
+/// The locks on `working_dir` and all directories along the `path` must not be held.
+pub fn open(path: &Path, working_dir: &DirRef, flags: OpenFlags) -> Result<FileHandle, &'static str> {
+    let file = match path.get(working_dir) {
+        Some(FileOrDir::File(file)) => file,
+        Some(FileOrDir::Dir(_)) => return Err("cannot open a directory as a file"),
+        None if flags.contains(OpenFlags::CREATE) => {
+            let parent_path = path.parent().ok_or("invalid path")?;
+            let parent = match parent_path.get(working_dir) {
+                Some(FileOrDir::Dir(dir)) => dir,
+                _ => return Err("the parent directory of the file doesn't exist"),
+            };
+            let name = path.basename();
+            HeapFile::new(String::from(name), &parent)?;
+            // Re-obtain the new file from its parent, because some directories (e.g., on FAT32 volumes)
+            // store a copy of an inserted node rather than the node itself.
+            let new_file = parent.lock().get_file(name);
+            new_file.ok_or("couldn't create the file")?
+        }
+        None => return Err("file does not exist"),
+    };
+    FileHandle::new(file, flags)
+}
+
+
+/// An open file, which has a cursor position and the mode it was opened in.
+pub struct FileHandle {
+    file: FileRef,
+    flags: OpenFlags,
+    /// The offset into the file at which the next read or write will start.
+    position: usize,
+}
+
+impl FileHandle {
+    /// Opens the given `file` with the given `flags`, with the cursor at the start of the file.
+    ///
+    /// Returns an error if `flags` allow neither reading nor writing,
+    /// or if they require a permission that the file doesn't have.
+    /// If `flags` includes `TRUNCATE`, the file is truncated to be empty.
+    pub fn new(file: FileRef, flags: OpenFlags) -> Result<FileHandle, &'static str> {
+        if !flags.contains(OpenFlags::READ) && !flags.is_writable() {
+            return Err("a file must be opened for reading, writing, or both");
+        }
+        if flags.contains(OpenFlags::TRUNCATE) && !flags.is_writable() {
+            return Err("a file must be opened for writing to be truncated");
+        }
+        {
+            let mut locked_file = file.lock();
+            let permissions = locked_file.metadata().permissions;
+            if flags.contains(OpenFlags::READ) && !permissions.contains(Permissions::READ) {
+                return Err("permission denied: file is not readable");
+            }
+            if flags.is_writable() && !permissions.contains(Permissions::WRITE) {
+                return Err("permission denied: file is not writable");
+            }
+            if flags.contains(OpenFlags::TRUNCATE) {
+                locked_file.truncate(0)?;
+            }
+        }
+        Ok(FileHandle {
+            file,
+            flags,
+            position: 0,
+        })
+    }
+
+    /// Returns the file that this handle refers to.
+    pub fn file(&self) -> &FileRef {
+        &self.file
+    }
+
+    /// Returns the flags that this handle was opened with.
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    /// Returns the current cursor position, i.e., the offset into the file of the next read or write.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Reads from the file at the current cursor position into the given `buffer`, and advances the cursor.
+    /// Returns the number of bytes read, which is `0` at the end of the file.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err("file was not opened for reading");
+        }
+        let locked_file = self.file.lock();
+        if self.position >= locked_file.size() {
+            return Ok(0);
+        }
+        let bytes_read = locked_file.read(buffer, self.position)?;
+        self.position += bytes_read;
+        Ok(bytes_read)
+    }
+
+    /// Writes the given `buffer` to the file at the current cursor position, or at its end if opened with `APPEND`,
+    /// and advances the cursor past the written bytes. Returns the number of bytes written.
+    ///
+    /// Writing with the cursor beyond the end of the file is an error.
+    pub fn write(&mut self, buffer: &[u8]) -> Result<usize, &'static str> {
+        if !self.flags.is_writable() {
+            return Err("file was not opened for writing");
+        }
+        let mut locked_file = self.file.lock();
+        if self.flags.contains(OpenFlags::APPEND) {
+            self.position = locked_file.size();
+        }
+        let bytes_written = locked_file.write(buffer, self.position)?;
+        self.position += bytes_written;
+        Ok(bytes_written)
+    }
+
+    /// Moves the cursor to the given position and returns the new position.
+    ///
+    /// The cursor may be moved beyond the end of the file, but not before its start.
+    pub fn seek(&mut self, position: SeekFrom) -> Result<usize, &'static str> {
+        let (base, offset) = match position {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::End(offset) => (self.file.lock().size() as i64, offset),
+            SeekFrom::Current(offset) => (self.position as i64, offset),
+        };
+        let new_position = base.checked_add(offset).ok_or("seek position overflowed")?;
+        if new_position < 0 {
+            return Err("cannot seek before the start of the file");
+        }
+        self.position = new_position as usize;
+        Ok(self.position)
+    }
+}
+
+impl Read for FileHandle {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, core_io::Error> {
+        FileHandle::read(self, buf).map_err(|e| core_io::Error::new(core_io::ErrorKind::Other, e))
+    }
+}
+
+impl Write for FileHandle {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, core_io::Error> {
+        FileHandle::write(self, buf).map_err(|e| core_io::Error::new(core_io::ErrorKind::Other, e))
+    }
+
+    /// Writes go directly to the underlying file, so there is nothing to flush.
+    fn flush(&mut self) -> Result<(), core_io::Error> {
+        Ok(())
+    }
+}
+
+impl Seek for FileHandle {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, core_io::Error> {
+        FileHandle::seek(self, pos)
+            .map(|p| p as u64)
+            .map_err(|e| core_io::Error::new(core_io::ErrorKind::InvalidInput, e))
+    }
+}
+
+
+/// A file descriptor, which identifies an open file handle within a task's `FdTable`.
+pub type Fd = usize;
+
+/// A reference to an open file handle, which can be shared by multiple descriptors or tasks.
+pub type FileHandleRef = Arc<Mutex<FileHandle>>;
+
+/// A table of a task's open file handles, indexed by their file descriptors.
+///
+/// Handles are shared references, so they can be used without holding the lock on the table.
+#[derive(Default)]
+pub struct FdTable {
+    handles: BTreeMap<Fd, FileHandleRef>,
+}
+
+impl FdTable {
+    /// Creates an empty file descriptor table.
+    pub fn new() -> FdTable {
+        FdTable::default()
+    }
+
+    /// Adds the given `handle` to this table and returns its file descriptor,
+    /// which is the lowest descriptor that isn't currently in use.
+    pub fn insert(&mut self, handle: FileHandle) -> Fd {
+        self.insert_ref(Arc::new(Mutex::new(handle)))
+    }
+
+    /// Like [`insert()`](#method.insert), but adds an existing shared handle, e.g., one duplicated from another descriptor.
+    pub fn insert_ref(&mut self, handle: FileHandleRef) -> Fd {
+        let fd = (0..).find(|fd| !self.handles.contains_key(fd)).expect("BUG: ran out of file descriptors");
+        self.handles.insert(fd, handle);
+        fd
+    }
+
+    /// Returns the handle that the given file descriptor refers to.
+    pub fn get(&self, fd: Fd) -> Option<FileHandleRef> {
+        self.handles.get(&fd).cloned()
+    }
+
+    /// Closes the given file descriptor. The handle itself is closed once no other descriptors refer to it.
+    pub fn close(&mut self, fd: Fd) -> Result<(), &'static str> {
+        self.handles.remove(&fd).map(|_| ()).ok_or("invalid file descriptor")
+    }
+
+    /// Closes all file descriptors in this table.
+    pub fn close_all(&mut self) {
+        self.handles.clear();
+    }
+
+    /// Returns the number of open file descriptors in this table.
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    /// Returns `true` if this table has no open file descriptors.
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+}
@@ -0,0 +1,97 @@
+//! Copies files and directories, possibly between different filesystems.
+//!
+//! A copy is made by reading the source through the `File` and `Directory` traits
+//! and creating new in-memory nodes (`HeapFile`s and `VFSDirectory`s) in the destination directory,
+//! so any kind of file can be copied, e.g., a `MemFile`, a `HeapFile`, or a file on disk.
+//! Symbolic links are copied as new symbolic links with the same target, rather than as the node they refer to.
+//! Directories that represent on-disk structures store their own copy of inserted nodes (see `FatDirectory`),
+//! so copies into those directories end up on disk.
+//!
+//! This is also how a node is moved between filesystems, which `fs_node::move_node()` refuses to do:
+//! copy it, then remove the original.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+extern crate fs_node;
+extern crate heapfile;
+extern crate vfs_node;
+extern crate symlink;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use fs_node::{DirRef, FileOrDir, FileRef};
+use heapfile::HeapFile;
+use vfs_node::VFSDirectory;
+use symlink::Symlink;
+
+
+/// Copies the given `source` node into the `destination` directory, where the copy will be called `new_name`,
+/// and returns the new node.
+///
+/// An existing file called `new_name` is replaced. 
+/// If `source` is a directory, `recursive` must be `true`, in which case all of its contents are copied as well;
+/// if a directory called `new_name` already exists, the contents are copied into it.
+///
+/// The locks on `source`, `destination`, and their contents must not be held.
+pub fn copy(source: &FileOrDir, destination: &DirRef, new_name: &str, recursive: bool) -> Result<FileOrDir, &'static str> {
+    match source {
+        FileOrDir::File(file) => copy_file(file, destination, new_name).map(FileOrDir::File),
+        FileOrDir::Dir(dir) => {
+            if !recursive {
+                return Err("cannot copy a directory without copying it recursively");
+            }
+            // Otherwise, we would keep copying the directory into its own copy.
+            if fs_node::is_ancestor_or_self(dir, destination) {
+                return Err("cannot copy a directory into itself");
+            }
+            copy_dir(dir, destination, new_name).map(FileOrDir::Dir)
+        }
+    }
+}
+
+fn copy_file(file: &FileRef, destination: &DirRef, new_name: &str) -> Result<FileRef, &'static str> {
+    if let Some(FileOrDir::Dir(_)) = destination.lock().get(new_name) {
+        return Err("a directory with the destination name already exists");
+    }
+    let target = file.lock().symlink_target();
+    if let Some(target) = target {
+        Symlink::new(String::from(new_name), target, destination)?;
+        return destination.lock().get_file(new_name).ok_or("couldn't find the copied symbolic link in its destination");
+    }
+    let contents = {
+        let locked_file = file.lock();
+        let mut contents = vec![0u8; locked_file.size()];
+        let bytes_read = locked_file.read(&mut contents, 0)?;
+        contents.truncate(bytes_read);
+        contents
+    };
+    HeapFile::from_vec(contents, String::from(new_name), destination)?;
+    // The destination may have stored its own copy of the new file, so get the node it actually holds.
+    destination.lock().get_file(new_name).ok_or("couldn't find the copied file in its destination")
+}
+
+fn copy_dir(dir: &DirRef, destination: &DirRef, new_name: &str) -> Result<DirRef, &'static str> {
+    let existing = destination.lock().get(new_name);
+    let new_dir = match existing {
+        Some(FileOrDir::Dir(d)) => d,
+        Some(FileOrDir::File(_)) => return Err("a file with the destination name already exists"),
+        None => {
+            VFSDirectory::new(String::from(new_name), destination)?;
+            // The destination may have stored its own copy of the new directory, so get the node it actually holds.
+            destination.lock().get_dir(new_name).ok_or("couldn't find the copied directory in its destination")?
+        }
+    };
+
+    let children: Vec<(String, FileOrDir)> = {
+        let locked_dir = dir.lock();
+        locked_dir.list().into_iter().filter_map(|name| locked_dir.get(&name).map(|node| (name, node))).collect()
+    };
+    for (name, child) in children {
+        copy(&child, &new_dir, &name, true)?;
+    }
+    Ok(new_dir)
+}
+
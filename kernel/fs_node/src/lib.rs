@@ -10,15 +10,40 @@
 //! Some functions return an enum FileOrDir; this allows us to seamlessly call functions on the return types of
 //! other filesystem functions, and then we simply match on the FSnode to extract the concrete type
 //! to perform the desired function
+//! 
+//! Nodes can be renamed within a directory using [`Directory::rename()`](trait.Directory.html#method.rename),
+//! and moved between directories of the same filesystem using [`move_node()`](fn.move_node.html).
+//! 
+//! Every node has [`Metadata`](struct.Metadata.html), which includes its kind, size, permissions, and timestamps.
+//! A node that isn't writable rejects `File::write()` and `Directory::insert()`.
+//! 
+//! # Links
+//! A symbolic link is a `File` whose [`symlink_target()`](trait.File.html#method.symlink_target) is the path it refers to;
+//! the `path` crate follows symbolic links when resolving a path.
+//! A hard link is an additional name for an existing file within a directory,
+//! created with [`Directory::insert_link()`](trait.Directory.html#method.insert_link).
+//! 
+//! # Watching
+//! Nodes that return a [`Watchers`](struct.Watchers.html) list from [`FsNode::watchers()`](trait.FsNode.html#method.watchers)
+//! can be watched for changes, see the [`watch`](watch/index.html) module.
 
 #[macro_use] extern crate alloc;
+#[macro_use] extern crate downcast_rs;
+#[macro_use] extern crate bitflags;
 extern crate spin;
 extern crate memory;
+extern crate rtc;
+
+mod metadata;
+pub use metadata::*;
+pub mod watch;
+pub use watch::{Watchers, WatchEvent, WatchEventKind, WatchSink};
 
 use alloc::string::String;
 use alloc::vec::Vec;
 use spin::Mutex;
 use alloc::sync::{Arc, Weak};
+use downcast_rs::Downcast;
 use memory::MappedPages;
 
 
@@ -60,6 +85,30 @@ pub trait FsNode {
     /// This is useful for ensuring correctness when inserting or remonving 
     /// files or directories from their parent directory.
     fn set_parent_dir(&mut self, new_parent: WeakDirRef);
+
+    /// Changes the name of this node, without changing the name under which its parent directory holds it.
+    /// 
+    /// This should only be used by `Directory` implementations to rename a node that they hold;
+    /// everyone else should use [`Directory::rename()`](trait.Directory.html#method.rename) instead.
+    /// By default, nodes cannot be renamed.
+    fn set_name(&mut self, _new_name: String) -> Result<(), &'static str> {
+        Err("this node cannot be renamed")
+    }
+
+    /// Returns this node's metadata, i.e., its kind, size, permissions, and timestamps.
+    fn metadata(&self) -> Metadata;
+
+    /// Changes this node's permissions. 
+    /// By default, the permissions of a node cannot be changed.
+    fn set_permissions(&mut self, _permissions: Permissions) -> Result<(), &'static str> {
+        Err("the permissions of this node cannot be changed")
+    }
+
+    /// Returns the list of watchers that are notified of changes to this node.
+    /// By default, nodes cannot be watched.
+    fn watchers(&self) -> Option<&Watchers> {
+        None
+    }
 } 
 
 // Trait for files, implementors of File must also implement FsNode
@@ -69,17 +118,45 @@ pub trait File : FsNode {
     fn read(&self, buffer: &mut [u8], offset: usize) -> Result<usize, &'static str>; 
 
     /// Writes the given `buffer` to this file starting at the given `offset`.
+    /// Fails if this file doesn't have the `WRITE` permission.
     fn write(&mut self, buffer: &[u8], offset: usize) -> Result<usize, &'static str>;
 
     /// Returns the size in bytes of this file.
     fn size(&self) -> usize;
 
+    /// Shrinks this file to the given `size` in bytes, discarding its contents beyond that size.
+    /// Fails if `size` is larger than the current size of this file, or if this file doesn't have the `WRITE` permission.
+    /// 
+    /// By default, files cannot be truncated.
+    fn truncate(&mut self, _size: usize) -> Result<(), &'static str> {
+        Err("this file cannot be truncated")
+    }
+
     /// Returns a view of this file as an immutable memory-mapped region.
     fn as_mapping(&self) -> Result<&MappedPages, &'static str>;
+
+    /// If this file is a symbolic link, returns the path that it refers to, 
+    /// which is either absolute or relative to the directory that contains the link.
+    /// 
+    /// By default, files are not symbolic links.
+    fn symlink_target(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Identifies the filesystem that a directory belongs to. 
+/// Nodes can only be moved between directories that belong to the same filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilesystemId(pub usize);
+
+impl FilesystemId {
+    /// The filesystem of in-memory nodes, e.g., `VFSDirectory`, `MemFile` and `HeapFile`,
+    /// which are only ever referenced by the directories that contain them.
+    pub const IN_MEMORY: FilesystemId = FilesystemId(0);
 }
 
 /// Trait for directories, implementors of Directory must also implement FsNode
-pub trait Directory : FsNode {
+pub trait Directory : FsNode + Downcast {
     /// Gets either the file or directory in this `Directory`  on its name.
     fn get(&self, name: &str) -> Option<FileOrDir>;
 
@@ -107,6 +184,8 @@ pub trait Directory : FsNode {
     /// However, if a node is replaced, that old node's parent directory will be cleared
     /// to reflect that it is no longer in this directory.
     /// 
+    /// Fails if this directory doesn't have the `WRITE` permission.
+    /// 
     /// The lock on `node` must not be held because it will be acquired within this function.
     fn insert(&mut self, node: FileOrDir) -> Result<Option<FileOrDir>, &'static str>;
 
@@ -118,7 +197,119 @@ pub trait Directory : FsNode {
 
     /// Lists the names of the nodes in this directory.
     fn list(&self) -> Vec<String>;
+
+    /// Inserts the given existing `file` into this directory under the given `name`, i.e., creates a hard link to it.
+    /// 
+    /// Unlike [`insert()`](#tymethod.insert), this does not change the file's own name or parent directory,
+    /// so the same `FileRef` can be held by several directories, or by one directory under several names.
+    /// An existing file called `name` is replaced and returned, but an existing directory is never replaced.
+    /// 
+    /// By default, directories don't support hard links.
+    fn insert_link(&mut self, _name: &str, _file: FileRef) -> Result<Option<FileOrDir>, &'static str> {
+        Err("this directory doesn't support hard links")
+    }
+
+    /// Returns `true` if the node called `name` in this directory is a hard link 
+    /// that was created with [`insert_link()`](#method.insert_link), rather than the node's original entry.
+    fn is_link(&self, _name: &str) -> bool {
+        false
+    }
+
+    /// Removes the node called `name` from this directory and returns it if found.
+    /// 
+    /// Unlike [`remove()`](#tymethod.remove), which finds the entry by the node's own name,
+    /// this removes exactly the entry called `name`, which matters if that entry is a hard link.
+    /// The node's parent directory is only cleared if its original entry was removed.
+    fn unlink(&mut self, name: &str) -> Option<FileOrDir> {
+        let node = self.get(name)?;
+        self.remove(&node)
+    }
+
+    /// Returns the filesystem that this directory belongs to.
+    fn filesystem_id(&self) -> FilesystemId {
+        FilesystemId::IN_MEMORY
+    }
+
+    /// Renames the node called `old_name` in this directory to `new_name`.
+    /// 
+    /// If a file called `new_name` already exists, it is replaced, 
+    /// but an existing directory called `new_name` is never replaced.
+    /// Because the caller holds the lock on this directory, no one else can observe the node under both names.
+    /// 
+    /// The default implementation removes the node, renames it using [`FsNode::set_name()`], 
+    /// and inserts it back into this directory, restoring the original name if that fails.
+    fn rename(&mut self, old_name: &str, new_name: &str) -> Result<(), &'static str> {
+        if self.is_link(old_name) {
+            return Err("cannot rename a hard link; remove it and create a new link instead");
+        }
+        if old_name == new_name {
+            return self.get(old_name).map(|_| ()).ok_or("source node does not exist");
+        }
+        let node = self.get(old_name).ok_or("source node does not exist")?;
+        if let Some(FileOrDir::Dir(_)) = self.get(new_name) {
+            return Err("a directory with the new name already exists");
+        }
+        let parent = node.get_parent_dir();
+        let mut node = self.remove(&node).ok_or("couldn't remove the node from its directory")?;
+        let result = node.set_name(String::from(new_name)).and_then(|_| self.insert(node.clone()));
+        if let Err(e) = result {
+            // Put the node back the way it was.
+            let _ = node.set_name(String::from(old_name));
+            if let Some(ref p) = parent {
+                node.set_parent_dir(Arc::downgrade(p));
+            }
+            self.insert(node)?;
+            return Err(e);
+        }
+        if let Some(ref p) = parent {
+            node.set_parent_dir(Arc::downgrade(p));
+        }
+        Ok(())
+    }
+
+    /// Moves the node called `name` from this directory into the `destination` directory, 
+    /// where it will be called `new_name`. 
+    /// `new_parent` is a weak reference to `destination`, which becomes the node's new parent directory. 
+    /// 
+    /// The `destination` must belong to the same filesystem as this directory and must not be this directory itself;
+    /// callers should generally use [`move_node()`](fn.move_node.html), which checks those conditions.
+    /// An existing file called `new_name` in the `destination` is replaced, but an existing directory is not.
+    /// 
+    /// The default implementation moves the node by reference, which is correct for in-memory filesystems.
+    /// Filesystems whose nodes represent on-disk structures must override it.
+    fn move_child(
+        &mut self,
+        name: &str,
+        destination: &mut dyn Directory,
+        new_parent: WeakDirRef,
+        new_name: &str,
+    ) -> Result<(), &'static str> {
+        if self.is_link(name) {
+            return Err("cannot move a hard link; remove it and create a new link instead");
+        }
+        let node = self.get(name).ok_or("source node does not exist")?;
+        if let Some(FileOrDir::Dir(_)) = destination.get(new_name) {
+            return Err("a directory with the new name already exists in the destination");
+        }
+        let old_parent = node.get_parent_dir();
+        let mut node = self.remove(&node).ok_or("couldn't remove the node from its directory")?;
+        let result = node.set_name(String::from(new_name)).and_then(|_| {
+            node.set_parent_dir(new_parent);
+            destination.insert(node.clone())
+        });
+        if let Err(e) = result {
+            // Put the node back where it was.
+            let _ = node.set_name(String::from(name));
+            if let Some(ref p) = old_parent {
+                node.set_parent_dir(Arc::downgrade(p));
+            }
+            self.insert(node)?;
+            return Err(e);
+        }
+        Ok(())
+    }
 }
+impl_downcast!(Directory);
 
 /// Allows us to return a generic type that can be matched by the caller to extract the underlying type
 #[derive(Clone)]
@@ -157,4 +348,157 @@ impl FsNode for FileOrDir {
             FileOrDir::Dir(dir) => dir.lock().set_parent_dir(new_parent),
         }
     }
+
+    fn set_name(&mut self, new_name: String) -> Result<(), &'static str> {
+        match self {
+            FileOrDir::File(file) => file.lock().set_name(new_name),
+            FileOrDir::Dir(dir) => dir.lock().set_name(new_name),
+        }
+    }
+
+    fn metadata(&self) -> Metadata {
+        match self {
+            FileOrDir::File(file) => file.lock().metadata(),
+            FileOrDir::Dir(dir) => dir.lock().metadata(),
+        }
+    }
+
+    fn set_permissions(&mut self, permissions: Permissions) -> Result<(), &'static str> {
+        match self {
+            FileOrDir::File(file) => file.lock().set_permissions(permissions),
+            FileOrDir::Dir(dir) => dir.lock().set_permissions(permissions),
+        }
+    }
+}
+
+
+impl FileOrDir {
+    /// Returns `true` if `self` and `other` refer to the same node.
+    /// Like [`is_same_dir()`](fn.is_same_dir.html), this doesn't acquire any locks.
+    pub fn is_same_node(&self, other: &FileOrDir) -> bool {
+        match (self, other) {
+            (FileOrDir::File(a), FileOrDir::File(b)) => {
+                let a_ptr = &**a as *const Mutex<dyn File + Send> as *const u8;
+                let b_ptr = &**b as *const Mutex<dyn File + Send> as *const u8;
+                a_ptr == b_ptr
+            }
+            (FileOrDir::Dir(a), FileOrDir::Dir(b)) => is_same_dir(a, b),
+            _ => false,
+        }
+    }
+
+    /// If this node is a file that can be watched, notifies its watchers of an event of the given `kind`,
+    /// which carries the file's own name.
+    /// Watchers of a directory are only told about changes to its entries, so nothing happens for a directory.
+    /// 
+    /// The lock on this node must not be held because it will be acquired within this function.
+    pub fn notify_watchers(&self, kind: WatchEventKind) {
+        if let FileOrDir::File(file) = self {
+            let file = file.lock();
+            if let Some(watchers) = file.watchers() {
+                watchers.notify_kind(kind, &file.get_name());
+            }
+        }
+    }
+}
+
+
+/// Returns `true` if the two given directory references point to the same directory.
+///
+/// This compares only the data pointers, not the vtable pointers, of the two trait objects,
+/// and does not acquire the lock on either directory.
+pub fn is_same_dir(a: &DirRef, b: &DirRef) -> bool {
+    dir_address(a) == dir_address(b)
+}
+
+/// Returns the address of the given directory reference's data, without its vtable pointer.
+fn dir_address(dir: &DirRef) -> usize {
+    &**dir as *const Mutex<dyn Directory + Send> as *const u8 as usize
+}
+
+/// Returns `true` if `ancestor` is the given `dir` itself or one of the directories that contain it.
+/// 
+/// The locks on `ancestor`, `dir`, and all directories above `dir` must not be held.
+pub fn is_ancestor_or_self(ancestor: &DirRef, dir: &DirRef) -> bool {
+    let mut current = Some(Arc::clone(dir));
+    while let Some(d) = current {
+        if is_same_dir(&d, ancestor) {
+            return true;
+        }
+        let parent = d.lock().get_parent_dir();
+        // The root directory is its own parent.
+        current = parent.filter(|p| !is_same_dir(p, &d));
+    }
+    false
+}
+
+/// Same as `is_ancestor_or_self(ancestor, destination)`, but for use while both `source` and `destination` are locked,
+/// whose parents are obtained from the given locked directories instead of locking them again.
+/// 
+/// The locks on `ancestor` and all other directories above `destination` must not be held.
+fn is_ancestor_or_self_locked(
+    ancestor: &DirRef,
+    source: &DirRef,
+    locked_source: &(dyn Directory + Send),
+    destination: &DirRef,
+    locked_destination: &(dyn Directory + Send),
+) -> bool {
+    let mut current = Some(Arc::clone(destination));
+    while let Some(d) = current {
+        if is_same_dir(&d, ancestor) {
+            return true;
+        }
+        let parent = if is_same_dir(&d, destination) {
+            locked_destination.get_parent_dir()
+        } else if is_same_dir(&d, source) {
+            locked_source.get_parent_dir()
+        } else {
+            d.lock().get_parent_dir()
+        };
+        // The root directory is its own parent.
+        current = parent.filter(|p| !is_same_dir(p, &d));
+    }
+    false
+}
+
+/// Serializes moves between different directories, such that no other move can change which directories
+/// contain the destination while a move checks that it isn't moving a directory into itself.
+static CROSS_DIRECTORY_MOVE: Mutex<()> = Mutex::new(());
+
+/// Moves the node called `name` in the `source` directory into the `destination` directory,
+/// where it will be called `new_name`. 
+/// If `source` and `destination` are the same directory, the node is simply renamed.
+/// 
+/// Both directories are locked for the duration of the move, so no one else can observe a partial move.
+/// Moves between different directories are serialized with each other.
+/// Moving a node to a directory of a different filesystem is an error, 
+/// since that requires copying its contents; see the `fs_copy` crate.
+/// 
+/// The locks on `source` and `destination` must not be held because they will be acquired within this function.
+pub fn move_node(source: &DirRef, name: &str, destination: &DirRef, new_name: &str) -> Result<(), &'static str> {
+    if is_same_dir(source, destination) {
+        return source.lock().rename(name, new_name);
+    }
+
+    let _move_guard = CROSS_DIRECTORY_MOVE.lock();
+    // The two directories are always locked in the same order, i.e., by their addresses,
+    // such that concurrent moves between them in opposite directions can't deadlock.
+    let (mut locked_source, mut locked_destination) = if dir_address(source) < dir_address(destination) {
+        let locked_source = source.lock();
+        (locked_source, destination.lock())
+    } else {
+        let locked_destination = destination.lock();
+        (source.lock(), locked_destination)
+    };
+
+    let node = locked_source.get(name).ok_or("source node does not exist")?;
+    if let FileOrDir::Dir(ref dir) = node {
+        if is_ancestor_or_self_locked(dir, source, &*locked_source, destination, &*locked_destination) {
+            return Err("cannot move a directory into itself");
+        }
+    }
+    if locked_source.filesystem_id() != locked_destination.filesystem_id() {
+        return Err("cannot move a node to a different filesystem, it must be copied instead");
+    }
+    locked_source.move_child(name, &mut *locked_destination, Arc::downgrade(destination), new_name)
 }
@@ -0,0 +1,148 @@
+//! Metadata about filesystem nodes: their kind, size, permissions, and timestamps.
+//!
+//! Node implementations that don't have their own on-disk metadata can embed a [`NodeAttributes`]
+//! to keep track of their permissions and timestamps, and use it to enforce their permissions.
+
+use core::cell::Cell;
+use core::fmt;
+use rtc::{self, RtcTime};
+
+
+/// A point in time, as read from the real-time clock.
+pub type Timestamp = RtcTime;
+
+/// Returns the current time from the real-time clock.
+pub fn now() -> Timestamp {
+    rtc::read_rtc()
+}
+
+
+/// The kind of a filesystem node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    /// A regular file.
+    File,
+    /// A directory.
+    Directory,
+    /// A file that represents a device.
+    Device,
+    /// A symbolic link to another node.
+    Symlink,
+}
+
+impl NodeKind {
+    /// Returns the character that `ls -l` uses to represent this kind of node.
+    pub fn as_char(&self) -> char {
+        match self {
+            NodeKind::File => '-',
+            NodeKind::Directory => 'd',
+            NodeKind::Device => 'c',
+            NodeKind::Symlink => 'l',
+        }
+    }
+}
+
+
+bitflags! {
+    /// The permissions of a filesystem node.
+    ///
+    /// `WRITE` is enforced by `File::write()` and `Directory::insert()`.
+    pub struct Permissions: u8 {
+        /// The node's contents can be read, i.e., a file can be read or a directory can be listed.
+        const READ    = 1 << 2;
+        /// The node's contents can be changed, i.e., a file can be written or nodes can be inserted into a directory.
+        const WRITE   = 1 << 1;
+        /// A file can be executed, or a directory can be traversed.
+        const EXECUTE = 1 << 0;
+    }
+}
+
+impl fmt::Display for Permissions {
+    /// Displays these permissions in the form `rwx`, with a `-` for each missing permission.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}{}",
+            if self.contains(Permissions::READ)    { 'r' } else { '-' },
+            if self.contains(Permissions::WRITE)   { 'w' } else { '-' },
+            if self.contains(Permissions::EXECUTE) { 'x' } else { '-' },
+        )
+    }
+}
+
+
+/// Information about a filesystem node, as returned by [`FsNode::metadata()`](trait.FsNode.html#tymethod.metadata).
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub kind: NodeKind,
+    /// The size in bytes of a file's contents, or `0` for nodes that have no contents of their own, e.g., directories.
+    pub size: usize,
+    pub permissions: Permissions,
+    /// The times at which the node was created, last modified, and last accessed,
+    /// which are `None` if the node doesn't keep track of them.
+    pub created: Option<Timestamp>,
+    pub modified: Option<Timestamp>,
+    pub accessed: Option<Timestamp>,
+}
+
+
+/// The permissions and timestamps of a node that doesn't store them anywhere else, e.g., an in-memory node.
+#[derive(Debug)]
+pub struct NodeAttributes {
+    permissions: Permissions,
+    created: Timestamp,
+    modified: Timestamp,
+    /// This is a `Cell` so that the access time can be updated by methods that take `&self`, e.g., `File::read()`.
+    accessed: Cell<Timestamp>,
+}
+
+impl NodeAttributes {
+    /// Creates the attributes of a new node with the given `permissions`, which was created just now.
+    pub fn new(permissions: Permissions) -> NodeAttributes {
+        let time = now();
+        NodeAttributes {
+            permissions,
+            created: time,
+            modified: time,
+            accessed: Cell::new(time),
+        }
+    }
+
+    /// Returns the metadata of a node of the given `kind` and `size` that has these attributes.
+    pub fn metadata(&self, kind: NodeKind, size: usize) -> Metadata {
+        Metadata {
+            kind,
+            size,
+            permissions: self.permissions,
+            created: Some(self.created),
+            modified: Some(self.modified),
+            accessed: Some(self.accessed.get()),
+        }
+    }
+
+    /// Returns an error if these attributes don't grant all of the `required` permissions.
+    pub fn check(&self, required: Permissions) -> Result<(), &'static str> {
+        if self.permissions.contains(required) {
+            Ok(())
+        } else if required.contains(Permissions::WRITE) && !self.permissions.contains(Permissions::WRITE) {
+            Err("permission denied: node is not writable")
+        } else {
+            Err("permission denied")
+        }
+    }
+
+    /// Changes the permissions.
+    pub fn set_permissions(&mut self, permissions: Permissions) {
+        self.permissions = permissions;
+    }
+
+    /// Records that the node was just modified, which also counts as an access.
+    pub fn mark_modified(&mut self) {
+        let time = now();
+        self.modified = time;
+        self.accessed.set(time);
+    }
+
+    /// Records that the node was just accessed.
+    pub fn mark_accessed(&self) {
+        self.accessed.set(now());
+    }
+}
@@ -0,0 +1,112 @@
+//! Notifications of changes to filesystem nodes.
+//!
+//! A node that supports being watched holds a [`Watchers`] list, which it returns from
+//! [`FsNode::watchers()`](../trait.FsNode.html#method.watchers), and notifies it of every change.
+//! A watcher is anything that implements [`WatchSink`]; the `fs_watch` crate provides one
+//! that delivers events to a task through an `async_channel`.
+//!
+//! Watching a directory reports changes to the entries in that directory, i.e.,
+//! the creation, replacement, removal, and renaming of its children, but not changes to the contents of those children.
+//! Watching a file reports changes to its contents, its renaming, and its removal from the directory that contains it.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+
+/// The kind of change that a [`WatchEvent`] describes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEventKind {
+    /// The node was newly inserted into the watched directory.
+    Created,
+    /// The contents of the watched file changed,
+    /// or an entry in the watched directory was replaced by a different node.
+    Modified,
+    /// The node was removed from its directory.
+    Deleted,
+    /// The node was renamed, and was previously called `old_name`.
+    Renamed { old_name: String },
+}
+
+/// A change to a watched node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    /// What happened to the node.
+    pub kind: WatchEventKind,
+    /// For a watched directory, the name of the affected entry in that directory;
+    /// for a watched file, the file's own (new) name.
+    pub name: String,
+}
+
+impl WatchEvent {
+    /// Creates a new event of the given `kind` for the node called `name`.
+    pub fn new(kind: WatchEventKind, name: &str) -> WatchEvent {
+        WatchEvent { kind, name: String::from(name) }
+    }
+}
+
+
+/// A destination for the events of a watched node.
+pub trait WatchSink: Send {
+    /// Delivers the given `event` to this watcher.
+    ///
+    /// Returns `false` if this watcher is no longer interested in events,
+    /// in which case it is removed from the node's [`Watchers`].
+    ///
+    /// This is invoked while the watched node is locked, so it must not block
+    /// or acquire the lock of any filesystem node.
+    fn deliver(&self, event: WatchEvent) -> bool;
+}
+
+/// The list of watchers registered on a single node.
+///
+/// It uses interior mutability so that watchers can be added and notified through a shared reference to the node.
+pub struct Watchers {
+    sinks: Mutex<Vec<Box<dyn WatchSink>>>,
+}
+
+impl Watchers {
+    /// Creates an empty list of watchers.
+    pub fn new() -> Watchers {
+        Watchers { sinks: Mutex::new(Vec::new()) }
+    }
+
+    /// Registers the given `sink` to receive all future events of this node.
+    pub fn add(&self, sink: Box<dyn WatchSink>) {
+        self.sinks.lock().push(sink);
+    }
+
+    /// Returns the number of registered watchers.
+    pub fn len(&self) -> usize {
+        self.sinks.lock().len()
+    }
+
+    /// Returns `true` if no watchers are registered.
+    pub fn is_empty(&self) -> bool {
+        self.sinks.lock().is_empty()
+    }
+
+    /// Delivers the given `event` to every registered watcher,
+    /// and removes the watchers that are no longer interested.
+    pub fn notify(&self, event: WatchEvent) {
+        let mut sinks = self.sinks.lock();
+        if sinks.is_empty() {
+            return;
+        }
+        sinks.retain(|sink| sink.deliver(event.clone()));
+    }
+
+    /// A convenience function for notifying watchers of an event of the given `kind` for the node called `name`.
+    pub fn notify_kind(&self, kind: WatchEventKind, name: &str) {
+        if !self.is_empty() {
+            self.notify(WatchEvent::new(kind, name));
+        }
+    }
+}
+
+impl Default for Watchers {
+    fn default() -> Watchers {
+        Watchers::new()
+    }
+}
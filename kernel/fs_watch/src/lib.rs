@@ -0,0 +1,82 @@
+//! Allows tasks to watch filesystem nodes for changes,
+//! receiving a [`WatchEvent`] through an `async_channel` whenever a watched node changes.
+//!
+//! Watching a directory reports the creation, replacement, removal, and renaming of its entries;
+//! watching a file reports changes to its contents, its renaming, and its removal.
+//! Only nodes that return a list of watchers from `FsNode::watchers()` can be watched,
+//! e.g., `VFSDirectory`, `MemFile`, and `HeapFile`.
+//!
+//! A watch lasts until its `Receiver` is dropped, after which it is removed from the node the next time the node changes.
+//!
+//! # Example
+//! ```rust,ignore
+//! let receiver = fs_watch::watch(&FileOrDir::Dir(namespace_dir))?;
+//! while let Ok(event) = receiver.receive() {
+//!     if event.kind == WatchEventKind::Created {
+//!         // load the new crate called `event.name`
+//!     }
+//! }
+//! ```
+
+#![no_std]
+
+#[macro_use] extern crate log;
+extern crate alloc;
+extern crate fs_node;
+extern crate async_channel;
+
+use alloc::boxed::Box;
+use async_channel::{ChannelError, Receiver, Sender};
+use fs_node::{FileOrDir, FsNode, WatchSink};
+
+pub use fs_node::{WatchEvent, WatchEventKind};
+
+
+/// The number of events that a watch buffers by default before further events are dropped.
+pub const DEFAULT_WATCH_CAPACITY: usize = 64;
+
+
+/// A watcher that sends events into an `async_channel`.
+struct ChannelSink {
+    sender: Sender<WatchEvent>,
+}
+
+impl WatchSink for ChannelSink {
+    fn deliver(&self, event: WatchEvent) -> bool {
+        match self.sender.try_send(event) {
+            Ok(()) => true,
+            // The watched node can't wait for a slow watcher, so the event is lost but the watch remains.
+            Err((event, ChannelError::ChannelFull)) => {
+                warn!("fs_watch: channel was full, dropping event {:?}", event);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+
+/// Starts watching the given `node` for changes, buffering up to [`DEFAULT_WATCH_CAPACITY`] events.
+///
+/// Returns the `Receiver` from which the events can be received.
+///
+/// The lock on `node` must not be held because it will be acquired within this function.
+pub fn watch(node: &FileOrDir) -> Result<Receiver<WatchEvent>, &'static str> {
+    watch_with_capacity(node, DEFAULT_WATCH_CAPACITY)
+}
+
+/// Like [`watch()`], but buffers up to `capacity` events before further events are dropped.
+pub fn watch_with_capacity(node: &FileOrDir, capacity: usize) -> Result<Receiver<WatchEvent>, &'static str> {
+    let (sender, receiver) = async_channel::new_channel(capacity);
+    let sink = Box::new(ChannelSink { sender });
+    let watchers_present = match node {
+        FileOrDir::File(file) => file.lock().watchers().map(|w| w.add(sink)).is_some(),
+        FileOrDir::Dir(dir) => dir.lock().watchers().map(|w| w.add(sink)).is_some(),
+    };
+    if watchers_present {
+        debug!("fs_watch: started watching {}", node.get_absolute_path());
+        Ok(receiver)
+    } else {
+        Err("this node cannot be watched")
+    }
+}
@@ -59,6 +59,8 @@ pub fn set_allocator(allocator: Box<dyn GlobalAlloc + Send + Sync>) {
 /// The heap which is used as a global allocator for the system.
 /// It starts off with one basic fixed size allocator, the `initial allocator`. 
 /// When a more complex heap is created and set as the `DEFAULT_ALLOCATOR`, then it is used.
+/// 
+/// Every allocation and deallocation is charged to the current task, see `memory::MemoryUsage`.
 pub struct Heap {
     initial_allocator: MutexIrqSafe<block_allocator::FixedSizeBlockAllocator>, 
 }
@@ -76,17 +78,22 @@ impl Heap {
 unsafe impl GlobalAlloc for Heap {
 
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        match DEFAULT_ALLOCATOR.try() {
+        let ptr = match DEFAULT_ALLOCATOR.try() {
             Some(allocator) => {
                 allocator.alloc(layout)
             }
             None => {       
                 self.initial_allocator.lock().allocate(layout)
             }
+        };
+        if !ptr.is_null() {
+            memory::record_heap_allocation(layout.size());
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        memory::record_heap_deallocation(layout.size());
         if (ptr as usize) < INITIAL_HEAP_END_ADDR {
             self.initial_allocator.lock().deallocate(ptr, layout);
         }
@@ -18,7 +18,7 @@ use alloc::{
     string::String,
 };
 use spin::Mutex;
-use fs_node::{FileOrDir, FileRef, DirRef, WeakDirRef, File, FsNode};
+use fs_node::{FileOrDir, FileRef, DirRef, WeakDirRef, File, FsNode, Metadata, NodeAttributes, NodeKind, Permissions, Watchers, WatchEventKind};
 use memory::MappedPages;
 
 /// A file in memory that is backed by the heap, i.e., a `Vec`.
@@ -29,6 +29,10 @@ pub struct HeapFile {
     vec: Vec<u8>,
     /// The parent directory that contains this file.
     parent: WeakDirRef,
+    /// The permissions and timestamps of this file.
+    attributes: NodeAttributes,
+    /// The watchers that are notified of changes to this file.
+    watchers: Watchers,
 }
 
 impl HeapFile {
@@ -41,10 +45,13 @@ impl HeapFile {
     /// Creates a new `HeapFile` in the given `parent` directory with the contents of the given `Vec`.
     /// No additional allocation or reallocation is performed.
     pub fn from_vec(vec: Vec<u8>, name: String, parent: &DirRef) -> Result<FileRef, &'static str> {
+        let permissions = Permissions::READ | Permissions::WRITE;
         let hf = HeapFile {
             name: name, 
             vec: vec, 
             parent: Arc::downgrade(parent), 
+            attributes: NodeAttributes::new(permissions),
+            watchers: Watchers::new(),
         };
         let file_ref = Arc::new(Mutex::new(hf)) as FileRef;
         parent.lock().insert(FileOrDir::File(file_ref.clone()))?;
@@ -59,14 +66,18 @@ impl File for HeapFile {
         }
         // read from the offset until the end of the file, but not more than the buffer length
         let read_bytes = core::cmp::min(self.vec.len() - offset, buffer.len());
-        buffer[..read_bytes].copy_from_slice(&self.vec[offset..read_bytes]); 
+        buffer[..read_bytes].copy_from_slice(&self.vec[offset .. offset + read_bytes]); 
+        self.attributes.mark_accessed();
         Ok(read_bytes) 
     }
 
     fn write(&mut self, buffer: &[u8], offset: usize) -> Result<usize, &'static str> {
+        self.attributes.check(Permissions::WRITE)?;
         if offset > self.vec.len() {
             return Err("offset out of bounds");
         }
+        self.attributes.mark_modified();
+        self.watchers.notify_kind(WatchEventKind::Modified, &self.name);
 
         // optimization for first write of an empty HeapFile
         if self.vec.is_empty() {
@@ -96,6 +107,17 @@ impl File for HeapFile {
         self.vec.len()
     }
 
+    fn truncate(&mut self, size: usize) -> Result<(), &'static str> {
+        self.attributes.check(Permissions::WRITE)?;
+        if size > self.vec.len() {
+            return Err("cannot truncate a file to a larger size");
+        }
+        self.vec.truncate(size);
+        self.attributes.mark_modified();
+        self.watchers.notify_kind(WatchEventKind::Modified, &self.name);
+        Ok(())
+    }
+
     fn as_mapping(&self) -> Result<&MappedPages, &'static str> {
         Err("Mapping a HeapFile as a MappedPages object is unimplemented")
     }
@@ -114,4 +136,25 @@ impl FsNode for HeapFile {
     fn set_parent_dir(&mut self, new_parent: WeakDirRef) {
         self.parent = new_parent;
     }
+
+    fn set_name(&mut self, new_name: String) -> Result<(), &'static str> {
+        if new_name != self.name {
+            let old_name = core::mem::replace(&mut self.name, new_name);
+            self.watchers.notify_kind(WatchEventKind::Renamed { old_name }, &self.name);
+        }
+        Ok(())
+    }
+
+    fn metadata(&self) -> Metadata {
+        self.attributes.metadata(NodeKind::File, self.vec.len())
+    }
+
+    fn set_permissions(&mut self, permissions: Permissions) -> Result<(), &'static str> {
+        self.attributes.set_permissions(permissions);
+        Ok(())
+    }
+
+    fn watchers(&self) -> Option<&Watchers> {
+        Some(&self.watchers)
+    }
 }
@@ -0,0 +1,323 @@
+//! A parser for cpio archives in the "newc" format (also known as the SVR4 format),
+//! with or without checksums, which is the format used by Linux initramfs images.
+//!
+//! Each entry consists of a 110-byte header of ASCII hexadecimal fields, followed by the entry's NUL-terminated name
+//! and its contents, both of which are padded to a multiple of 4 bytes.
+//! The archive ends with an entry called `TRAILER!!!`.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::str;
+use super::{ArchiveEntry, EntryKind, InitrdError};
+
+
+const HEADER_SIZE: usize = 110;
+/// The magic value of the format without checksums.
+const MAGIC_NEWC: &'static [u8] = b"070701";
+/// The magic value of the format with checksums, which we don't verify.
+const MAGIC_CRC: &'static [u8] = b"070702";
+/// The name of the last entry in an archive.
+const TRAILER_NAME: &'static str = "TRAILER!!!";
+
+/// The indices of the header fields that we use, each of which is 8 hexadecimal digits after the 6-byte magic value.
+const FIELD_INODE: usize = 0;
+const FIELD_MODE: usize = 1;
+const FIELD_NLINK: usize = 4;
+const FIELD_FILESIZE: usize = 6;
+const FIELD_DEVMAJOR: usize = 7;
+const FIELD_DEVMINOR: usize = 8;
+const FIELD_NAMESIZE: usize = 11;
+
+/// The bits of the mode that give the type of an entry, and the values of those bits for each type.
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+
+/// Returns `true` if the given `data` starts with a newc cpio header.
+pub(crate) fn is_cpio(data: &[u8]) -> bool {
+    data.starts_with(MAGIC_NEWC) || data.starts_with(MAGIC_CRC)
+}
+
+/// An iterator over the entries of a newc cpio archive.
+pub(crate) struct CpioEntries<'a> {
+    data: &'a [u8],
+    /// The offset of the next header.
+    offset: usize,
+    finished: bool,
+    /// The path of the first entry seen for each file that has multiple hard links, by device and inode number.
+    links: BTreeMap<(u32, u32, u32), String>,
+}
+
+impl<'a> CpioEntries<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> CpioEntries<'a> {
+        CpioEntries { data, offset: 0, finished: false, links: BTreeMap::new() }
+    }
+
+    /// Parses the next entry, returning `None` at the end of the archive.
+    fn next_entry(&mut self) -> Result<Option<ArchiveEntry<'a>>, InitrdError> {
+        let archive = self.data;
+        let header_offset = self.offset;
+        if header_offset >= archive.len() {
+            warn!("initrd: cpio archive ended without a trailer entry");
+            return Ok(None);
+        }
+        let malformed = |reason| InitrdError::malformed(header_offset, reason);
+
+        let header = archive.get(header_offset .. header_offset + HEADER_SIZE).ok_or_else(|| malformed("cpio header was truncated"))?;
+        if !is_cpio(header) {
+            return Err(malformed("cpio header did not have the newc magic value"));
+        }
+        let field = |index: usize| -> Result<u32, InitrdError> {
+            let start = MAGIC_NEWC.len() + index * 8;
+            str::from_utf8(&header[start .. start + 8]).ok()
+                .and_then(|s| u32::from_str_radix(s, 16).ok())
+                .ok_or_else(|| malformed("cpio header had a field that was not a hexadecimal number"))
+        };
+        let mode = field(FIELD_MODE)?;
+        let file_size = field(FIELD_FILESIZE)? as usize;
+        let name_size = field(FIELD_NAMESIZE)? as usize;
+        let link_key = (field(FIELD_DEVMAJOR)?, field(FIELD_DEVMINOR)?, field(FIELD_INODE)?);
+        let nlink = field(FIELD_NLINK)?;
+
+        // The name size includes the terminating NUL.
+        let name_start = header_offset + HEADER_SIZE;
+        let name_bytes = archive.get(name_start .. name_start + name_size).ok_or_else(|| malformed("cpio entry's name was truncated"))?;
+        let path = match name_bytes.split_last() {
+            Some((&0, name)) => str::from_utf8(name).map(String::from).map_err(|_| malformed("cpio entry's name was not valid UTF-8"))?,
+            _ => return Err(malformed("cpio entry's name was not NUL-terminated")),
+        };
+
+        let data_start = align_up(name_start + name_size);
+        let data = archive.get(data_start .. data_start + file_size).ok_or_else(|| malformed("cpio entry's contents were truncated"))?;
+        self.offset = align_up(data_start + file_size);
+
+        if path == TRAILER_NAME {
+            return Ok(None);
+        }
+
+        let kind = match mode & S_IFMT {
+            S_IFREG if nlink > 1 => {
+                // All entries for a file with multiple hard links share an inode number,
+                // and only one of them (usually the last) has the file's contents.
+                match self.links.get(&link_key) {
+                    Some(first_path) => EntryKind::HardLink(first_path.clone()),
+                    None => {
+                        self.links.insert(link_key, path.clone());
+                        EntryKind::File
+                    }
+                }
+            }
+            S_IFREG => EntryKind::File,
+            S_IFDIR => EntryKind::Directory,
+            S_IFLNK => {
+                let target = str::from_utf8(data).map_err(|_| malformed("cpio symbolic link's target was not valid UTF-8"))?;
+                EntryKind::Symlink(String::from(target))
+            }
+            _ => EntryKind::Other,
+        };
+        let data = match kind {
+            EntryKind::File | EntryKind::HardLink(_) => data,
+            _ => &[],
+        };
+        Ok(Some(ArchiveEntry { path, kind, mode, data }))
+    }
+}
+
+impl<'a> Iterator for CpioEntries<'a> {
+    type Item = Result<ArchiveEntry<'a>, InitrdError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let result = self.next_entry();
+        match result {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Err(e) => {
+                self.finished = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+
+/// Rounds the given offset up to the next multiple of 4 bytes.
+fn align_up(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const S_IFCHR: u32 = 0o020000;
+
+    /// Appends an entry with the given header fields, name, and contents to the `archive`.
+    fn append(archive: &mut Vec<u8>, inode: u32, mode: u32, nlink: u32, name: &str, data: &[u8]) {
+        let fields = [inode, mode, 0, 0, nlink, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+        archive.extend_from_slice(MAGIC_NEWC);
+        for field in fields.iter() {
+            archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        pad(archive);
+        archive.extend_from_slice(data);
+        pad(archive);
+    }
+
+    fn pad(archive: &mut Vec<u8>) {
+        while archive.len() % 4 != 0 {
+            archive.push(0);
+        }
+    }
+
+    fn trailer(archive: &mut Vec<u8>) {
+        append(archive, 0, 0, 1, TRAILER_NAME, &[]);
+    }
+
+    /// Returns an archive with a single entry whose header field at the given `index` is replaced by the given `value`.
+    fn with_field(index: usize, value: &[u8]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        append(&mut archive, 1, S_IFREG | 0o644, 1, "a", b"a");
+        let start = MAGIC_NEWC.len() + index * 8;
+        archive[start .. start + 8].copy_from_slice(value);
+        archive
+    }
+
+    fn entries(archive: &[u8]) -> Result<Vec<(String, EntryKind, Vec<u8>)>, InitrdError> {
+        CpioEntries::new(archive)
+            .map(|entry| entry.map(|e| (e.path, e.kind, e.data.to_vec())))
+            .collect()
+    }
+
+    fn entry(path: &str, kind: EntryKind, data: &[u8]) -> (String, EntryKind, Vec<u8>) {
+        (String::from(path), kind, data.to_vec())
+    }
+
+    #[test]
+    fn entry_kinds() {
+        let mut archive = Vec::new();
+        append(&mut archive, 1, S_IFDIR | 0o755, 2, "etc", &[]);
+        append(&mut archive, 2, S_IFREG | 0o644, 1, "etc/hosts", b"127.0.0.1 localhost\n");
+        append(&mut archive, 3, S_IFLNK | 0o777, 1, "etc/link", b"hosts");
+        append(&mut archive, 4, S_IFCHR | 0o666, 1, "dev/null", &[]);
+        trailer(&mut archive);
+        // anything after the trailer is ignored
+        archive.extend_from_slice(b"garbage");
+
+        assert!(is_cpio(&archive));
+        assert_eq!(entries(&archive).unwrap(), [
+            entry("etc", EntryKind::Directory, b""),
+            entry("etc/hosts", EntryKind::File, b"127.0.0.1 localhost\n"),
+            entry("etc/link", EntryKind::Symlink(String::from("hosts")), b""),
+            entry("dev/null", EntryKind::Other, b""),
+        ]);
+    }
+
+    #[test]
+    fn hard_links() {
+        let mut archive = Vec::new();
+        append(&mut archive, 7, S_IFREG | 0o644, 2, "a", &[]);
+        append(&mut archive, 7, S_IFREG | 0o644, 2, "b", b"contents");
+        // the same inode number with a different device number is a different file
+        let device_field = MAGIC_NEWC.len() + FIELD_DEVMAJOR * 8;
+        let third_entry = archive.len();
+        append(&mut archive, 7, S_IFREG | 0o644, 2, "c", &[]);
+        archive[third_entry + device_field .. third_entry + device_field + 8].copy_from_slice(b"00000001");
+        trailer(&mut archive);
+
+        assert_eq!(entries(&archive).unwrap(), [
+            entry("a", EntryKind::File, b""),
+            entry("b", EntryKind::HardLink(String::from("a")), b"contents"),
+            entry("c", EntryKind::File, b""),
+        ]);
+    }
+
+    #[test]
+    fn archive_with_checksums() {
+        let mut archive = Vec::new();
+        append(&mut archive, 1, S_IFREG | 0o644, 1, "a", b"a");
+        trailer(&mut archive);
+        archive[.. MAGIC_CRC.len()].copy_from_slice(MAGIC_CRC);
+        assert!(is_cpio(&archive));
+        assert_eq!(entries(&archive).unwrap(), [entry("a", EntryKind::File, b"a")]);
+    }
+
+    #[test]
+    fn archive_without_trailer() {
+        let mut archive = Vec::new();
+        append(&mut archive, 1, S_IFREG | 0o644, 1, "a", b"a");
+        assert_eq!(entries(&archive).unwrap(), [entry("a", EntryKind::File, b"a")]);
+    }
+
+    #[test]
+    fn truncated_archives() {
+        let mut archive = Vec::new();
+        append(&mut archive, 1, S_IFREG | 0o644, 1, "a", b"a");
+        let second_header = archive.len();
+        append(&mut archive, 2, S_IFREG | 0o644, 1, "b", &[b'b'; 100]);
+
+        assert_eq!(entries(&archive[.. second_header + HEADER_SIZE - 1]),
+            Err(InitrdError::malformed(second_header, "cpio header was truncated")));
+        assert_eq!(entries(&archive[.. second_header + HEADER_SIZE + 1]),
+            Err(InitrdError::malformed(second_header, "cpio entry's name was truncated")));
+        assert_eq!(entries(&archive[.. archive.len() - 1]),
+            Err(InitrdError::malformed(second_header, "cpio entry's contents were truncated")));
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let mut bad_magic = Vec::new();
+        append(&mut bad_magic, 1, S_IFREG | 0o644, 1, "a", b"a");
+        let second_header = bad_magic.len();
+        append(&mut bad_magic, 2, S_IFREG | 0o644, 1, "b", b"b");
+        bad_magic[second_header + 5] = b'7';
+        assert_eq!(entries(&bad_magic), Err(InitrdError::malformed(second_header, "cpio header did not have the newc magic value")));
+
+        assert_eq!(entries(&with_field(FIELD_FILESIZE, b"0000000G")),
+            Err(InitrdError::malformed(0, "cpio header had a field that was not a hexadecimal number")));
+        assert_eq!(entries(&with_field(FIELD_MODE, b"-0000001")),
+            Err(InitrdError::malformed(0, "cpio header had a field that was not a hexadecimal number")));
+
+        // the name size doesn't include the name's terminating NUL, or is zero
+        assert_eq!(entries(&with_field(FIELD_NAMESIZE, b"00000001")),
+            Err(InitrdError::malformed(0, "cpio entry's name was not NUL-terminated")));
+        assert_eq!(entries(&with_field(FIELD_NAMESIZE, b"00000000")),
+            Err(InitrdError::malformed(0, "cpio entry's name was not NUL-terminated")));
+
+        let mut bad_name = Vec::new();
+        append(&mut bad_name, 1, S_IFREG | 0o644, 1, "a", b"a");
+        bad_name[HEADER_SIZE] = 0xFF;
+        assert_eq!(entries(&bad_name), Err(InitrdError::malformed(0, "cpio entry's name was not valid UTF-8")));
+
+        let mut bad_link = Vec::new();
+        append(&mut bad_link, 1, S_IFLNK | 0o777, 1, "a", &[0xFF]);
+        assert_eq!(entries(&bad_link), Err(InitrdError::malformed(0, "cpio symbolic link's target was not valid UTF-8")));
+    }
+
+    #[test]
+    fn overflowing_sizes() {
+        assert_eq!(entries(&with_field(FIELD_NAMESIZE, b"FFFFFFFF")),
+            Err(InitrdError::malformed(0, "cpio entry's name was truncated")));
+        assert_eq!(entries(&with_field(FIELD_FILESIZE, b"FFFFFFFF")),
+            Err(InitrdError::malformed(0, "cpio entry's contents were truncated")));
+    }
+
+    #[test]
+    fn iteration_stops_after_an_error() {
+        let archive = with_field(FIELD_FILESIZE, b"FFFFFFFF");
+        let mut entries = CpioEntries::new(&archive);
+        assert!(entries.next().unwrap().is_err());
+        assert!(entries.next().is_none());
+    }
+}
@@ -0,0 +1,364 @@
+//! Unpacks archives loaded by the bootloader, i.e., initial RAM disks ("initrds"),
+//! into in-memory filesystem trees that are mounted at a given path, e.g., `/etc` or `/data`.
+//! This allows config files, scripts, data, and images to be shipped with the OS image.
+//!
+//! Both tar archives (POSIX ustar, including GNU long names and pax `path`/`linkpath` records)
+//! and cpio archives (the "newc" format) are supported; the format is detected from the archive's contents.
+//!
+//! At boot, [`init()`](fn.init.html) loads every bootloader data module called `initrd+<mount path>.tar`
+//! or `initrd+<mount path>.cpio`, i.e., loaded as `d#initrd+...` (see `mod_mgmt::DATA_MODULE_PREFIX`),
+//! in which `+` separates the components of the mount path.
+//! For example, `initrd+etc.tar` is mounted at `/etc` and `initrd+data+images.cpio` at `/data/images`.
+//! Such archives can be built from directories on the build machine using the Makefile's `INITRDS` variable.
+//!
+//! Files are unpacked into `MemFile`s and directories into `VFSDirectory`s.
+//! Regular files, directories, symbolic links, and hard links are preserved,
+//! while other kinds of entries (e.g., device nodes) are skipped.
+//! The owner permission bits of each entry become the permissions of the unpacked node.
+
+#![no_std]
+
+#[cfg(test)]
+extern crate std;
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate fs_node;
+extern crate vfs_node;
+extern crate memfs;
+extern crate symlink;
+extern crate root;
+extern crate mount_table;
+extern crate mod_mgmt;
+
+mod tar;
+mod cpio;
+
+use core::fmt;
+use alloc::collections::BTreeMap;
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use spin::Mutex;
+use fs_node::{DirRef, FileOrDir, FileRef, FsNode, NodeAttributes, Permissions, Watchers};
+use vfs_node::VFSDirectory;
+use memfs::MemFile;
+use symlink::Symlink;
+
+
+/// The prefix of the names of the bootloader data modules that [`init()`](fn.init.html) loads as initrds.
+pub const INITRD_MODULE_PREFIX: &'static str = "initrd+";
+
+/// The character that separates the components of the mount path in the name of an initrd module.
+const MOUNT_PATH_SEPARATOR: char = '+';
+
+
+/// The formats of archives that can be unpacked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// A POSIX ustar archive, as created by `tar --format=ustar`, `--format=gnu`, or `--format=pax`.
+    Tar,
+    /// A cpio archive in the "newc" format, as created by `cpio -H newc`.
+    Cpio,
+}
+
+/// Returns the format of the given `archive`, or `None` if it isn't in a supported format.
+pub fn detect_format(archive: &[u8]) -> Option<ArchiveFormat> {
+    if tar::is_tar(archive) {
+        Some(ArchiveFormat::Tar)
+    } else if cpio::is_cpio(archive) {
+        Some(ArchiveFormat::Cpio)
+    } else {
+        None
+    }
+}
+
+
+/// An error that occurred while unpacking an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InitrdError {
+    /// The archive is malformed at the given byte `offset`, which is the start of the offending entry's header.
+    Malformed { offset: usize, reason: &'static str },
+    /// The entry with the given `path` couldn't be unpacked into the filesystem.
+    Entry { path: String, reason: &'static str },
+    /// Another error, e.g., the unpacked tree couldn't be mounted.
+    Other(&'static str),
+}
+
+impl InitrdError {
+    pub(crate) fn malformed(offset: usize, reason: &'static str) -> InitrdError {
+        InitrdError::Malformed { offset, reason }
+    }
+}
+
+impl From<&'static str> for InitrdError {
+    fn from(reason: &'static str) -> InitrdError {
+        InitrdError::Other(reason)
+    }
+}
+
+impl fmt::Display for InitrdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InitrdError::Malformed { offset, reason } => write!(f, "malformed archive at byte offset {}: {}", offset, reason),
+            InitrdError::Entry { path, reason } => write!(f, "couldn't unpack entry {:?}: {}", path, reason),
+            InitrdError::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+
+/// The kind of an entry in an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum EntryKind {
+    File,
+    Directory,
+    /// A symbolic link to the given target path.
+    Symlink(String),
+    /// A hard link to the file with the given path, which appears earlier in the archive.
+    /// If the entry has contents, they replace that file's contents (this is how cpio stores hard links).
+    HardLink(String),
+    /// Any other kind of entry, which is skipped.
+    Other,
+}
+
+/// A single entry in an archive, which borrows its contents from the archive.
+pub(crate) struct ArchiveEntry<'a> {
+    /// The path of this entry relative to the archive's root.
+    pub path: String,
+    pub kind: EntryKind,
+    /// The Unix mode bits of this entry, of which only the permission bits are used.
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+
+/// Unpacks the given `archive` into the given `root` directory, creating directories as needed.
+/// Existing files with the same paths as entries in the archive are replaced.
+///
+/// Returns the number of entries that were unpacked.
+pub fn unpack(archive: &[u8], root: &DirRef) -> Result<usize, InitrdError> {
+    match detect_format(archive) {
+        Some(ArchiveFormat::Tar) => unpack_entries(tar::TarEntries::new(archive), root),
+        Some(ArchiveFormat::Cpio) => unpack_entries(cpio::CpioEntries::new(archive), root),
+        None => Err(InitrdError::malformed(0, "archive was neither a ustar tar archive nor a newc cpio archive")),
+    }
+}
+
+/// Unpacks all of the given `entries` into the given `root` directory.
+fn unpack_entries<'a, I>(entries: I, root: &DirRef) -> Result<usize, InitrdError> 
+    where I: Iterator<Item = Result<ArchiveEntry<'a>, InitrdError>>
+{
+    // Permissions are applied once all entries are unpacked, such that read-only directories and files can still be populated.
+    let mut permissions: BTreeMap<String, (FileOrDir, Permissions)> = BTreeMap::new();
+    let mut count = 0;
+    for entry in entries {
+        let entry = entry?;
+        let to_entry_error = |reason| InitrdError::Entry { path: entry.path.clone(), reason };
+        if let Some(node) = unpack_entry(root, &entry).map_err(to_entry_error)? {
+            permissions.insert(entry.path.clone(), (node, permissions_from_mode(entry.mode)));
+            count += 1;
+        }
+    }
+    for (_path, (mut node, perms)) in permissions {
+        // Nodes without permissions of their own, like symbolic links, ignore this.
+        let _ = node.set_permissions(perms);
+    }
+    Ok(count)
+}
+
+/// Unpacks a single `entry` into the tree rooted at `root`.
+///
+/// Returns the unpacked node, whose permissions should be set to the entry's mode,
+/// or `None` if the entry was skipped or refers to the root itself.
+fn unpack_entry(root: &DirRef, entry: &ArchiveEntry) -> Result<Option<FileOrDir>, &'static str> {
+    let components = split_path(&entry.path)?;
+    let (name, parents) = match components.split_last() {
+        Some(split) => split,
+        // The archive's root directory, e.g., "./"
+        None => return Ok(None),
+    };
+    let dir = create_dirs(root, parents)?;
+
+    match entry.kind {
+        EntryKind::Directory => {
+            let new_dir = create_dirs(&dir, &[*name])?;
+            Ok(Some(FileOrDir::Dir(new_dir)))
+        }
+        EntryKind::File => {
+            if let Some(FileOrDir::Dir(_)) = dir.lock().get(name) {
+                return Err("a directory with the same name already exists");
+            }
+            let file = MemFile::new(String::from(*name), &dir)?;
+            if !entry.data.is_empty() {
+                file.lock().write(entry.data, 0)?;
+            }
+            Ok(Some(FileOrDir::File(file)))
+        }
+        EntryKind::Symlink(ref target) => {
+            let link = Symlink::new(String::from(*name), target.clone(), &dir)?;
+            Ok(Some(FileOrDir::File(link)))
+        }
+        EntryKind::HardLink(ref target) => {
+            let file = lookup_file(root, &split_path(target)?).ok_or("the hard link's target was not found earlier in the archive")?;
+            if !entry.data.is_empty() {
+                let mut locked_file = file.lock();
+                locked_file.truncate(0)?;
+                locked_file.write(entry.data, 0)?;
+            }
+            dir.lock().insert_link(name, Arc::clone(&file))?;
+            Ok(Some(FileOrDir::File(file)))
+        }
+        EntryKind::Other => {
+            warn!("initrd: skipping entry {:?} of an unsupported kind", entry.path);
+            Ok(None)
+        }
+    }
+}
+
+
+/// Unpacks the given `archive` into a new in-memory filesystem and mounts it at the given absolute `mount_path`,
+/// creating the mount point and its ancestors if they don't exist.
+///
+/// The `source` describes where the archive came from, e.g., the name of the bootloader module, and is shown in the mount table.
+///
+/// Returns the root directory of the mounted filesystem.
+pub fn load(archive: &[u8], mount_path: &str, source: &str) -> Result<DirRef, InitrdError> {
+    let components = split_path(mount_path)?;
+    let name = components.last().ok_or("an initrd cannot be mounted at the root directory")?;
+
+    let mut mount_point = Arc::clone(root::get_root());
+    for component in &components {
+        // Descend into the filesystem that is mounted on this directory, if any.
+        let dir = mount_table::resolve(mount_point);
+        let existing = dir.lock().get(component);
+        mount_point = match existing {
+            Some(FileOrDir::Dir(d)) => d,
+            Some(FileOrDir::File(_)) => return Err(InitrdError::Other("a file is in the way of the mount point")),
+            None => VFSDirectory::new(String::from(*component), &dir)?,
+        };
+    }
+
+    let fs_root = Arc::new(Mutex::new(VFSDirectory {
+        name: String::from(*name),
+        children: BTreeMap::new(),
+        links: BTreeSet::new(),
+        parent: Weak::<Mutex<VFSDirectory>>::new(),
+        attributes: NodeAttributes::new(Permissions::all()),
+        watchers: Watchers::new(),
+    })) as DirRef;
+
+    let count = unpack(archive, &fs_root)?;
+    mount_table::mount(&mount_point, Arc::clone(&fs_root), "memfs", source)?;
+    info!("initrd: unpacked {} entries from {:?} and mounted them at /{}", count, source, components.join("/"));
+    Ok(fs_root)
+}
+
+
+/// Loads every initrd among the bootloader data modules, see the [crate-level documentation](index.html).
+///
+/// An initrd that is malformed or can't be mounted is reported and skipped, so it doesn't prevent booting.
+pub fn init() -> Result<(), &'static str> {
+    let modules_dir = match root::get_root().lock().get_dir(mod_mgmt::DATA_MODULES_DIRECTORY_NAME) {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+    let module_names = modules_dir.lock().list();
+    for module_name in module_names.iter().filter(|n| n.starts_with(INITRD_MODULE_PREFIX)) {
+        let mount_path = match mount_path_of_module(module_name) {
+            Some(path) => path,
+            None => {
+                error!("initrd: module {:?} must be called \"{}<mount path>.tar\" or \"{}<mount path>.cpio\"",
+                    module_name, INITRD_MODULE_PREFIX, INITRD_MODULE_PREFIX
+                );
+                continue;
+            }
+        };
+        let file = match modules_dir.lock().get_file(module_name) {
+            Some(file) => file,
+            None => continue,
+        };
+        let locked_file = file.lock();
+        let size = locked_file.size();
+        let result = locked_file.as_mapping()
+            .and_then(|mp| mp.as_slice::<u8>(0, size))
+            .map_err(InitrdError::from)
+            .and_then(|archive| load(archive, &mount_path, module_name));
+        if let Err(e) = result {
+            error!("initrd: couldn't load module {:?} at {}: {}", module_name, mount_path, e);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the absolute mount path encoded in the name of the given initrd module,
+/// e.g., `/data/images` for `initrd+data+images.tar`.
+fn mount_path_of_module(module_name: &str) -> Option<String> {
+    let encoded = module_name.trim_start_matches(INITRD_MODULE_PREFIX);
+    let encoded = if encoded.ends_with(".tar") {
+        &encoded[.. encoded.len() - ".tar".len()]
+    } else if encoded.ends_with(".cpio") {
+        &encoded[.. encoded.len() - ".cpio".len()]
+    } else {
+        return None;
+    };
+    if encoded.is_empty() {
+        return None;
+    }
+    let components: Vec<&str> = encoded.split(MOUNT_PATH_SEPARATOR).collect();
+    Some(format!("/{}", components.join("/")))
+}
+
+
+/// Splits the given path into its components, ignoring empty and `.` components.
+/// Fails if the path contains a `..` component, which could escape the archive's root.
+fn split_path(path: &str) -> Result<Vec<&str>, &'static str> {
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty() && *c != ".").collect();
+    if components.iter().any(|c| *c == "..") {
+        return Err("paths in an archive must not contain \"..\"");
+    }
+    Ok(components)
+}
+
+/// Returns the directory at the given path of `components` below `root`, creating any directories that don't exist.
+fn create_dirs(root: &DirRef, components: &[&str]) -> Result<DirRef, &'static str> {
+    let mut dir = Arc::clone(root);
+    for component in components {
+        let existing = dir.lock().get(component);
+        dir = match existing {
+            Some(FileOrDir::Dir(d)) => d,
+            Some(FileOrDir::File(_)) => return Err("a file with the same name as a directory already exists"),
+            None => VFSDirectory::new(String::from(*component), &dir)?,
+        };
+    }
+    Ok(dir)
+}
+
+/// Returns the file at the given path of `components` below `root`, if it exists.
+fn lookup_file(root: &DirRef, components: &[&str]) -> Option<FileRef> {
+    let (name, parents) = components.split_last()?;
+    let mut dir = Arc::clone(root);
+    for component in parents {
+        let next = dir.lock().get_dir(component)?;
+        dir = next;
+    }
+    let file = dir.lock().get_file(name);
+    file
+}
+
+/// Converts the owner permission bits of the given Unix `mode` into `Permissions`.
+fn permissions_from_mode(mode: u32) -> Permissions {
+    let mut permissions = Permissions::empty();
+    if mode & 0o400 != 0 {
+        permissions |= Permissions::READ;
+    }
+    if mode & 0o200 != 0 {
+        permissions |= Permissions::WRITE;
+    }
+    if mode & 0o100 != 0 {
+        permissions |= Permissions::EXECUTE;
+    }
+    permissions
+}
@@ -0,0 +1,429 @@
+//! A parser for tar archives in the POSIX ustar format.
+//!
+//! The GNU extensions for long names (`L` and `K` entries) and the `path` and `linkpath` records
+//! of pax extended headers (`x` entries) are also supported, so archives created by GNU tar in its default format can be read.
+
+use alloc::string::String;
+use core::str;
+use super::{ArchiveEntry, EntryKind, InitrdError};
+
+
+/// Tar archives consist of blocks of this size, and each header occupies one block.
+const BLOCK_SIZE: usize = 512;
+
+/// The offsets and lengths of the header fields that we use.
+const NAME:      (usize, usize) = (0, 100);
+const MODE:      (usize, usize) = (100, 8);
+const SIZE:      (usize, usize) = (124, 12);
+const CHECKSUM:  (usize, usize) = (148, 8);
+const TYPEFLAG:  usize = 156;
+const LINKNAME:  (usize, usize) = (157, 100);
+const MAGIC:     (usize, usize) = (257, 5);
+const PREFIX:    (usize, usize) = (345, 155);
+
+
+/// Returns `true` if the given `data` starts with a tar header.
+pub(crate) fn is_tar(data: &[u8]) -> bool {
+    data.len() >= BLOCK_SIZE && field(data, MAGIC) == b"ustar"
+}
+
+/// An iterator over the entries of a tar archive.
+pub(crate) struct TarEntries<'a> {
+    data: &'a [u8],
+    /// The offset of the next header.
+    offset: usize,
+    finished: bool,
+}
+
+impl<'a> TarEntries<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> TarEntries<'a> {
+        TarEntries { data, offset: 0, finished: false }
+    }
+
+    /// Parses the next entry, including any extension entries that precede it.
+    /// Returns `None` at the end of the archive.
+    fn next_entry(&mut self) -> Result<Option<ArchiveEntry<'a>>, InitrdError> {
+        let archive = self.data;
+        let mut long_name: Option<String> = None;
+        let mut long_link_name: Option<String> = None;
+        loop {
+            let header_offset = self.offset;
+            if header_offset >= archive.len() {
+                if header_offset != 0 {
+                    warn!("initrd: tar archive ended without its end-of-archive marker");
+                }
+                return Ok(None);
+            }
+            let malformed = |reason| InitrdError::malformed(header_offset, reason);
+
+            let header = archive.get(header_offset .. header_offset + BLOCK_SIZE).ok_or_else(|| malformed("tar header was truncated"))?;
+            // The end of the archive is marked by blocks of zeros.
+            if header.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+            if field(header, MAGIC) != b"ustar" {
+                return Err(malformed("tar header did not have the ustar magic value"));
+            }
+            verify_checksum(header).map_err(malformed)?;
+
+            let size = parse_octal(field(header, SIZE)).ok_or_else(|| malformed("tar header had an invalid size field"))?;
+            let data_start = header_offset + BLOCK_SIZE;
+            let data_end = data_start.checked_add(size).ok_or_else(|| malformed("tar entry's size was too large"))?;
+            let data = archive.get(data_start .. data_end).ok_or_else(|| malformed("tar entry's contents were truncated"))?;
+            // The contents are padded to a whole number of blocks.
+            let padded_size = (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+            self.offset = data_start + padded_size;
+
+            let typeflag = header[TYPEFLAG];
+            match typeflag {
+                b'L' => {
+                    long_name = Some(string_from(data).map_err(malformed)?);
+                    continue;
+                }
+                b'K' => {
+                    long_link_name = Some(string_from(data).map_err(malformed)?);
+                    continue;
+                }
+                b'x' => {
+                    parse_pax_records(data, &mut long_name, &mut long_link_name).map_err(malformed)?;
+                    continue;
+                }
+                // Global pax headers don't contain anything we use.
+                b'g' => continue,
+                _ => { }
+            }
+
+            let path = match long_name.take() {
+                Some(name) => name,
+                None => {
+                    let name = string_from(field(header, NAME)).map_err(malformed)?;
+                    let prefix = string_from(field(header, PREFIX)).map_err(malformed)?;
+                    if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) }
+                }
+            };
+            let link_name = match long_link_name.take() {
+                Some(name) => name,
+                None => string_from(field(header, LINKNAME)).map_err(malformed)?,
+            };
+            let mode = parse_octal(field(header, MODE)).ok_or_else(|| malformed("tar header had an invalid mode field"))?;
+
+            let kind = match typeflag {
+                // Old archives mark directories only by a trailing slash.
+                b'0' | 0 if path.ends_with('/') => EntryKind::Directory,
+                // '7' is a contiguous file, which is just a regular file for us.
+                b'0' | 0 | b'7' => EntryKind::File,
+                b'1' => EntryKind::HardLink(link_name),
+                b'2' => EntryKind::Symlink(link_name),
+                b'5' => EntryKind::Directory,
+                _ => EntryKind::Other,
+            };
+            // Only regular files have contents; e.g., hard links in tar archives never do.
+            let data = if kind == EntryKind::File { data } else { &[] };
+            return Ok(Some(ArchiveEntry { path, kind, mode: mode as u32, data }));
+        }
+    }
+}
+
+impl<'a> Iterator for TarEntries<'a> {
+    type Item = Result<ArchiveEntry<'a>, InitrdError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let result = self.next_entry();
+        match result {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Err(e) => {
+                self.finished = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+
+/// Returns the given `(offset, length)` field of a `header`.
+fn field(header: &[u8], (offset, length): (usize, usize)) -> &[u8] {
+    &header[offset .. offset + length]
+}
+
+/// Checks the header's checksum, which is the sum of all header bytes with the checksum field itself taken as spaces.
+fn verify_checksum(header: &[u8]) -> Result<(), &'static str> {
+    let expected = parse_octal(field(header, CHECKSUM)).ok_or("tar header had an invalid checksum field")?;
+    let (start, end) = (CHECKSUM.0, CHECKSUM.0 + CHECKSUM.1);
+    let mut unsigned_sum = 0usize;
+    let mut signed_sum = 0isize;
+    for (i, &b) in header.iter().enumerate() {
+        let b = if i >= start && i < end { b' ' } else { b };
+        unsigned_sum += b as usize;
+        signed_sum += b as i8 as isize;
+    }
+    // Some old tar implementations computed the checksum using signed bytes.
+    if expected == unsigned_sum || expected as isize == signed_sum {
+        Ok(())
+    } else {
+        Err("tar header's checksum did not match")
+    }
+}
+
+/// Parses a numeric field, which is an octal number terminated by a space or NUL,
+/// or a big-endian binary number if the first byte has its high bit set (a GNU extension for large values).
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    if let Some(&first) = field.first() {
+        if first & 0x80 != 0 {
+            return field[1..].iter().try_fold((first & 0x7F) as usize, |value, &b| {
+                value.checked_mul(256).map(|v| v | b as usize)
+            });
+        }
+    }
+    let digits = field.iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| b != b' ' && b != 0);
+    let mut value = 0usize;
+    for &b in digits {
+        if b < b'0' || b > b'7' {
+            return None;
+        }
+        value = value.checked_mul(8)?.checked_add((b - b'0') as usize)?;
+    }
+    Some(value)
+}
+
+/// Returns the given NUL-terminated (or NUL-padded) bytes as a `String`.
+fn string_from(bytes: &[u8]) -> Result<String, &'static str> {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    str::from_utf8(&bytes[..end])
+        .map(String::from)
+        .map_err(|_| "tar entry's name was not valid UTF-8")
+}
+
+/// Parses the records of a pax extended header, each of which is formatted as `"<length> <key>=<value>\n"`,
+/// and extracts the `path` and `linkpath` records, which override the names in the following header.
+fn parse_pax_records(data: &[u8], path: &mut Option<String>, link_path: &mut Option<String>) -> Result<(), &'static str> {
+    const INVALID: &'static str = "tar pax extended header had an invalid record";
+    let mut rest = data;
+    while !rest.is_empty() && rest[0] != 0 {
+        let space = rest.iter().position(|&b| b == b' ').ok_or(INVALID)?;
+        let length: usize = str::from_utf8(&rest[..space]).ok().and_then(|s| s.parse().ok()).ok_or(INVALID)?;
+        if length <= space + 1 || length > rest.len() || rest[length - 1] != b'\n' {
+            return Err(INVALID);
+        }
+        let record = str::from_utf8(&rest[space + 1 .. length - 1]).map_err(|_| INVALID)?;
+        let equals = record.find('=').ok_or(INVALID)?;
+        match &record[..equals] {
+            "path" => *path = Some(String::from(&record[equals + 1 ..])),
+            "linkpath" => *link_path = Some(String::from(&record[equals + 1 ..])),
+            _ => { }
+        }
+        rest = &rest[length..];
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Returns a header block with the given fields and a valid checksum.
+    fn header(name: &str, typeflag: u8, size: usize, link_name: &str) -> Vec<u8> {
+        let mut header = vec![0u8; BLOCK_SIZE];
+        header[NAME.0 .. NAME.0 + name.len()].copy_from_slice(name.as_bytes());
+        header[MODE.0 .. MODE.0 + 8].copy_from_slice(b"0000644\0");
+        header[SIZE.0 .. SIZE.0 + 12].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+        header[TYPEFLAG] = typeflag;
+        header[LINKNAME.0 .. LINKNAME.0 + link_name.len()].copy_from_slice(link_name.as_bytes());
+        header[MAGIC.0 .. MAGIC.0 + 8].copy_from_slice(b"ustar\000");
+        set_checksum(&mut header);
+        header
+    }
+
+    fn set_checksum(header: &mut [u8]) {
+        for b in &mut header[CHECKSUM.0 .. CHECKSUM.0 + CHECKSUM.1] {
+            *b = b' ';
+        }
+        let sum: usize = header.iter().map(|&b| b as usize).sum();
+        header[CHECKSUM.0 .. CHECKSUM.0 + 8].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    }
+
+    /// Appends an entry with the given `header` and contents to the `archive`, padding the contents to a whole block.
+    fn append(archive: &mut Vec<u8>, header: &[u8], data: &[u8]) {
+        archive.extend_from_slice(header);
+        archive.extend_from_slice(data);
+        let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+        archive.extend(core::iter::repeat(0).take(padding));
+    }
+
+    fn append_file(archive: &mut Vec<u8>, name: &str, data: &[u8]) {
+        append(archive, &header(name, b'0', data.len(), ""), data);
+    }
+
+    fn end(archive: &mut Vec<u8>) {
+        archive.extend(core::iter::repeat(0).take(2 * BLOCK_SIZE));
+    }
+
+    fn entries(archive: &[u8]) -> Result<Vec<(String, EntryKind, Vec<u8>)>, InitrdError> {
+        TarEntries::new(archive)
+            .map(|entry| entry.map(|e| (e.path, e.kind, e.data.to_vec())))
+            .collect()
+    }
+
+    fn file(path: &str, data: &[u8]) -> (String, EntryKind, Vec<u8>) {
+        (String::from(path), EntryKind::File, data.to_vec())
+    }
+
+    fn other(path: &str, kind: EntryKind) -> (String, EntryKind, Vec<u8>) {
+        (String::from(path), kind, Vec::new())
+    }
+
+    #[test]
+    fn entry_kinds() {
+        let mut archive = Vec::new();
+        append(&mut archive, &header("etc/", b'5', 0, ""), &[]);
+        append_file(&mut archive, "etc/hosts", b"127.0.0.1 localhost\n");
+        append(&mut archive, &header("old_dir/", b'0', 0, ""), &[]);
+        append(&mut archive, &header("etc/link", b'2', 0, "hosts"), &[]);
+        append(&mut archive, &header("etc/hard", b'1', 0, "etc/hosts"), &[]);
+        append(&mut archive, &header("dev/null", b'3', 0, ""), &[]);
+        let mut prefixed = header("file", b'0', 0, "");
+        prefixed[PREFIX.0 .. PREFIX.0 + 4].copy_from_slice(b"long");
+        set_checksum(&mut prefixed);
+        append(&mut archive, &prefixed, &[]);
+        end(&mut archive);
+
+        assert!(is_tar(&archive));
+        assert_eq!(entries(&archive).unwrap(), [
+            other("etc/", EntryKind::Directory),
+            file("etc/hosts", b"127.0.0.1 localhost\n"),
+            other("old_dir/", EntryKind::Directory),
+            other("etc/link", EntryKind::Symlink(String::from("hosts"))),
+            other("etc/hard", EntryKind::HardLink(String::from("etc/hosts"))),
+            other("dev/null", EntryKind::Other),
+            file("long/file", b""),
+        ]);
+    }
+
+    #[test]
+    fn gnu_long_names_and_pax_records() {
+        let long_name = "a/".repeat(100) + "file";
+        let mut archive = Vec::new();
+        append(&mut archive, &header("././@LongLink", b'L', long_name.len() + 1, ""), format!("{}\0", long_name).as_bytes());
+        append_file(&mut archive, "truncated name", b"gnu");
+        let records = "14 path=a/pax\n19 linkpath=target\n";
+        append(&mut archive, &header("PaxHeader", b'x', records.len(), ""), records.as_bytes());
+        append(&mut archive, &header("a/truncated", b'2', 0, "truncated"), &[]);
+        append(&mut archive, &header("global", b'g', 0, ""), &[]);
+        append_file(&mut archive, "short", b"");
+        end(&mut archive);
+
+        assert_eq!(entries(&archive).unwrap(), [
+            file(&long_name, b"gnu"),
+            other("a/pax", EntryKind::Symlink(String::from("target"))),
+            file("short", b""),
+        ]);
+    }
+
+    #[test]
+    fn archive_without_end_marker() {
+        let mut archive = Vec::new();
+        append_file(&mut archive, "a", b"a");
+        assert_eq!(entries(&archive).unwrap(), [file("a", b"a")]);
+        assert!(entries(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_archives() {
+        let mut archive = Vec::new();
+        append_file(&mut archive, "a", b"a");
+        let second_header = archive.len();
+        append_file(&mut archive, "b", &[b'b'; 1000]);
+
+        assert_eq!(entries(&archive[.. second_header + 100]),
+            Err(InitrdError::malformed(second_header, "tar header was truncated")));
+        assert_eq!(entries(&archive[.. second_header + BLOCK_SIZE + 999]),
+            Err(InitrdError::malformed(second_header, "tar entry's contents were truncated")));
+        assert!(!is_tar(&archive[.. BLOCK_SIZE - 1]));
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let mut bad_checksum = header("a", b'0', 0, "");
+        bad_checksum[NAME.0] = b'b';
+        assert_eq!(entries(&bad_checksum), Err(InitrdError::malformed(0, "tar header's checksum did not match")));
+
+        let mut bad_magic = header("a", b'0', 0, "");
+        bad_magic[MAGIC.0] = b'x';
+        set_checksum(&mut bad_magic);
+        assert_eq!(entries(&bad_magic), Err(InitrdError::malformed(0, "tar header did not have the ustar magic value")));
+
+        let mut bad_size = header("a", b'0', 0, "");
+        bad_size[SIZE.0] = b'9';
+        set_checksum(&mut bad_size);
+        assert_eq!(entries(&bad_size), Err(InitrdError::malformed(0, "tar header had an invalid size field")));
+
+        let mut bad_name = header("a", b'0', 0, "");
+        bad_name[NAME.0] = 0xFF;
+        set_checksum(&mut bad_name);
+        assert_eq!(entries(&bad_name), Err(InitrdError::malformed(0, "tar entry's name was not valid UTF-8")));
+    }
+
+    #[test]
+    fn overflowing_sizes() {
+        // a binary size field whose value doesn't fit in a usize
+        let mut too_large = header("a", b'0', 0, "");
+        for b in &mut too_large[SIZE.0 .. SIZE.0 + SIZE.1] {
+            *b = 0xFF;
+        }
+        set_checksum(&mut too_large);
+        assert_eq!(entries(&too_large), Err(InitrdError::malformed(0, "tar header had an invalid size field")));
+
+        // a binary size field whose value overflows when added to the entry's offset
+        let mut overflowing = header("a", b'0', 0, "");
+        overflowing[SIZE.0 .. SIZE.0 + 4].copy_from_slice(&[0x80, 0, 0, 0]);
+        for b in &mut overflowing[SIZE.0 + 4 .. SIZE.0 + SIZE.1] {
+            *b = 0xFF;
+        }
+        set_checksum(&mut overflowing);
+        assert_eq!(entries(&overflowing), Err(InitrdError::malformed(0, "tar entry's size was too large")));
+
+        // an octal size field that is larger than the archive
+        let huge = header("a", b'0', 0o77777777777, "");
+        assert_eq!(entries(&huge), Err(InitrdError::malformed(0, "tar entry's contents were truncated")));
+    }
+
+    #[test]
+    fn malformed_pax_records() {
+        for records in &["99 path=a\n", "2 path=a\n", "8 path=a\n", "x path=a\n", "7 path\n", "no length"] {
+            let mut archive = Vec::new();
+            append(&mut archive, &header("PaxHeader", b'x', records.len(), ""), records.as_bytes());
+            append_file(&mut archive, "a", b"");
+            assert_eq!(entries(&archive), Err(InitrdError::malformed(0, "tar pax extended header had an invalid record")),
+                "records: {:?}", records);
+        }
+    }
+
+    #[test]
+    fn iteration_stops_after_an_error() {
+        let mut archive = header("a", b'0', 0, "");
+        archive[NAME.0] = b'b';
+        let mut entries = TarEntries::new(&archive);
+        assert!(entries.next().unwrap().is_err());
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn numeric_fields() {
+        assert_eq!(parse_octal(b"0000644\0"), Some(0o644));
+        assert_eq!(parse_octal(b"   12 \0"), Some(0o12));
+        assert_eq!(parse_octal(b"\0\0\0"), Some(0));
+        assert_eq!(parse_octal(&[0x80, 0, 0, 1, 0]), Some(256));
+        assert_eq!(parse_octal(b"0008"), None);
+        assert_eq!(parse_octal(b"7777777777777777777777"), None);
+    }
+}
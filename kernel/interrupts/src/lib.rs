@@ -24,6 +24,8 @@ extern crate keyboard;
 extern crate mouse;
 extern crate ps2;
 extern crate tlb_shootdown;
+extern crate ata;
+extern crate sleep;
 
 
 
@@ -334,6 +336,9 @@ pub static APIC_TIMER_TICKS: AtomicUsize = AtomicUsize::new(0);
 extern "x86-interrupt" fn lapic_timer_handler(_stack_frame: &mut ExceptionStackFrame) {
     let _ticks = APIC_TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
     // info!(" ({}) APIC TIMER HANDLER! TICKS = {}", apic::get_my_apic_id(), _ticks);
+
+    // wake up the tasks that have slept long enough, such that they can be scheduled in below
+    sleep::unblock_sleeping_tasks();
     
     // we must acknowledge the interrupt first before handling it because we switch tasks here, which doesn't return
     eoi(None); // None, because 0x22 IRQ cannot possibly be a PIC interrupt
@@ -431,7 +436,7 @@ extern "x86-interrupt" fn pic_spurious_interrupt_handler(_stack_frame: &mut Exce
 
 /// 0x2E
 extern "x86-interrupt" fn primary_ata_handler(_stack_frame: &mut ExceptionStackFrame ) {
-    info!("Primary ATA Interrupt (0x2E)");
+    ata::handle_primary_interrupt();
 
     eoi(Some(PIC_MASTER_OFFSET + 0xE));
 }
@@ -439,8 +444,8 @@ extern "x86-interrupt" fn primary_ata_handler(_stack_frame: &mut ExceptionStackF
 
 /// 0x2F
 extern "x86-interrupt" fn secondary_ata_handler(_stack_frame: &mut ExceptionStackFrame ) {
-    info!("Secondary ATA Interrupt (0x2F)");
-    
+    ata::handle_secondary_interrupt();
+
     eoi(Some(PIC_MASTER_OFFSET + 0xF));
 }
 
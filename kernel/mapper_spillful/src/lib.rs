@@ -112,9 +112,9 @@ impl MapperSpillful {
 
             for page in PageRange::from_virt_addr(vaddr, size).clone() {
                 let frame = allocator.allocate_frame().ok_or("MapperSpillful::map() -- out of memory trying to alloc frame")?;
-                let p3 = self.p4_mut().next_table_create(page.p4_index(), top_level_flags, &mut *allocator);
-                let p2 = p3.next_table_create(page.p3_index(), top_level_flags, &mut *allocator);
-                let p1 = p2.next_table_create(page.p2_index(), top_level_flags, &mut *allocator);
+                let p3 = self.p4_mut().next_table_create(page.p4_index(), top_level_flags, &mut *allocator)?;
+                let p2 = p3.next_table_create(page.p3_index(), top_level_flags, &mut *allocator)?;
+                let p1 = p2.next_table_create(page.p2_index(), top_level_flags, &mut *allocator)?;
 
                 if !p1[page.p1_index()].is_unused() {
                     error!("MapperSpillful::map() page {:#x} -> frame {:#X}, page was already in use!", page.start_address(), frame.start_address());
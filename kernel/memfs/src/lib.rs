@@ -15,7 +15,7 @@ extern crate irq_safety;
 // use alloc::vec::Vec;
 use core::ops::DerefMut;
 use alloc::string::String;
-use fs_node::{DirRef, WeakDirRef, File, FsNode};
+use fs_node::{DirRef, WeakDirRef, File, FsNode, Metadata, NodeAttributes, NodeKind, Permissions, Watchers, WatchEventKind};
 use memory::{MappedPages, get_kernel_mmi_ref, allocate_pages_by_bytes, get_frame_allocator_ref, EntryFlags};
 use alloc::sync::Arc;
 use spin::Mutex;
@@ -32,6 +32,10 @@ pub struct MemFile {
     mp: MappedPages,
     /// The parent directory that contains this file.
     parent: WeakDirRef,
+    /// The permissions and timestamps of this file.
+    attributes: NodeAttributes,
+    /// The watchers that are notified of changes to this file.
+    watchers: Watchers,
 }
 
 impl MemFile {
@@ -42,12 +46,24 @@ impl MemFile {
     }
 
     /// Creates a new `MemFile` in the given `parent` directory with the contents of the given `mapped_pages`.
+    /// 
+    /// The file's permissions are derived from the flags of the `mapped_pages`:
+    /// it is writable if they are writable (or empty), and executable if they are executable.
     pub fn from_mapped_pages(mapped_pages: MappedPages, name: String, size: usize, parent: &DirRef) -> Result<FileRef, &'static str> {
+        let mut permissions = Permissions::READ;
+        if mapped_pages.size_in_bytes() == 0 || mapped_pages.flags().is_writable() {
+            permissions |= Permissions::WRITE;
+        }
+        if mapped_pages.size_in_bytes() != 0 && mapped_pages.flags().is_executable() {
+            permissions |= Permissions::EXECUTE;
+        }
         let memfile = MemFile {
             name: name, 
             size: size, 
             mp: mapped_pages, 
             parent: Arc::downgrade(parent), 
+            attributes: NodeAttributes::new(permissions),
+            watchers: Watchers::new(),
         };
         let file_ref = Arc::new(Mutex::new(memfile)) as FileRef;
         parent.lock().insert(FileOrDir::File(file_ref.clone()))?; // adds the newly created file to the tree
@@ -64,10 +80,12 @@ impl File for MemFile {
         // read from the offset until the end of the file, but not more than the buffer length
         let read_bytes = core::cmp::min(self.size - offset, buffer.len());
         buffer[..read_bytes].copy_from_slice(self.mp.as_slice(offset, read_bytes)?); 
+        self.attributes.mark_accessed();
         Ok(read_bytes) 
     }
 
     fn write(&mut self, buffer: &[u8], offset: usize) -> Result<usize, &'static str> {
+        self.attributes.check(Permissions::WRITE)?;
         // error out if the underlying mapped pages are already allocated and not writeable
         if !self.mp.flags().is_writable() && self.mp.size_in_bytes() != 0 {
             return Err("MemFile::write(): existing MappedPages were not writable");
@@ -84,6 +102,8 @@ impl File for MemFile {
             if end > self.size { 
                 self.size = end; 
             }
+            self.attributes.mark_modified();
+            self.watchers.notify_kind(WatchEventKind::Modified, &self.name);
             Ok(buffer.len()) // we wrote all of the requested bytes successfully
         } 
         // if not, we need to reallocate a new mapped pages 
@@ -125,6 +145,8 @@ impl File for MemFile {
             }
             self.mp = new_mapped_pages;
             self.size = end;
+            self.attributes.mark_modified();
+            self.watchers.notify_kind(WatchEventKind::Modified, &self.name);
             Ok(buffer.len())
         }
     }
@@ -134,6 +156,18 @@ impl File for MemFile {
         self.size
     }
 
+    /// The underlying `MappedPages` are kept, so truncating a file doesn't free any memory.
+    fn truncate(&mut self, size: usize) -> Result<(), &'static str> {
+        self.attributes.check(Permissions::WRITE)?;
+        if size > self.size {
+            return Err("cannot truncate a file to a larger size");
+        }
+        self.size = size;
+        self.attributes.mark_modified();
+        self.watchers.notify_kind(WatchEventKind::Modified, &self.name);
+        Ok(())
+    }
+
     fn as_mapping(&self) -> Result<&MappedPages, &'static str> {
         Ok(&self.mp)
     }
@@ -152,5 +186,25 @@ impl FsNode for MemFile {
     fn set_parent_dir(&mut self, new_parent: WeakDirRef) {
         self.parent = new_parent;
     }
-}
 
+    fn set_name(&mut self, new_name: String) -> Result<(), &'static str> {
+        if new_name != self.name {
+            let old_name = core::mem::replace(&mut self.name, new_name);
+            self.watchers.notify_kind(WatchEventKind::Renamed { old_name }, &self.name);
+        }
+        Ok(())
+    }
+
+    fn metadata(&self) -> Metadata {
+        self.attributes.metadata(NodeKind::File, self.size)
+    }
+
+    fn set_permissions(&mut self, permissions: Permissions) -> Result<(), &'static str> {
+        self.attributes.set_permissions(permissions);
+        Ok(())
+    }
+
+    fn watchers(&self) -> Option<&Watchers> {
+        Some(&self.watchers)
+    }
+}
@@ -0,0 +1,167 @@
+//! Accounting of the physical frames and heap memory allocated by each task and by the whole system,
+//! as well as of the `MappedPages` that currently exist.
+//!
+//! Each task has a [`MemoryUsage`] whose counters are updated whenever that task allocates or deallocates
+//! frames or heap memory. Memory is always charged to the task that performs the allocation or deallocation,
+//! so memory that one task allocates and another task frees is counted as allocated by the former and deallocated by the latter.
+//! Thus, the amount of memory a task has in use is only exact for tasks that free their own memory.
+//!
+//! This crate cannot depend on the task crate, so the task crate registers a function
+//! that returns the current task's `MemoryUsage`, see [`set_current_memory_usage_cb()`].
+//!
+//! Frames are only counted once the heap has been set up, when the frame allocator starts supporting deallocation.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
+
+
+/// Counters of the frames and heap memory allocated and deallocated by a task or by the whole system.
+#[derive(Debug, Default)]
+pub struct MemoryUsage {
+    frames_allocated: AtomicUsize,
+    frames_deallocated: AtomicUsize,
+    heap_bytes_allocated: AtomicUsize,
+    heap_bytes_deallocated: AtomicUsize,
+}
+
+impl MemoryUsage {
+    /// Returns a new `MemoryUsage` with all counters at zero.
+    pub const fn new() -> MemoryUsage {
+        MemoryUsage {
+            frames_allocated: AtomicUsize::new(0),
+            frames_deallocated: AtomicUsize::new(0),
+            heap_bytes_allocated: AtomicUsize::new(0),
+            heap_bytes_deallocated: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the current values of these counters.
+    pub fn stats(&self) -> MemoryUsageStats {
+        MemoryUsageStats {
+            frames_allocated: self.frames_allocated.load(Ordering::Relaxed),
+            frames_deallocated: self.frames_deallocated.load(Ordering::Relaxed),
+            heap_bytes_allocated: self.heap_bytes_allocated.load(Ordering::Relaxed),
+            heap_bytes_deallocated: self.heap_bytes_deallocated.load(Ordering::Relaxed),
+        }
+    }
+}
+
+
+/// The values of the counters of a [`MemoryUsage`] at one point in time.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsageStats {
+    /// The total number of frames allocated so far.
+    pub frames_allocated: usize,
+    /// The total number of frames deallocated so far.
+    pub frames_deallocated: usize,
+    /// The total number of bytes allocated from the heap so far.
+    pub heap_bytes_allocated: usize,
+    /// The total number of bytes deallocated back to the heap so far.
+    pub heap_bytes_deallocated: usize,
+}
+
+impl MemoryUsageStats {
+    /// The number of frames that were allocated but not yet deallocated.
+    pub fn frames_in_use(&self) -> usize {
+        self.frames_allocated.saturating_sub(self.frames_deallocated)
+    }
+
+    /// The number of heap bytes that were allocated but not yet deallocated.
+    pub fn heap_bytes_in_use(&self) -> usize {
+        self.heap_bytes_allocated.saturating_sub(self.heap_bytes_deallocated)
+    }
+}
+
+
+/// The number of `MappedPages` objects that currently exist and the number of pages they cover.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MappedPagesStats {
+    /// The number of `MappedPages` objects that currently exist, not counting empty ones.
+    pub count: usize,
+    /// The total number of pages covered by those `MappedPages`,
+    /// including pages of lazy mappings that haven't been populated yet.
+    pub pages: usize,
+}
+
+
+/// The memory allocated and deallocated by all tasks, including before tasking was set up.
+static SYSTEM_MEMORY_USAGE: MemoryUsage = MemoryUsage::new();
+static MAPPED_PAGES_COUNT: AtomicUsize = AtomicUsize::new(0);
+static MAPPED_PAGES_SIZE_IN_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// The function that returns the `MemoryUsage` of the current task, if there is one.
+static CURRENT_MEMORY_USAGE_FUNC: Once<fn() -> Option<&'static MemoryUsage>> = Once::new();
+
+/// Sets the function callback that returns the `MemoryUsage` of the currently-running task,
+/// which is charged for every allocation and deallocation of frames and heap memory.
+///
+/// The callback is invoked while allocating memory, so it must not allocate memory or acquire any locks itself.
+pub fn set_current_memory_usage_cb(func: fn() -> Option<&'static MemoryUsage>) {
+    CURRENT_MEMORY_USAGE_FUNC.call_once(|| func);
+}
+
+/// Returns the frames and heap memory allocated and deallocated by the whole system so far.
+pub fn system_memory_usage() -> MemoryUsageStats {
+    SYSTEM_MEMORY_USAGE.stats()
+}
+
+/// Returns the number of `MappedPages` objects that currently exist and the number of pages they cover.
+pub fn mapped_pages_stats() -> MappedPagesStats {
+    MappedPagesStats {
+        count: MAPPED_PAGES_COUNT.load(Ordering::Relaxed),
+        pages: MAPPED_PAGES_SIZE_IN_PAGES.load(Ordering::Relaxed),
+    }
+}
+
+
+/// Records that the current task allocated `num_bytes` bytes from the heap.
+/// This is invoked by the heap, and should not be invoked otherwise.
+pub fn record_heap_allocation(num_bytes: usize) {
+    record(|usage| &usage.heap_bytes_allocated, num_bytes);
+}
+
+/// Records that the current task deallocated `num_bytes` bytes back to the heap.
+/// This is invoked by the heap, and should not be invoked otherwise.
+pub fn record_heap_deallocation(num_bytes: usize) {
+    record(|usage| &usage.heap_bytes_deallocated, num_bytes);
+}
+
+/// Records that the current task allocated `num_frames` frames.
+pub(crate) fn record_frame_allocation(num_frames: usize) {
+    record(|usage| &usage.frames_allocated, num_frames);
+}
+
+/// Records that the current task deallocated `num_frames` frames.
+pub(crate) fn record_frame_deallocation(num_frames: usize) {
+    record(|usage| &usage.frames_deallocated, num_frames);
+}
+
+/// Adds `amount` to the given counter of both the system-wide and the current task's `MemoryUsage`.
+fn record<F: Fn(&MemoryUsage) -> &AtomicUsize>(counter: F, amount: usize) {
+    counter(&SYSTEM_MEMORY_USAGE).fetch_add(amount, Ordering::Relaxed);
+    if let Some(usage) = CURRENT_MEMORY_USAGE_FUNC.try().and_then(|func| func()) {
+        counter(usage).fetch_add(amount, Ordering::Relaxed);
+    }
+}
+
+
+/// Records that a new `MappedPages` covering `num_pages` pages was created.
+pub(crate) fn record_mapped_pages_created(num_pages: usize) {
+    if num_pages > 0 {
+        MAPPED_PAGES_COUNT.fetch_add(1, Ordering::Relaxed);
+        MAPPED_PAGES_SIZE_IN_PAGES.fetch_add(num_pages, Ordering::Relaxed);
+    }
+}
+
+/// Records that a `MappedPages` covering `num_pages` pages was dropped.
+pub(crate) fn record_mapped_pages_dropped(num_pages: usize) {
+    if num_pages > 0 {
+        MAPPED_PAGES_COUNT.fetch_sub(1, Ordering::Relaxed);
+        MAPPED_PAGES_SIZE_IN_PAGES.fetch_sub(num_pages, Ordering::Relaxed);
+    }
+}
+
+/// Records that a `MappedPages` was merged into another one, such that its pages are now covered by that one.
+pub(crate) fn record_mapped_pages_merged() {
+    MAPPED_PAGES_COUNT.fetch_sub(1, Ordering::Relaxed);
+}
@@ -8,6 +8,8 @@
 // except according to those terms.
 
 use super::{Frame, FrameAllocator, FrameRange, PhysicalAddress, PhysicalMemoryArea};
+use buddy_allocator::BuddyAllocator;
+use accounting::{record_frame_allocation, record_frame_deallocation};
 use alloc::vec::Vec;
 use kernel_config::memory::PAGE_SIZE;
 
@@ -34,6 +36,13 @@ impl<T: Clone> VectorArray<T> {
         }
     }
 
+    pub fn as_slice(&self) -> &[T] {
+        match self {
+            &VectorArray::Array((count, ref arr)) => &arr[..count],
+            &VectorArray::Vector(ref v) => &v[..],
+        }
+    }
+
     // pub fn iter(&self) -> ::core::slice::Iter<T> {
     //     match self {
     //         &VectorArray::Array((_count, arr)) => arr.iter(),
@@ -46,16 +55,42 @@ impl<T: Clone> VectorArray<T> {
 
 
 
+/// The number of frames managed by the frame allocator and how many of them are free.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// The number of frames in the available memory areas, excluding occupied areas.
+    pub total_frames: usize,
+    /// The number of those frames that are not currently allocated.
+    pub free_frames: usize,
+}
+
+impl FrameStats {
+    /// The number of frames that are currently allocated.
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+}
+
+
 /// A frame allocator that uses the memory areas from the multiboot information structure as
 /// source. The {kernel, multiboot}_{start, end} fields are used to avoid returning memory that is
 /// already in use.
 ///
 /// `kernel_end` and `multiboot_end` are _inclusive_ bounds.
+/// 
+/// Until the heap is set up, frames are handed out in increasing order and can't be deallocated. 
+/// Once [`alloc_ready()`](#method.alloc_ready) is invoked, all remaining frames are handed over to a buddy allocator,
+/// which supports deallocation, alignment constraints, and reserving occupied areas that are added later. 
+/// Frames outside of the available areas, or inside occupied areas, e.g., device memory, are never deallocated.
 pub struct AreaFrameAllocator {
     next_free_frame: Frame,
     current_area: Option<PhysicalMemoryArea>,
     available: VectorArray<PhysicalMemoryArea>,
     occupied: VectorArray<PhysicalMemoryArea>,
+    /// The allocator used once the heap is ready, see `alloc_ready()`.
+    buddy: Option<BuddyAllocator>,
+    /// The number of frames in the available areas excluding occupied areas, computed in `alloc_ready()`.
+    total_frames: usize,
 }
 
 impl AreaFrameAllocator {
@@ -70,6 +105,8 @@ impl AreaFrameAllocator {
             current_area: None,
             available: VectorArray::Array((avail_len, available)),
             occupied: VectorArray::Array((occ_len, occupied)),
+            buddy: None,
+            total_frames: 0,
         };
         allocator.select_next_area();
         Ok(allocator)
@@ -94,6 +131,32 @@ impl AreaFrameAllocator {
             }
         }
 
+        if let Some(ref mut buddy) = self.buddy {
+            let (start, end) = area_frames(&area);
+            if available {
+                // Free the frames of the new area, except those in occupied areas or beyond the end of the buddy allocator's range. 
+                let occupied = self.occupied.as_slice();
+                let mut added = 0;
+                for_each_unoccupied_range(start, end, occupied, |s, e| {
+                    for frame in FrameRange::new(s, e) {
+                        if buddy.covers(frame) && !buddy.is_free(frame) {
+                            buddy.free_range(frame, frame);
+                            added += 1;
+                        }
+                    }
+                });
+                self.total_frames += added;
+                if added < end.number + 1 - start.number {
+                    warn!("AreaFrameAllocator::add_area(): only {} frames of new area {:?} could be used", added, area);
+                }
+            } else {
+                let reserved = buddy.reserve_range(start, end);
+                if reserved > 0 {
+                    debug!("AreaFrameAllocator::add_area(): reserved {} free frames in occupied area {:?}", reserved, area);
+                }
+            }
+        }
+
         // debugging stuff below
         trace!("AreaFrameAllocator: updated {} area: =======================================", if available { "available" } else { "occupied" });
         match if available { &self.available } else { &self.occupied } {
@@ -177,11 +240,97 @@ impl AreaFrameAllocator {
             self.skip_occupied_frames();
         }
     }
+
+    /// Invokes the given function with each range of frames (inclusive) in the available areas 
+    /// that starts at or after the given `lower_bound` and is not in an occupied area.
+    fn for_each_usable_range<F: FnMut(Frame, Frame)>(&self, lower_bound: Frame, mut func: F) {
+        let occupied = self.occupied.as_slice();
+        for area in self.available.as_slice().iter().filter(|area| area.typ == 1 && area.size_in_bytes > 0) {
+            let (start, end) = area_frames(area);
+            let start = if start < lower_bound { lower_bound } else { start };
+            if start <= end {
+                for_each_unoccupied_range(start, end, occupied, &mut func);
+            }
+        }
+    }
+
+    /// Returns `true` if the given `frame` is in an available area and not in an occupied area,
+    /// i.e., it may have been allocated by this allocator.
+    fn is_usable(&self, frame: Frame) -> bool {
+        let contains = |area: &PhysicalMemoryArea| {
+            let (start, end) = area_frames(area);
+            area.size_in_bytes > 0 && frame >= start && frame <= end
+        };
+        self.available.as_slice().iter().any(|area| area.typ == 1 && contains(area))
+            && !self.occupied.as_slice().iter().any(contains)
+    }
+
+    /// Returns the number of frames managed by this allocator and how many of them are free. 
+    pub fn stats(&self) -> FrameStats {
+        match self.buddy {
+            Some(ref buddy) => FrameStats { total_frames: self.total_frames, free_frames: buddy.free_frames() },
+            None => {
+                let mut total_frames = 0;
+                let mut free_frames = 0;
+                let next_free_frame = self.next_free_frame;
+                self.for_each_usable_range(Frame::containing_address(PhysicalAddress::zero()), |start, end| {
+                    total_frames += end.number + 1 - start.number;
+                    if end >= next_free_frame {
+                        let free_start = if start < next_free_frame { next_free_frame } else { start };
+                        free_frames += end.number + 1 - free_start.number;
+                    }
+                });
+                FrameStats { total_frames, free_frames }
+            }
+        }
+    }
+}
+
+
+/// Returns the first and last frames (inclusive) of the given memory area.
+fn area_frames(area: &PhysicalMemoryArea) -> (Frame, Frame) {
+    let start = Frame::containing_address(area.base_addr);
+    let end = Frame::containing_address(area.base_addr + area.size_in_bytes.saturating_sub(1));
+    (start, end)
+}
+
+/// Invokes the given function with each range of frames (inclusive) between `start` and `end` 
+/// that doesn't overlap any of the `occupied` areas.
+fn for_each_unoccupied_range<F: FnMut(Frame, Frame)>(start: Frame, end: Frame, occupied: &[PhysicalMemoryArea], mut func: F) {
+    let mut current = start;
+    while current <= end {
+        // find the first occupied area that overlaps the remaining range
+        let next_occupied = occupied.iter()
+            .filter(|area| area.size_in_bytes > 0)
+            .map(area_frames)
+            .filter(|&(occ_start, occ_end)| occ_end >= current && occ_start <= end)
+            .min_by_key(|&(occ_start, _)| occ_start);
+        match next_occupied {
+            Some((occ_start, occ_end)) => {
+                if occ_start > current {
+                    func(current, occ_start - 1);
+                }
+                current = occ_end + 1;
+            }
+            None => {
+                func(current, end);
+                return;
+            }
+        }
+    }
 }
 
 impl FrameAllocator for AreaFrameAllocator {
 
     fn allocate_frames(&mut self, num_frames: usize) -> Option<FrameRange> {
+        if self.buddy.is_some() {
+            let frames = self.allocate_aligned_frames(num_frames, 1);
+            if frames.is_none() {
+                error!("AreaFrameAllocator::allocate_frames(): couldn't allocate {} contiguous frames, out of memory!", num_frames);
+            }
+            return frames;
+        }
+
         // this is just a shitty way to get contiguous frames, since right now it's really easy to get them
         // it wastes the frames that are allocated 
 
@@ -217,7 +366,34 @@ impl FrameAllocator for AreaFrameAllocator {
     }
 
 
+    /// Aligned allocations are only possible after the heap has been set up,
+    /// except for an `alignment` of a single frame.
+    fn allocate_aligned_frames(&mut self, num_frames: usize, alignment: usize) -> Option<FrameRange> {
+        match self.buddy {
+            Some(ref mut buddy) => {
+                let frames = buddy.allocate(num_frames, alignment);
+                if frames.is_some() {
+                    record_frame_allocation(num_frames);
+                }
+                frames
+            }
+            None if alignment <= 1 => self.allocate_frames(num_frames),
+            None => None,
+        }
+    }
+
+
     fn allocate_frame(&mut self) -> Option<Frame> {
+        if let Some(ref mut buddy) = self.buddy {
+            let frame = buddy.allocate(1, 1).map(|frames| *frames.start());
+            if frame.is_none() {
+                error!("FATAL ERROR: AreaFrameAllocator: out of physical memory!!!");
+            } else {
+                record_frame_allocation(1);
+            }
+            return frame;
+        }
+
         if let Some(area) = self.current_area {
             // first, see if we need to skip beyond the current area (it may be already occupied)
             self.skip_occupied_frames();
@@ -250,14 +426,62 @@ impl FrameAllocator for AreaFrameAllocator {
     }
 
     
-    fn deallocate_frame(&mut self, _frame: Frame) {
-        unimplemented!()
+    /// Frames that weren't allocated by this allocator, e.g., device memory, are ignored. 
+    /// Frames cannot be deallocated before the heap is set up, so they are leaked. 
+    fn deallocate_frame(&mut self, frame: Frame) {
+        if !self.is_usable(frame) {
+            return;
+        }
+        let buddy = match self.buddy {
+            Some(ref mut buddy) => buddy,
+            None => {
+                warn!("AreaFrameAllocator::deallocate_frame(): leaking frame {:?}, deallocation isn't supported before the heap is set up", frame);
+                return;
+            }
+        };
+        if !buddy.covers(frame) {
+            error!("BUG: AreaFrameAllocator::deallocate_frame(): frame {:?} is beyond the allocator's range", frame);
+        } else if buddy.is_free(frame) {
+            error!("BUG: AreaFrameAllocator::deallocate_frame(): frame {:?} was already free!", frame);
+        } else {
+            buddy.free_range(frame, frame);
+            record_frame_deallocation(1);
+        }
     }
 
 
-    /// Call this when the kernel heap has been set up
+    /// Call this when the kernel heap has been set up.
+    /// 
+    /// This hands over all frames that haven't yet been allocated to a buddy allocator,
+    /// after which frames can be deallocated.
     fn alloc_ready(&mut self) {
         self.available.upgrade_to_vector();
         self.occupied.upgrade_to_vector();
+        if self.buddy.is_some() {
+            return;
+        }
+
+        // The buddy allocator must cover all available areas, including the frames already allocated, which may be freed later.
+        let mut lowest: Option<Frame> = None;
+        let mut highest: Option<Frame> = None;
+        self.for_each_usable_range(Frame::containing_address(PhysicalAddress::zero()), |start, end| {
+            if lowest.as_ref().map_or(true, |l| start < *l) { lowest = Some(start); }
+            if highest.as_ref().map_or(true, |h| end > *h) { highest = Some(end); }
+        });
+        let (lowest, highest) = match (lowest, highest) {
+            (Some(l), Some(h)) => (l, h),
+            _ => {
+                error!("AreaFrameAllocator::alloc_ready(): there are no available memory areas!");
+                return;
+            }
+        };
+
+        let stats = self.stats();
+        let mut buddy = BuddyAllocator::new(lowest, highest);
+        let next_free_frame = self.next_free_frame;
+        self.for_each_usable_range(next_free_frame, |start, end| buddy.free_range(start, end));
+        debug!("AreaFrameAllocator: switched to buddy allocator with {} of {} frames free", buddy.free_frames(), stats.total_frames);
+        self.total_frames = stats.total_frames;
+        self.buddy = Some(buddy);
     }
 }
@@ -0,0 +1,330 @@
+//! A binary buddy allocator for physical memory frames.
+//!
+//! Free memory is tracked as blocks of `2^order` frames that are aligned to their own size,
+//! using one bitmap per order, in which a set bit marks a free block that is not part of a larger free block.
+//! When a block is freed while its buddy (the other half of the enclosing block of the next order) is also free,
+//! the two are merged into one block of the next order, so freed memory doesn't stay fragmented.
+//!
+//! The bitmaps are allocated once when the allocator is created,
+//! so allocating and deallocating frames never uses the heap, which itself needs frames in order to grow.
+
+use core::{cmp, iter};
+use alloc::vec::Vec;
+use super::{Frame, FrameRange};
+
+
+/// The largest block holds `2^MAX_ORDER` frames, i.e., 1 GiB.
+pub const MAX_ORDER: usize = 18;
+const NUM_ORDERS: usize = MAX_ORDER + 1;
+const BITS_PER_WORD: usize = 64;
+
+
+pub struct BuddyAllocator {
+    /// The number of the first frame covered by the bitmaps, which is aligned to the size of the largest block.
+    base: usize,
+    /// The number of frames covered by the bitmaps, which is a multiple of the size of the largest block.
+    num_frames: usize,
+    /// For each order, a bitmap with one bit per block of that order.
+    bitmaps: Vec<Vec<u64>>,
+    /// For each order, the index of the first word in its bitmap that may have a bit set.
+    first_word_hint: [usize; NUM_ORDERS],
+    /// For each order, the number of free blocks, so that orders without free blocks can be skipped.
+    free_blocks: [usize; NUM_ORDERS],
+    free_frames: usize,
+}
+
+impl BuddyAllocator {
+    /// Creates an allocator that can manage all frames from `start` to `end` (inclusive),
+    /// none of which are initially free.
+    pub fn new(start: Frame, end: Frame) -> BuddyAllocator {
+        let largest_block = 1 << MAX_ORDER;
+        let base = start.number & !(largest_block - 1);
+        let num_frames = (end.number / largest_block + 1) * largest_block - base;
+        let bitmaps = (0 .. NUM_ORDERS).map(|order| {
+            let num_blocks = num_frames >> order;
+            iter::repeat(0u64).take((num_blocks + BITS_PER_WORD - 1) / BITS_PER_WORD).collect()
+        }).collect();
+
+        BuddyAllocator {
+            base,
+            num_frames,
+            bitmaps,
+            first_word_hint: [0; NUM_ORDERS],
+            free_blocks: [0; NUM_ORDERS],
+            free_frames: 0,
+        }
+    }
+
+    /// Returns the number of frames that are currently free.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Returns `true` if the given `frame` lies within the range of frames that this allocator can manage.
+    pub fn covers(&self, frame: Frame) -> bool {
+        frame.number >= self.base && frame.number < self.base + self.num_frames
+    }
+
+    /// Returns `true` if the given `frame` is currently free.
+    /// The `frame` must be covered by this allocator.
+    pub fn is_free(&self, frame: Frame) -> bool {
+        let offset = frame.number - self.base;
+        (0 .. NUM_ORDERS).any(|order| self.test(order, offset >> order))
+    }
+
+    /// Marks all frames from `start` to `end` (inclusive) as free, merging them with any adjacent free blocks.
+    ///
+    /// The frames must be covered by this allocator and must not already be free.
+    pub fn free_range(&mut self, start: Frame, end: Frame) {
+        let mut offset = start.number - self.base;
+        let end_offset = end.number - self.base + 1; // exclusive
+        while offset < end_offset {
+            // the largest block that starts at `offset`, which must be aligned to its size, and doesn't extend past the end
+            let mut order = cmp::min(offset.trailing_zeros() as usize, MAX_ORDER);
+            while offset + (1 << order) > end_offset {
+                order -= 1;
+            }
+            self.free_block(order, offset >> order);
+            self.free_frames += 1 << order;
+            offset += 1 << order;
+        }
+    }
+
+    /// Allocates `num_frames` contiguous frames, the first of which has a frame number
+    /// that is a multiple of `alignment`, which must be a power of two.
+    ///
+    /// Returns `None` if there is no free block large enough.
+    pub fn allocate(&mut self, num_frames: usize, alignment: usize) -> Option<FrameRange> {
+        if num_frames == 0 || !alignment.is_power_of_two() {
+            return None;
+        }
+        let block_size = cmp::max(num_frames.next_power_of_two(), alignment);
+        let order = block_size.trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            error!("BuddyAllocator::allocate(): cannot allocate {} frames aligned to {} frames, the largest block is {} frames",
+                num_frames, alignment, 1 << MAX_ORDER
+            );
+            return None;
+        }
+
+        // Take the smallest free block that is at least as large as needed, and split it down to the needed order,
+        // freeing the upper half of each split.
+        let mut found_order = (order .. NUM_ORDERS).find(|&o| self.free_blocks[o] > 0)?;
+        let mut index = self.find_free_block(found_order)?;
+        self.clear(found_order, index);
+        while found_order > order {
+            found_order -= 1;
+            index <<= 1;
+            self.set(found_order, index + 1);
+        }
+        self.free_frames -= block_size;
+
+        let start = Frame { number: self.base + (index << order) };
+        // Return the unneeded frames at the end of the block.
+        if block_size > num_frames {
+            self.free_range(start + num_frames, start + (block_size - 1));
+        }
+        Some(FrameRange::new(start, start + (num_frames - 1)))
+    }
+
+    /// Removes all free frames from `start` to `end` (inclusive) such that they will never be allocated,
+    /// ignoring any frames that aren't covered by this allocator.
+    ///
+    /// Returns the number of frames in that range that were free.
+    pub fn reserve_range(&mut self, start: Frame, end: Frame) -> usize {
+        let start = cmp::max(start.number, self.base);
+        let end = cmp::min(end.number, self.base + self.num_frames - 1);
+        if start > end {
+            return 0;
+        }
+        let (start, end) = (start - self.base, end - self.base);
+
+        let mut reserved = 0;
+        for order in (0 .. NUM_ORDERS).rev() {
+            for index in (start >> order) ..= (end >> order) {
+                if !self.test(order, index) {
+                    continue;
+                }
+                self.clear(order, index);
+                self.free_frames -= 1 << order;
+                // Give back the parts of this free block that lie outside of the reserved range.
+                let block_start = index << order;
+                let block_end = block_start + (1 << order) - 1;
+                reserved += cmp::min(block_end, end) + 1 - cmp::max(block_start, start);
+                if block_start < start {
+                    self.free_range(Frame { number: self.base + block_start }, Frame { number: self.base + start - 1 });
+                }
+                if block_end > end {
+                    self.free_range(Frame { number: self.base + end + 1 }, Frame { number: self.base + block_end });
+                }
+            }
+        }
+        reserved
+    }
+
+
+    /// Frees the block at `index` of the given `order`, merging it with its buddy for as long as that buddy is free.
+    fn free_block(&mut self, mut order: usize, mut index: usize) {
+        while order < MAX_ORDER && self.test(order, index ^ 1) {
+            self.clear(order, index ^ 1);
+            index >>= 1;
+            order += 1;
+        }
+        self.set(order, index);
+    }
+
+    /// Returns the index of the first free block of the given `order`.
+    fn find_free_block(&mut self, order: usize) -> Option<usize> {
+        let found = self.bitmaps[order].iter()
+            .enumerate()
+            .skip(self.first_word_hint[order])
+            .find(|(_, &word)| word != 0)
+            .map(|(i, &word)| (i, i * BITS_PER_WORD + word.trailing_zeros() as usize));
+        match found {
+            Some((word_index, block_index)) => {
+                self.first_word_hint[order] = word_index;
+                Some(block_index)
+            }
+            None => {
+                self.first_word_hint[order] = self.bitmaps[order].len();
+                None
+            }
+        }
+    }
+
+    fn test(&self, order: usize, index: usize) -> bool {
+        self.bitmaps[order]
+            .get(index / BITS_PER_WORD)
+            .map(|word| word & (1 << (index % BITS_PER_WORD)) != 0)
+            .unwrap_or(false)
+    }
+
+    fn set(&mut self, order: usize, index: usize) {
+        let word_index = index / BITS_PER_WORD;
+        self.bitmaps[order][word_index] |= 1 << (index % BITS_PER_WORD);
+        self.free_blocks[order] += 1;
+        self.first_word_hint[order] = cmp::min(self.first_word_hint[order], word_index);
+    }
+
+    fn clear(&mut self, order: usize, index: usize) {
+        self.bitmaps[order][index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+        self.free_blocks[order] -= 1;
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LARGEST_BLOCK: usize = 1 << MAX_ORDER;
+
+    fn frame(number: usize) -> Frame {
+        Frame { number }
+    }
+
+    /// Returns an allocator covering one largest block, of which frames `0` to `num_free - 1` are free.
+    fn allocator_with_free_frames(num_free: usize) -> BuddyAllocator {
+        let mut buddy = BuddyAllocator::new(frame(0), frame(LARGEST_BLOCK - 1));
+        buddy.free_range(frame(0), frame(num_free - 1));
+        buddy
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let mut buddy = allocator_with_free_frames(LARGEST_BLOCK);
+        assert_eq!(buddy.free_blocks[MAX_ORDER], 1);
+
+        // allocating one frame splits the largest block once per order
+        let frames = buddy.allocate(1, 1).expect("allocation failed");
+        assert_eq!(*frames.start(), frame(0));
+        assert_eq!(buddy.free_frames(), LARGEST_BLOCK - 1);
+        for order in 0 .. MAX_ORDER {
+            assert_eq!(buddy.free_blocks[order], 1, "order {} should have one free block after splitting", order);
+        }
+        assert_eq!(buddy.free_blocks[MAX_ORDER], 0);
+
+        // freeing it again merges all of the split blocks back into the largest block
+        buddy.free_range(frame(0), frame(0));
+        assert_eq!(buddy.free_frames(), LARGEST_BLOCK);
+        for order in 0 .. MAX_ORDER {
+            assert_eq!(buddy.free_blocks[order], 0, "order {} should have no free blocks after merging", order);
+        }
+        assert_eq!(buddy.free_blocks[MAX_ORDER], 1);
+    }
+
+    #[test]
+    fn unaligned_free_range_is_split_into_aligned_blocks() {
+        let mut buddy = BuddyAllocator::new(frame(0), frame(LARGEST_BLOCK - 1));
+        buddy.free_range(frame(3), frame(12));
+        assert_eq!(buddy.free_frames(), 10);
+        // frames 3, 4-7, 8-11, and 12
+        assert_eq!(buddy.free_blocks[0], 2);
+        assert_eq!(buddy.free_blocks[2], 2);
+        assert!(!buddy.is_free(frame(2)));
+        assert!((3 ..= 12).all(|n| buddy.is_free(frame(n))));
+        assert!(!buddy.is_free(frame(13)));
+    }
+
+    #[test]
+    fn aligned_allocation() {
+        let mut buddy = allocator_with_free_frames(LARGEST_BLOCK);
+        buddy.allocate(1, 1).expect("allocation failed");
+
+        let frames = buddy.allocate(3, 16).expect("aligned allocation failed");
+        assert_eq!(frames.start().number % 16, 0);
+        assert_eq!(frames.size_in_frames(), 3);
+        // the rest of the 16-frame block that was taken is given back
+        assert_eq!(buddy.free_frames(), LARGEST_BLOCK - 1 - 3);
+        assert!(buddy.is_free(*frames.end() + 1));
+
+        let frames = buddy.allocate(1, 1 << 10).expect("aligned allocation failed");
+        assert_eq!(frames.start().number % (1 << 10), 0);
+    }
+
+    #[test]
+    fn invalid_allocations() {
+        let mut buddy = allocator_with_free_frames(LARGEST_BLOCK);
+        assert!(buddy.allocate(0, 1).is_none());
+        assert!(buddy.allocate(1, 3).is_none(), "the alignment must be a power of two");
+        assert!(buddy.allocate(LARGEST_BLOCK + 1, 1).is_none(), "no block is larger than the largest block");
+        assert!(buddy.allocate(LARGEST_BLOCK, 1).is_some());
+        assert!(buddy.allocate(1, 1).is_none(), "all frames were already allocated");
+    }
+
+    #[test]
+    fn reserved_frames_are_never_allocated() {
+        let mut buddy = allocator_with_free_frames(1024);
+        assert_eq!(buddy.reserve_range(frame(100), frame(199)), 100);
+        assert_eq!(buddy.free_frames(), 924);
+        assert!(buddy.is_free(frame(99)));
+        assert!(!buddy.is_free(frame(100)));
+        assert!(!buddy.is_free(frame(199)));
+        assert!(buddy.is_free(frame(200)));
+
+        // reserving frames that are already reserved, or that aren't covered, reserves nothing
+        assert_eq!(buddy.reserve_range(frame(150), frame(160)), 0);
+        assert_eq!(buddy.reserve_range(frame(LARGEST_BLOCK), frame(2 * LARGEST_BLOCK)), 0);
+
+        for _ in 0 .. 924 {
+            let frames = buddy.allocate(1, 1).expect("allocation failed");
+            assert!(frames.start().number < 100 || frames.start().number > 199, "allocated reserved frame {:?}", frames.start());
+        }
+        assert!(buddy.allocate(1, 1).is_none());
+    }
+
+    #[test]
+    fn freed_frames_are_reported_free_for_double_free_detection() {
+        let mut buddy = allocator_with_free_frames(8);
+        let frames = buddy.allocate(1, 1).expect("allocation failed");
+        let allocated = *frames.start();
+        assert!(!buddy.is_free(allocated));
+
+        // after it's freed, the frame is merged back into a larger block,
+        // but is still reported as free, such that freeing it again can be detected
+        buddy.free_range(allocated, allocated);
+        assert_eq!(buddy.free_blocks[3], 1);
+        assert!((0 .. 8).all(|n| buddy.is_free(frame(n))));
+        assert_eq!(buddy.free_frames(), 8);
+    }
+}
@@ -25,7 +25,9 @@ extern crate x86_64;
 extern crate memory_structs;
 
 
+mod accounting;
 mod area_frame_allocator;
+mod buddy_allocator;
 mod stack_allocator;
 #[cfg(not(mapper_spillful))]
 mod paging;
@@ -34,7 +36,9 @@ mod paging;
 pub mod paging;
 
 
-pub use self::area_frame_allocator::AreaFrameAllocator;
+pub use self::accounting::{MemoryUsage, MemoryUsageStats, MappedPagesStats, set_current_memory_usage_cb,
+    system_memory_usage, mapped_pages_stats, record_heap_allocation, record_heap_deallocation};
+pub use self::area_frame_allocator::{AreaFrameAllocator, FrameStats};
 pub use self::paging::*;
 pub use self::stack_allocator::{StackAllocator, Stack};
 
@@ -48,6 +52,7 @@ pub use memory_x86_64::EntryFlags;// Export EntryFlags so that others does not n
 
 
 use spin::Once;
+use paging::FrameOwnership;
 use irq_safety::MutexIrqSafe;
 use alloc::vec::Vec;
 use alloc::sync::Arc;
@@ -92,6 +97,30 @@ pub fn allocate_frames(num_frames: usize) -> Option<FrameRange> {
     FRAME_ALLOCATOR.try().and_then(|fa| fa.lock().allocate_frames(num_frames))
 }
 
+/// Convenience method for allocating several contiguous Frames, 
+/// the first of which is aligned to `alignment` frames, which must be a power of two.
+pub fn allocate_aligned_frames(num_frames: usize, alignment: usize) -> Option<FrameRange> {
+    let frames = FRAME_ALLOCATOR.try().and_then(|fa| fa.lock().allocate_aligned_frames(num_frames, alignment));
+    if frames.is_none() {
+        error!("allocate_aligned_frames(): couldn't allocate {} contiguous frames aligned to {} frames", num_frames, alignment);
+    }
+    frames
+}
+
+/// Convenience method for deallocating Frames that were obtained from [`allocate_frames()`](fn.allocate_frames.html)
+/// or a similar function, and are not mapped by a `MappedPages` that will deallocate them itself.
+pub fn deallocate_frames(frames: FrameRange) {
+    if let Some(fa) = FRAME_ALLOCATOR.try() {
+        fa.lock().deallocate_frames(frames);
+    }
+}
+
+/// Returns the number of frames managed by the system-wide frame allocator and how many of them are free,
+/// or `None` if it wasn't yet initialized.
+pub fn frame_stats() -> Option<FrameStats> {
+    FRAME_ALLOCATOR.try().map(|fa| fa.lock().stats())
+}
+
 
 /// This holds all the information for a `Task`'s memory mappings and address space
 /// (this is basically the equivalent of Linux's mm_struct)
@@ -122,6 +151,15 @@ impl MemoryManagementInfo {
             self.stack_allocator.alloc_stack(&mut self.page_table, fa, size_in_pages)
         )
     }
+
+    /// Same as [`alloc_stack()`](#method.alloc_stack), but allocates a growable stack of up to `size_in_pages`,
+    /// whose pages are only backed by frames once the stack grows into them.
+    /// See [`StackAllocator::alloc_growable_stack()`](struct.StackAllocator.html#method.alloc_growable_stack).
+    pub fn alloc_growable_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
+        get_frame_allocator_ref().and_then(|fa| 
+            self.stack_allocator.alloc_growable_stack(&mut self.page_table, fa, size_in_pages)
+        )
+    }
 }
 
 
@@ -143,7 +181,9 @@ pub fn create_contiguous_mapping(size_in_bytes: usize, flags: EntryFlags) -> Res
     let frames = frame_allocator.allocate_frames(allocated_pages.size_in_pages())
         .ok_or("create_contiguous_mapping(): couldnt allocate a new frame")?;
     let starting_phys_addr = frames.start_address();
-    let mp = kernel_mmi.page_table.map_allocated_pages_to(allocated_pages, frames, flags, &mut *frame_allocator)?;
+    let mut mp = kernel_mmi.page_table.map_allocated_pages_to(allocated_pages, frames, flags, &mut *frame_allocator)?;
+    // the frames were allocated just for this mapping, so they should be deallocated along with it
+    mp.frames = FrameOwnership::Owned;
     Ok((mp, starting_phys_addr))
 }
 
@@ -262,6 +302,11 @@ pub fn init_post_heap(page_table: PageTable, mut higher_half_mapped_pages: [Opti
     let mut higher_half_mapped_pages: Vec<MappedPages> = higher_half_mapped_pages.iter_mut().filter_map(|opt| opt.take()).collect();
     higher_half_mapped_pages.push(heap_mapped_pages);
     let identity_mapped_pages: Vec<MappedPages> = identity_mapped_pages.iter_mut().filter_map(|opt| opt.take()).collect();
+
+    // now that the heap is ready, the frame allocator can switch to an allocator that supports deallocation
+    if let Some(fa) = FRAME_ALLOCATOR.try() {
+        fa.lock().alloc_ready();
+    }
    
     // init the kernel stack allocator, a singleton
     let kernel_stack_allocator = {
@@ -288,7 +333,19 @@ pub fn init_post_heap(page_table: PageTable, mut higher_half_mapped_pages: [Opti
 pub trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame>;
     fn allocate_frames(&mut self, num_frames: usize) -> Option<FrameRange>;
+    /// Allocates `num_frames` contiguous frames, the first of which is aligned to `alignment` frames,
+    /// which must be a power of two. 
+    /// Returns `None` without logging an error if that isn't possible, so the caller can fall back to smaller allocations.
+    fn allocate_aligned_frames(&mut self, _num_frames: usize, _alignment: usize) -> Option<FrameRange> {
+        None
+    }
     fn deallocate_frame(&mut self, frame: Frame);
+    /// Deallocates each frame in the given range.
+    fn deallocate_frames(&mut self, frames: FrameRange) {
+        for frame in frames {
+            self.deallocate_frame(frame);
+        }
+    }
     /// Call this when a heap is set up, and the `alloc` types can be used.
     fn alloc_ready(&mut self);
 }
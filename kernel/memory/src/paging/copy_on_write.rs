@@ -0,0 +1,172 @@
+//! Copy-on-write mappings, which share their frames with the mapping they were copied from until either one writes to them.
+//!
+//! [`MappedPages::copy_on_write()`] maps new pages to the same frames as an existing mapping,
+//! and records in `COW_FRAMES` how many mappings share each of those frames.
+//! The writable pages of both mappings are then mapped read-only and marked with the `COPY_ON_WRITE` entry flag.
+//! When such a page is written, the page fault handler invokes [`handle_cow_page_fault()`],
+//! which copies the shared frame into a new frame that only the faulting mapping uses
+//! and maps it writable, after which the faulting write is retried.
+//! The last mapping that still uses a shared frame simply maps it writable again without copying it.
+//!
+//! Thus, copying a large mapping of which only a few pages are ever written only costs those few pages.
+//!
+//! [`MappedPages::copy_on_write_one_sided()`] does the same, but leaves the existing mapping writable,
+//! such that only the new mapping's pages are copied when written,
+//! while writes through the existing mapping are visible in the new mapping's pages that weren't yet copied.
+//!
+//! # Locking / Deadlock
+//! Because pages are copied from within the page fault handler, a copy-on-write page
+//! must not be written while holding the lock on the frame allocator or the virtual page allocator.
+//! The frame allocator's lock may be held while `COW_FRAMES` is locked, but never the other way around.
+
+use core::ops::DerefMut;
+use alloc::collections::BTreeMap;
+use irq_safety::MutexIrqSafe;
+use kernel_config::memory::PAGE_SIZE;
+use {BROADCAST_TLB_SHOOTDOWN_FUNC, Frame, FrameRange, FrameAllocator, Page, PageRange, VirtualAddress, EntryFlags, get_frame_allocator_ref};
+use super::{Mapper, MappedPages, PageSize, allocate_pages, tlb_flush_virt_addr};
+
+
+lazy_static! {
+    /// The number of copy-on-write mappings that share each frame, for each frame that is shared by at least two of them.
+    /// A frame is removed once only one mapping still uses it, which then owns that frame exclusively.
+    static ref COW_FRAMES: MutexIrqSafe<BTreeMap<Frame, usize>> = MutexIrqSafe::new(BTreeMap::new());
+}
+
+
+/// Returns the flags of a page table entry that maps a page of a copy-on-write mapping with the given `flags`:
+/// writable pages are mapped read-only and marked `COPY_ON_WRITE`, such that the first write to them causes a page fault.
+pub(crate) fn cow_entry_flags(flags: EntryFlags) -> EntryFlags {
+    if flags.is_writable() {
+        (flags - EntryFlags::WRITABLE) | EntryFlags::COPY_ON_WRITE
+    } else {
+        flags
+    }
+}
+
+/// Records that the given `frames`, which were used by one mapping, are now also used by another copy-on-write mapping.
+pub(crate) fn share_frames(frames: &[Frame]) {
+    let mut cow_frames = COW_FRAMES.lock();
+    for frame in frames {
+        *cow_frames.entry(*frame).or_insert(1) += 1;
+    }
+}
+
+/// Returns `true` if the given `frame` is shared by multiple copy-on-write mappings.
+pub(crate) fn is_shared_cow_frame(frame: Frame) -> bool {
+    COW_FRAMES.lock().contains_key(&frame)
+}
+
+/// Returns `true` if any of the given `frames` is shared by multiple copy-on-write mappings.
+pub(crate) fn any_shared_cow_frames(frames: &FrameRange) -> bool {
+    COW_FRAMES.lock().range(*frames.start() ..= *frames.end()).next().is_some()
+}
+
+/// Records that a mapping of the given `frame` was unmapped,
+/// and returns `true` if that frame is still used by another copy-on-write mapping, such that it must not be deallocated.
+pub(crate) fn release_cow_frame(frame: Frame) -> bool {
+    let mut cow_frames = COW_FRAMES.lock();
+    let remaining = match cow_frames.get_mut(&frame) {
+        Some(count) => {
+            *count -= 1;
+            *count
+        }
+        None => return false,
+    };
+    if remaining == 1 {
+        cow_frames.remove(&frame);
+    }
+    true
+}
+
+
+/// Handles a page fault caused by a write to the given `vaddr` by giving the page that contains it a private copy of its frame,
+/// if that page is part of a copy-on-write mapping.
+///
+/// Returns `Ok(true)` if the page can now be written, such that the faulting write can be retried,
+/// `Ok(false)` if the page isn't part of a writable copy-on-write mapping,
+/// or an error if the page couldn't be copied, e.g., because there are no free frames.
+pub fn handle_cow_page_fault(vaddr: VirtualAddress) -> Result<bool, &'static str> {
+    let page = Page::containing_address(vaddr);
+    let mut mapper = Mapper::from_current();
+    match mapper.entry(page) {
+        Some((entry, PageSize::Normal4KiB)) if entry.flags().contains(EntryFlags::PRESENT | EntryFlags::COPY_ON_WRITE) => { }
+        Some((entry, _)) if entry.flags().contains(EntryFlags::PRESENT | EntryFlags::WRITABLE) => return Ok(true),
+        _ => return Ok(false),
+    }
+
+    // The frame for the private copy is allocated and mapped before `COW_FRAMES` is locked,
+    // because `MappedPages::copy_on_write()` locks `COW_FRAMES` while its caller holds the frame allocator's lock,
+    // so the frame allocator must never be locked while holding `COW_FRAMES`.
+    let allocator_ref = get_frame_allocator_ref().ok_or("handle_cow_page_fault(): couldn't get frame allocator")?;
+    let new_frame = allocator_ref.lock().allocate_frame().ok_or("handle_cow_page_fault(): couldn't allocate frame, out of memory!")?;
+    let copy_mapping = allocate_pages(1).ok_or("handle_cow_page_fault(): couldn't allocate temporary pages").and_then(|temporary_pages|
+        mapper.map_allocated_pages_to(temporary_pages, FrameRange::new(new_frame, new_frame), EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, allocator_ref.lock().deref_mut())
+    );
+    let mut copy_mapping = match copy_mapping {
+        Ok(mp) => mp,
+        Err(e) => {
+            allocator_ref.lock().deallocate_frame(new_frame);
+            return Err(e);
+        }
+    };
+
+    let result = remap_cow_page(&mut mapper, page, new_frame, &mut copy_mapping);
+    // The temporary mapping borrows the new frame, so unmapping it doesn't deallocate that frame.
+    drop(copy_mapping);
+    match result {
+        Ok((writable, new_frame_used)) => {
+            if !new_frame_used {
+                allocator_ref.lock().deallocate_frame(new_frame);
+            }
+            Ok(writable)
+        }
+        Err(e) => {
+            allocator_ref.lock().deallocate_frame(new_frame);
+            Err(e)
+        }
+    }
+}
+
+
+/// Makes the given copy-on-write `page` writable, copying its contents into `new_frame` through `copy_mapping`, 
+/// which maps that frame, if its current frame is still shared with other mappings.
+///
+/// Returns whether the page can now be written (see `handle_cow_page_fault()`) and whether `new_frame` is now used by the page.
+fn remap_cow_page(mapper: &mut Mapper, page: Page, new_frame: Frame, copy_mapping: &mut MappedPages) -> Result<(bool, bool), &'static str> {
+    // The lock is held until the page is remapped, so that other cores that fault on the same page wait until it's done,
+    // and the number of mappings that share the frame can't change in the meantime.
+    let mut cow_frames = COW_FRAMES.lock();
+    let (frame, flags) = match mapper.entry(page) {
+        Some((entry, PageSize::Normal4KiB)) if entry.flags().contains(EntryFlags::PRESENT | EntryFlags::COPY_ON_WRITE) => {
+            (entry.pointed_frame().ok_or("handle_cow_page_fault(): page not mapped")?, entry.flags())
+        }
+        // another core may have copied this page while this one was waiting for the lock
+        Some((entry, _)) if entry.flags().contains(EntryFlags::PRESENT | EntryFlags::WRITABLE) => return Ok((true, false)),
+        _ => return Ok((false, false)),
+    };
+
+    let new_frame_used = cow_frames.contains_key(&frame);
+    if new_frame_used {
+        // The page is still mapped read-only to the shared frame, so it can be copied through the page itself.
+        // No other mapping can write that frame while it's shared, except for the original mapping of a one-sided copy,
+        // whose concurrent writes may or may not be visible in the copy.
+        let source = unsafe { core::slice::from_raw_parts(page.start_address().value() as *const u8, PAGE_SIZE) };
+        copy_mapping.as_slice_mut::<u8>(0, PAGE_SIZE)?.copy_from_slice(source);
+        let remaining = cow_frames.get_mut(&frame).map(|count| { *count -= 1; *count }).unwrap_or(0);
+        if remaining <= 1 {
+            cow_frames.remove(&frame);
+        }
+    }
+    // otherwise, this is the last mapping that uses the frame, so it can simply be written
+    let writable_frame = if new_frame_used { new_frame } else { frame };
+
+    let (entry, _) = mapper.entry_mut(page).ok_or("handle_cow_page_fault(): page not mapped")?;
+    entry.set(writable_frame, (flags - EntryFlags::COPY_ON_WRITE) | EntryFlags::WRITABLE);
+    tlb_flush_virt_addr(page.start_address());
+    // other cores must not keep using the shared frame through this mapping
+    if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.try() {
+        func(PageRange::new(page, page));
+    }
+    Ok((true, new_frame_used))
+}
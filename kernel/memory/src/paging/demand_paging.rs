@@ -0,0 +1,237 @@
+//! Demand paging, i.e., lazy mappings whose pages are only backed by physical frames once they are first accessed.
+//!
+//! A lazy mapping, created by [`Mapper::map_allocated_pages_lazily()`] or [`Mapper::map_pages_lazily()`],
+//! reserves a range of virtual pages and records it here, but doesn't map any of them.
+//! When a page in that range is first accessed, the page fault handler invokes [`handle_lazy_page_fault()`],
+//! which allocates a new frame, fills it with the contents given by the mapping's [`PageProvider`] (or zeroes it),
+//! and maps it with the mapping's flags, after which the faulting access is retried.
+//! This allows reserving large, sparsely-used buffers or growable stacks that only use memory for the pages that are touched,
+//! such as `Private` file mappings in the `mmap` crate, which copy each page of the file when it's first accessed.
+//!
+//! A growable stack can't be populated by the page fault handler when the faulting task's stack pointer
+//! has moved into an unpopulated page, because the page fault itself can't be pushed onto that stack.
+//! That causes a double fault instead, which runs on its own stack and invokes [`handle_lazy_stack_fault()`].
+//!
+//! Accesses outside of any lazy mapping, or that aren't permitted by the lazy mapping's flags,
+//! are not handled here, such that they are still reported as invalid accesses.
+//!
+//! # Locking / Deadlock
+//! Because pages are populated from within the page fault handler, an unpopulated page of a lazy mapping
+//! must not be accessed while holding the lock on the frame allocator or the virtual page allocator.
+//! Growable stacks are the exception, since any code may grow its stack; see [`handle_lazy_stack_fault()`].
+
+use core::mem;
+use core::ops::DerefMut;
+use alloc::sync::Arc;
+use alloc::collections::BTreeMap;
+use irq_safety::{MutexIrqSafe, MutexIrqSafeGuard};
+use kernel_config::memory::PAGE_SIZE;
+use {Frame, FrameRange, FrameAllocator, Page, PageRange, VirtualAddress, EntryFlags, get_frame_allocator_ref};
+use super::{Mapper, allocate_pages};
+use accounting::record_mapped_pages_dropped;
+
+
+/// Provides the contents of the pages of a lazy mapping when they are first accessed,
+/// e.g., by reading them from a file or from swap space.
+pub trait PageProvider: Send + Sync {
+    /// Fills the given `page`, a page-sized buffer, with the contents of the page
+    /// that starts at `offset` bytes from the beginning of the lazy mapping.
+    ///
+    /// This is invoked from the page fault handler with interrupts disabled,
+    /// so it must not block or wait for an interrupt to occur.
+    fn provide_page(&self, offset: usize, page: &mut [u8]) -> Result<(), &'static str>;
+}
+
+
+/// A range of pages that is mapped lazily.
+struct LazyRegion {
+    /// The last page of this region (inclusive); the first page is its key in `LAZY_REGIONS`.
+    end: Page,
+    /// The flags with which pages are mapped when they are populated.
+    flags: EntryFlags,
+    /// The frame containing the P4 page table in which this region is mapped.
+    page_table_p4: Frame,
+    /// Provides the initial contents of each page; pages are zeroed if there is none.
+    provider: Option<Arc<dyn PageProvider>>,
+}
+
+lazy_static! {
+    /// All lazy mappings, keyed by their first page.
+    static ref LAZY_REGIONS: MutexIrqSafe<BTreeMap<Page, LazyRegion>> = MutexIrqSafe::new(BTreeMap::new());
+}
+
+
+/// Records that the given `pages` are mapped lazily, such that they are populated when first accessed.
+pub(crate) fn register_lazy_region(pages: &PageRange, flags: EntryFlags, page_table_p4: Frame, provider: Option<Arc<dyn PageProvider>>)
+    -> Result<(), &'static str>
+{
+    let mut regions = LAZY_REGIONS.lock();
+    if let Some((_, region)) = regions.range(..= *pages.end()).next_back() {
+        if region.end >= *pages.start() {
+            return Err("pages overlap an existing lazy mapping");
+        }
+    }
+    regions.insert(*pages.start(), LazyRegion { end: *pages.end(), flags, page_table_p4, provider });
+    Ok(())
+}
+
+/// Removes the lazy mapping that starts at the given page, such that none of its pages can be populated anymore.
+pub(crate) fn unregister_lazy_region(start: Page) {
+    let region = LAZY_REGIONS.lock().remove(&start);
+    // the region's provider is dropped here, after the lock was released
+    drop(region);
+}
+
+/// Sets the flags with which the remaining pages of the lazy mapping that starts at the given page will be populated.
+pub(crate) fn set_lazy_region_flags(start: Page, flags: EntryFlags) {
+    if let Some(region) = LAZY_REGIONS.lock().get_mut(&start) {
+        region.flags = flags;
+    }
+}
+
+
+/// Handles a page fault at the given `vaddr` by populating the page that contains it,
+/// if that page is part of a lazy mapping that permits the faulting access.
+///
+/// # Arguments
+/// * `vaddr`: the address whose access caused the page fault.
+/// * `is_write`: whether the access was a write.
+/// * `is_instruction_fetch`: whether the access was an instruction fetch.
+///
+/// Returns `Ok(true)` if the page was populated, such that the faulting access can be retried,
+/// `Ok(false)` if the page isn't part of a lazy mapping or the access isn't permitted by its flags,
+/// or an error if the page couldn't be populated, e.g., because there are no free frames.
+pub fn handle_lazy_page_fault(vaddr: VirtualAddress, is_write: bool, is_instruction_fetch: bool) -> Result<bool, &'static str> {
+    let page = Page::containing_address(vaddr);
+
+    // The lock is held until the page is populated, so that other cores that fault on the same page wait until it's done,
+    // and the lazy mapping can't be unmapped in the meantime.
+    let regions = LAZY_REGIONS.lock();
+    let (start, region) = match regions.range(..= page).next_back() {
+        Some((&start, region)) if page <= region.end => (start, region),
+        _ => return Ok(false),
+    };
+    if (is_write && !region.flags.is_writable()) || (is_instruction_fetch && !region.flags.is_executable()) {
+        return Ok(false);
+    }
+
+    let mut mapper = Mapper::from_current();
+    if mapper.target_p4 != region.page_table_p4 {
+        return Ok(false);
+    }
+    // another core may have populated this page while this one was waiting for the lock
+    if mapper.translate_page(page).is_some() {
+        return Ok(true);
+    }
+
+    let allocator_ref = get_frame_allocator_ref().ok_or("handle_lazy_page_fault(): couldn't get frame allocator")?;
+    let frame = allocator_ref.lock().allocate_frame().ok_or("handle_lazy_page_fault(): couldn't allocate frame, out of memory!")?;
+    let offset = page.start_address().value() - start.start_address().value();
+    let result = fill_frame(&mut mapper, frame, offset, region.provider.as_ref())
+        .and_then(|_| mapper.map_to(page, frame, region.flags, allocator_ref.lock().deref_mut()));
+    match result {
+        Ok(mp) => {
+            // the lazy mapping's `MappedPages` unmaps this page and deallocates its frame
+            mem::forget(mp);
+            record_mapped_pages_dropped(1);
+            Ok(true)
+        }
+        Err(e) => {
+            allocator_ref.lock().deallocate_frame(frame);
+            Err(e)
+        }
+    }
+}
+
+
+/// Fills the given `frame` with the contents of the page at `offset` bytes from the beginning of a lazy mapping.
+///
+/// The frame is filled through a temporary mapping before it's mapped to its lazy page,
+/// such that other cores can never see its contents before they're complete.
+fn fill_frame(mapper: &mut Mapper, frame: Frame, offset: usize, provider: Option<&Arc<dyn PageProvider>>) -> Result<(), &'static str> {
+    let allocator_ref = get_frame_allocator_ref().ok_or("handle_lazy_page_fault(): couldn't get frame allocator")?;
+    let temporary_page = allocate_pages(1).ok_or("handle_lazy_page_fault(): couldn't allocate temporary page")?;
+    let mut temporary_mapping = mapper.map_allocated_pages_to(
+        temporary_page,
+        FrameRange::new(frame, frame),
+        EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+        allocator_ref.lock().deref_mut(),
+    )?;
+
+    let contents = temporary_mapping.as_slice_mut::<u8>(0, PAGE_SIZE)?;
+    match provider {
+        Some(provider) => provider.provide_page(offset, contents),
+        None => {
+            for byte in contents.iter_mut() {
+                *byte = 0;
+            }
+            Ok(())
+        }
+    }
+    // the temporary mapping is unmapped here, which doesn't deallocate the frame
+}
+
+
+/// The number of times that [`handle_lazy_stack_fault()`] tries to acquire a lock before giving up.
+/// Another core may hold the lock for a short while, but if this core already holds it, it will never be released.
+const STACK_FAULT_LOCK_TRIES: usize = 1 << 20;
+
+/// Handles a fault at the given `vaddr` on a growable stack by populating the page that contains it with a zeroed frame,
+/// if that page is part of a writable lazy mapping without a `PageProvider`.
+///
+/// Unlike [`handle_lazy_page_fault()`], this never blocks, so it can be invoked by the double fault handler
+/// when a task's stack grows into an unpopulated page while the interrupted code held the lock on the lazy mappings
+/// or on the frame allocator. In that case, the page isn't populated and an error is returned after retrying for a while.
+/// It also doesn't allocate any virtual pages, because the new frame is zeroed in place after it's mapped,
+/// which is only safe because a stack page is only accessed by the task that owns it.
+///
+/// Returns `Ok(true)` if the page was populated, `Ok(false)` if it isn't part of such a lazy mapping,
+/// or an error if the page couldn't be populated.
+pub fn handle_lazy_stack_fault(vaddr: VirtualAddress) -> Result<bool, &'static str> {
+    let page = Page::containing_address(vaddr);
+
+    let regions = try_lock_for_stack_fault(&LAZY_REGIONS).ok_or("handle_lazy_stack_fault(): the lazy mappings were locked")?;
+    let region = match regions.range(..= page).next_back() {
+        Some((_, region)) if page <= region.end => region,
+        _ => return Ok(false),
+    };
+    if region.provider.is_some() || !region.flags.is_writable() {
+        return Ok(false);
+    }
+
+    let mut mapper = Mapper::from_current();
+    if mapper.target_p4 != region.page_table_p4 {
+        return Ok(false);
+    }
+    if mapper.translate_page(page).is_some() {
+        return Ok(true);
+    }
+
+    let allocator_ref = get_frame_allocator_ref().ok_or("handle_lazy_stack_fault(): couldn't get frame allocator")?;
+    let mut allocator = try_lock_for_stack_fault(allocator_ref).ok_or("handle_lazy_stack_fault(): the frame allocator was locked")?;
+    let frame = allocator.allocate_frame().ok_or("handle_lazy_stack_fault(): couldn't allocate frame, out of memory!")?;
+    match mapper.map_to(page, frame, region.flags, allocator.deref_mut()) {
+        Ok(mp) => {
+            unsafe { core::ptr::write_bytes(page.start_address().value() as *mut u8, 0, PAGE_SIZE); }
+            // the lazy mapping's `MappedPages` unmaps this page and deallocates its frame
+            mem::forget(mp);
+            record_mapped_pages_dropped(1);
+            Ok(true)
+        }
+        Err(e) => {
+            allocator.deallocate_frame(frame);
+            Err(e)
+        }
+    }
+}
+
+/// Acquires the given lock without blocking indefinitely, see [`STACK_FAULT_LOCK_TRIES`].
+fn try_lock_for_stack_fault<T>(lock: &MutexIrqSafe<T>) -> Option<MutexIrqSafeGuard<T>> {
+    for _ in 0 .. STACK_FAULT_LOCK_TRIES {
+        if let Some(guard) = lock.try_lock() {
+            return Some(guard);
+        }
+        core::sync::atomic::spin_loop_hint();
+    }
+    None
+}
@@ -11,12 +11,65 @@ use core::mem;
 use core::ops::Deref;
 use core::ptr::Unique;
 use core::slice;
+use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
 use {BROADCAST_TLB_SHOOTDOWN_FUNC, VirtualAddress, PhysicalAddress, get_frame_allocator_ref, FrameRange, Page, Frame, FrameAllocator, AllocatedPages}; 
-use paging::{PageRange, get_current_p4};
+use paging::{PageRange, Entry, get_current_p4};
 use paging::table::{P4, Table, Level4};
 use kernel_config::memory::{ENTRIES_PER_PAGE_TABLE, PAGE_SIZE};
 use irq_safety::MutexIrqSafe;
-use super::{EntryFlags, tlb_flush_virt_addr};
+use super::{EntryFlags, tlb_flush_virt_addr, supports_1gib_pages};
+use super::demand_paging::{PageProvider, register_lazy_region, unregister_lazy_region, set_lazy_region_flags};
+use super::copy_on_write::{cow_entry_flags, share_frames, is_shared_cow_frame, any_shared_cow_frames, release_cow_frame};
+use accounting::{record_mapped_pages_created, record_mapped_pages_dropped, record_mapped_pages_merged};
+
+
+/// The sizes of pages that a `Mapper` can map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PageSize {
+    /// A normal 4 KiB page, mapped by a P1 entry.
+    Normal4KiB,
+    /// A 2 MiB huge page, mapped by a P2 entry.
+    Huge2MiB,
+    /// A 1 GiB huge page, mapped by a P3 entry. 
+    /// Only supported on CPUs that report it, otherwise 2 MiB huge pages are used instead.
+    Huge1GiB,
+}
+
+impl PageSize {
+    /// Returns the number of normal 4 KiB pages covered by a page of this size.
+    pub fn size_in_pages(&self) -> usize {
+        match self {
+            PageSize::Normal4KiB => 1,
+            PageSize::Huge2MiB => ENTRIES_PER_PAGE_TABLE,
+            PageSize::Huge1GiB => ENTRIES_PER_PAGE_TABLE * ENTRIES_PER_PAGE_TABLE,
+        }
+    }
+
+    /// Returns the size in bytes of a page of this size.
+    pub fn size_in_bytes(&self) -> usize {
+        self.size_in_pages() * PAGE_SIZE
+    }
+
+    /// Returns the next smaller page size, if any.
+    fn smaller(&self) -> Option<PageSize> {
+        match self {
+            PageSize::Normal4KiB => None,
+            PageSize::Huge2MiB => Some(PageSize::Normal4KiB),
+            PageSize::Huge1GiB => Some(PageSize::Huge2MiB),
+        }
+    }
+
+    /// Returns the flags of a page table entry that maps a page of this size with the given `flags`.
+    fn entry_flags(&self, flags: EntryFlags) -> EntryFlags {
+        match self {
+            PageSize::Normal4KiB => flags | EntryFlags::PRESENT,
+            PageSize::Huge2MiB | PageSize::Huge1GiB => flags.into_huge() | EntryFlags::PRESENT,
+        }
+    }
+}
+
 
 pub struct Mapper {
     p4: Unique<Table<Level4>>,
@@ -46,6 +99,7 @@ impl Mapper {
 
     /// Dumps all page table entries at all four levels for the given `VirtualAddress`, 
     /// and also shows their `EntryFlags`.
+    /// If the address is part of a huge page, there are no entries at the levels below the one that maps it.
     /// 
     /// Useful for debugging page faults. 
     pub fn dump_pte(&self, virtual_address: VirtualAddress) {
@@ -54,13 +108,14 @@ impl Mapper {
         let p3 = p4.next_table(page.p4_index());
         let p2 = p3.and_then(|p3| p3.next_table(page.p3_index()));
         let p1 = p2.and_then(|p2| p2.next_table(page.p2_index()));
-        if let Some(_pte) = p1.map(|p1| &p1[page.p1_index()]) {
-            debug!("VirtualAddress: {:#X}:
+        if let Some((_pte, page_size)) = self.entry(page) {
+            debug!("VirtualAddress: {:#X} ({:?} page):
                     P4 entry:        {:#X}   ({:?})
                     P3 entry:        {:#X}   ({:?})
                     P2 entry:        {:#X}   ({:?})
                     P1 entry: (PTE)  {:#X}   ({:?})",
                 virtual_address, 
+                page_size,
                 &p4[page.p4_index()].value(), 
                 &p4[page.p4_index()].flags(),
                 p3.map(|p3| &p3[page.p3_index()]).map(|p3_entry| p3_entry.value()).unwrap_or(0x0), 
@@ -85,41 +140,44 @@ impl Mapper {
     }
 
     /// Translates a virtual memory `Page` to a physical memory `Frame` by walking the page tables.
+    /// If the `page` is part of a huge page, this returns the frame at the same offset within the huge page's frames.
     pub fn translate_page(&self, page: Page) -> Option<Frame> {
-        let p3 = self.p4().next_table(page.p4_index());
-
-        let huge_page = || {
-            p3.and_then(|p3| {
-                let p3_entry = &p3[page.p3_index()];
-                // 1GiB page?
-                if let Some(start_frame) = p3_entry.pointed_frame() {
-                    if p3_entry.flags().is_huge() {
-                        // address must be 1GiB aligned
-                        assert!(start_frame.number % (ENTRIES_PER_PAGE_TABLE * ENTRIES_PER_PAGE_TABLE) == 0);
-                        return Some(Frame {
-                            number: start_frame.number + page.p2_index() * ENTRIES_PER_PAGE_TABLE + page.p1_index(),
-                        });
-                    }
-                }
-                if let Some(p2) = p3.next_table(page.p3_index()) {
-                    let p2_entry = &p2[page.p2_index()];
-                    // 2MiB page?
-                    if let Some(start_frame) = p2_entry.pointed_frame() {
-                        if p2_entry.flags().is_huge() {
-                            // address must be 2MiB aligned
-                            assert!(start_frame.number % ENTRIES_PER_PAGE_TABLE == 0);
-                            return Some(Frame { number: start_frame.number + page.p1_index() });
-                        }
-                    }
-                }
-                None
-            })
-        };
+        let (entry, page_size) = self.entry(page)?;
+        let start_frame = entry.pointed_frame()?;
+        let offset_in_pages = (page.start_address().value() % page_size.size_in_bytes()) / PAGE_SIZE;
+        Some(start_frame + offset_in_pages)
+    }
 
-        p3.and_then(|p3| p3.next_table(page.p3_index()))
-            .and_then(|p2| p2.next_table(page.p2_index()))
-            .and_then(|p1| p1[page.p1_index()].pointed_frame())
-            .or_else(huge_page)
+    /// Returns the page table entry that maps the given `page`, along with the size of the page that it maps:
+    /// a P3 entry for a 1 GiB huge page, a P2 entry for a 2 MiB huge page, or otherwise a P1 entry. 
+    /// 
+    /// The returned entry may not be present, e.g., if the page isn't mapped; 
+    /// returns `None` only if there is no page table at a level above the entry. 
+    fn entry(&self, page: Page) -> Option<(&Entry, PageSize)> {
+        let p3 = self.p4().next_table(page.p4_index())?;
+        if p3[page.p3_index()].flags().is_huge() {
+            return Some((&p3[page.p3_index()], PageSize::Huge1GiB));
+        }
+        let p2 = p3.next_table(page.p3_index())?;
+        if p2[page.p2_index()].flags().is_huge() {
+            return Some((&p2[page.p2_index()], PageSize::Huge2MiB));
+        }
+        let p1 = p2.next_table(page.p2_index())?;
+        Some((&p1[page.p1_index()], PageSize::Normal4KiB))
+    }
+
+    /// Same as [`entry()`](#method.entry), but returns a mutable reference to the entry.
+    fn entry_mut(&mut self, page: Page) -> Option<(&mut Entry, PageSize)> {
+        let p3 = self.p4_mut().next_table_mut(page.p4_index())?;
+        if p3[page.p3_index()].flags().is_huge() {
+            return Some((&mut p3[page.p3_index()], PageSize::Huge1GiB));
+        }
+        let p2 = p3.next_table_mut(page.p3_index())?;
+        if p2[page.p2_index()].flags().is_huge() {
+            return Some((&mut p2[page.p2_index()], PageSize::Huge2MiB));
+        }
+        let p1 = p2.next_table_mut(page.p2_index())?;
+        Some((&mut p1[page.p1_index()], PageSize::Normal4KiB))
     }
 
 
@@ -127,58 +185,159 @@ impl Mapper {
     fn internal_map<A>(&mut self, pages: PageRange, flags: EntryFlags, allocator: &mut A)
         -> Result<MappedPages, &'static str>
         where A: FrameAllocator
+    {
+        self.internal_map_huge(pages, None, flags, PageSize::Normal4KiB, allocator)
+    }
+
+    /// the internal function that actually does all of the mapping from pages to frames.
+    fn internal_map_to<A>(&mut self, pages: PageRange, frames: FrameRange, flags: EntryFlags, allocator: &mut A)
+        -> Result<MappedPages, &'static str>
+        where A: FrameAllocator
+    {
+        let pages_count = pages.size_in_pages();
+        let frames_count = frames.size_in_frames();
+        if pages_count != frames_count {
+            error!("map_to_internal(): page count {} must equal frame count {}!", pages_count, frames_count);
+            return Err("map_to_internal(): page count must equal frame count");
+        }
+        self.internal_map_huge(pages, Some(frames), flags, PageSize::Normal4KiB, allocator)
+    }
+
+    /// the internal function that maps the given `pages` to the given contiguous `frames`, 
+    /// or to newly-allocated frames if no `frames` are provided, using pages of up to `max_page_size`. 
+    /// 
+    /// Each part of the range is mapped with the largest page size that both the pages and frames are aligned to
+    /// and that the rest of the range is large enough for, falling back to smaller pages otherwise,
+    /// e.g., when there are no free frames that are aligned to a huge page. 
+    fn internal_map_huge<A>(&mut self, pages: PageRange, frames: Option<FrameRange>, flags: EntryFlags, max_page_size: PageSize, allocator: &mut A)
+        -> Result<MappedPages, &'static str>
+        where A: FrameAllocator
     {
         // P4, P3, and P2 entries should never set NO_EXECUTE, only the lowest-level P1 entry should. 
         let mut top_level_flags = flags.clone();
         top_level_flags.set(EntryFlags::NO_EXECUTE, false);
         // top_level_flags.set(EntryFlags::WRITABLE, true); // is the same true for the WRITABLE bit?
 
-        for page in pages.clone() {
-            let frame = allocator.allocate_frame().ok_or("Mapper::internal_map(): couldn't allocate new frame, out of memory!")?;
-
-            let p3 = self.p4_mut().next_table_create(page.p4_index(), top_level_flags, allocator);
-            let p2 = p3.next_table_create(page.p3_index(), top_level_flags, allocator);
-            let p1 = p2.next_table_create(page.p2_index(), top_level_flags, allocator);
-
-            if !p1[page.p1_index()].is_unused() {
-                error!("Mapper::internal_map(): page {:#x} -> frame {:#X}, page was already in use!", page.start_address(), frame.start_address());
-                return Err("page was already in use");
-            } 
-
-            p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+        let total_pages = pages.size_in_pages();
+        let mut mapped_pages = 0;
+        while mapped_pages < total_pages {
+            let page = *pages.start() + mapped_pages;
+            let given_frame = frames.as_ref().map(|frames| *frames.start() + mapped_pages);
+            let mut page_size = self.largest_page_size(page, total_pages - mapped_pages, given_frame, max_page_size);
+            let frame = match given_frame {
+                Some(frame) => frame,
+                None => loop {
+                    let num_frames = page_size.size_in_pages();
+                    let allocated_frame = if page_size == PageSize::Normal4KiB {
+                        allocator.allocate_frame()
+                    } else {
+                        allocator.allocate_aligned_frames(num_frames, num_frames).map(|frames| *frames.start())
+                    };
+                    if let Some(frame) = allocated_frame {
+                        break frame;
+                    }
+                    page_size = match page_size.smaller() {
+                        Some(smaller) => smaller,
+                        None => return Err("Mapper::internal_map(): couldn't allocate new frame, out of memory!"),
+                    };
+                },
+            };
+
+            let result = self.entry_create(page, page_size, top_level_flags, allocator).and_then(|entry| {
+                if entry.is_unused() {
+                    entry.set(frame, page_size.entry_flags(flags));
+                    Ok(())
+                } else {
+                    Err("page was already in use")
+                }
+            });
+            if let Err(e) = result {
+                error!("Mapper::internal_map_huge(): page {:#x} -> frame {:#X} ({:?}), {}!", page.start_address(), frame.start_address(), page_size, e);
+                if given_frame.is_none() {
+                    allocator.deallocate_frames(FrameRange::new(frame, frame + (page_size.size_in_pages() - 1)));
+                }
+                return Err(e);
+            }
+            mapped_pages += page_size.size_in_pages();
         }
 
+        record_mapped_pages_created(pages.size_in_pages());
         Ok(MappedPages {
             page_table_p4: self.target_p4.clone(),
             pages: MaybeAllocatedPages::NotAllocated(pages),
             flags: flags,
+            frames: if frames.is_some() { FrameOwnership::Borrowed } else { FrameOwnership::Owned },
+            lazy: false,
         })
     }
 
-    /// the internal function that actually does all of the mapping from pages to frames.
-    fn internal_map_to<A>(&mut self, pages: PageRange, frames: FrameRange, flags: EntryFlags, allocator: &mut A)
-        -> Result<MappedPages, &'static str>
+    /// Returns the largest page size, up to `max_page_size`, that can map the given `page`
+    /// as the start of the remaining `num_pages` pages of a mapping, optionally to the given `frame`. 
+    /// 
+    /// A huge page requires that the page and frame are aligned to its size, 
+    /// and that the entry that would map it isn't in use, e.g., by a lower-level page table left behind by earlier mappings.
+    fn largest_page_size(&self, page: Page, num_pages: usize, frame: Option<Frame>, max_page_size: PageSize) -> PageSize {
+        let mut page_size = max_page_size;
+        if page_size == PageSize::Huge1GiB && !supports_1gib_pages() {
+            page_size = PageSize::Huge2MiB;
+        }
+
+        while let Some(smaller) = page_size.smaller() {
+            let size_in_bytes = page_size.size_in_bytes();
+            let aligned = page.start_address().value() % size_in_bytes == 0
+                && frame.map_or(true, |f| f.start_address().value() % size_in_bytes == 0);
+            let entry_unused = || match self.p4().next_table(page.p4_index()) {
+                None => true,
+                Some(p3) if page_size == PageSize::Huge1GiB => p3[page.p3_index()].is_unused(),
+                Some(p3) => match p3.next_table(page.p3_index()) {
+                    Some(p2) => p2[page.p2_index()].is_unused(),
+                    None => p3[page.p3_index()].is_unused(),
+                },
+            };
+            if aligned && num_pages >= page_size.size_in_pages() && entry_unused() {
+                return page_size;
+            }
+            page_size = smaller;
+        }
+        page_size
+    }
+
+    /// Returns the entry that would map the given `page` with a page of the given size,
+    /// creating any missing page tables above it.
+    fn entry_create<A>(&mut self, page: Page, page_size: PageSize, top_level_flags: EntryFlags, allocator: &mut A)
+        -> Result<&mut Entry, &'static str>
         where A: FrameAllocator
+    {
+        let p3 = self.p4_mut().next_table_create(page.p4_index(), top_level_flags, allocator)?;
+        if page_size == PageSize::Huge1GiB {
+            return Ok(&mut p3[page.p3_index()]);
+        }
+        let p2 = p3.next_table_create(page.p3_index(), top_level_flags, allocator)?;
+        if page_size == PageSize::Huge2MiB {
+            return Ok(&mut p2[page.p2_index()]);
+        }
+        let p1 = p2.next_table_create(page.p2_index(), top_level_flags, allocator)?;
+        Ok(&mut p1[page.p1_index()])
+    }
+
+    /// the internal function that maps each page to the corresponding frame given by the `frames` iterator,
+    /// which must yield at least as many frames as there are `pages`. The frames need not be contiguous.
+    fn internal_map_to_frames<A, I>(&mut self, pages: PageRange, frames: I, flags: EntryFlags, allocator: &mut A)
+        -> Result<MappedPages, &'static str>
+        where A: FrameAllocator, I: IntoIterator<Item = Frame>
     {
         // P4, P3, and P2 entries should never set NO_EXECUTE, only the lowest-level P1 entry should. 
         let mut top_level_flags = flags.clone();
         top_level_flags.set(EntryFlags::NO_EXECUTE, false);
+        top_level_flags.set(EntryFlags::COPY_ON_WRITE, false);
         // top_level_flags.set(EntryFlags::WRITABLE, true); // is the same true for the WRITABLE bit?
 
-        let pages_count = pages.size_in_pages();
-        let frames_count = frames.size_in_frames();
-        if pages_count != frames_count {
-            error!("map_to_internal(): page count {} must equal frame count {}!", pages_count, frames_count);
-            return Err("map_to_internal(): page count must equal frame count");
-        }
-            
-
         // iterate over pages and frames in lockstep
         for (page, frame) in pages.clone().into_iter().zip(frames) {
 
-            let p3 = self.p4_mut().next_table_create(page.p4_index(), top_level_flags, allocator);
-            let p2 = p3.next_table_create(page.p3_index(), top_level_flags, allocator);
-            let p1 = p2.next_table_create(page.p2_index(), top_level_flags, allocator);
+            let p3 = self.p4_mut().next_table_create(page.p4_index(), top_level_flags, allocator)?;
+            let p2 = p3.next_table_create(page.p3_index(), top_level_flags, allocator)?;
+            let p1 = p2.next_table_create(page.p2_index(), top_level_flags, allocator)?;
 
             if !p1[page.p1_index()].is_unused() {
                 error!("map_to() page {:#x} -> frame {:#X}, page was already in use!", page.start_address(), frame.start_address());
@@ -188,10 +347,13 @@ impl Mapper {
             p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
         }
 
+        record_mapped_pages_created(pages.size_in_pages());
         Ok(MappedPages {
             page_table_p4: self.target_p4.clone(),
             pages: MaybeAllocatedPages::NotAllocated(pages),
             flags: flags,
+            frames: FrameOwnership::Borrowed,
+            lazy: false,
         })
     }
 
@@ -244,6 +406,36 @@ impl Mapper {
     }
 
 
+    /// maps the given `AllocatedPages` to the given `frames`, one frame per page, which need not be contiguous,
+    /// e.g., to create a second mapping of the frames behind an existing `MappedPages`.
+    /// Consumes the given `AllocatedPages` and returns a `MappedPages` object which contains that `AllocatedPages` object.
+    /// 
+    /// Unmapping the returned `MappedPages` does not affect any other mapping of the same frames.
+    /// The frames are shared among all of their mappings: if the mapping that they were originally allocated for
+    /// is unmapped first, they are only deallocated once the last of these additional mappings is unmapped too.
+    pub fn map_allocated_pages_to_frames<A>(&mut self, allocated_pages: AllocatedPages, frames: &[Frame], flags: EntryFlags, allocator: &mut A)
+        -> Result<MappedPages, &'static str>
+        where A: FrameAllocator
+    {
+        if allocated_pages.size_in_pages() != frames.len() {
+            error!("map_allocated_pages_to_frames(): page count {} must equal frame count {}!", allocated_pages.size_in_pages(), frames.len());
+            return Err("map_allocated_pages_to_frames(): page count must equal frame count");
+        }
+        let mut mp = self.internal_map_to_frames(allocated_pages.pages.clone(), frames.iter().cloned(), flags, allocator)?;
+        mp.pages = MaybeAllocatedPages::Allocated(allocated_pages);
+        {
+            let mut shared_frames = SHARED_FRAMES.lock();
+            for frame in frames {
+                shared_frames.entry(*frame)
+                    .or_insert(SharedFrame { additional_mappings: 0, owner_unmapped: false })
+                    .additional_mappings += 1;
+            }
+        }
+        mp.frames = FrameOwnership::Shared;
+        Ok(mp)
+    }
+
+
     /// maps the given `AllocatedPages` to randomly chosen (allocated) frames
     /// Consumes the given `AllocatedPages` and returns a `MappedPages` object which contains that `AllocatedPages` object.
     pub fn map_allocated_pages<A>(&mut self, allocated_pages: AllocatedPages, flags: EntryFlags, allocator: &mut A)
@@ -255,6 +447,83 @@ impl Mapper {
             mp
         })
     }
+
+
+    /// maps the given `AllocatedPages` to randomly chosen (allocated) frames, like [`map_allocated_pages()`](#method.map_allocated_pages),
+    /// but uses huge pages of up to `max_page_size` wherever the pages are aligned to them,
+    /// which requires fewer page table frames and TLB entries for large mappings. 
+    /// Parts of the range that aren't aligned, or for which no aligned frames are free, are mapped with smaller pages. 
+    /// Use [`allocate_aligned_pages()`](fn.allocate_aligned_pages.html) to obtain pages that are aligned to a huge page.
+    /// 
+    /// Consumes the given `AllocatedPages` and returns a `MappedPages` object which contains that `AllocatedPages` object.
+    pub fn map_allocated_pages_huge<A>(&mut self, allocated_pages: AllocatedPages, flags: EntryFlags, max_page_size: PageSize, allocator: &mut A)
+        -> Result<MappedPages, &'static str>
+        where A: FrameAllocator
+    {
+        self.internal_map_huge(allocated_pages.pages.clone(), None, flags, max_page_size, allocator).map(|mut mp| {
+            mp.pages = MaybeAllocatedPages::Allocated(allocated_pages);
+            mp
+        })
+    }
+
+
+    /// maps the given `AllocatedPages` to the given actual frames, like [`map_allocated_pages_to()`](#method.map_allocated_pages_to),
+    /// but uses huge pages of up to `max_page_size` wherever both the pages and the frames are aligned to them,
+    /// e.g., for a large framebuffer. Other parts of the range are mapped with smaller pages. 
+    /// 
+    /// Consumes the given `AllocatedPages` and returns a `MappedPages` object which contains that `AllocatedPages` object.
+    pub fn map_allocated_pages_to_huge<A>(&mut self, allocated_pages: AllocatedPages, frames: FrameRange, flags: EntryFlags, max_page_size: PageSize, allocator: &mut A)
+        -> Result<MappedPages, &'static str>
+        where A: FrameAllocator
+    {
+        if allocated_pages.size_in_pages() != frames.size_in_frames() {
+            error!("map_allocated_pages_to_huge(): page count {} must equal frame count {}!", allocated_pages.size_in_pages(), frames.size_in_frames());
+            return Err("map_allocated_pages_to_huge(): page count must equal frame count");
+        }
+        self.internal_map_huge(allocated_pages.pages.clone(), Some(frames), flags, max_page_size, allocator).map(|mut mp| {
+            mp.pages = MaybeAllocatedPages::Allocated(allocated_pages);
+            mp
+        })
+    }
+
+
+    /// Reserves the given `pages` for a lazy mapping with the given `flags`, without mapping any of them yet. 
+    /// Instead, each page is mapped to a newly-allocated frame when it's first accessed, 
+    /// which is filled in by the given `provider`, or zeroed if there is none. 
+    /// Accesses that the `flags` don't permit, e.g., writes to a non-writable mapping, are still reported as page faults.
+    /// See [`handle_lazy_page_fault()`](fn.handle_lazy_page_fault.html).
+    /// 
+    /// The returned `MappedPages` deallocates the frames of the pages that were populated when it is unmapped.
+    pub fn map_pages_lazily(&mut self, pages: PageRange, flags: EntryFlags, provider: Option<Arc<dyn PageProvider>>)
+        -> Result<MappedPages, &'static str>
+    {
+        if let Some(page) = pages.clone().into_iter().find(|page| self.translate_page(*page).is_some()) {
+            error!("Mapper::map_pages_lazily(): page {:#x} was already in use!", page.start_address());
+            return Err("page was already in use");
+        }
+        register_lazy_region(&pages, flags, self.target_p4, provider)?;
+
+        record_mapped_pages_created(pages.size_in_pages());
+        Ok(MappedPages {
+            page_table_p4: self.target_p4.clone(),
+            pages: MaybeAllocatedPages::NotAllocated(pages),
+            flags: flags,
+            frames: FrameOwnership::Owned,
+            lazy: true,
+        })
+    }
+
+
+    /// Same as [`map_pages_lazily()`](#method.map_pages_lazily), but for the given `AllocatedPages`. 
+    /// Consumes the given `AllocatedPages` and returns a `MappedPages` object which contains that `AllocatedPages` object.
+    pub fn map_allocated_pages_lazily(&mut self, allocated_pages: AllocatedPages, flags: EntryFlags, provider: Option<Arc<dyn PageProvider>>)
+        -> Result<MappedPages, &'static str>
+    {
+        self.map_pages_lazily(allocated_pages.pages.clone(), flags, provider).map(|mut mp| {
+            mp.pages = MaybeAllocatedPages::Allocated(allocated_pages);
+            mp
+        })
+    }
 }
 
 #[repr(C)]
@@ -297,12 +566,116 @@ impl Deref for MaybeAllocatedPages {
 }
 
 
+/// Whether the frames mapped by a `MappedPages` object are deallocated when it is unmapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FrameOwnership {
+    /// The frames were allocated for this mapping, and are deallocated when it is unmapped.
+    Owned,
+    /// The frames were chosen by the creator of this mapping, who remains responsible for them,
+    /// e.g., device memory, the kernel image, or page table frames. 
+    Borrowed,
+    /// The frames are an additional mapping of frames that are also mapped elsewhere,
+    /// see [`Mapper::map_allocated_pages_to_frames()`](struct.Mapper.html#method.map_allocated_pages_to_frames).
+    Shared,
+}
+
+/// The bookkeeping for a frame that has additional `Shared` mappings.
+struct SharedFrame {
+    /// The number of `Shared` mappings of this frame that haven't yet been unmapped.
+    additional_mappings: usize,
+    /// Whether the `Owned` mapping of this frame was unmapped, 
+    /// in which case the frame is deallocated when its last additional mapping is unmapped.
+    owner_unmapped: bool,
+}
+
+lazy_static! {
+    /// The frames that have additional `Shared` mappings.
+    static ref SHARED_FRAMES: MutexIrqSafe<BTreeMap<Frame, SharedFrame>> = MutexIrqSafe::new(BTreeMap::new());
+}
+
+/// Records that a mapping of the given `frame` with the given `ownership` was unmapped,
+/// and returns `true` if the frame should now be deallocated.
+fn release_frame(frame: Frame, ownership: FrameOwnership) -> bool {
+    match ownership {
+        FrameOwnership::Borrowed => false,
+        FrameOwnership::Owned => {
+            // another copy-on-write mapping still uses this frame
+            if release_cow_frame(frame) {
+                return false;
+            }
+            match SHARED_FRAMES.lock().get_mut(&frame) {
+                // another mapping still uses this frame, so it will be deallocated when that mapping is unmapped
+                Some(shared) => {
+                    shared.owner_unmapped = true;
+                    false
+                }
+                None => true,
+            }
+        }
+        FrameOwnership::Shared => {
+            let mut shared_frames = SHARED_FRAMES.lock();
+            let last_mapping = match shared_frames.get_mut(&frame) {
+                Some(shared) => {
+                    shared.additional_mappings -= 1;
+                    shared.additional_mappings == 0
+                }
+                None => {
+                    error!("BUG: release_frame(): shared frame {:?} had no record of its mappings", frame);
+                    return false;
+                }
+            };
+            last_mapping && shared_frames.remove(&frame).map_or(false, |shared| shared.owner_unmapped)
+        }
+    }
+}
+
+/// Records that a mapping of the given `frames` with the given `ownership` was unmapped,
+/// and deallocates those frames that are no longer mapped anywhere.
+/// 
+/// The frame allocator must not be locked by the caller, since it's locked here in order to deallocate the frames.
+fn release_frames<A: FrameAllocator>(frames: FrameRange, ownership: FrameOwnership, allocator_ref: &MutexIrqSafe<A>) {
+    #[cfg(debug_assertions)]
+    debug_assert_allocator_unlocked(allocator_ref);
+
+    // the common case: none of the frames have additional mappings, so they can be deallocated all at once
+    if ownership == FrameOwnership::Owned 
+        && SHARED_FRAMES.lock().range(*frames.start() ..= *frames.end()).next().is_none()
+        && !any_shared_cow_frames(&frames)
+    {
+        allocator_ref.lock().deallocate_frames(frames);
+        return;
+    }
+    for frame in frames {
+        if release_frame(frame, ownership) {
+            allocator_ref.lock().deallocate_frame(frame);
+        }
+    }
+}
+
+/// Panics if the frame allocator stays locked, which most likely means that this core already holds its lock
+/// and would deadlock upon deallocating frames.
+/// Another core may hold the lock for a short while, so acquiring it is retried many times before panicking.
+#[cfg(debug_assertions)]
+fn debug_assert_allocator_unlocked<A>(allocator_ref: &MutexIrqSafe<A>) {
+    const MAX_TRIES: usize = 1 << 20;
+    let mut tries = 0;
+    while allocator_ref.try_lock().is_none() {
+        tries += 1;
+        debug_assert!(tries < MAX_TRIES, "BUG: the frame allocator was locked while releasing the frames of unmapped pages");
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+
 /// Represents a contiguous range of virtual memory pages that are currently mapped. 
 /// A `MappedPages` object can only have a single range of contiguous pages, not multiple disjoint ranges.
 /// This does not guarantee that its pages are mapped to frames that are contiguous in physical memory.
 /// 
 /// This object also represents ownership of those pages; if this object falls out of scope,
 /// it will be dropped, and the pages will be unmapped, and if they were allocated, then also de-allocated. 
+/// Likewise, if the frames were allocated when the pages were mapped, e.g., by [`Mapper::map_pages()`](struct.Mapper.html#method.map_pages),
+/// then they are deallocated as well, so the frame allocator must not be locked while such a `MappedPages` is dropped;
+/// frames given by the caller, e.g., to [`Mapper::map_frames()`](struct.Mapper.html#method.map_frames), are not deallocated. 
 /// Thus, it ensures memory safety by guaranteeing that this object must be held 
 /// in order to access data stored in these mapped pages, 
 /// just like a MutexGuard guarantees that data protected by a Mutex can only be accessed
@@ -315,6 +688,11 @@ pub struct MappedPages {
     pages: MaybeAllocatedPages,
     // The EntryFlags that define the page permissions of this mapping
     flags: EntryFlags,
+    /// Whether the frames of this mapping are deallocated when it is unmapped.
+    pub(crate) frames: FrameOwnership,
+    /// Whether this mapping's pages are only mapped when first accessed, 
+    /// in which case some of them may not be mapped yet.
+    lazy: bool,
 }
 impl Deref for MappedPages {
     type Target = PageRange;
@@ -331,6 +709,8 @@ impl MappedPages {
             page_table_p4: get_current_p4(),
             pages: MaybeAllocatedPages::NotAllocated(PageRange::empty()),
             flags: Default::default(),
+            frames: FrameOwnership::Borrowed,
+            lazy: false,
         }
     }
 
@@ -339,15 +719,24 @@ impl MappedPages {
         self.flags
     }
 
+    /// Returns `true` if this is a lazy mapping whose pages are only mapped when first accessed,
+    /// see [`Mapper::map_pages_lazily()`](struct.Mapper.html#method.map_pages_lazily).
+    pub fn is_lazy(&self) -> bool {
+        self.lazy
+    }
+
     /// Constructs a MappedPages object from an already existing mapping.
     /// Useful for creating idle task Stacks, for example. 
     // TODO FIXME: remove this function, it's dangerous!!
     #[deprecated]
     pub fn from_existing(already_mapped_pages: PageRange, flags: EntryFlags) -> MappedPages {
+        record_mapped_pages_created(already_mapped_pages.size_in_pages());
         MappedPages {
             page_table_p4: get_current_p4(),
             pages: MaybeAllocatedPages::NotAllocated(already_mapped_pages),
             flags: flags,
+            frames: FrameOwnership::Borrowed,
+            lazy: false,
         }
     }
 
@@ -362,6 +751,9 @@ impl MappedPages {
     /// In addition, the `MappedPages` objects must have the same flags and page table root frame
     /// (i.e., they must have all been mapped using the same set of page tables).
     /// 
+    /// In addition, the `MappedPages` objects must all deallocate their frames in the same way when unmapped,
+    /// and none of them can be lazy mappings.
+    /// 
     /// In addition, the `MappedPages` objects must either all have AllocatedPages or all have no AllocatedPages.
     /// `MappedPages` that were mapped to allocated virtual pages cannot be merged with those that weren't mapped to allocated pages.
     /// 
@@ -397,6 +789,14 @@ impl MappedPages {
             error!("MappedPages::merge(): some mapping were mapped to AllocatedPages, while others were not.");
             err = Some("some mappings were mapped to AllocatedPages, while others were not");
         }
+        else if mp.frames != self.frames {
+            error!("MappedPages::merge(): mappings had different frame ownership: {:?} vs. {:?}", mp.frames, self.frames);
+            err = Some("mappings had different frame ownership");
+        }
+        else if mp.lazy || self.lazy {
+            error!("MappedPages::merge(): lazy mappings cannot be merged.");
+            err = Some("lazy mappings cannot be merged");
+        }
         previous_end = *mp.pages.end();
         
         if let Some(e) = err {
@@ -408,6 +808,7 @@ impl MappedPages {
 
         // to ensure the existing mapping doesn't run its drop handler and unmap those pages
         mem::forget(mp); 
+        record_mapped_pages_merged();
         
         let new_page_range = PageRange::new(*self.pages.start(), previous_end);
         let new_pages = if self.pages.is_allocated(){
@@ -418,7 +819,8 @@ impl MappedPages {
             MaybeAllocatedPages::NotAllocated(new_page_range)
         };
         
-        self.pages = new_pages;
+        // the new AllocatedPages cover the old ones, which must not be deallocated when replaced
+        mem::forget(mem::replace(&mut self.pages, new_pages));
         Ok(())
     }
 
@@ -464,6 +866,102 @@ impl MappedPages {
         Ok(new_mapped_pages)
     }
 
+
+    /// Creates a copy-on-write copy of this `MappedPages` memory region,
+    /// which maps a new memory region to the same physical memory frames, rather than duplicating them like [`deep_copy()`](#method.deep_copy).
+    /// 
+    /// The writable pages of both this mapping and the new mapping are mapped read-only until they are first written,
+    /// at which point the page fault handler gives the written page its own copy of the frame, see [`handle_cow_page_fault()`](fn.handle_cow_page_fault.html).
+    /// Thus, the two mappings never observe each other's writes, but only the pages that are actually written are ever duplicated. 
+    /// The shared frames are deallocated once the last mapping that uses them is unmapped. 
+    /// 
+    /// The caller can optionally specify new flags for the duplicated mapping,
+    /// otherwise, the same flags as the existing `MappedPages` will be used. 
+    /// 
+    /// Only mappings whose frames were allocated when they were mapped can be copied on write, 
+    /// i.e., not lazy mappings, mappings with huge pages, or mappings of frames that were given by the caller or have additional shared mappings.
+    /// 
+    /// # Locking / Deadlock
+    /// Writing to either mapping may cause a page fault that needs to allocate frames and pages,
+    /// so they must not be written while holding the lock on the frame allocator or the virtual page allocator.
+    pub fn copy_on_write<A: FrameAllocator>(&mut self, new_flags: Option<EntryFlags>, active_table_mapper: &mut Mapper, allocator: &mut A) -> Result<MappedPages, &'static str> {
+        let (new_mapped_pages, frames) = self.map_copy_on_write(self.pages.deref().clone(), new_flags, active_table_mapper, allocator)?;
+
+        // Writes to this mapping must now cause page faults too, so that they aren't visible through the new mapping.
+        if self.flags.is_writable() {
+            for (page, frame) in self.pages.deref().clone().into_iter().zip(frames) {
+                let (entry, _) = active_table_mapper.entry_mut(page).ok_or("copy_on_write(): page not mapped")?;
+                let flags = entry.flags();
+                entry.set(frame, cow_entry_flags(flags));
+                tlb_flush_virt_addr(page.start_address());
+            }
+            if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.try() {
+                func(self.pages.deref().clone());
+            }
+        }
+
+        Ok(new_mapped_pages)
+    }
+
+
+    /// Same as [`copy_on_write()`](#method.copy_on_write), but only the new mapping is copy-on-write,
+    /// and it only covers the pages of this mapping that contain the `length` bytes starting at `offset`. 
+    /// 
+    /// This mapping stays writable, so its writes are visible through the pages of the new mapping that weren't yet written,
+    /// whereas the new mapping's writes are never visible through this mapping. 
+    /// This is useful for a private copy of memory whose owner must keep writing to it in place, such as a file's contents.
+    /// The shared frames are still only deallocated once both mappings are unmapped.
+    pub fn copy_on_write_one_sided<A: FrameAllocator>(&self, offset: usize, length: usize, new_flags: Option<EntryFlags>, active_table_mapper: &mut Mapper, allocator: &mut A) 
+        -> Result<MappedPages, &'static str> 
+    {
+        let end = offset.checked_add(length).ok_or("copy_on_write_one_sided(): region is too large")?;
+        if length == 0 || end > self.size_in_bytes() {
+            return Err("copy_on_write_one_sided(): region must be non-empty and lie within this mapping");
+        }
+        let pages = PageRange::new(*self.pages.start() + offset / PAGE_SIZE, *self.pages.start() + (end - 1) / PAGE_SIZE);
+        self.map_copy_on_write(pages, new_flags, active_table_mapper, allocator).map(|(new_mapped_pages, _frames)| new_mapped_pages)
+    }
+
+
+    /// The internal routine for both `copy_on_write()` variants, which maps new copy-on-write pages 
+    /// to the same frames as the given `pages` of this mapping.
+    /// Returns the new mapping and the frames it shares with this mapping.
+    fn map_copy_on_write<A: FrameAllocator>(&self, pages: PageRange, new_flags: Option<EntryFlags>, active_table_mapper: &mut Mapper, allocator: &mut A) 
+        -> Result<(MappedPages, Vec<Frame>), &'static str> 
+    {
+        if self.frames != FrameOwnership::Owned || self.lazy {
+            return Err("copy_on_write(): only mappings that own all of their frames can be copied on write");
+        }
+        let new_flags = new_flags.unwrap_or(self.flags);
+
+        // First, find all of the frames, so that nothing needs to be undone if any of them can't be shared.
+        let mut frames: Vec<Frame> = Vec::with_capacity(pages.size_in_pages());
+        for page in pages {
+            match active_table_mapper.entry(page) {
+                Some((entry, PageSize::Normal4KiB)) if entry.flags().contains(EntryFlags::PRESENT) => {
+                    frames.push(entry.pointed_frame().ok_or("copy_on_write(): page not mapped")?);
+                }
+                Some((_, PageSize::Huge2MiB)) | Some((_, PageSize::Huge1GiB)) => return Err("copy_on_write(): huge pages cannot be copied on write"),
+                _ => return Err("copy_on_write(): page not mapped"),
+            }
+        }
+        {
+            let shared_frames = SHARED_FRAMES.lock();
+            if frames.iter().any(|frame| shared_frames.contains_key(frame)) {
+                return Err("copy_on_write(): frames that have additional shared mappings cannot be copied on write");
+            }
+        }
+
+        use paging::allocate_pages;
+        let new_pages = allocate_pages(frames.len()).ok_or_else(|| "Couldn't allocate_pages()")?;
+        let mut new_mapped_pages = active_table_mapper.internal_map_to_frames(new_pages.pages.clone(), frames.iter().cloned(), cow_entry_flags(new_flags), allocator)?;
+        new_mapped_pages.pages = MaybeAllocatedPages::Allocated(new_pages);
+        new_mapped_pages.flags = new_flags;
+        new_mapped_pages.frames = FrameOwnership::Owned;
+        share_frames(&frames);
+        Ok((new_mapped_pages, frames))
+    }
+
     
     /// Change the permissions (`new_flags`) of this `MappedPages`'s page table entries.
     pub fn remap(&mut self, active_table_mapper: &mut Mapper, new_flags: EntryFlags) -> Result<(), &'static str> {
@@ -474,17 +972,25 @@ impl MappedPages {
             return Ok(());
         }
 
-        for page in self.pages.clone() {
-            let p1 = active_table_mapper.p4_mut()
-                .next_table_mut(page.p4_index())
-                .and_then(|p3| p3.next_table_mut(page.p3_index()))
-                .and_then(|p2| p2.next_table_mut(page.p2_index()))
-                .ok_or("mapping code does not support huge pages")?;
+        if self.lazy {
+            // pages that are populated from now on must be mapped with the new flags
+            set_lazy_region_flags(*self.pages.start(), new_flags);
+        }
+
+        let mut page = *self.pages.start();
+        while page <= *self.pages.end() {
+            let (entry, page_size) = match self.mapped_entry(active_table_mapper, page)? {
+                Some(found) => found,
+                None => { page += 1; continue; }
+            };
             
-            let frame = p1[page.p1_index()].pointed_frame().ok_or("remap(): page not mapped")?;
-            p1[page.p1_index()].set(frame, new_flags | EntryFlags::PRESENT);
+            let frame = entry.pointed_frame().ok_or("remap(): page not mapped")?;
+            // a page whose frame is still shared with a copy-on-write mapping must not become writable until it's copied
+            let flags = if new_flags.is_writable() && is_shared_cow_frame(frame) { cow_entry_flags(new_flags) } else { new_flags };
+            entry.set(frame, page_size.entry_flags(flags));
 
             tlb_flush_virt_addr(page.start_address());
+            page += page_size.size_in_pages();
         }
         
         if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.try() {
@@ -496,27 +1002,115 @@ impl MappedPages {
     }   
 
 
+    /// Returns the pages of this mapping that have been written to since they were mapped
+    /// or since the last call to this function, and clears their `DIRTY` bits. 
+    /// 
+    /// The dirty bit is set by the hardware when a page is written, 
+    /// so this can be used to find which pages must be written back to a backing store.
+    /// A huge page only has a single dirty bit, so if it was written, all of the 4 KiB pages it covers are returned.
+    pub fn take_dirty_pages(&mut self, active_table_mapper: &mut Mapper) -> Result<Vec<Page>, &'static str> {
+        let mut dirty_pages = Vec::new();
+        if self.size_in_pages() == 0 { return Ok(dirty_pages); }
+
+        let mut page = *self.pages.start();
+        while page <= *self.pages.end() {
+            let (entry, page_size) = match self.mapped_entry(active_table_mapper, page)? {
+                Some(found) => found,
+                None => { page += 1; continue; }
+            };
+
+            let flags = entry.flags();
+            if flags.contains(EntryFlags::DIRTY) {
+                let frame = entry.pointed_frame().ok_or("take_dirty_pages(): page not mapped")?;
+                entry.set(frame, flags - EntryFlags::DIRTY);
+                // The TLB may cache the dirty state, so the page must be flushed for future writes to set the bit again.
+                tlb_flush_virt_addr(page.start_address());
+                dirty_pages.extend((0 .. page_size.size_in_pages()).map(|i| page + i));
+            }
+            page += page_size.size_in_pages();
+        }
+
+        if !dirty_pages.is_empty() {
+            if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.try() {
+                func(self.pages.deref().clone());
+            }
+        }
+        Ok(dirty_pages)
+    }
+
+
+    /// Returns the present page table entry that maps the given `page` of this mapping, and the size of the page that it maps,
+    /// or `None` if this is a lazy mapping in which that page wasn't yet populated. 
+    /// 
+    /// Returns an error if the page isn't mapped, or if this mapping only covers part of the huge page that maps it.
+    fn mapped_entry<'m>(&self, active_table_mapper: &'m mut Mapper, page: Page) -> Result<Option<(&'m mut Entry, PageSize)>, &'static str> {
+        let found = active_table_mapper.entry_mut(page).filter(|(entry, _)| entry.flags().contains(EntryFlags::PRESENT));
+        match found {
+            Some((entry, page_size)) => {
+                self.check_whole_page(page, page_size)?;
+                Ok(Some((entry, page_size)))
+            }
+            None if self.lazy => Ok(None),
+            None => Err("MappedPages: page not mapped"),
+        }
+    }
+
+
+    /// Returns an error if the given `page` that is mapped by a page of the given size
+    /// isn't the start of that page, or if that page extends beyond the end of this mapping,
+    /// i.e., if this mapping only covers part of a huge page. 
+    fn check_whole_page(&self, page: Page, page_size: PageSize) -> Result<(), &'static str> {
+        if page.start_address().value() % page_size.size_in_bytes() != 0
+            || page + (page_size.size_in_pages() - 1) > *self.pages.end()
+        {
+            error!("MappedPages: {:?} only covers part of the {:?} page at {:?}", self.pages.deref(), page_size, page);
+            return Err("MappedPages only covers part of a huge page");
+        }
+        Ok(())
+    }
+
+
     /// Remove the virtual memory mapping for the given `Page`s.
     /// This should NOT be public because it should only be invoked when a `MappedPages` object is dropped.
-    fn unmap<A>(&mut self, active_table_mapper: &mut Mapper, _allocator_ref: &MutexIrqSafe<A>) -> Result<(), &'static str> 
+    /// 
+    /// If this mapping's frames should be deallocated, that only happens after the TLB shootdown,
+    /// such that no other core can still access them through this mapping. 
+    /// 
+    /// Unmapping pages whose `Owned` frames aren't shared with any other mapping doesn't use the heap,
+    /// so the heap's own `MappedPages` can be dropped while the heap itself is locked.
+    /// However, unmapping a lazy mapping, or frames that are `Shared` or copy-on-write, updates their bookkeeping,
+    /// which is held in heap-allocated maps, so such a `MappedPages` must not be dropped while the heap is locked.
+    fn unmap<A>(&mut self, active_table_mapper: &mut Mapper, allocator_ref: &MutexIrqSafe<A>) -> Result<(), &'static str> 
         where A: FrameAllocator
     {
         if self.size_in_pages() == 0 { return Ok(()); }
+        let should_release_frames = self.frames != FrameOwnership::Borrowed;
+        if self.lazy {
+            // no more pages can be populated after this
+            unregister_lazy_region(*self.pages.start());
+        }
 
-        for page in self.pages.clone() {            
-            let p1 = active_table_mapper.p4_mut()
-                .next_table_mut(page.p4_index())
-                .and_then(|p3| p3.next_table_mut(page.p3_index()))
-                .and_then(|p2| p2.next_table_mut(page.p2_index()))
-                .ok_or("mapping code does not support huge pages")?;
+        let mut page = *self.pages.start();
+        while page <= *self.pages.end() {
+            let (entry, page_size) = match self.mapped_entry(active_table_mapper, page)? {
+                Some(found) => found,
+                None => { page += 1; continue; }
+            };
             
-            let _frame = p1[page.p1_index()].pointed_frame().ok_or("unmap(): page not mapped")?;
-            p1[page.p1_index()].set_unused();
+            let frame = entry.pointed_frame().ok_or("unmap(): page not mapped")?;
+            if should_release_frames {
+                // leave a non-present entry that only holds the frame's address (and whether it's a huge page),
+                // so the frames can be released below
+                let leftover_flags = if page_size == PageSize::Normal4KiB { EntryFlags::empty() } else { EntryFlags::HUGE_PAGE };
+                entry.set(frame, leftover_flags);
+            } else {
+                entry.set_unused();
+            }
 
             tlb_flush_virt_addr(page.start_address());
+            page += page_size.size_in_pages();
             
             // TODO free p(1,2,3) table if empty
-            // _allocator_ref.lock().deallocate_frame(frame);
         }
     
         #[cfg(not(bm_map))]
@@ -526,6 +1120,23 @@ impl MappedPages {
             }
         }
 
+        if should_release_frames {
+            let mut page = *self.pages.start();
+            while page <= *self.pages.end() {
+                let found = active_table_mapper.entry_mut(page).filter(|(entry, _)| !entry.is_unused());
+                let (entry, page_size) = match found {
+                    Some(found) => found,
+                    // a page of a lazy mapping that was never populated
+                    None if self.lazy => { page += 1; continue; }
+                    None => return Err("unmap(): page not mapped"),
+                };
+                let frame = Frame::containing_address(PhysicalAddress::new_canonical(entry.value() as usize));
+                entry.set_unused();
+                release_frames(FrameRange::new(frame, frame + (page_size.size_in_pages() - 1)), self.frames, allocator_ref);
+                page += page_size.size_in_pages();
+            }
+        }
+
         Ok(())
     }
 
@@ -779,6 +1390,7 @@ pub fn mapped_pages_unmap<A: FrameAllocator>(
 impl Drop for MappedPages {
     fn drop(&mut self) {
         if self.size_in_pages() == 0 { return; }
+        record_mapped_pages_dropped(self.size_in_pages());
         // trace!("MappedPages::drop(): unmapping MappedPages start: {:?} to end: {:?}", self.pages.start(), self.pages.end());
 
         let mut mapper = Mapper::from_current();
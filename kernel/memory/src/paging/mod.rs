@@ -11,6 +11,8 @@ mod virtual_address_allocator;
 mod entry;
 mod temporary_page;
 mod mapper;
+mod demand_paging;
+mod copy_on_write;
 #[cfg(not(mapper_spillful))]
 mod table;
 #[cfg(mapper_spillful)]
@@ -21,6 +23,8 @@ pub use self::entry::*;
 pub use self::temporary_page::TemporaryPage;
 pub use self::mapper::*;
 pub use self::virtual_address_allocator::*;
+pub use self::demand_paging::{PageProvider, handle_lazy_page_fault, handle_lazy_stack_fault};
+pub use self::copy_on_write::handle_cow_page_fault;
 
 use core::{
     ops::{Deref, DerefMut},
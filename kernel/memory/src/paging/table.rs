@@ -79,21 +79,27 @@ impl<L> Table<L>
         self.next_table_address(index).map(|vaddr| unsafe { &mut *(vaddr.value() as *mut _) })
     }
 
+    /// returns the next lowest page table (so P4 would give P3, P3 -> P2, P2 -> P1), 
+    /// allocating and zeroing a new one if that entry is unused.
+    /// 
+    /// Returns an error if the entry at `index` maps a huge page instead of pointing to a lower-level table,
+    /// or if a frame for the new table couldn't be allocated.
     pub fn next_table_create<A>(&mut self,
                                 index: usize,
                                 flags: EntryFlags,
                                 allocator: &mut A)
-                                -> &mut Table<L::NextLevel>
+                                -> Result<&mut Table<L::NextLevel>, &'static str>
         where A: FrameAllocator
     {
         if self.next_table(index).is_none() {
-            assert!(!self[index].flags().is_huge(),
-                    "mapping code does not support huge pages");
-            let frame = allocator.allocate_frame().expect("no frames available");
+            if self[index].flags().is_huge() {
+                return Err("next_table_create(): entry maps a huge page, not a lower-level page table");
+            }
+            let frame = allocator.allocate_frame().ok_or("next_table_create(): couldn't allocate frame for new page table")?;
             self[index].set(frame, flags.into_writable() | EntryFlags::PRESENT); // must be PRESENT | WRITABLE for x86_64
             self.next_table_mut(index).unwrap().zero();
         }
-        self.next_table_mut(index).unwrap()
+        Ok(self.next_table_mut(index).unwrap())
     }
 }
 
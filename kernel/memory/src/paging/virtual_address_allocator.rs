@@ -1,29 +1,27 @@
-//! Provides a (currently mediocre) virtual address allocator,
+//! Provides a virtual address allocator,
 //! which allocates pages (not physical memory) starting from kernel_config::memory::KERNEL_TEXT_START. 
 //! The minimum unit of allocation is a single page. 
+//! Pages are deallocated when their `AllocatedPages` object is dropped,
+//! and free chunks of pages are merged with their neighbors to avoid fragmentation.
 
 use core::ops::Deref;
 use kernel_config::memory::{KERNEL_TEXT_START, KERNEL_TEXT_MAX_SIZE, PAGE_SIZE};
 use super::{VirtualAddress, Page, PageRange};
 use spin::Mutex;
-use alloc::collections::LinkedList;
+use alloc::collections::{BTreeMap, BTreeSet};
 
-/// A group of contiguous pages, much like a hole in other allocators. 
+/// A group of contiguous free pages, much like a hole in other allocators. 
+#[derive(Debug, Clone, Copy)]
 struct Chunk {
-	/// Whether or not this Chunk is currently allocated. If false, it is free.
-	allocated: bool,
 	/// The Page at which this chunk starts. 
 	start_page: Page,
 	/// The size of this chunk, specified in number of pages, not bytes.
 	size_in_pages: usize,
 }
 impl Chunk {
-	fn as_allocated_pages(&self) -> AllocatedPages {
-		// subtract one because it's an inclusive range
-		let end_page = self.start_page + self.size_in_pages - 1;
-		AllocatedPages {
-			pages: PageRange::new(self.start_page, end_page),							
-		}
+	/// Returns the last Page of this chunk (inclusive).
+	fn end_page(&self) -> Page {
+		self.start_page + (self.size_in_pages - 1)
 	}
 }
 
@@ -35,7 +33,7 @@ impl Chunk {
 /// See `MappedPages` struct for a similar object that unmaps pages when dropped.
 #[derive(Debug)]
 pub struct AllocatedPages {
-	pub pages: PageRange,
+	pub(crate) pages: PageRange,
 }
 
 impl AllocatedPages {
@@ -56,29 +54,95 @@ impl Deref for AllocatedPages {
     }
 }
 
-// impl Drop for AllocatedPages {
-//     fn drop(&mut self) {
-//         if let Err(_) = deallocate_pages(self) {
-// 			error!("AllocatedPages::drop(): error deallocating pages");
-// 		}
-//     }
-// }
+impl Drop for AllocatedPages {
+	fn drop(&mut self) {
+		if self.size_in_pages() == 0 { return; }
+		if let Err(e) = deallocate_pages(self) {
+			error!("AllocatedPages::drop(): error deallocating pages {:?}: {}", self.pages, e);
+		}
+	}
+}
+
+
+/// The free chunks of virtual address space. 
+/// Free chunks are never adjacent to each other, because freed pages are merged with their neighboring free chunks.
+struct FreePages {
+	/// The free chunks, keyed by their starting Page, which is used to find a chunk's neighbors
+	/// and the chunk that contains a specific address. 
+	by_address: BTreeMap<Page, usize>,
+	/// The same free chunks ordered by their size in pages and then their starting Page,
+	/// which is used to find the smallest chunk that fits an allocation (best fit).
+	by_size: BTreeSet<(usize, Page)>,
+}
+impl FreePages {
+	fn insert(&mut self, chunk: Chunk) {
+		self.by_address.insert(chunk.start_page, chunk.size_in_pages);
+		self.by_size.insert((chunk.size_in_pages, chunk.start_page));
+	}
+
+	fn remove(&mut self, chunk: Chunk) {
+		self.by_address.remove(&chunk.start_page);
+		self.by_size.remove(&(chunk.size_in_pages, chunk.start_page));
+	}
+
+	/// Returns the free chunk that starts at or before the given `page`, i.e., the only chunk that could contain it.
+	fn chunk_at_or_before(&self, page: Page) -> Option<Chunk> {
+		self.by_address.range(..=page).next_back()
+			.map(|(&start_page, &size_in_pages)| Chunk { start_page, size_in_pages })
+	}
+
+	/// Removes the given `pages` from the free `chunk` that contains them,
+	/// putting back the parts of the chunk before and after those pages.
+	fn take_from(&mut self, chunk: Chunk, pages: &PageRange) {
+		self.remove(chunk);
+		let (first, last) = (*pages.start(), *pages.end());
+		if chunk.start_page < first {
+			self.insert(Chunk { start_page: chunk.start_page, size_in_pages: PageRange::new(chunk.start_page, first - 1).size_in_pages() });
+		}
+		if chunk.end_page() > last {
+			self.insert(Chunk { start_page: last + 1, size_in_pages: PageRange::new(last + 1, chunk.end_page()).size_in_pages() });
+		}
+	}
+
+	/// Returns the given `pages` to the free chunks, merging them with any adjacent free chunks.
+	fn free(&mut self, pages: &PageRange) -> Result<(), &'static str> {
+		let (first, last) = (*pages.start(), *pages.end());
+		let mut chunk = Chunk { start_page: first, size_in_pages: pages.size_in_pages() };
 
+		// merge with the free chunk that ends right before these pages, after checking that it doesn't overlap them
+		if let Some(prev) = self.chunk_at_or_before(last) {
+			if prev.end_page() >= first {
+				return Err("pages were already free");
+			}
+			if prev.end_page() + 1 == first {
+				self.remove(prev);
+				chunk = Chunk { start_page: prev.start_page, size_in_pages: prev.size_in_pages + chunk.size_in_pages };
+			}
+		}
+		// merge with the free chunk that starts right after these pages
+		let next_start = last + 1;
+		if let Some(&next_size) = self.by_address.get(&next_start) {
+			self.remove(Chunk { start_page: next_start, size_in_pages: next_size });
+			chunk.size_in_pages += next_size;
+		}
+		self.insert(chunk);
+		Ok(())
+	}
+}
 
 
 lazy_static!{
-	static ref FREE_PAGE_LIST: Mutex<LinkedList<Chunk>> = {
+	static ref FREE_PAGES: Mutex<FreePages> = {
 		// we need to create the first chunk here, 
 		// which is one giant chunk that starts at KERNEL_TEXT_START
 		// and goes until the end of the kernel free text section
 		let initial_chunk: Chunk = Chunk {
-			allocated: false,
 			start_page: Page::containing_address(VirtualAddress::new_canonical(KERNEL_TEXT_START)),
 			size_in_pages: KERNEL_TEXT_MAX_SIZE / PAGE_SIZE,
 		};
-		let mut list: LinkedList<Chunk> = LinkedList::new();
-		list.push_front(initial_chunk);
-		Mutex::new(list)
+		let mut free_pages = FreePages { by_address: BTreeMap::new(), by_size: BTreeSet::new() };
+		free_pages.insert(initial_chunk);
+		Mutex::new(free_pages)
 	};
 }
 
@@ -96,9 +160,8 @@ pub fn allocate_pages_by_bytes(num_bytes: usize) -> Option<AllocatedPages> {
 /// Allocates the given number of pages, but simply reserves the virtual addresses; 
 /// it does not allocate actual physical memory frames nor do any mapping. 
 /// Thus these pages aren't directly usable until they are mapped to physical frames. 
-/// Allocation is quick, technically O(n) but generally will allocate immediately
-/// because the largest free chunks are stored at the front of the list.
-/// Fragmentation isn't cleaned up until we're out of address space, but not really a big deal.
+/// Allocation takes logarithmic time, using the smallest free chunk that is large enough (best fit).
+/// The pages are deallocated when the returned `AllocatedPages` is dropped.
 pub fn allocate_pages(num_pages: usize) -> Option<AllocatedPages> {
 
 	if num_pages == 0 {
@@ -106,58 +169,182 @@ pub fn allocate_pages(num_pages: usize) -> Option<AllocatedPages> {
 		return None;
 	}
 
-	// the Page where the newly-allocated Chunk starts, which we'll return if successfully allocated.
-	let mut new_start_page: Option<Page> = None;
-
-	let mut locked_list = FREE_PAGE_LIST.lock();
-	for mut c in locked_list.iter_mut() {
-		// skip already-allocated chunks and chunks that are too small
-		if c.allocated || c.size_in_pages < num_pages {
-			continue;
+	let mut free_pages = FREE_PAGES.lock();
+	let chunk = match free_pages.by_size.range((num_pages, Page::containing_address(VirtualAddress::zero())) ..).next() {
+		Some(&(size_in_pages, start_page)) => Chunk { start_page, size_in_pages },
+		None => {
+			error!("VirtualAddressAllocator: out of virtual address space."); 
+			return None;
 		}
+	};
 
-		// here: we have found a suitable chunk
-		let start_page = c.start_page;
-		let remaining_size = c.size_in_pages - num_pages;
-		if remaining_size == 0 {
-			// if the chunk is exactly the right size, just update it in-place as 'allocated'
-			c.allocated = true;
-			return Some(c.as_allocated_pages())
-		}
+	// take the pages from the start of the chunk
+	let pages = PageRange::new(chunk.start_page, chunk.start_page + (num_pages - 1));
+	free_pages.take_from(chunk, &pages);
+	Some(AllocatedPages { pages })
+}
 
-		// here: we have the chunk and we need to split it up into two chunks
-		assert!(c.allocated == false, "BUG: an already-allocated chunk is going to be split!");
-		
-		// first, update in-place the original free (unallocated) chunk to be smaller, since we're removing pages from it
-		c.size_in_pages = remaining_size;
-		c.start_page += num_pages;
 
-		// second, create a new chunk that has the pages we've peeled off the original chunk being split
-		// (or rather, we create the chunk below outside of the iterator loop, so here we just tell it where to start)
-		new_start_page = Some(start_page);
-		break;
+/// Allocates the given number of pages, the first of which starts at a virtual address
+/// that is a multiple of `alignment` pages, which must be a power of two,
+/// e.g., so that they can be mapped with huge pages.
+/// See [`allocate_pages()`](fn.allocate_pages.html).
+///
+/// Unlike `allocate_pages()`, this doesn't take logarithmic time:
+/// it checks the free chunks that are large enough in order of size until one fits the aligned pages,
+/// so in the worst case it takes time linear in the number of free chunks.
+pub fn allocate_aligned_pages(num_pages: usize, alignment: usize) -> Option<AllocatedPages> {
+	if num_pages == 0 {
+		warn!("allocate_aligned_pages(): requested an allocation of 0 pages... stupid!");
+		return None;
+	}
+	if !alignment.is_power_of_two() {
+		error!("allocate_aligned_pages(): alignment {} must be a power of two", alignment);
+		return None;
 	}
 
-	if let Some(p) = new_start_page {
-		let new_chunk = Chunk {
-			allocated: true,
-			start_page: p,
-			size_in_pages: num_pages,
-		};
-		let ret = new_chunk.as_allocated_pages();
-		locked_list.push_back(new_chunk);
-		Some(ret)
+	let alignment_in_bytes = alignment * PAGE_SIZE;
+	let mut free_pages = FREE_PAGES.lock();
+	// the smallest chunk that still fits the pages after skipping the ones before the first aligned page
+	let found = free_pages.by_size.range((num_pages, Page::containing_address(VirtualAddress::zero())) ..)
+		.filter_map(|&(size_in_pages, start_page)| {
+			let start_vaddr = start_page.start_address().value();
+			let aligned_vaddr = start_vaddr.checked_add(alignment_in_bytes - 1)? & !(alignment_in_bytes - 1);
+			let skipped_pages = (aligned_vaddr - start_vaddr) / PAGE_SIZE;
+			if size_in_pages >= skipped_pages + num_pages {
+				Some((Chunk { start_page, size_in_pages }, Page::containing_address(VirtualAddress::new_canonical(aligned_vaddr))))
+			} else {
+				None
+			}
+		})
+		.next();
+
+	match found {
+		Some((chunk, first_page)) => {
+			let pages = PageRange::new(first_page, first_page + (num_pages - 1));
+			free_pages.take_from(chunk, &pages);
+			Some(AllocatedPages { pages })
+		}
+		None => {
+			error!("VirtualAddressAllocator: out of virtual address space for {} pages aligned to {} pages.", num_pages, alignment);
+			None
+		}
 	}
-	else {
-		error!("VirtualAddressAllocator: out of virtual address space."); 
+}
+
+
+/// Allocates the given number of pages starting at the page that contains the given virtual address.
+/// 
+/// Returns `None` if any of those pages are already allocated or lie outside of the region managed by this allocator.
+/// See [`allocate_pages()`](fn.allocate_pages.html).
+pub fn allocate_pages_at(vaddr: VirtualAddress, num_pages: usize) -> Option<AllocatedPages> {
+	if num_pages == 0 {
+		warn!("allocate_pages_at(): requested an allocation of 0 pages... stupid!");
 		return None;
 	}
+
+	let start_page = Page::containing_address(vaddr);
+	let pages = PageRange::new(start_page, start_page + (num_pages - 1));
+	let mut free_pages = FREE_PAGES.lock();
+	match free_pages.chunk_at_or_before(start_page) {
+		Some(chunk) if chunk.end_page() >= *pages.end() => {
+			free_pages.take_from(chunk, &pages);
+			Some(AllocatedPages { pages })
+		}
+		_ => {
+			error!("allocate_pages_at(): pages {:?} are not free", pages);
+			None
+		}
+	}
 }
 
 
-#[allow(dead_code)]
-fn deallocate_pages(_pages: &mut AllocatedPages) -> Result<(), ()> {
-	trace!("Virtual Address Allocator: deallocate_pages is not yet implemented, trying to dealloc: {:?}", _pages);
-	Ok(())
-	// unimplemented!();
-}
\ No newline at end of file
+/// Returns the given pages to the allocator, merging them with any adjacent free chunks.
+fn deallocate_pages(pages: &AllocatedPages) -> Result<(), &'static str> {
+	FREE_PAGES.lock().free(&pages.pages)
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use alloc::vec::Vec;
+
+	fn page(number: usize) -> Page {
+		Page::containing_address(VirtualAddress::new_canonical(number * PAGE_SIZE))
+	}
+
+	fn pages(first: usize, last: usize) -> PageRange {
+		PageRange::new(page(first), page(last))
+	}
+
+	/// Returns the free chunks as `(start page number, size in pages)`, checking that both collections agree.
+	fn chunks(free_pages: &FreePages) -> Vec<(usize, usize)> {
+		assert_eq!(free_pages.by_address.len(), free_pages.by_size.len());
+		for (&start_page, &size_in_pages) in free_pages.by_address.iter() {
+			assert!(free_pages.by_size.contains(&(size_in_pages, start_page)));
+		}
+		free_pages.by_address.iter().map(|(&start_page, &size_in_pages)| (start_page.start_address().value() / PAGE_SIZE, size_in_pages)).collect()
+	}
+
+	/// Returns free pages with one chunk of pages `0` to `99`, from which the given ranges were taken.
+	fn free_pages_without(taken: &[PageRange]) -> FreePages {
+		let mut free_pages = FreePages { by_address: BTreeMap::new(), by_size: BTreeSet::new() };
+		free_pages.insert(Chunk { start_page: page(0), size_in_pages: 100 });
+		for pages in taken {
+			let chunk = free_pages.chunk_at_or_before(*pages.start()).expect("pages are not free");
+			free_pages.take_from(chunk, pages);
+		}
+		free_pages
+	}
+
+	#[test]
+	fn take_from_splits_chunk() {
+		let free_pages = free_pages_without(&[pages(10, 19)]);
+		assert_eq!(chunks(&free_pages), [(0, 10), (20, 80)]);
+
+		let free_pages = free_pages_without(&[pages(0, 9), pages(90, 99)]);
+		assert_eq!(chunks(&free_pages), [(10, 80)]);
+	}
+
+	#[test]
+	fn free_merges_with_previous_chunk() {
+		let mut free_pages = free_pages_without(&[pages(10, 19), pages(20, 29)]);
+		free_pages.free(&pages(10, 19)).unwrap();
+		assert_eq!(chunks(&free_pages), [(0, 20), (30, 70)]);
+	}
+
+	#[test]
+	fn free_merges_with_next_chunk() {
+		let mut free_pages = free_pages_without(&[pages(10, 19), pages(20, 29)]);
+		free_pages.free(&pages(20, 29)).unwrap();
+		assert_eq!(chunks(&free_pages), [(0, 10), (20, 80)]);
+	}
+
+	#[test]
+	fn free_merges_with_both_neighbors() {
+		let mut free_pages = free_pages_without(&[pages(10, 19)]);
+		free_pages.free(&pages(10, 19)).unwrap();
+		assert_eq!(chunks(&free_pages), [(0, 100)]);
+	}
+
+	#[test]
+	fn free_without_free_neighbors() {
+		let mut free_pages = free_pages_without(&[pages(10, 19), pages(20, 29), pages(30, 39)]);
+		free_pages.free(&pages(20, 29)).unwrap();
+		assert_eq!(chunks(&free_pages), [(0, 10), (20, 10), (40, 60)]);
+	}
+
+	#[test]
+	fn double_free_is_detected() {
+		let mut free_pages = free_pages_without(&[pages(10, 19)]);
+		free_pages.free(&pages(10, 19)).unwrap();
+		assert_eq!(free_pages.free(&pages(10, 19)), Err("pages were already free"));
+		// pages that only partially overlap a free chunk, at either end
+		let mut free_pages = free_pages_without(&[pages(10, 19)]);
+		assert_eq!(free_pages.free(&pages(5, 14)), Err("pages were already free"));
+		assert_eq!(free_pages.free(&pages(15, 24)), Err("pages were already free"));
+		// a failed free leaves the free chunks unchanged
+		assert_eq!(chunks(&free_pages), [(0, 10), (20, 80)]);
+	}
+}
@@ -1,6 +1,6 @@
 use core::ops::{Deref, DerefMut};
 use super::paging::*;
-use super::{PAGE_SIZE, FrameAllocator, FrameAllocatorRef, VirtualAddress, EntryFlags, PageRange};
+use super::{PAGE_SIZE, FrameAllocator, FrameAllocatorRef, VirtualAddress, EntryFlags, PageRange, Page};
 use super::Mapper;
 
 #[derive(Debug)]
@@ -30,6 +30,26 @@ impl StackAllocator {
     /// Returns the newly-allocated stack and a VMA to represent its mapping.
     pub fn alloc_stack<FA>(&mut self, page_table: &mut Mapper, frame_allocator_ref: &FrameAllocatorRef<FA>, size_in_pages: usize)
             -> Option<Stack> where FA: FrameAllocator 
+    {
+        self.alloc_stack_internal(page_table, frame_allocator_ref, size_in_pages, false)
+    }
+
+    /// Same as [`alloc_stack()`](#method.alloc_stack), but reserves a growable stack of up to `size_in_pages`,
+    /// of which only the topmost page is mapped initially. 
+    /// The rest of the stack is mapped lazily, i.e., each page is only backed by a frame once the stack grows into it,
+    /// see [`Mapper::map_pages_lazily()`](struct.Mapper.html#method.map_pages_lazily).
+    /// 
+    /// Once the stack pointer moves into a page that isn't yet mapped, the page fault can't be pushed onto the stack,
+    /// so the stack is grown by the double fault handler instead, which runs on its own stack;
+    /// see [`handle_lazy_stack_fault()`](fn.handle_lazy_stack_fault.html). 
+    pub fn alloc_growable_stack<FA>(&mut self, page_table: &mut Mapper, frame_allocator_ref: &FrameAllocatorRef<FA>, size_in_pages: usize)
+            -> Option<Stack> where FA: FrameAllocator 
+    {
+        self.alloc_stack_internal(page_table, frame_allocator_ref, size_in_pages, true)
+    }
+
+    fn alloc_stack_internal<FA>(&mut self, page_table: &mut Mapper, frame_allocator_ref: &FrameAllocatorRef<FA>, size_in_pages: usize, growable: bool)
+            -> Option<Stack> where FA: FrameAllocator 
     {
         if size_in_pages == 0 {
             return None; /* a zero sized stack maikes no sense */
@@ -59,7 +79,13 @@ impl StackAllocator {
 
                 // map stack pages to physical frames
                 // but don't map the guard page, that should be left unmapped
-                let stack_pages = match page_table.map_pages(PageRange::new(start, end), flags, &mut *frame_allocator_ref.lock()) {
+                let stack_pages = if growable {
+                    page_table.map_pages_lazily(PageRange::new(start, end), flags, None)
+                        .and_then(|mp| populate_top_page(end).map(|_| mp))
+                } else {
+                    page_table.map_pages(PageRange::new(start, end), flags, &mut *frame_allocator_ref.lock())
+                };
+                let stack_pages = match stack_pages {
                     Ok(pages) => pages,
                     Err(e) => {
                         error!("alloc_stack(): couldn't map_pages for the new Stack, error: {}", e);
@@ -80,6 +106,15 @@ impl StackAllocator {
     }
 }
 
+/// Populates the topmost page of a growable stack, 
+/// so that a new task's initial context can be written to it without causing a page fault.
+fn populate_top_page(top_page: Page) -> Result<(), &'static str> {
+    match handle_lazy_page_fault(top_page.start_address(), true, false)? {
+        true => Ok(()),
+        false => Err("couldn't populate the top page of a growable stack"),
+    }
+}
+
 #[derive(Debug)]
 pub struct Stack {
     top: VirtualAddress,
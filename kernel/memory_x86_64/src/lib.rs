@@ -337,3 +337,16 @@ pub fn tlb_flush_all() {
 pub fn get_p4() -> PhysicalAddress {
     PhysicalAddress::new_canonical(control_regs::cr3().0 as usize)
 }
+
+/// Returns `true` if the CPU supports mapping 1 GiB huge pages with P3 entries,
+/// as reported by the `Page1GB` bit of CPUID leaf `0x8000_0001`.
+/// 2 MiB huge pages are always supported on x86_64.
+pub fn supports_1gib_pages() -> bool {
+    use core::arch::x86_64::__cpuid;
+    // SAFE: the `cpuid` instruction is available on all x86_64 CPUs,
+    // and the extended leaf is only queried if the CPU reports that it exists. 
+    unsafe {
+        __cpuid(0x8000_0000).eax >= 0x8000_0001
+            && __cpuid(0x8000_0001).edx & (1 << 26) != 0
+    }
+}
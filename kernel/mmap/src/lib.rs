@@ -0,0 +1,314 @@
+//! Maps the contents of a file into memory, an API similar to `mmap()` in Unix-like systems.
+//!
+//! A [`FileMapping`] is backed by a `MappedPages` object, and can be created in one of three [`MapMode`]s:
+//! * `ReadOnly`: the mapped memory cannot be written.
+//! * `ReadWrite`: writes to the mapped memory are written back to the file
+//!   when the mapping is synced with [`FileMapping::sync()`] or unmapped.
+//! * `Private`: the mapped memory is a private copy of the file that can be written,
+//!   but those writes are never written back to the file.
+//!
+//! Files whose contents already reside in memory, i.e., those whose `File::as_mapping()` succeeds (such as a `MemFile`),
+//! are mapped by sharing the frames that hold their contents, so writes through a `ReadWrite` mapping
+//! are immediately visible to readers of the file and vice versa.
+//! Note that if such a file grows beyond its current capacity, it moves its contents to new frames,
+//! after which the mapping no longer reflects the file.
+//! All other files, e.g., a `HeapFile` or a file on disk, are mapped by copying their contents into newly-allocated pages.
+//!
+//! A `Private` mapping of a file whose contents reside in memory is a one-sided copy-on-write mapping
+//! of the frames that hold them, see `MappedPages::copy_on_write_one_sided()`:
+//! each of its pages is only copied when it's first written, so pages that are only read use no additional memory,
+//! while the file itself keeps writing to its own frames.
+//! Thus, changes made to the file after it was mapped are visible in pages of the mapping that weren't yet written.
+//! `Private` mappings of all other files are created by eagerly copying the file's contents.
+//!
+//! Only the pages that were actually written are written back, which is determined from the hardware's dirty bits.
+
+#![no_std]
+
+#[macro_use] extern crate log;
+extern crate alloc;
+extern crate memory;
+extern crate fs_node;
+extern crate kernel_config;
+
+use alloc::vec::Vec;
+use core::ops::DerefMut;
+use fs_node::{FileRef, Permissions};
+use memory::{MappedPages, EntryFlags, Frame, VirtualAddress, get_kernel_mmi_ref, get_frame_allocator_ref, allocate_pages_by_bytes};
+use kernel_config::memory::PAGE_SIZE;
+
+
+/// The ways in which a file can be mapped into memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapMode {
+    /// The mapping can only be read. Requires the file's `READ` permission.
+    ReadOnly,
+    /// The mapping can be read and written, and writes are written back to the file.
+    /// Requires the file's `READ` and `WRITE` permissions.
+    ReadWrite,
+    /// The mapping can be read and written, but writes are never written back to the file.
+    /// Requires the file's `READ` permission.
+    Private,
+}
+
+impl MapMode {
+    /// Returns the permissions that a file must have in order to be mapped in this mode.
+    pub fn required_permissions(&self) -> Permissions {
+        match self {
+            MapMode::ReadWrite => Permissions::READ | Permissions::WRITE,
+            MapMode::ReadOnly | MapMode::Private => Permissions::READ,
+        }
+    }
+
+    /// Returns the page table entry flags of a mapping in this mode.
+    fn entry_flags(&self) -> EntryFlags {
+        match self {
+            MapMode::ReadOnly => EntryFlags::NO_EXECUTE,
+            MapMode::ReadWrite | MapMode::Private => EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+        }
+    }
+}
+
+
+/// A region of a file that has been mapped into memory.
+///
+/// When dropped, a `ReadWrite` mapping writes its modified pages back to the file and is then unmapped.
+/// Errors that occur while writing back are only logged, so use [`unmap()`](#method.unmap) to handle them.
+pub struct FileMapping {
+    /// The file that was mapped, which is kept alive by this mapping.
+    file: FileRef,
+    /// The offset into the file at which the mapped region begins.
+    offset: usize,
+    /// The length in bytes of the mapped region.
+    length: usize,
+    mode: MapMode,
+    /// Whether `pages` are mapped to the same frames that hold the file's contents,
+    /// rather than to a copy of them.
+    shared: bool,
+    pages: MappedPages,
+    /// Whether this mapping was already written back by [`unmap()`](#method.unmap),
+    /// such that it must not be written back again when dropped.
+    unmapped: bool,
+}
+
+impl FileMapping {
+    /// Returns the mode in which the file was mapped.
+    pub fn mode(&self) -> MapMode {
+        self.mode
+    }
+
+    /// Returns the file that was mapped.
+    pub fn file(&self) -> &FileRef {
+        &self.file
+    }
+
+    /// Returns the offset into the file at which the mapped region begins.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the length in bytes of the mapped region.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Returns the virtual address at which the mapped region begins.
+    pub fn start_address(&self) -> VirtualAddress {
+        self.pages.start_address()
+    }
+
+    /// Returns `true` if this mapping shares the memory that holds the file's contents,
+    /// instead of being a copy of them.
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    /// Returns the underlying `MappedPages`, which may extend beyond the mapped region to the end of its last page.
+    pub fn mapped_pages(&self) -> &MappedPages {
+        &self.pages
+    }
+
+    /// Returns the mapped region as a byte slice.
+    pub fn as_slice(&self) -> Result<&[u8], &'static str> {
+        self.pages.as_slice(0, self.length)
+    }
+
+    /// Returns the mapped region as a mutable byte slice.
+    /// Fails if this mapping is `ReadOnly`.
+    pub fn as_slice_mut(&mut self) -> Result<&mut [u8], &'static str> {
+        self.pages.as_slice_mut(0, self.length)
+    }
+
+    /// Writes the pages of this mapping that were modified since they were mapped or last synced back to the file.
+    /// Does nothing unless this mapping is `ReadWrite`.
+    ///
+    /// Returns the number of bytes that were written to the file.
+    pub fn sync(&mut self) -> Result<usize, &'static str> {
+        if self.mode != MapMode::ReadWrite {
+            return Ok(0);
+        }
+
+        let dirty_pages = {
+            let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("mmap::sync(): KERNEL_MMI was not yet initialized!")?;
+            let mut kernel_mmi = kernel_mmi_ref.lock();
+            self.pages.take_dirty_pages(&mut kernel_mmi.page_table)?
+        };
+        // A shared mapping is the file's own memory, so there is nothing to write back.
+        if self.shared || dirty_pages.is_empty() {
+            return Ok(0);
+        }
+
+        let start = self.pages.start_address().value();
+        let mut written = 0;
+        let mut file = self.file.lock();
+        for page in dirty_pages {
+            let page_offset = page.start_address().value() - start;
+            let page_length = core::cmp::min(PAGE_SIZE, self.length - page_offset);
+            let contents = self.pages.as_slice::<u8>(page_offset, page_length)?;
+            written += file.write(contents, self.offset + page_offset)?;
+        }
+        Ok(written)
+    }
+
+    /// Writes back any modified pages, like [`sync()`](#method.sync), and then unmaps this mapping.
+    ///
+    /// Unlike dropping this mapping, this returns any error that occurs while writing back,
+    /// in which case the mapping is still unmapped.
+    pub fn unmap(mut self) -> Result<usize, &'static str> {
+        let result = self.sync();
+        self.unmapped = true;
+        result
+    }
+}
+
+impl Drop for FileMapping {
+    fn drop(&mut self) {
+        if self.unmapped {
+            return;
+        }
+        if let Err(e) = self.sync() {
+            error!("mmap: failed to write back mapping of file {:?} at offset {:#X} when unmapping it: {}",
+                self.file.lock().get_name(), self.offset, e
+            );
+        }
+    }
+}
+
+
+/// Maps `length` bytes of the given `file`, starting at `offset`, into memory in the given `mode`.
+///
+/// The `offset` must be a multiple of the page size, and the region must lie entirely within the file.
+/// The file must have the permissions required by the `mode`, see [`MapMode::required_permissions()`].
+///
+/// # Locking / Deadlock
+/// This function acquires the locks on the `file`, the frame allocator, and the kernel's `MemoryManagementInfo`,
+/// so the caller must not hold any of them.
+pub fn map_file(file: &FileRef, offset: usize, length: usize, mode: MapMode) -> Result<FileMapping, &'static str> {
+    if offset % PAGE_SIZE != 0 {
+        return Err("mmap::map_file(): offset must be a multiple of the page size");
+    }
+    if length == 0 {
+        return Err("mmap::map_file(): cannot map an empty region");
+    }
+    let end = offset.checked_add(length).ok_or("mmap::map_file(): region is too large")?;
+    {
+        let f = file.lock();
+        let metadata = f.metadata();
+        let required = mode.required_permissions();
+        if !metadata.permissions.contains(required) {
+            return Err(if required.contains(Permissions::WRITE) && !metadata.permissions.contains(Permissions::WRITE) {
+                "permission denied: file is not writable"
+            } else {
+                "permission denied: file is not readable"
+            });
+        }
+        if end > f.size() {
+            return Err("mmap::map_file(): region extends beyond the end of the file");
+        }
+    }
+
+    let flags = mode.entry_flags();
+    if mode == MapMode::Private {
+        if let Some(pages) = map_copy_on_write(file, offset, length, flags)? {
+            return Ok(FileMapping { file: file.clone(), offset, length, mode, shared: false, pages, unmapped: false });
+        }
+    }
+    else if let Some(pages) = map_shared(file, offset, length, flags)? {
+        return Ok(FileMapping { file: file.clone(), offset, length, mode, shared: true, pages, unmapped: false });
+    }
+    let pages = map_copy(file, offset, length, flags)?;
+    Ok(FileMapping { file: file.clone(), offset, length, mode, shared: false, pages, unmapped: false })
+}
+
+
+/// Maps new pages to the frames that hold the given region of the file's contents.
+/// Returns `None` if the file's contents aren't held in memory.
+fn map_shared(file: &FileRef, offset: usize, length: usize, flags: EntryFlags) -> Result<Option<MappedPages>, &'static str> {
+    let f = file.lock();
+    let file_mp = match f.as_mapping() {
+        Ok(mp) if mp.size_in_bytes() >= offset + length => mp,
+        _ => return Ok(None),
+    };
+    if flags.is_writable() && !file_mp.flags().is_writable() {
+        return Err("mmap::map_file(): the file's memory is not writable");
+    }
+
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("mmap::map_file(): KERNEL_MMI was not yet initialized!")?;
+    let mut kernel_mmi = kernel_mmi_ref.lock();
+    let first_page = *file_mp.start() + offset / PAGE_SIZE;
+    let num_pages = (length + PAGE_SIZE - 1) / PAGE_SIZE;
+    let frames = (0 .. num_pages)
+        .map(|i| kernel_mmi.page_table.translate_page(first_page + i).ok_or("mmap::map_file(): the file's page was not mapped"))
+        .collect::<Result<Vec<Frame>, &'static str>>()?;
+
+    let pages = allocate_pages_by_bytes(length).ok_or("mmap::map_file(): couldn't allocate pages")?;
+    let allocator = get_frame_allocator_ref().ok_or("mmap::map_file(): couldn't get frame allocator")?;
+    let mp = kernel_mmi.page_table.map_allocated_pages_to_frames(pages, &frames, flags, allocator.lock().deref_mut())?;
+    Ok(Some(mp))
+}
+
+
+/// Maps new copy-on-write pages to the frames that hold the given region of the file's contents,
+/// such that each page is only copied when it's first written through the new mapping.
+/// Returns `None` if the file's contents aren't held in memory, or if its frames can't be copied on write,
+/// e.g., because they also have a `ReadWrite` mapping.
+fn map_copy_on_write(file: &FileRef, offset: usize, length: usize, flags: EntryFlags) -> Result<Option<MappedPages>, &'static str> {
+    let f = file.lock();
+    let file_mp = match f.as_mapping() {
+        Ok(mp) if mp.size_in_bytes() >= offset + length => mp,
+        _ => return Ok(None),
+    };
+
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("mmap::map_file(): KERNEL_MMI was not yet initialized!")?;
+    let allocator = get_frame_allocator_ref().ok_or("mmap::map_file(): couldn't get frame allocator")?;
+    let mut kernel_mmi = kernel_mmi_ref.lock();
+    match file_mp.copy_on_write_one_sided(offset, length, Some(flags), &mut kernel_mmi.page_table, allocator.lock().deref_mut()) {
+        Ok(mp) => Ok(Some(mp)),
+        Err(_e) => Ok(None),
+    }
+}
+
+
+/// Maps new pages to new frames and copies the given region of the file's contents into them.
+fn map_copy(file: &FileRef, offset: usize, length: usize, flags: EntryFlags) -> Result<MappedPages, &'static str> {
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("mmap::map_file(): KERNEL_MMI was not yet initialized!")?;
+    let allocator = get_frame_allocator_ref().ok_or("mmap::map_file(): couldn't get frame allocator")?;
+    let pages = allocate_pages_by_bytes(length).ok_or("mmap::map_file(): couldn't allocate pages")?;
+    // The pages must be writable in order to copy the contents into them.
+    let mut mp = kernel_mmi_ref.lock().page_table.map_allocated_pages(pages, flags | EntryFlags::WRITABLE, allocator.lock().deref_mut())?;
+
+    // The kernel MMI lock must not be held here, since reading a file may need to create new mappings, e.g., for DMA.
+    {
+        let dest = mp.as_slice_mut::<u8>(0, length)?;
+        let bytes_read = file.lock().read(dest, offset)?;
+        if bytes_read < length {
+            return Err("mmap::map_file(): couldn't read the entire region of the file");
+        }
+    }
+
+    let mut kernel_mmi = kernel_mmi_ref.lock();
+    mp.remap(&mut kernel_mmi.page_table, flags)?;
+    // Copying the contents dirtied every page, but only pages written from now on need to be written back.
+    mp.take_dirty_pages(&mut kernel_mmi.page_table)?;
+    Ok(mp)
+}
@@ -60,6 +60,13 @@ pub mod replace_nano_core_crates;
 /// The name of the directory that contains all of the CrateNamespace files.
 pub const NAMESPACES_DIRECTORY_NAME: &'static str = "namespaces";
 
+/// The name of the directory that contains all of the bootloader modules that are data files rather than crates.
+pub const DATA_MODULES_DIRECTORY_NAME: &'static str = "modules";
+
+/// The prefix that marks a bootloader module as a data file (e.g., a disk image) rather than a crate object file,
+/// e.g., `d#disk.img`. Such modules are placed into the [`DATA_MODULES_DIRECTORY_NAME`] directory without the prefix.
+pub const DATA_MODULE_PREFIX: &'static str = "d#";
+
 /// The initial `CrateNamespace` that all kernel crates are added to by default.
 static INITIAL_KERNEL_NAMESPACE: Once<Arc<CrateNamespace>> = Once::new();
 
@@ -75,6 +82,14 @@ pub fn get_namespaces_directory() -> Option<DirRef> {
     root::get_root().lock().get_dir(NAMESPACES_DIRECTORY_NAME)
 }
 
+/// Returns the bootloader data module with the given `name` (without the `DATA_MODULE_PREFIX`),
+/// e.g., `disk.img` for a module loaded as `d#disk.img`.
+pub fn get_data_module(name: &str) -> Option<FileRef> {
+    root::get_root().lock()
+        .get_dir(DATA_MODULES_DIRECTORY_NAME)
+        .and_then(|dir| dir.lock().get_file(name))
+}
+
 
 /// Create a new application `CrateNamespace` that uses the default application directory 
 /// and is structured atop the given `recursive_namespace`. 
@@ -120,6 +135,9 @@ pub fn init(boot_info: &BootInformation, kernel_mmi: &mut MemoryManagementInfo)
 /// This function does not create any namespaces, it just populates the files and directories
 /// such that namespaces can be created based on those files.
 /// 
+/// Data modules, whose names start with the `DATA_MODULE_PREFIX`, are instead placed into the top-level
+/// data modules directory, see [`get_data_module()`](fn.get_data_module.html).
+/// 
 /// Returns a tuple of: 
 /// * the top-level root "namespaces" directory that contains all other namespace directories,
 /// * the directory of the default kernel crate namespace.
@@ -141,12 +159,12 @@ fn parse_bootloader_modules_into_files(
         VFSDirectory::new(dir_name.to_string(), &namespaces_dir).map(|d| NamespaceDir(d))
     };
 
+    // The directory that holds data modules, which is only created if there are any data modules.
+    let mut data_modules_dir: Option<DirRef> = None;
+
     for m in boot_info.module_tags() {
         let size_in_bytes = (m.end_address() - m.start_address()) as usize;
         let frames = FrameRange::from_phys_addr(PhysicalAddress::new(m.start_address() as usize)?, size_in_bytes);
-        let (crate_type, prefix, file_name) = CrateType::from_module_name(m.name())?;
-        let dir_name = format!("{}{}", prefix, crate_type.default_namespace_name());
-        let name = String::from(file_name);
 
         let pages = allocate_pages_by_bytes(size_in_bytes).ok_or("Couldn't allocate virtual pages for bootloader module area")?;
         let mp = kernel_mmi.page_table.map_allocated_pages_to(
@@ -156,6 +174,20 @@ fn parse_bootloader_modules_into_files(
             fa.lock().deref_mut()
         )?;
 
+        if m.name().starts_with(DATA_MODULE_PREFIX) {
+            let name = String::from(&m.name()[DATA_MODULE_PREFIX.len() ..]);
+            if data_modules_dir.is_none() {
+                data_modules_dir = Some(VFSDirectory::new(DATA_MODULES_DIRECTORY_NAME.to_string(), root::get_root())?);
+            }
+            let dir = data_modules_dir.as_ref().ok_or("BUG: data modules directory wasn't created")?;
+            MemFile::from_mapped_pages(mp, name, size_in_bytes, dir)?;
+            continue;
+        }
+
+        let (crate_type, prefix, file_name) = CrateType::from_module_name(m.name())?;
+        let dir_name = format!("{}{}", prefix, crate_type.default_namespace_name());
+        let name = String::from(file_name);
+
         // debug!("Module: {:?}, size {}, mp: {:?}", name, size_in_bytes, mp);
 
         let create_file = |dir: &DirRef| {
@@ -690,6 +722,13 @@ impl CrateNamespace {
     /// so to load an application crate multiple times to spawn multiple instances of it,
     /// you can create a new top-level namespace to hold that application crate.
     /// 
+    /// If this namespace was created by [`clone_on_write()`](#method.clone_on_write) from a namespace 
+    /// into which the application crate was already loaded, the shared crate is copied on write
+    /// instead of being loaded again, see [`copy_shared_crate()`](#method.copy_shared_crate).
+    /// This makes it cheap to spawn an application in many namespaces cloned from the same one,
+    /// but the copy starts out with the contents of the shared crate's data and bss sections as they currently are,
+    /// so the application should not have been run in the namespace that was cloned.
+    /// 
     /// Returns a Result containing the newly-loaded application crate itself.
     pub fn load_crate_as_application(
         namespace: &Arc<CrateNamespace>,
@@ -698,6 +737,19 @@ impl CrateNamespace {
         verbose_log: bool
     ) -> Result<AppCrateRef, &'static str> {
         debug!("load_crate_as_application(): trying to load application crate at {:?}", crate_object_file.lock().get_absolute_path());
+        #[cfg(internal_deps)]
+        {
+            let crate_name = crate_name_from_path(&Path::new(crate_object_file.lock().get_absolute_path())).to_string();
+            let is_shared = namespace.crate_tree.lock().get_str(&crate_name).map_or(false, |c| c.is_shared());
+            if is_shared {
+                let new_crate_ref = namespace.copy_shared_crate(&crate_name, kernel_mmi_ref)?;
+                info!("copied shared application crate: {:?} on write", crate_name);
+                return Ok(AppCrateRef {
+                    crate_ref: new_crate_ref,
+                    namespace: Arc::clone(namespace),
+                });
+            }
+        }
         // Don't use a backup namespace when loading applications;
         // we must be able to find all symbols in only this namespace and its backing recursive namespaces.
         let new_crate_ref = namespace.load_crate_internal(crate_object_file, None, kernel_mmi_ref, verbose_log)?;
@@ -831,6 +883,10 @@ impl CrateNamespace {
     /// The existing versions of `B` and `C` would still depend on `A`, 
     /// but they would no longer be part of the new namespace. 
     /// 
+    /// A shared crate can also be replaced with an exclusive copy without modifying it, 
+    /// e.g., to give each namespace its own instance of a crate's data and bss sections,
+    /// see [`copy_shared_crate()`](#method.copy_shared_crate).
+    /// 
     pub fn clone_on_write(&self) -> CrateNamespace {
         CrateNamespace {
             name: self.name.clone(),
@@ -843,6 +899,38 @@ impl CrateNamespace {
     }
 
 
+    /// Replaces the crate with the given `crate_name` in this namespace, if it is shared with other namespaces,
+    /// e.g., ones created by [`clone_on_write()`](#method.clone_on_write), 
+    /// with a copy of that crate that is exclusively owned by this namespace. 
+    /// The copied crate's global symbols replace those of the shared crate in this namespace's symbol map.
+    /// 
+    /// The copy shares its memory with the original crate in a copy-on-write manner, 
+    /// see [`LoadedCrate::deep_copy()`](../crate_metadata/struct.LoadedCrate.html#method.deep_copy),
+    /// so copying a crate only duplicates the pages that are written afterwards by either crate,
+    /// which makes it cheap to give many namespaces their own instances of the same crate. 
+    /// 
+    /// Other crates that depend on the shared crate are not modified and still depend on the shared crate,
+    /// so this is intended for crates that no other crate in this namespace depends on, e.g., application crates.
+    /// 
+    /// Returns the exclusive crate, which is the existing crate if it was not shared.
+    /// 
+    /// This is only available when the `internal_deps` cfg option is set.
+    #[cfg(internal_deps)]
+    pub fn copy_shared_crate(&self, crate_name: &str, kernel_mmi_ref: &MmiRef) -> Result<StrongCrateRef, &'static str> {
+        let old_crate_ref = self.crate_tree.lock().get_str(crate_name)
+            .map(|c| CowArc::clone_shallow(c))
+            .ok_or("copy_shared_crate(): couldn't find crate in this namespace")?;
+        if !old_crate_ref.is_shared() {
+            return Ok(old_crate_ref);
+        }
+
+        let new_crate_ref = old_crate_ref.lock_as_ref().deep_copy(kernel_mmi_ref)?;
+        self.add_symbols(new_crate_ref.lock_as_ref().sections.values(), false);
+        self.crate_tree.lock().insert_str(crate_name, CowArc::clone_shallow(&new_crate_ref));
+        Ok(new_crate_ref)
+    }
+
+
     /// Finds all of the weak dependents (sections that depend on the given `old_section`)
     /// and rewrites their relocation entries to point to the given `new_section`.
     /// This effectively replaces the usage of the `old_section` with the `new_section`,
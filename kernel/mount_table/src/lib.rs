@@ -0,0 +1,172 @@
+#![no_std]
+//! A table of the filesystems that are mounted onto directories in the filesystem tree.
+//!
+//! Mounting a filesystem instance, i.e., the root directory of a memfs tree, the task filesystem,
+//! or a disk filesystem like FAT32, onto an existing directory (the *mount point*)
+//! hides the mount point's contents behind the mounted filesystem's root directory
+//! until that filesystem is unmounted.
+//! Multiple filesystems can be stacked on the same mount point, in which case the most recent one is visible.
+//!
+//! Mount points are crossed by path resolution, see `path::Path::get()`,
+//! which uses [`resolve()`](fn.resolve.html) to descend into a mounted filesystem
+//! and [`mount_point_of()`](fn.mount_point_of.html) to ascend back out of it via `..`.
+//!
+//! Absolute paths of nodes are computed from the names of their ancestors,
+//! so the root directory of a mounted filesystem should have the same name as its mount point.
+
+#[macro_use] extern crate lazy_static;
+#[macro_use] extern crate log;
+extern crate alloc;
+extern crate spin;
+extern crate fs_node;
+extern crate vfs_node;
+
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use spin::Mutex;
+use fs_node::{DirRef, FsNode, is_same_dir};
+use vfs_node::VFSDirectory;
+
+
+lazy_static! {
+    /// The list of all mounted filesystems, in the order they were mounted.
+    static ref MOUNT_TABLE: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+}
+
+
+/// Information about a mounted filesystem, as returned by [`mounts()`](fn.mounts.html).
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    /// The absolute path of the mount point at the time the filesystem was mounted.
+    pub path: String,
+    /// The type of the filesystem, e.g., `"memfs"` or `"fat32"`.
+    pub fs_type: String,
+    /// Where the filesystem came from, e.g., the name of the storage device it resides on.
+    pub source: String,
+}
+
+/// A single entry in the mount table.
+struct Mount {
+    /// The directory that the filesystem is mounted on, which is hidden while the filesystem is mounted.
+    mount_point: DirRef,
+    /// The root directory of the mounted filesystem.
+    root: DirRef,
+    info: MountInfo,
+}
+
+
+/// Returns `true` if the given absolute `path` is strictly below the given absolute `dir_path`.
+fn is_below(path: &str, dir_path: &str) -> bool {
+    if dir_path == "/" {
+        return path != "/";
+    }
+    path.starts_with(dir_path) && path[dir_path.len() ..].starts_with('/')
+}
+
+/// Mounts the filesystem whose root directory is `fs_root` onto the given `mount_point` directory.
+///
+/// If another filesystem is already mounted on `mount_point`, the new filesystem is stacked on top of it.
+/// The `fs_root`'s parent directory is set to the `mount_point`'s parent directory,
+/// such that `..` within the mounted filesystem leads out of it.
+///
+/// # Arguments
+/// * `mount_point`: the existing directory to mount onto.
+/// * `fs_root`: the root directory of the filesystem to mount, which must not already be mounted.
+/// * `fs_type`: the type of the filesystem, used only for display purposes.
+/// * `source`: where the filesystem came from, used only for display purposes.
+pub fn mount(mount_point: &DirRef, fs_root: DirRef, fs_type: &str, source: &str) -> Result<(), &'static str> {
+    if is_same_dir(mount_point, &fs_root) {
+        return Err("cannot mount a directory onto itself");
+    }
+    // Stack the new filesystem on top of any existing ones at this mount point.
+    let mount_point = resolve(Arc::clone(mount_point));
+    let (path, mount_point_parent) = {
+        let locked_mount_point = mount_point.lock();
+        (locked_mount_point.get_absolute_path(), locked_mount_point.get_parent_dir())
+    };
+
+    {
+        let table = MOUNT_TABLE.lock();
+        if table.iter().any(|m| is_same_dir(&m.root, &fs_root)) {
+            return Err("that filesystem is already mounted");
+        }
+    }
+
+    if let Some(parent) = mount_point_parent {
+        fs_root.lock().set_parent_dir(Arc::downgrade(&parent));
+    }
+
+    debug!("Mounting {} filesystem from {:?} at {:?}", fs_type, source, path);
+    MOUNT_TABLE.lock().push(Mount {
+        mount_point,
+        root: fs_root,
+        info: MountInfo {
+            path,
+            fs_type: String::from(fs_type),
+            source: String::from(source),
+        },
+    });
+    Ok(())
+}
+
+/// Unmounts the filesystem that is mounted on the given `dir`,
+/// which can be either the mount point itself or the root directory of the mounted filesystem.
+/// If multiple filesystems are stacked on a mount point, the most recently mounted one is unmounted.
+///
+/// Returns the root directory of the unmounted filesystem.
+/// An error is returned if no filesystem is mounted on `dir`,
+/// or if another filesystem is still mounted on a directory within the filesystem being unmounted.
+pub fn unmount(dir: &DirRef) -> Result<DirRef, &'static str> {
+    let mut table = MOUNT_TABLE.lock();
+    let index = table.iter().rposition(|m| is_same_dir(&m.root, dir))
+        .or_else(|| table.iter().rposition(|m| is_same_dir(&m.mount_point, dir)))
+        .ok_or("no filesystem is mounted there")?;
+
+    // Only the topmost filesystem on a mount point can be unmounted.
+    let mount_point = Arc::clone(&table[index].mount_point);
+    let root = Arc::clone(&table[index].root);
+    if table.iter().any(|m| is_same_dir(&m.mount_point, &root)) {
+        return Err("another filesystem is mounted on top of that filesystem");
+    }
+    // A filesystem that has other filesystems mounted within it cannot be unmounted.
+    let root_path = table[index].info.path.clone();
+    let nested = table.iter().any(|m| !is_same_dir(&m.mount_point, &mount_point) && is_below(&m.info.path, &root_path));
+    if nested {
+        return Err("another filesystem is mounted within that filesystem");
+    }
+
+    let removed = table.remove(index);
+    drop(table);
+    removed.root.lock().set_parent_dir(Weak::<Mutex<VFSDirectory>>::new());
+    debug!("Unmounted {} filesystem from {:?}", removed.info.fs_type, removed.info.path);
+    Ok(removed.root)
+}
+
+/// If a filesystem is mounted on the given `dir`, this returns the root directory of that filesystem
+/// (following stacked mounts to the most recently mounted filesystem).
+/// Otherwise, `dir` itself is returned.
+pub fn resolve(dir: DirRef) -> DirRef {
+    let table = MOUNT_TABLE.lock();
+    let mut current = dir;
+    while let Some(m) = table.iter().rev().find(|m| is_same_dir(&m.mount_point, &current)) {
+        current = Arc::clone(&m.root);
+    }
+    current
+}
+
+/// If the given `dir` is the root directory of a mounted filesystem,
+/// this returns the directory that it is mounted on.
+pub fn mount_point_of(dir: &DirRef) -> Option<DirRef> {
+    MOUNT_TABLE.lock().iter()
+        .find(|m| is_same_dir(&m.root, dir))
+        .map(|m| Arc::clone(&m.mount_point))
+}
+
+/// Returns information about every mounted filesystem, in the order they were mounted.
+pub fn mounts() -> Vec<MountInfo> {
+    MOUNT_TABLE.lock().iter().map(|m| m.info.clone()).collect()
+}
+
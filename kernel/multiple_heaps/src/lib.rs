@@ -18,6 +18,15 @@
 //! When a per-core heap runs out of memory, pages are first moved between the slab allocators of the per-core heap, then requested from other per-core heaps.
 //! If no empty pages are available within any of the per-core heaps, then more virtual pages are allocated from the range of virtual addresses dedicated to the heap
 //! [KERNEL_HEAP_START](../kernel_config/memory/constant.KERNEL_HEAP_START.html) and dynamically mapped to physical memory frames.
+//! 
+//! When a deallocation leaves a size class of a per-core heap with more than `EMPTY_PAGES_WATERMARK` empty pages,
+//! the heaps note that pages can be reclaimed. The extra empty pages are then unmapped and their frames are returned to the frame allocator
+//! by [`reclaim_empty_pages()`](fn.reclaim_empty_pages.html), which the captain's background reclaim task calls periodically, 
+//! so that memory used by a burst of allocations doesn't stay in one heap forever.
+//! Pages are never unmapped within `dealloc()` itself, since the memory may be deallocated while holding locks that unmapping acquires.
+//! The address of a reclaimed page is reused the next time a heap is grown.
+//! Empty pages can also be moved between per-core heaps with [`move_empty_pages()`](fn.move_empty_pages.html).
+//! Pages are not reclaimed when using the unsafe heap, since all of its pages belong to a single `MappedPages` object.
 
 #![feature(const_fn)]
 #![feature(allocator_api)]
@@ -30,7 +39,14 @@ extern crate kernel_config;
 extern crate apic;
 extern crate heap;
 extern crate hashbrown;
+extern crate spin;
 #[macro_use] extern crate cfg_if;
+#[cfg(feature = "tracing_allocator")]
+extern crate tracing_allocator;
+
+// the tracing allocator can only record the call stacks of allocations by following frame pointers
+#[cfg(all(feature = "tracing_allocator", not(frame_pointers)))]
+compile_error!("the `tracing_allocator` feature requires frame pointers, i.e., the `-C force-frame-pointers=yes` rust flags option");
 
 #[cfg(all(not(unsafe_heap), not(safe_heap)))]
 extern crate slabmalloc;
@@ -44,13 +60,16 @@ extern crate slabmalloc_safe;
 use core::ptr::NonNull;
 use alloc::alloc::{GlobalAlloc, Layout};
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use hashbrown::HashMap;
 use memory::{MappedPages, VirtualAddress, get_frame_allocator_ref, get_kernel_mmi_ref, PageRange, create_mapping};
 use kernel_config::memory::{PAGE_SIZE, KERNEL_HEAP_START, KERNEL_HEAP_INITIAL_SIZE, KERNEL_HEAP_MAX_SIZE};
 use core::ops::{Add, Deref, DerefMut};
 use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use heap::HEAP_FLAGS;
 use irq_safety::MutexIrqSafe;
+use spin::Once;
 
 #[cfg(all(not(unsafe_heap), not(safe_heap)))]
 use slabmalloc::{ZoneAllocator, ObjectPage8k, AllocablePage, MappedPages8k};
@@ -63,7 +82,7 @@ use slabmalloc_safe::{ZoneAllocator, ObjectPage8k, AllocablePage, MappedPages8k}
 
 /// The size of each MappedPages Object that is allocated for the per-core heaps, in bytes.
 /// We curently work with 8KiB, so that the per core heaps can allocate objects up to `ZoneAllocator::MAX_ALLOC_SIZE`.  
-const HEAP_MAPPED_PAGES_SIZE_IN_BYTES: usize = ObjectPage8k::SIZE;
+pub const HEAP_MAPPED_PAGES_SIZE_IN_BYTES: usize = ObjectPage8k::SIZE;
 
 /// The size of each MappedPages Object that is allocated for the per-core heaps, in pages.
 /// We curently work with 2 pages, so that the per core heaps can allocate objects up to `ZoneAllocator::MAX_ALLOC_SIZE`.   
@@ -77,6 +96,16 @@ const EMPTY_PAGES_THRESHOLD: usize = ZoneAllocator::MAX_BASE_SIZE_CLASSES * 2;
 /// The number of pages each size class in the ZoneAllocator is initialized with. It is approximately 100 KiB.
 const PAGES_PER_SIZE_CLASS: usize = 24; 
 
+/// The number of empty mapped pages that each size class of a per-core heap keeps when memory is deallocated.
+/// Any further empty pages are unmapped and their frames are returned to the frame allocator.
+/// This is the number of mapped pages each size class is initialized with, so a heap never shrinks below its initial size.
+const EMPTY_PAGES_WATERMARK: usize = PAGES_PER_SIZE_CLASS / HEAP_MAPPED_PAGES_SIZE_IN_PAGES;
+
+/// The maximum number of addresses of reclaimed pages that are kept for reuse when the heaps grow.
+/// They are kept in a vector whose capacity is reserved up front, such that recording them never allocates memory.
+#[cfg(not(unsafe_heap))]
+const MAX_RECLAIMED_PAGES: usize = 4096;
+
 /// Starting size of each per-core heap. 
 pub const PER_CORE_HEAP_INITIAL_SIZE_PAGES: usize = ZoneAllocator::MAX_BASE_SIZE_CLASSES *  PAGES_PER_SIZE_CLASS;
 
@@ -95,18 +124,128 @@ fn initialize_multiple_heaps() -> Result<MultipleHeaps, &'static str> {
 }
 
 
+/// The multiple heaps that were set as the default allocator by `switch_to_multiple_heaps()`,
+/// which are kept here so that their memory usage can be inspected, see `stats()`.
+static MULTIPLE_HEAPS: Once<MultipleHeaps> = Once::new();
+
 /// The setup routine for multiple heaps. It creates and initializes the multiple heaps,
 /// then sets the multiple heaps as the default allocator.
 /// Only call this function when the multiple heaps are ready to be used.
 pub fn switch_to_multiple_heaps() -> Result<(), &'static str> {
-    let multiple_heaps = Box::new(initialize_multiple_heaps()?);
+    let multiple_heaps = initialize_multiple_heaps()?;
+    let multiple_heaps: &'static MultipleHeaps = MULTIPLE_HEAPS.call_once(|| multiple_heaps);
     //set the multiple heaps as the default allocator
-    heap::set_allocator(multiple_heaps);
+    #[cfg(not(feature = "tracing_allocator"))]
+    heap::set_allocator(Box::new(multiple_heaps));
+    // trace all allocations made from the multiple heaps, see the `tracing_allocator` crate
+    #[cfg(feature = "tracing_allocator")]
+    heap::set_allocator(Box::new(tracing_allocator::TracingAllocator::new(multiple_heaps)));
 
     Ok(())
 }
 
 
+/// The memory used by one per-core heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerCoreHeapStats {
+    /// The id of this heap, i.e., the apic id of the core it belongs to.
+    pub heap_id: usize,
+    /// The number of bytes in the pages that this heap allocates from.
+    pub size_in_bytes: usize,
+    /// The number of bytes currently allocated from this heap, as requested by those allocations.
+    pub allocated_bytes: usize,
+    /// The number of bytes in this heap's empty pages, which hold no allocations
+    /// and can be reclaimed or moved to other heaps.
+    pub empty_bytes: usize,
+}
+
+impl PerCoreHeapStats {
+    /// The number of bytes in this heap's non-empty pages that are not allocated,
+    /// i.e., the memory lost to fragmentation and to rounding allocations up to their size class.
+    pub fn fragmented_bytes(&self) -> usize {
+        self.size_in_bytes.saturating_sub(self.empty_bytes).saturating_sub(self.allocated_bytes)
+    }
+}
+
+/// The memory used by the multiple heaps.
+#[derive(Debug, Clone)]
+pub struct MultipleHeapsStats {
+    /// The memory used by each per-core heap, in order of heap id.
+    pub per_core: Vec<PerCoreHeapStats>,
+    /// The number of bytes currently allocated by large allocations,
+    /// which are mapped separately instead of being allocated from a per-core heap.
+    pub large_allocation_bytes: usize,
+    /// The number of pages (of `ObjectPage8k::SIZE` bytes each) that were mapped to grow the per-core heaps.
+    pub pages_mapped: usize,
+    /// The number of empty pages that were unmapped and whose frames were returned to the frame allocator.
+    pub pages_reclaimed: usize,
+    /// The number of empty pages that were moved from one per-core heap to another.
+    pub pages_moved: usize,
+}
+
+impl MultipleHeapsStats {
+    /// The number of bytes in the pages of all per-core heaps, plus the number of bytes in large allocations.
+    pub fn size_in_bytes(&self) -> usize {
+        self.per_core.iter().map(|h| h.size_in_bytes).sum::<usize>() + self.large_allocation_bytes
+    }
+
+    /// The number of bytes currently allocated from all per-core heaps and by large allocations.
+    pub fn allocated_bytes(&self) -> usize {
+        self.per_core.iter().map(|h| h.allocated_bytes).sum::<usize>() + self.large_allocation_bytes
+    }
+}
+
+/// Returns the memory used by the multiple heaps,
+/// or `None` if they haven't yet been set as the default allocator.
+pub fn stats() -> Option<MultipleHeapsStats> {
+    MULTIPLE_HEAPS.try().map(|multiple_heaps| multiple_heaps.stats())
+}
+
+/// Moves up to `count` empty pages from the per-core heap given by `from_heap_id` to the one given by `to_heap_id`.
+/// Returns the number of pages that were moved.
+pub fn move_empty_pages(from_heap_id: usize, to_heap_id: usize, count: usize) -> Result<usize, &'static str> {
+    MULTIPLE_HEAPS.try().ok_or("multiple heaps have not been set as the default allocator")?
+        .move_empty_pages(from_heap_id, to_heap_id, count)
+}
+
+/// Unmaps the empty pages beyond `EMPTY_PAGES_WATERMARK` in every size class of every per-core heap,
+/// and returns their frames to the frame allocator.
+/// Returns the number of pages that were reclaimed.
+/// 
+/// This must be called from a task that doesn't hold any memory management locks, e.g., the frame allocator's.
+#[cfg(not(unsafe_heap))]
+pub fn reclaim_empty_pages() -> Result<usize, &'static str> {
+    Ok(MULTIPLE_HEAPS.try().ok_or("multiple heaps have not been set as the default allocator")?
+        .reclaim_empty_pages())
+}
+
+/// Pages can't be reclaimed from the unsafe heap, since all of its pages belong to a single `MappedPages` object.
+#[cfg(unsafe_heap)]
+pub fn reclaim_empty_pages() -> Result<usize, &'static str> {
+    Err("empty pages can't be reclaimed from the unsafe heap")
+}
+
+/// Returns `true` if deallocations have left more than `EMPTY_PAGES_WATERMARK` empty pages in a size class of a per-core heap
+/// since empty pages were last reclaimed, i.e., if calling `reclaim_empty_pages()` would be worthwhile.
+#[cfg(not(unsafe_heap))]
+pub fn has_reclaimable_pages() -> bool {
+    MULTIPLE_HEAPS.try().map_or(false, |multiple_heaps| multiple_heaps.has_reclaimable_pages())
+}
+
+/// Pages can't be reclaimed from the unsafe heap, so this always returns `false`.
+#[cfg(unsafe_heap)]
+pub fn has_reclaimable_pages() -> bool {
+    false
+}
+
+/// Returns the layout with which the size class for objects of the given `size` is refilled.
+fn size_class_layout(size: usize) -> Result<Layout, &'static str> {
+    // the alignment is equal to the size unless the size is not a multiple of 2
+    let alignment = if size == ZoneAllocator::MAX_BASE_ALLOC_SIZE { 8 } else { size };
+    Layout::from_size_align(size, alignment).map_err(|_e| "Incorrect layout")
+}
+
+
 
 /// Allocates pages from the given starting address and maps them to frames.
 /// Returns the new mapped pages or an error if the heap memory limit is reached.
@@ -141,9 +280,6 @@ fn create_heap_mapping(starting_address: VirtualAddress, size_in_bytes: usize) -
 cfg_if! {
 if #[cfg(unsafe_heap)] {
     #[macro_use] extern crate alloc;
-    extern crate spin;
-
-    use spin::Once;
 
     /// Initializes the heap given by `key`.
     /// There are 11 size classes in each heap ranging from [8,16,32,64 ..`ZoneAllocator::MAX_ALLOC_SIZE`].
@@ -184,10 +320,11 @@ if #[cfg(unsafe_heap)] {
         }
 
         // store the new end of the heap after this core has been initialized
+        let heap_size = heap_end_addr.value() - heap_end.value();
         *heap_end = heap_end_addr;
 
         // store the newly created allocator in the multiple heaps object
-        if let Some(_heap) = multiple_heaps.heaps.insert(key, LockedHeap(MutexIrqSafe::new(zone_allocator))) {
+        if let Some(_heap) = multiple_heaps.heaps.insert(key, LockedHeap(MutexIrqSafe::new(zone_allocator), HeapUsage::new(heap_size))) {
             return Err("New heap created with a previously used id");
         }
         trace!("Created heap {} with max alloc size: {} bytes", key, ZoneAllocator::MAX_ALLOC_SIZE);
@@ -232,10 +369,11 @@ if #[cfg(unsafe_heap)] {
         }
 
         // store the new end of the heap after this core has been initialized
+        let heap_size = heap_end_addr.value() - heap_end.value();
         *heap_end = heap_end_addr;
 
         // store the newly created allocator in the multiple heaps object
-        if let Some(_heap) = multiple_heaps.heaps.insert(key, LockedHeap(MutexIrqSafe::new(zone_allocator))) {
+        if let Some(_heap) = multiple_heaps.heaps.insert(key, LockedHeap(MutexIrqSafe::new(zone_allocator), HeapUsage::new(heap_size))) {
             return Err("New heap created with a previously used id");
         }
         trace!("Created heap {} with max alloc size: {} bytes", key, ZoneAllocator::MAX_ALLOC_SIZE);
@@ -259,7 +397,7 @@ fn get_key() -> usize {
 cfg_if! {
 if #[cfg(safe_heap)] {
     #[repr(align(64))]
-    struct LockedHeap (MutexIrqSafe<ZoneAllocator>);
+    struct LockedHeap (MutexIrqSafe<ZoneAllocator>, HeapUsage);
 
     impl Deref for LockedHeap {
         type Target = MutexIrqSafe<ZoneAllocator>;
@@ -269,7 +407,7 @@ if #[cfg(safe_heap)] {
     }
 } else {
     #[repr(align(64))]
-    struct LockedHeap (MutexIrqSafe<ZoneAllocator<'static>>);
+    struct LockedHeap (MutexIrqSafe<ZoneAllocator<'static>>, HeapUsage);
 
     impl Deref for LockedHeap {
         type Target = MutexIrqSafe<ZoneAllocator<'static>>;
@@ -280,6 +418,23 @@ if #[cfg(safe_heap)] {
 }
 } // end cfg_if for LockedHeap versions
 
+/// The memory used by one per-core heap, which is only modified while that heap's lock is held.
+struct HeapUsage {
+    /// The number of bytes in the pages that the heap allocates from.
+    size: AtomicUsize,
+    /// The number of bytes currently allocated from the heap.
+    allocated: AtomicUsize,
+}
+
+impl HeapUsage {
+    fn new(size: usize) -> HeapUsage {
+        HeapUsage {
+            size: AtomicUsize::new(size),
+            allocated: AtomicUsize::new(0),
+        }
+    }
+}
+
 
 /// An allocator that contains multiple heaps. The heap that is used on each allocation is
 /// determined by a key. Currently the apic id is used as the key.
@@ -289,10 +444,26 @@ pub struct MultipleHeaps{
     /// Red-black tree to store large allocations
     #[cfg(not(unsafe_large_allocations))]    
     large_allocations: MutexIrqSafe<RBTree<LargeAllocationAdapter>>,
-    /// We currently don't return memory back to the OS. Because of this all memory in the heap is contiguous
-    /// and extra memory for the heap is always allocated from the end.
+    /// The end of the heap, from which extra memory for the heap is allocated
+    /// if there are no reclaimed pages whose addresses can be reused.
     /// The Mutex also serves the purpose of helping to synchronize new allocations.
     end: MutexIrqSafe<VirtualAddress>, 
+    /// The starting addresses of pages that were reclaimed, i.e., unmapped and returned to the frame allocator,
+    /// which are reused before the heap is extended from its end.
+    #[cfg(not(unsafe_heap))]
+    reclaimed: MutexIrqSafe<Vec<VirtualAddress>>,
+    /// Whether a deallocation left a size class of a per-core heap with more than `EMPTY_PAGES_WATERMARK` empty pages
+    /// since the last call to `reclaim_empty_pages()`.
+    #[cfg(not(unsafe_heap))]
+    reclaim_pending: AtomicBool,
+    /// The number of bytes currently allocated by large allocations.
+    large_allocation_bytes: AtomicUsize,
+    /// The number of pages mapped to grow the per-core heaps.
+    pages_mapped: AtomicUsize,
+    /// The number of empty pages that were reclaimed.
+    pages_reclaimed: AtomicUsize,
+    /// The number of empty pages moved between per-core heaps.
+    pages_moved: AtomicUsize,
     /// The mapped pages for the unsafe heap are stored here so that they are not dropped and unmapped.
     #[cfg(unsafe_heap)]    
     mp: Once<MutexIrqSafe<MappedPages>>
@@ -316,6 +487,12 @@ if #[cfg(unsafe_heap)] {
 
                 end: MutexIrqSafe::new(VirtualAddress::new_canonical(KERNEL_HEAP_START + KERNEL_HEAP_INITIAL_SIZE)),
 
+                large_allocation_bytes: AtomicUsize::new(0),
+
+                pages_mapped: AtomicUsize::new(0),
+                pages_reclaimed: AtomicUsize::new(0),
+                pages_moved: AtomicUsize::new(0),
+
                 mp: Once::new()
             }
         }
@@ -334,6 +511,9 @@ if #[cfg(unsafe_heap)] {
             for locked_heap in self.heaps.values() {
                 if let Some(mp) = locked_heap.try_lock().and_then(|mut giving_heap| giving_heap.retrieve_empty_page(EMPTY_PAGES_THRESHOLD)) {
                     info!("Added page from another heap to heap: {}", heap.heap_id);
+                    locked_heap.1.size.fetch_sub(HEAP_MAPPED_PAGES_SIZE_IN_BYTES, Ordering::Relaxed);
+                    self.record_heap_growth(heap.heap_id);
+                    self.pages_moved.fetch_add(1, Ordering::Relaxed);
                     return heap.refill(layout, mp);
                 }
             }
@@ -345,6 +525,8 @@ if #[cfg(unsafe_heap)] {
             let page = unsafe{ core::mem::transmute(start_addr) };
             info!("grow_heap:: Allocated a page to refill core heap {} for size :{} at address: {:#X}", heap.heap_id, layout.size(), *heap_end);
             *heap_end += HEAP_MAPPED_PAGES_SIZE_IN_BYTES;
+            self.record_heap_growth(heap.heap_id);
+            self.pages_mapped.fetch_add(1, Ordering::Relaxed);
             heap.refill(layout, page)
         } 
 
@@ -370,13 +552,22 @@ if #[cfg(unsafe_heap)] {
                 #[cfg(not(unsafe_large_allocations))]
                 large_allocations: MutexIrqSafe::new(RBTree::new(LargeAllocationAdapter::new())),
 
-                end: MutexIrqSafe::new(VirtualAddress::new_canonical(KERNEL_HEAP_START + KERNEL_HEAP_INITIAL_SIZE))
+                end: MutexIrqSafe::new(VirtualAddress::new_canonical(KERNEL_HEAP_START + KERNEL_HEAP_INITIAL_SIZE)),
+
+                reclaimed: MutexIrqSafe::new(Vec::with_capacity(MAX_RECLAIMED_PAGES)),
+                reclaim_pending: AtomicBool::new(false),
+
+                large_allocation_bytes: AtomicUsize::new(0),
+
+                pages_mapped: AtomicUsize::new(0),
+                pages_reclaimed: AtomicUsize::new(0),
+                pages_moved: AtomicUsize::new(0),
             }
         }
 
         /// Called when a call to allocate() returns a null pointer. The following steps are used to recover memory:
         /// (1) Pages are first taken from another heap.
-        /// (2) If the above fails, then more pages are allocated from the OS.
+        /// (2) If the above fails, then more pages are allocated from the OS, reusing the address of a reclaimed page if there is one.
         /// 
         /// An Err is returned if there is no more memory to be allocated in the heap memory area or if the heap page limit is reached.
         /// 
@@ -388,14 +579,16 @@ if #[cfg(unsafe_heap)] {
             for locked_heap in self.heaps.values() {
                 if let Some(mp) = locked_heap.try_lock().and_then(|mut giving_heap| giving_heap.retrieve_empty_page(EMPTY_PAGES_THRESHOLD)) {
                     info!("Added page from another heap to heap: {}", heap.heap_id);
+                    locked_heap.1.size.fetch_sub(HEAP_MAPPED_PAGES_SIZE_IN_BYTES, Ordering::Relaxed);
+                    self.record_heap_growth(heap.heap_id);
+                    self.pages_moved.fetch_add(1, Ordering::Relaxed);
                     return heap.refill(layout, mp);
                 }
             }
             // (2) Allocate page from the OS
-            let mut heap_end = self.end.lock();
-            let mp = MappedPages8k::new(create_heap_mapping(*heap_end, HEAP_MAPPED_PAGES_SIZE_IN_BYTES)?)?;
-            info!("grow_heap:: Allocated a page to refill core heap {} for size :{} at address: {:#X}", heap.heap_id, layout.size(), *heap_end);
-            *heap_end += HEAP_MAPPED_PAGES_SIZE_IN_BYTES;
+            let mp = self.map_heap_page()?;
+            info!("grow_heap:: Allocated a page to refill core heap {} for size :{} at address: {:#X}", heap.heap_id, layout.size(), mp.start_address());
+            self.record_heap_growth(heap.heap_id);
             heap.refill(layout, mp)
         }  
     }
@@ -409,7 +602,16 @@ if #[cfg(unsafe_heap)] {
                 #[cfg(not(unsafe_large_allocations))]
                 large_allocations: MutexIrqSafe::new(RBTree::new(LargeAllocationAdapter::new())),
 
-                end: MutexIrqSafe::new(VirtualAddress::new_canonical(KERNEL_HEAP_START + KERNEL_HEAP_INITIAL_SIZE))
+                end: MutexIrqSafe::new(VirtualAddress::new_canonical(KERNEL_HEAP_START + KERNEL_HEAP_INITIAL_SIZE)),
+
+                reclaimed: MutexIrqSafe::new(Vec::with_capacity(MAX_RECLAIMED_PAGES)),
+                reclaim_pending: AtomicBool::new(false),
+
+                large_allocation_bytes: AtomicUsize::new(0),
+
+                pages_mapped: AtomicUsize::new(0),
+                pages_reclaimed: AtomicUsize::new(0),
+                pages_moved: AtomicUsize::new(0),
             }
         }
 
@@ -427,20 +629,164 @@ if #[cfg(unsafe_heap)] {
             for locked_heap in self.heaps.values() {
                 if let Some(mp) = locked_heap.try_lock().and_then(|mut giving_heap| giving_heap.retrieve_empty_page(EMPTY_PAGES_THRESHOLD)) {
                     info!("Added page from another heap to heap: {}", heap.heap_id);
+                    locked_heap.1.size.fetch_sub(HEAP_MAPPED_PAGES_SIZE_IN_BYTES, Ordering::Relaxed);
+                    self.record_heap_growth(heap.heap_id);
+                    self.pages_moved.fetch_add(1, Ordering::Relaxed);
                     return heap.refill(layout, mp);
                 }
             }
             // (2) Allocate page from the OS
-            let mut heap_end = self.end.lock();
-            let mp = MappedPages8k::new(create_heap_mapping(*heap_end, HEAP_MAPPED_PAGES_SIZE_IN_BYTES)?)?;
-            info!("grow_heap:: Allocated a page to refill core heap {} for size :{} at address: {:#X}", heap.heap_id, layout.size(), *heap_end);
-            *heap_end += HEAP_MAPPED_PAGES_SIZE_IN_BYTES;
+            let mp = self.map_heap_page()?;
+            info!("grow_heap:: Allocated a page to refill core heap {} for size :{} at address: {:#X}", heap.heap_id, layout.size(), mp.start_address());
+            self.record_heap_growth(heap.heap_id);
             heap.refill(layout, mp)
         }  
     }
 }
 } // end cfg_if for MultipleHeaps impl
 
+impl MultipleHeaps {
+    /// Returns the memory used by each per-core heap and by large allocations.
+    pub fn stats(&self) -> MultipleHeapsStats {
+        let mut per_core: Vec<PerCoreHeapStats> = self.heaps.iter().map(|(heap_id, locked_heap)| PerCoreHeapStats {
+            heap_id: *heap_id,
+            size_in_bytes: locked_heap.1.size.load(Ordering::Relaxed),
+            allocated_bytes: locked_heap.1.allocated.load(Ordering::Relaxed),
+            empty_bytes: locked_heap.lock().empty_pages() * HEAP_MAPPED_PAGES_SIZE_IN_BYTES,
+        }).collect();
+        per_core.sort_by_key(|h| h.heap_id);
+        MultipleHeapsStats {
+            per_core,
+            large_allocation_bytes: self.large_allocation_bytes.load(Ordering::Relaxed),
+            pages_mapped: self.pages_mapped.load(Ordering::Relaxed),
+            pages_reclaimed: self.pages_reclaimed.load(Ordering::Relaxed),
+            pages_moved: self.pages_moved.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Moves up to `count` empty pages from the per-core heap given by `from_heap_id` to the one given by `to_heap_id`,
+    /// where each page is given to the size class that has the fewest empty pages.
+    /// Returns the number of pages that were moved.
+    pub fn move_empty_pages(&self, from_heap_id: usize, to_heap_id: usize, count: usize) -> Result<usize, &'static str> {
+        let from_heap = self.heaps.get(&from_heap_id).ok_or("move_empty_pages(): there is no heap with the given id")?;
+        let to_heap = self.heaps.get(&to_heap_id).ok_or("move_empty_pages(): there is no heap with the given id")?;
+        if from_heap_id == to_heap_id {
+            return Ok(0);
+        }
+
+        let mut moved = 0;
+        while moved < count {
+            // only one heap is locked at a time, so this can't deadlock with another core moving pages the other way
+            let page = match from_heap.lock().retrieve_empty_page(0) {
+                Some(page) => page,
+                None => break,
+            };
+            from_heap.1.size.fetch_sub(HEAP_MAPPED_PAGES_SIZE_IN_BYTES, Ordering::Relaxed);
+
+            let mut heap = to_heap.lock();
+            let size_class = heap.empty_pages_per_size_class().iter().enumerate()
+                .min_by_key(|&(_, empty_pages)| *empty_pages)
+                .map(|(size_class, _)| size_class)
+                .unwrap_or(0);
+            heap.refill(size_class_layout(ZoneAllocator::BASE_ALLOC_SIZES[size_class])?, page)?;
+            to_heap.1.size.fetch_add(HEAP_MAPPED_PAGES_SIZE_IN_BYTES, Ordering::Relaxed);
+            self.pages_moved.fetch_add(1, Ordering::Relaxed);
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
+    /// Unmaps the empty pages beyond `EMPTY_PAGES_WATERMARK` in every size class of every per-core heap,
+    /// and returns their frames to the frame allocator.
+    /// Returns the number of pages that were reclaimed.
+    /// 
+    /// This must not be called while holding a lock that unmapping pages acquires, e.g., the frame allocator's.
+    #[cfg(not(unsafe_heap))]
+    pub fn reclaim_empty_pages(&self) -> usize {
+        self.reclaim_pending.store(false, Ordering::Relaxed);
+        let mut reclaimed = 0;
+        for locked_heap in self.heaps.values() {
+            for size in ZoneAllocator::BASE_ALLOC_SIZES.iter() {
+                let layout = match size_class_layout(*size) {
+                    Ok(layout) => layout,
+                    Err(_e) => continue,
+                };
+                loop {
+                    let mp = match locked_heap.lock().retrieve_empty_page_above_watermark(layout, EMPTY_PAGES_WATERMARK) {
+                        Some(mp) => mp,
+                        None => break,
+                    };
+                    self.reclaim_page(locked_heap, mp);
+                    reclaimed += 1;
+                }
+            }
+        }
+        reclaimed
+    }
+
+    /// Returns `true` if a size class of a per-core heap was left with more than `EMPTY_PAGES_WATERMARK` empty pages
+    /// since the last call to `reclaim_empty_pages()`.
+    #[cfg(not(unsafe_heap))]
+    pub fn has_reclaimable_pages(&self) -> bool {
+        self.reclaim_pending.load(Ordering::Relaxed)
+    }
+
+    /// Unmaps the given empty page `mp` that was removed from the per-core heap `locked_heap`,
+    /// which returns its frames to the frame allocator, and records its address for reuse.
+    /// 
+    /// The heap's lock must not be held, since returning the frames to the frame allocator may allocate memory.
+    #[cfg(not(unsafe_heap))]
+    fn reclaim_page(&self, locked_heap: &LockedHeap, mp: MappedPages8k) {
+        let start_address = mp.start_address();
+        drop(mp);
+        self.record_reclaimed_address(start_address);
+        locked_heap.1.size.fetch_sub(HEAP_MAPPED_PAGES_SIZE_IN_BYTES, Ordering::Relaxed);
+        self.pages_reclaimed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that the page at the given `start_address` is no longer mapped, such that the heaps can be grown into it again.
+    #[cfg(not(unsafe_heap))]
+    fn record_reclaimed_address(&self, start_address: VirtualAddress) {
+        let mut reclaimed = self.reclaimed.lock();
+        // Pushing beyond the reserved capacity would allocate memory while holding the lock, 
+        // so the address is forgotten instead, which only wastes a bit of the heap's virtual address space.
+        if reclaimed.len() < reclaimed.capacity() {
+            reclaimed.push(start_address);
+        }
+    }
+
+    /// Maps a new page for the per-core heaps at the address of a reclaimed page,
+    /// or at the end of the heap if there are none.
+    #[cfg(not(unsafe_heap))]
+    fn map_heap_page(&self) -> Result<MappedPages8k, &'static str> {
+        let mut heap_end = self.end.lock();
+        let reclaimed_address = self.reclaimed.lock().pop();
+        let start_address = reclaimed_address.unwrap_or(*heap_end);
+        match create_heap_mapping(start_address, HEAP_MAPPED_PAGES_SIZE_IN_BYTES).and_then(MappedPages8k::new) {
+            Ok(mp) => {
+                if reclaimed_address.is_none() {
+                    *heap_end += HEAP_MAPPED_PAGES_SIZE_IN_BYTES;
+                }
+                self.pages_mapped.fetch_add(1, Ordering::Relaxed);
+                Ok(mp)
+            }
+            Err(e) => {
+                if let Some(start_address) = reclaimed_address {
+                    self.record_reclaimed_address(start_address);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Records that the per-core heap given by `heap_id` was given another page of `HEAP_MAPPED_PAGES_SIZE_IN_BYTES`.
+    fn record_heap_growth(&self, heap_id: usize) {
+        if let Some(locked_heap) = self.heaps.get(&heap_id) {
+            locked_heap.1.size.fetch_add(HEAP_MAPPED_PAGES_SIZE_IN_BYTES, Ordering::Relaxed);
+        }
+    }
+}
+
 
 unsafe impl GlobalAlloc for MultipleHeaps {
 
@@ -451,21 +797,31 @@ unsafe impl GlobalAlloc for MultipleHeaps {
         // allocate a large object by directly obtaining mapped pages from the OS
         if layout.size() > ZoneAllocator::MAX_ALLOC_SIZE {
             #[cfg(not(unsafe_large_allocations))]
-            return allocate_large_object(
+            let ptr = allocate_large_object(
                 layout, 
                 &mut self.large_allocations.lock()
             );
 
             #[cfg(unsafe_large_allocations)]
-            return allocate_large_object(layout);
+            let ptr = allocate_large_object(layout);
+
+            if !ptr.is_null() {
+                self.large_allocation_bytes.fetch_add(layout.size(), Ordering::Relaxed);
+            }
+            return ptr;
         }
 
         let id = get_key();
-        let mut heap = self.heaps.get(&id).expect("Multiple Heaps: heap is not initialized!").lock();
+        let locked_heap = self.heaps.get(&id).expect("Multiple Heaps: heap is not initialized!");
+        let mut heap = locked_heap.lock();
 
-        heap.allocate(layout)
+        let ptr = heap.allocate(layout)
             .or_else(|_e| self.grow_heap(layout, &mut heap).and_then(|_| heap.allocate(layout)))
-            .map(|allocation| allocation.as_ptr()).unwrap_or(ptr::null_mut())
+            .map(|allocation| allocation.as_ptr()).unwrap_or(ptr::null_mut());
+        if !ptr.is_null() {
+            locked_heap.1.allocated.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
     }
 
     /// Deallocates the memory at the address given by `ptr`.
@@ -474,6 +830,8 @@ unsafe impl GlobalAlloc for MultipleHeaps {
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {   
         // deallocate a large object by directly returning mapped pages to the OS
         if layout.size() > ZoneAllocator::MAX_ALLOC_SIZE {
+            self.large_allocation_bytes.fetch_sub(layout.size(), Ordering::Relaxed);
+
             #[cfg(not(unsafe_large_allocations))]
             return deallocate_large_object(
                 ptr, 
@@ -492,8 +850,33 @@ unsafe impl GlobalAlloc for MultipleHeaps {
         let page_addr = (ptr as usize) & !(ObjectPage8k::SIZE - 1);
         // find the heap id
         let id = *((page_addr as *mut u8).offset(ObjectPage8k::HEAP_ID_OFFSET as isize) as *mut usize);
-        let mut heap = self.heaps.get(&id).expect("Multiple Heaps: Heap not initialized").lock();
+        let locked_heap = self.heaps.get(&id).expect("Multiple Heaps: Heap not initialized");
+        let mut heap = locked_heap.lock();
         heap.deallocate(NonNull::new_unchecked(ptr), layout).expect("Couldn't deallocate");
+        locked_heap.1.allocated.fetch_sub(layout.size(), Ordering::Relaxed);
+
+        // Only note that this size class now has too many empty pages. They're unmapped later by `reclaim_empty_pages()`,
+        // because unmapping takes locks (e.g., the frame allocator's) that the code deallocating this memory may be holding.
+        #[cfg(not(unsafe_heap))]
+        {
+            if heap.empty_pages_for(layout) > EMPTY_PAGES_WATERMARK {
+                self.reclaim_pending.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Forwards to the `MultipleHeaps` that `switch_to_multiple_heaps()` sets as the default allocator,
+/// which are kept in a static such that their memory usage can still be inspected.
+unsafe impl<'a> GlobalAlloc for &'a MultipleHeaps {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        (**self).alloc(layout)
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        (**self).dealloc(ptr, layout)
     }
 }
 
@@ -0,0 +1,179 @@
+//! Parsing of GUID Partition Tables (GPT).
+//!
+//! A GPT consists of a header at sector 1 that describes an array of partition entries, typically starting at sector 2.
+//! A backup copy of the header and the entry array is kept at the end of the disk.
+//! Both the header and the entry array are protected by CRC32 checksums stored in the header.
+//!
+//! See <https://wiki.osdev.org/GPT> and <https://en.wikipedia.org/wiki/GUID_Partition_Table>.
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use storage_device::StorageDeviceRef;
+use super::{Guid, PartitionEntry, PartitionType, read_sectors, read_u32, read_u64};
+
+
+/// The signature at the start of a GPT header.
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// The sector that holds the primary GPT header.
+const PRIMARY_HEADER_LBA: usize = 1;
+/// The minimum size of a GPT header, which is the size of all of its defined fields.
+const MIN_HEADER_SIZE: usize = 92;
+/// The offset of the header's CRC32 field within the header, which is treated as zero when computing the CRC32.
+const HEADER_CRC32_OFFSET: usize = 16;
+/// The minimum size of each entry in the partition entry array.
+const MIN_ENTRY_SIZE: usize = 128;
+/// The maximum size of the partition entry array that we accept, which guards against corrupted headers.
+const MAX_ENTRY_ARRAY_SIZE: usize = 1024 * 1024;
+/// The offset and size of the partition name within a partition entry, which is UTF-16LE encoded.
+const ENTRY_NAME_OFFSET: usize = 56;
+const ENTRY_NAME_SIZE: usize = 72;
+
+
+/// The fields of a GPT header that we use.
+struct GptHeader {
+    /// The sector of the other copy of the header, i.e., the backup header if this is the primary header.
+    alternate_lba: usize,
+    first_usable_lba: usize,
+    last_usable_lba: usize,
+    /// The first sector of the partition entry array.
+    entry_array_lba: usize,
+    entry_count: usize,
+    entry_size: usize,
+    /// The CRC32 of the whole partition entry array.
+    entry_array_crc32: u32,
+}
+
+/// Returns the partitions in the GPT on the given `device`.
+///
+/// The primary GPT is used if it is valid, otherwise the backup GPT at the end of the device is used.
+pub(crate) fn read_entries(
+    device: &StorageDeviceRef,
+    sector_size: usize,
+    device_sectors: usize,
+) -> Result<Vec<PartitionEntry>, &'static str> {
+    let primary = read_header(device, PRIMARY_HEADER_LBA, sector_size)
+        .and_then(|header| read_entry_array(device, &header, sector_size).map(|array| (header, array)));
+
+    let (header, entry_array) = match primary {
+        Ok(gpt) => gpt,
+        Err(e) => {
+            warn!("Primary GPT was invalid ({}), trying the backup GPT", e);
+            let backup_lba = device_sectors.checked_sub(1).ok_or("storage device was empty")?;
+            let header = read_header(device, backup_lba, sector_size)?;
+            let entry_array = read_entry_array(device, &header, sector_size)?;
+            (header, entry_array)
+        }
+    };
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entry_array.chunks_exact(header.entry_size).take(header.entry_count).enumerate() {
+        let mut type_guid = Guid::default();
+        type_guid.0.copy_from_slice(&entry[0 .. 16]);
+        if type_guid.is_zero() {
+            continue;
+        }
+        let mut unique_guid = Guid::default();
+        unique_guid.0.copy_from_slice(&entry[16 .. 32]);
+        let first_lba = read_u64(entry, 32) as usize;
+        // The last LBA of a GPT partition is inclusive.
+        let last_lba = read_u64(entry, 40) as usize;
+        let sector_count = match last_lba.checked_sub(first_lba).and_then(|n| n.checked_add(1)) {
+            Some(n) if first_lba >= header.first_usable_lba && last_lba <= header.last_usable_lba => n,
+            _ => {
+                warn!("GPT partition {} had invalid bounds ({} to {}), ignoring it", i + 1, first_lba, last_lba);
+                continue;
+            }
+        };
+
+        let name = parse_name(&entry[ENTRY_NAME_OFFSET .. ENTRY_NAME_OFFSET + ENTRY_NAME_SIZE]);
+        partitions.push(PartitionEntry {
+            number: i + 1,
+            start_sector: first_lba,
+            sector_count,
+            partition_type: PartitionType::Gpt(type_guid),
+            unique_guid: Some(unique_guid),
+            label: if name.is_empty() { None } else { Some(name) },
+        });
+    }
+    Ok(partitions)
+}
+
+/// Reads and validates the GPT header at the given `lba`.
+fn read_header(device: &StorageDeviceRef, lba: usize, sector_size: usize) -> Result<GptHeader, &'static str> {
+    let mut sector = read_sectors(device, lba, 1)?;
+    if &sector[0 .. 8] != GPT_SIGNATURE {
+        return Err("GPT header had an invalid signature");
+    }
+    let header_size = read_u32(&sector, 12) as usize;
+    if header_size < MIN_HEADER_SIZE || header_size > sector_size {
+        return Err("GPT header had an invalid size");
+    }
+    let expected_crc32 = read_u32(&sector, HEADER_CRC32_OFFSET);
+    for b in &mut sector[HEADER_CRC32_OFFSET .. HEADER_CRC32_OFFSET + 4] {
+        *b = 0;
+    }
+    if crc32(&sector[.. header_size]) != expected_crc32 {
+        return Err("GPT header had an invalid CRC32");
+    }
+    if read_u64(&sector, 24) as usize != lba {
+        return Err("GPT header was not at the location it specified");
+    }
+
+    let header = GptHeader {
+        alternate_lba: read_u64(&sector, 32) as usize,
+        first_usable_lba: read_u64(&sector, 40) as usize,
+        last_usable_lba: read_u64(&sector, 48) as usize,
+        entry_array_lba: read_u64(&sector, 72) as usize,
+        entry_count: read_u32(&sector, 80) as usize,
+        entry_size: read_u32(&sector, 84) as usize,
+        entry_array_crc32: read_u32(&sector, 88),
+    };
+    // The entry size must be 128 multiplied by a power of two.
+    if header.entry_size < MIN_ENTRY_SIZE || !header.entry_size.is_power_of_two() {
+        return Err("GPT header had an invalid partition entry size");
+    }
+    if header.entry_count.saturating_mul(header.entry_size) > MAX_ENTRY_ARRAY_SIZE {
+        return Err("GPT header had too many partition entries");
+    }
+    trace!("GPT header at sector {}: alternate header at sector {}, {} entries of {} bytes at sector {}",
+        lba, header.alternate_lba, header.entry_count, header.entry_size, header.entry_array_lba
+    );
+    Ok(header)
+}
+
+/// Reads the partition entry array described by the given `header` and validates its CRC32.
+fn read_entry_array(device: &StorageDeviceRef, header: &GptHeader, sector_size: usize) -> Result<Vec<u8>, &'static str> {
+    let array_size = header.entry_count * header.entry_size;
+    let sector_count = (array_size + sector_size - 1) / sector_size;
+    let mut array = read_sectors(device, header.entry_array_lba, sector_count)?;
+    array.truncate(array_size);
+    if crc32(&array) != header.entry_array_crc32 {
+        return Err("GPT partition entry array had an invalid CRC32");
+    }
+    Ok(array)
+}
+
+/// Decodes a partition name, which is a null-terminated (or full-length) UTF-16LE string.
+fn parse_name(bytes: &[u8]) -> String {
+    let units = bytes.chunks_exact(2)
+        .map(|c| (c[0] as u16) | (c[1] as u16) << 8)
+        .take_while(|&u| u != 0);
+    core::char::decode_utf16(units)
+        .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// Computes the CRC32 (IEEE 802.3, as used by GPT) of the given `bytes`.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0 .. 8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
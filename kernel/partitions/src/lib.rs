@@ -0,0 +1,568 @@
+//! Support for partitioned storage devices, i.e., disks that hold more than one volume.
+//!
+//! This crate parses the two common partition table formats:
+//! * the legacy Master Boot Record (MBR), including logical partitions within an extended partition,
+//! * the GUID Partition Table (GPT), whose header and partition entry array are validated using their CRC32 checksums,
+//!   falling back to the backup GPT at the end of the disk if the primary GPT is corrupted.
+//!
+//! Each partition found by [`read_partitions()`](fn.read_partitions.html) is represented by a [`Partition`],
+//! which is itself a `StorageDevice` that forwards reads and writes to the underlying storage device,
+//! offset by the partition's first sector and bounds-checked against the partition's size.
+//! Thus, a filesystem can be opened on a single partition exactly as it would be on a whole disk.
+
+#![no_std]
+
+#[cfg(test)]
+extern crate std;
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate storage_device;
+
+mod mbr;
+mod gpt;
+
+use core::fmt;
+use alloc::{
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use spin::Mutex;
+use storage_device::{StorageDevice, StorageDeviceRef};
+
+
+/// The type of a partition, as specified in its partition table entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    /// A partition from an MBR partition table, which is identified by a one-byte system ID, e.g., `0x0C` for FAT32 (LBA).
+    Mbr(u8),
+    /// A partition from a GPT, which is identified by a partition type GUID.
+    Gpt(Guid),
+}
+
+impl fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionType::Mbr(id) => write!(f, "{:#04X}", id),
+            PartitionType::Gpt(guid) => write!(f, "{}", guid),
+        }
+    }
+}
+
+
+/// A 128-bit globally unique identifier, as used in a GPT.
+///
+/// The GUID is stored in its on-disk byte order, in which the first three fields are little-endian.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// Returns `true` if this GUID is all zeros, which marks an unused GPT partition entry.
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|&b| b == 0)
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(f, "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            b[3], b[2], b[1], b[0],
+            b[5], b[4],
+            b[7], b[6],
+            b[8], b[9],
+            b[10], b[11], b[12], b[13], b[14], b[15],
+        )
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+
+/// A single partition on a storage device, which is itself usable as a `StorageDevice`.
+///
+/// All sector offsets given to this partition's `StorageDevice` methods are relative to the start of the partition,
+/// and accesses that extend beyond the end of the partition are rejected.
+pub struct Partition {
+    /// The storage device that this partition resides on.
+    device: StorageDeviceRef,
+    /// The number of this partition within its partition table, starting from 1.
+    /// MBR logical partitions are numbered from 5, after the four primary partition slots.
+    number: usize,
+    /// The absolute sector number on the underlying device where this partition starts.
+    start_sector: usize,
+    /// The number of sectors in this partition.
+    sector_count: usize,
+    /// The type of this partition.
+    partition_type: PartitionType,
+    /// The GUID that uniquely identifies this partition, only available for GPT partitions.
+    unique_guid: Option<Guid>,
+    /// The name of this partition, only available for GPT partitions.
+    label: Option<String>,
+}
+
+impl Partition {
+    /// Returns the number of this partition within its partition table, starting from 1.
+    pub fn number(&self) -> usize {
+        self.number
+    }
+
+    /// Returns the absolute sector number on the underlying storage device where this partition starts.
+    pub fn start_sector(&self) -> usize {
+        self.start_sector
+    }
+
+    /// Returns the type of this partition, either its MBR system ID or its GPT partition type GUID.
+    pub fn partition_type(&self) -> PartitionType {
+        self.partition_type
+    }
+
+    /// Returns the GUID that uniquely identifies this partition, if it is a GPT partition.
+    pub fn unique_guid(&self) -> Option<Guid> {
+        self.unique_guid
+    }
+
+    /// Returns the name of this partition, if it has one.
+    /// Only GPT partitions have names; MBR partitions do not.
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_ref().map(|s| s.as_str())
+    }
+
+    /// Returns the storage device that this partition resides on.
+    pub fn device(&self) -> &StorageDeviceRef {
+        &self.device
+    }
+
+    /// Checks that a transfer of `buffer_length` bytes starting at the partition-relative `offset_in_sectors`
+    /// lies entirely within this partition, and returns the absolute starting sector on the underlying device.
+    fn absolute_offset(&self, buffer_length: usize, offset_in_sectors: usize) -> Result<usize, &'static str> {
+        let sector_size = self.sector_size_in_bytes();
+        if buffer_length % sector_size != 0 {
+            return Err("Partition: the buffer length must be a multiple of the sector size");
+        }
+        let end = offset_in_sectors.checked_add(buffer_length / sector_size)
+            .ok_or("Partition: offset_in_sectors was out of bounds")?;
+        if end > self.sector_count {
+            return Err("Partition: access extended beyond the end of the partition");
+        }
+        Ok(self.start_sector + offset_in_sectors)
+    }
+}
+
+impl StorageDevice for Partition {
+    fn read_sectors(&mut self, buffer: &mut [u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+        let absolute_offset = self.absolute_offset(buffer.len(), offset_in_sectors)?;
+        self.device.lock().read_sectors(buffer, absolute_offset)
+    }
+
+    fn write_sectors(&mut self, buffer: &[u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+        let absolute_offset = self.absolute_offset(buffer.len(), offset_in_sectors)?;
+        self.device.lock().write_sectors(buffer, absolute_offset)
+    }
+
+    fn sector_size_in_bytes(&self) -> usize {
+        self.device.lock().sector_size_in_bytes()
+    }
+
+    fn size_in_sectors(&self) -> usize {
+        self.sector_count
+    }
+}
+
+impl fmt::Debug for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Partition")
+            .field("number", &self.number)
+            .field("start_sector", &self.start_sector)
+            .field("sector_count", &self.sector_count)
+            .field("partition_type", &self.partition_type)
+            .field("unique_guid", &self.unique_guid)
+            .field("label", &self.label)
+            .finish()
+    }
+}
+
+/// A `Partition` wrapped in an Arc and Mutex, which can be shared in a thread-safe manner.
+pub type PartitionRef = Arc<Mutex<Partition>>;
+
+
+/// A partition table entry as parsed from disk, before it is bound to its storage device.
+struct PartitionEntry {
+    number: usize,
+    start_sector: usize,
+    sector_count: usize,
+    partition_type: PartitionType,
+    unique_guid: Option<Guid>,
+    label: Option<String>,
+}
+
+
+/// Reads the partition table on the given storage `device` and returns all of the partitions on it.
+///
+/// The device is first checked for a GPT, which is indicated by a protective MBR,
+/// otherwise its MBR partition table is used.
+/// An empty list is returned if the device has no partition table, e.g., if a filesystem occupies the whole device.
+pub fn read_partitions(device: &StorageDeviceRef) -> Result<Vec<Partition>, &'static str> {
+    let (sector_size, device_sectors) = {
+        let locked_device = device.lock();
+        (locked_device.sector_size_in_bytes(), locked_device.size_in_sectors())
+    };
+    if sector_size < mbr::MBR_SIZE {
+        return Err("storage device's sector size was too small to hold a partition table");
+    }
+
+    let first_sector = read_sectors(device, 0, 1)?;
+    let mbr_entries = match mbr::parse_primary_entries(&first_sector) {
+        Some(entries) => entries,
+        None => return Ok(Vec::new()),
+    };
+
+    let entries = if mbr_entries.iter().any(|e| e.system_id == mbr::GPT_PROTECTIVE_ID) {
+        gpt::read_entries(device, sector_size, device_sectors)?
+    } else {
+        mbr::read_entries(device, &mbr_entries)?
+    };
+
+    let mut partitions = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let end = entry.start_sector.checked_add(entry.sector_count);
+        if entry.sector_count == 0 || end.map_or(true, |end| end > device_sectors) {
+            warn!("Skipping partition {} (start: {}, sectors: {}), which extends beyond the end of the storage device ({} sectors)",
+                entry.number, entry.start_sector, entry.sector_count, device_sectors
+            );
+            continue;
+        }
+        partitions.push(Partition {
+            device: Arc::clone(device),
+            number: entry.number,
+            start_sector: entry.start_sector,
+            sector_count: entry.sector_count,
+            partition_type: entry.partition_type,
+            unique_guid: entry.unique_guid,
+            label: entry.label,
+        });
+    }
+    Ok(partitions)
+}
+
+/// Reads `count` sectors starting at the absolute sector `lba` from the given `device` into a new buffer.
+fn read_sectors(device: &StorageDeviceRef, lba: usize, count: usize) -> Result<Vec<u8>, &'static str> {
+    let mut locked_device = device.lock();
+    let mut buffer = vec![0u8; count * locked_device.sector_size_in_bytes()];
+    locked_device.read_sectors(&mut buffer, lba)?;
+    Ok(buffer)
+}
+
+/// Reads a little-endian `u16` from the given `bytes` at the given `offset`.
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    (bytes[offset] as u16) | (bytes[offset + 1] as u16) << 8
+}
+
+/// Reads a little-endian `u32` from the given `bytes` at the given `offset`.
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    (read_u16(bytes, offset) as u32) | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+/// Reads a little-endian `u64` from the given `bytes` at the given `offset`.
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    (read_u32(bytes, offset) as u64) | (read_u32(bytes, offset + 4) as u64) << 32
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SECTOR_SIZE: usize = 512;
+    const DISK_SECTORS: usize = 256;
+    const GPT_ENTRY_COUNT: usize = 128;
+    const GPT_ENTRY_SIZE: usize = 128;
+    const GPT_ENTRY_ARRAY_SECTORS: usize = GPT_ENTRY_COUNT * GPT_ENTRY_SIZE / SECTOR_SIZE;
+    const FIRST_USABLE_LBA: usize = 2 + GPT_ENTRY_ARRAY_SECTORS;
+    const LAST_USABLE_LBA: usize = DISK_SECTORS - 2 - GPT_ENTRY_ARRAY_SECTORS;
+    const BACKUP_HEADER_LBA: usize = DISK_SECTORS - 1;
+
+    /// A storage device whose sectors are held in memory.
+    struct RamDisk {
+        sector_size: usize,
+        data: Vec<u8>,
+    }
+
+    impl StorageDevice for RamDisk {
+        fn read_sectors(&mut self, buffer: &mut [u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+            let start = offset_in_sectors.checked_mul(self.sector_size).ok_or("read was out of bounds")?;
+            let end = start.checked_add(buffer.len()).ok_or("read was out of bounds")?;
+            if end > self.data.len() {
+                return Err("read was out of bounds");
+            }
+            buffer.copy_from_slice(&self.data[start .. end]);
+            Ok(buffer.len() / self.sector_size)
+        }
+
+        fn write_sectors(&mut self, _buffer: &[u8], _offset_in_sectors: usize) -> Result<usize, &'static str> {
+            Err("RamDisk is read-only")
+        }
+
+        fn sector_size_in_bytes(&self) -> usize {
+            self.sector_size
+        }
+
+        fn size_in_sectors(&self) -> usize {
+            self.data.len() / self.sector_size
+        }
+    }
+
+    fn device(data: Vec<u8>) -> StorageDeviceRef {
+        Arc::new(Mutex::new(RamDisk { sector_size: SECTOR_SIZE, data }))
+    }
+
+    fn sector_mut(disk: &mut [u8], lba: usize) -> &mut [u8] {
+        &mut disk[lba * SECTOR_SIZE .. (lba + 1) * SECTOR_SIZE]
+    }
+
+    fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+        for i in 0 .. 4 {
+            bytes[offset + i] = (value >> (8 * i)) as u8;
+        }
+    }
+
+    fn put_u64(bytes: &mut [u8], offset: usize, value: u64) {
+        put_u32(bytes, offset, value as u32);
+        put_u32(bytes, offset + 4, (value >> 32) as u32);
+    }
+
+    /// Writes an MBR or EBR with the given `(system_id, relative_start, sector_count)` entries into the given `sector`.
+    fn write_mbr(sector: &mut [u8], entries: &[(u8, u32, u32)]) {
+        for (i, &(system_id, relative_start, sector_count)) in entries.iter().enumerate() {
+            let offset = 446 + i * 16;
+            sector[offset + 4] = system_id;
+            put_u32(sector, offset + 8, relative_start);
+            put_u32(sector, offset + 12, sector_count);
+        }
+        sector[510] = 0x55;
+        sector[511] = 0xAA;
+    }
+
+    fn mbr_disk(entries: &[(u8, u32, u32)]) -> Vec<u8> {
+        let mut disk = vec![0u8; DISK_SECTORS * SECTOR_SIZE];
+        write_mbr(sector_mut(&mut disk, 0), entries);
+        disk
+    }
+
+    /// Writes a GPT partition entry at the given `index` of the given entry `array`.
+    fn write_gpt_entry(array: &mut [u8], index: usize, first_lba: u64, last_lba: u64, name: &str) {
+        let entry = &mut array[index * GPT_ENTRY_SIZE .. (index + 1) * GPT_ENTRY_SIZE];
+        for b in &mut entry[0 .. 16] {
+            *b = 0xAF;
+        }
+        for b in &mut entry[16 .. 32] {
+            *b = index as u8 + 1;
+        }
+        put_u64(entry, 32, first_lba);
+        put_u64(entry, 40, last_lba);
+        for (i, unit) in name.encode_utf16().enumerate() {
+            entry[56 + 2 * i] = unit as u8;
+            entry[57 + 2 * i] = (unit >> 8) as u8;
+        }
+    }
+
+    /// Recomputes the CRC32 of the given GPT header `sector`, e.g., after one of its fields was changed.
+    fn seal_gpt_header(sector: &mut [u8]) {
+        put_u32(sector, 16, 0);
+        let crc32 = gpt::crc32(&sector[.. 92]);
+        put_u32(sector, 16, crc32);
+    }
+
+    fn write_gpt_header(sector: &mut [u8], lba: usize, alternate_lba: usize, entry_array_lba: usize, entry_array: &[u8]) {
+        sector[0 .. 8].copy_from_slice(b"EFI PART");
+        put_u32(sector, 8, 0x0001_0000);
+        put_u32(sector, 12, 92);
+        put_u64(sector, 24, lba as u64);
+        put_u64(sector, 32, alternate_lba as u64);
+        put_u64(sector, 40, FIRST_USABLE_LBA as u64);
+        put_u64(sector, 48, LAST_USABLE_LBA as u64);
+        put_u64(sector, 72, entry_array_lba as u64);
+        put_u32(sector, 80, GPT_ENTRY_COUNT as u32);
+        put_u32(sector, 84, GPT_ENTRY_SIZE as u32);
+        put_u32(sector, 88, gpt::crc32(entry_array));
+        seal_gpt_header(sector);
+    }
+
+    /// Returns a disk with a protective MBR and both a primary and a backup GPT with the given entry `array`.
+    fn gpt_disk(array: &[u8]) -> Vec<u8> {
+        let mut disk = mbr_disk(&[(mbr::GPT_PROTECTIVE_ID, 1, (DISK_SECTORS - 1) as u32)]);
+        let backup_entry_array_lba = LAST_USABLE_LBA + 1;
+        write_gpt_header(sector_mut(&mut disk, 1), 1, BACKUP_HEADER_LBA, 2, array);
+        disk[2 * SECTOR_SIZE .. FIRST_USABLE_LBA * SECTOR_SIZE].copy_from_slice(array);
+        disk[backup_entry_array_lba * SECTOR_SIZE .. BACKUP_HEADER_LBA * SECTOR_SIZE].copy_from_slice(array);
+        write_gpt_header(sector_mut(&mut disk, BACKUP_HEADER_LBA), BACKUP_HEADER_LBA, 1, backup_entry_array_lba, array);
+        disk
+    }
+
+    fn gpt_entry_array() -> Vec<u8> {
+        let mut array = vec![0u8; GPT_ENTRY_COUNT * GPT_ENTRY_SIZE];
+        write_gpt_entry(&mut array, 0, 40, 99, "root");
+        write_gpt_entry(&mut array, 2, 100, 199, "");
+        array
+    }
+
+    /// Returns the `(number, start_sector, size_in_sectors)` of each of the given partitions.
+    fn bounds(partitions: &[Partition]) -> Vec<(usize, usize, usize)> {
+        partitions.iter().map(|p| (p.number(), p.start_sector(), p.size_in_sectors())).collect()
+    }
+
+    #[test]
+    fn device_without_mbr_signature_has_no_partitions() {
+        let partitions = read_partitions(&device(vec![0u8; DISK_SECTORS * SECTOR_SIZE])).unwrap();
+        assert!(partitions.is_empty());
+    }
+
+    #[test]
+    fn truncated_mbr_is_rejected() {
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        write_mbr(&mut sector, &[(0x0C, 1, 10)]);
+        assert!(mbr::parse_primary_entries(&sector).is_some());
+        assert!(mbr::parse_primary_entries(&sector[.. SECTOR_SIZE - 1]).is_none());
+        assert!(mbr::parse_primary_entries(&[]).is_none());
+    }
+
+    #[test]
+    fn sector_size_too_small_for_mbr() {
+        let device: StorageDeviceRef = Arc::new(Mutex::new(RamDisk { sector_size: 256, data: vec![0u8; 4096] }));
+        assert!(read_partitions(&device).is_err());
+    }
+
+    #[test]
+    fn primary_mbr_partitions() {
+        let disk = mbr_disk(&[(0x0C, 1, 99), (0, 0, 0), (0x83, 100, 50)]);
+        let partitions = read_partitions(&device(disk)).unwrap();
+        assert_eq!(bounds(&partitions), [(1, 1, 99), (3, 100, 50)]);
+        assert_eq!(partitions[0].partition_type(), PartitionType::Mbr(0x0C));
+        assert_eq!(partitions[1].partition_type(), PartitionType::Mbr(0x83));
+        assert!(partitions[0].unique_guid().is_none() && partitions[0].label().is_none());
+    }
+
+    #[test]
+    fn mbr_partitions_beyond_the_device_are_skipped() {
+        let disk = mbr_disk(&[(0x0C, 1, 99), (0x0C, 200, 57), (0x0C, u32::max_value(), u32::max_value())]);
+        let partitions = read_partitions(&device(disk)).unwrap();
+        assert_eq!(bounds(&partitions), [(1, 1, 99)]);
+    }
+
+    #[test]
+    fn logical_partitions() {
+        let mut disk = mbr_disk(&[(0x0C, 1, 99), (0x05, 100, 100)]);
+        write_mbr(sector_mut(&mut disk, 100), &[(0x83, 1, 10), (0x05, 20, 30)]);
+        write_mbr(sector_mut(&mut disk, 120), &[(0x83, 1, 10)]);
+        let partitions = read_partitions(&device(disk)).unwrap();
+        assert_eq!(bounds(&partitions), [(1, 1, 99), (5, 101, 10), (6, 121, 10)]);
+    }
+
+    #[test]
+    fn malformed_ebr_chains_are_cut_off() {
+        // an EBR that points back to itself
+        let mut disk = mbr_disk(&[(0x05, 100, 100)]);
+        write_mbr(sector_mut(&mut disk, 100), &[(0x83, 1, 10), (0x05, 0, 30)]);
+        assert_eq!(bounds(&read_partitions(&device(disk)).unwrap()), [(5, 101, 10)]);
+
+        // an EBR that points beyond the extended partition, whose logical partition also extends beyond it
+        let mut disk = mbr_disk(&[(0x05, 100, 100)]);
+        write_mbr(sector_mut(&mut disk, 100), &[(0x83, 1, 1000), (0x05, u32::max_value(), 30)]);
+        assert!(read_partitions(&device(disk)).unwrap().is_empty());
+
+        // an EBR without a valid signature
+        let disk = mbr_disk(&[(0x0C, 1, 99), (0x05, 100, 100)]);
+        assert_eq!(bounds(&read_partitions(&device(disk)).unwrap()), [(1, 1, 99)]);
+    }
+
+    #[test]
+    fn protective_mbr_with_gpt() {
+        let partitions = read_partitions(&device(gpt_disk(&gpt_entry_array()))).unwrap();
+        assert_eq!(bounds(&partitions), [(1, 40, 60), (3, 100, 100)]);
+        assert_eq!(partitions[0].partition_type(), PartitionType::Gpt(Guid([0xAF; 16])));
+        assert_eq!(partitions[0].unique_guid(), Some(Guid([1; 16])));
+        assert_eq!(partitions[0].label(), Some("root"));
+        assert_eq!(partitions[1].label(), None);
+    }
+
+    #[test]
+    fn corrupted_primary_gpt_falls_back_to_backup() {
+        // a corrupted header
+        let mut disk = gpt_disk(&gpt_entry_array());
+        sector_mut(&mut disk, 1)[40] ^= 1;
+        assert_eq!(bounds(&read_partitions(&device(disk)).unwrap()), [(1, 40, 60), (3, 100, 100)]);
+
+        // a corrupted entry array
+        let mut disk = gpt_disk(&gpt_entry_array());
+        sector_mut(&mut disk, 2)[32] ^= 1;
+        assert_eq!(bounds(&read_partitions(&device(disk)).unwrap()), [(1, 40, 60), (3, 100, 100)]);
+
+        // a truncated header
+        let mut disk = gpt_disk(&gpt_entry_array());
+        put_u32(sector_mut(&mut disk, 1), 12, 91);
+        seal_gpt_header(sector_mut(&mut disk, 1));
+        assert_eq!(bounds(&read_partitions(&device(disk)).unwrap()), [(1, 40, 60), (3, 100, 100)]);
+    }
+
+    #[test]
+    fn corrupted_primary_and_backup_gpts() {
+        let mut disk = gpt_disk(&gpt_entry_array());
+        sector_mut(&mut disk, 1)[0] = 0;
+        sector_mut(&mut disk, BACKUP_HEADER_LBA)[0] = 0;
+        assert!(read_partitions(&device(disk)).is_err());
+    }
+
+    #[test]
+    fn gpt_header_with_overflowing_entry_array() {
+        // too many entries, whose array size would overflow
+        let mut disk = gpt_disk(&gpt_entry_array());
+        put_u32(sector_mut(&mut disk, 1), 80, u32::max_value());
+        put_u32(sector_mut(&mut disk, 1), 84, 1 << 31);
+        seal_gpt_header(sector_mut(&mut disk, 1));
+        assert_eq!(bounds(&read_partitions(&device(disk)).unwrap()), [(1, 40, 60), (3, 100, 100)]);
+
+        // an entry size that isn't a power of two
+        let mut disk = gpt_disk(&gpt_entry_array());
+        put_u32(sector_mut(&mut disk, 1), 84, 129);
+        seal_gpt_header(sector_mut(&mut disk, 1));
+        assert_eq!(bounds(&read_partitions(&device(disk)).unwrap()), [(1, 40, 60), (3, 100, 100)]);
+
+        // an entry array beyond the end of the device
+        let mut disk = gpt_disk(&gpt_entry_array());
+        put_u64(sector_mut(&mut disk, 1), 72, u64::max_value());
+        seal_gpt_header(sector_mut(&mut disk, 1));
+        assert_eq!(bounds(&read_partitions(&device(disk)).unwrap()), [(1, 40, 60), (3, 100, 100)]);
+    }
+
+    #[test]
+    fn gpt_entries_with_invalid_bounds_are_ignored() {
+        let mut array = gpt_entry_array();
+        // the last LBA comes before the first LBA
+        write_gpt_entry(&mut array, 3, 150, 149, "backwards");
+        // the partition starts before the first usable LBA, i.e., it overlaps the primary GPT
+        write_gpt_entry(&mut array, 4, 1, 50, "before");
+        // the partition ends after the last usable LBA, i.e., it overlaps the backup GPT
+        write_gpt_entry(&mut array, 5, 200, 230, "after");
+        let partitions = read_partitions(&device(gpt_disk(&array))).unwrap();
+        assert_eq!(bounds(&partitions), [(1, 40, 60), (3, 100, 100)]);
+    }
+
+    #[test]
+    fn gpt_entry_with_overflowing_size_is_ignored() {
+        let mut array = gpt_entry_array();
+        write_gpt_entry(&mut array, 3, 0, u64::max_value(), "everything");
+        let mut disk = gpt_disk(&array);
+        // allow the whole range of LBAs, such that only the size of the last entry is invalid
+        put_u64(sector_mut(&mut disk, 1), 40, 0);
+        put_u64(sector_mut(&mut disk, 1), 48, u64::max_value());
+        seal_gpt_header(sector_mut(&mut disk, 1));
+        let partitions = read_partitions(&device(disk)).unwrap();
+        assert_eq!(bounds(&partitions), [(1, 40, 60), (3, 100, 100)]);
+    }
+}
@@ -0,0 +1,165 @@
+//! Parsing of legacy Master Boot Record (MBR) partition tables,
+//! including the chain of Extended Boot Records (EBRs) that describe the logical partitions within an extended partition.
+//!
+//! See <https://wiki.osdev.org/Partition_Table> and <https://en.wikipedia.org/wiki/Extended_boot_record>.
+
+use alloc::vec::Vec;
+use storage_device::StorageDeviceRef;
+use super::{PartitionEntry, PartitionType, read_sectors, read_u32};
+
+
+/// The size of the MBR (and each EBR), which occupies the beginning of a sector.
+pub const MBR_SIZE: usize = 512;
+/// The system ID of the single partition in a protective MBR, which indicates that the device uses a GPT.
+pub const GPT_PROTECTIVE_ID: u8 = 0xEE;
+
+/// The offset of the partition table within an MBR or EBR.
+const PARTITION_TABLE_OFFSET: usize = 446;
+/// The size of each entry in an MBR partition table.
+const PARTITION_ENTRY_SIZE: usize = 16;
+/// The number of entries in an MBR partition table.
+const PARTITION_ENTRY_COUNT: usize = 4;
+/// The two-byte signature at the end of an MBR or EBR.
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+/// The system IDs of an extended partition, which contains a chain of logical partitions.
+const EXTENDED_PARTITION_IDS: [u8; 3] = [0x05, 0x0F, 0x85];
+/// Logical partitions are numbered after the four primary partition slots.
+const FIRST_LOGICAL_PARTITION_NUMBER: usize = 5;
+/// The maximum number of EBRs we follow, which guards against a corrupted chain that loops back on itself.
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+
+/// A single entry in an MBR or EBR partition table.
+#[derive(Debug, Clone, Copy)]
+pub struct MbrEntry {
+    /// The type of the partition, or `0` if this entry is unused.
+    pub system_id: u8,
+    /// The first sector of the partition, relative to a base that depends on where this entry resides.
+    pub relative_start: u32,
+    /// The number of sectors in the partition.
+    pub sector_count: u32,
+}
+
+impl MbrEntry {
+    fn is_used(&self) -> bool {
+        self.system_id != 0 && self.sector_count != 0
+    }
+
+    fn is_extended(&self) -> bool {
+        EXTENDED_PARTITION_IDS.contains(&self.system_id)
+    }
+}
+
+
+/// Parses the four partition table entries in the given `sector`, which holds an MBR or EBR.
+///
+/// Returns `None` if the sector does not have a valid MBR signature.
+pub fn parse_primary_entries(sector: &[u8]) -> Option<[MbrEntry; PARTITION_ENTRY_COUNT]> {
+    if sector.len() < MBR_SIZE || sector[MBR_SIZE - 2 .. MBR_SIZE] != MBR_SIGNATURE {
+        return None;
+    }
+    let mut entries = [MbrEntry { system_id: 0, relative_start: 0, sector_count: 0 }; PARTITION_ENTRY_COUNT];
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let offset = PARTITION_TABLE_OFFSET + i * PARTITION_ENTRY_SIZE;
+        *entry = MbrEntry {
+            system_id: sector[offset + 4],
+            relative_start: read_u32(sector, offset + 8),
+            sector_count: read_u32(sector, offset + 12),
+        };
+    }
+    Some(entries)
+}
+
+/// Returns the partitions described by the given `primary_entries` of the MBR on the given `device`,
+/// followed by the logical partitions within the extended partition, if there is one.
+pub(crate) fn read_entries(
+    device: &StorageDeviceRef,
+    primary_entries: &[MbrEntry; PARTITION_ENTRY_COUNT],
+) -> Result<Vec<PartitionEntry>, &'static str> {
+    let mut partitions = Vec::new();
+    let mut extended = None;
+
+    for (i, entry) in primary_entries.iter().enumerate() {
+        if !entry.is_used() {
+            continue;
+        }
+        if entry.is_extended() {
+            if extended.is_some() {
+                warn!("MBR has more than one extended partition, ignoring partition {}", i + 1);
+            } else {
+                extended = Some(*entry);
+            }
+            continue;
+        }
+        partitions.push(PartitionEntry {
+            number: i + 1,
+            start_sector: entry.relative_start as usize,
+            sector_count: entry.sector_count as usize,
+            partition_type: PartitionType::Mbr(entry.system_id),
+            unique_guid: None,
+            label: None,
+        });
+    }
+
+    if let Some(extended) = extended {
+        read_logical_entries(device, &extended, &mut partitions)?;
+    }
+    Ok(partitions)
+}
+
+/// Follows the chain of EBRs within the given `extended` partition and appends each logical partition to `partitions`.
+///
+/// In each EBR, the first entry describes a logical partition relative to that EBR,
+/// and the second entry points to the next EBR relative to the start of the extended partition.
+fn read_logical_entries(
+    device: &StorageDeviceRef,
+    extended: &MbrEntry,
+    partitions: &mut Vec<PartitionEntry>,
+) -> Result<(), &'static str> {
+    let extended_start = extended.relative_start as usize;
+    let extended_end = extended_start + extended.sector_count as usize;
+    let mut ebr_sector = extended_start;
+
+    for i in 0 .. MAX_LOGICAL_PARTITIONS {
+        let sector = read_sectors(device, ebr_sector, 1)?;
+        let entries = match parse_primary_entries(&sector) {
+            Some(entries) => entries,
+            None => {
+                warn!("EBR at sector {} had an invalid signature, ignoring the rest of the extended partition", ebr_sector);
+                return Ok(());
+            }
+        };
+
+        let logical = entries[0];
+        if logical.is_used() {
+            let start_sector = ebr_sector + logical.relative_start as usize;
+            if start_sector + logical.sector_count as usize > extended_end {
+                warn!("Logical partition at sector {} extends beyond its extended partition, ignoring it", start_sector);
+            } else {
+                partitions.push(PartitionEntry {
+                    number: FIRST_LOGICAL_PARTITION_NUMBER + i,
+                    start_sector,
+                    sector_count: logical.sector_count as usize,
+                    partition_type: PartitionType::Mbr(logical.system_id),
+                    unique_guid: None,
+                    label: None,
+                });
+            }
+        }
+
+        let next = entries[1];
+        if !next.is_used() || !next.is_extended() {
+            return Ok(());
+        }
+        let next_ebr_sector = extended_start + next.relative_start as usize;
+        // Each EBR must come after the previous one, otherwise the chain would loop.
+        if next_ebr_sector <= ebr_sector || next_ebr_sector >= extended_end {
+            warn!("EBR at sector {} pointed to an invalid next EBR at sector {}", ebr_sector, next_ebr_sector);
+            return Ok(());
+        }
+        ebr_sector = next_ebr_sector;
+    }
+
+    warn!("Extended partition had more than {} logical partitions, ignoring the rest", MAX_LOGICAL_PARTITIONS);
+    Ok(())
+}
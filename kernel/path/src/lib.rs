@@ -6,6 +6,7 @@
 extern crate spin;
 extern crate fs_node;
 extern crate root;
+extern crate mount_table;
 
 use core::fmt;
 use core::ops::{Deref, DerefMut};
@@ -14,11 +15,27 @@ use alloc::{
     vec::Vec,
     sync::Arc,
 };
-use fs_node::{FileOrDir, DirRef};
+use spin::Mutex;
+use fs_node::{FileOrDir, DirRef, File, FileRef};
 
 pub const PATH_DELIMITER: &str = "/";
 pub const EXTENSION_DELIMITER: &str = ".";
 
+/// The maximum number of symbolic links that can be nested within each other, 
+/// i.e., links whose targets are being resolved at the same time, while resolving a single path.
+pub const MAX_SYMLINK_DEPTH: usize = 8;
+/// The maximum total number of symbolic links that can be followed while resolving a single path.
+pub const MAX_SYMLINK_EXPANSIONS: usize = 40;
+
+/// Tracks the symbolic links that have been followed while resolving a single path.
+struct SymlinkState {
+    /// The identities of the symbolic links whose targets are currently being resolved,
+    /// which are used to detect a link that refers back to itself.
+    active: Vec<usize>,
+    /// The total number of symbolic links followed so far.
+    expansions: usize,
+}
+
 
 /// A structure that represents a file  
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -90,6 +107,24 @@ impl Path {
             .unwrap_or_else(|| &self.path)
     }
 
+    /// Returns the path of the directory that contains the trailing component of this path,
+    /// or `None` if this path has no components, e.g., the root directory.
+    /// # Examples
+    /// `"/path/to/my/file.a"` -> `"/path/to/my"`
+    /// `"/file.a"` -> `"/"`
+    /// `"file.a"` -> `"."`
+    pub fn parent(&self) -> Option<Path> {
+        let trimmed = self.path.trim_end_matches(PATH_DELIMITER);
+        if trimmed.is_empty() {
+            return None;
+        }
+        match trimmed.rfind(PATH_DELIMITER) {
+            Some(0) => Some(Path::new(String::from(PATH_DELIMITER))),
+            Some(i) => Some(Path::new(String::from(&trimmed[.. i]))),
+            None => Some(Path::new(String::from("."))),
+        }
+    }
+
     /// Like [`basename()`](#method.basename), but excludes the file extension, if present.
     pub fn file_stem<'a>(&'a self) -> &'a str {
         self.basename()
@@ -192,39 +227,107 @@ impl Path {
 
     /// Returns the file or directory specified by the given path, 
     /// which can either be absolute, or relative from the given the current working directory 
+    /// 
+    /// Mount points are crossed transparently: entering a directory that has a filesystem mounted on it
+    /// yields the root directory of that filesystem, and `..` from the root of a mounted filesystem
+    /// leads to the parent of its mount point.
+    /// 
+    /// Symbolic links are followed, including a symbolic link in the trailing component of this path;
+    /// use [`get_no_follow()`](#method.get_no_follow) to obtain a symbolic link itself.
+    /// See [`resolve()`](#method.resolve) for why resolution can fail.
     pub fn get(&self, starting_dir: &DirRef) -> Option<FileOrDir> {
+        self.resolve(starting_dir, true).ok()
+    }
+
+    /// Like [`get()`](#method.get), but if the trailing component of this path is a symbolic link,
+    /// the symbolic link itself is returned rather than the node it refers to. 
+    /// Symbolic links in earlier components are still followed.
+    pub fn get_no_follow(&self, starting_dir: &DirRef) -> Option<FileOrDir> {
+        self.resolve(starting_dir, false).ok()
+    }
+
+    /// Returns the file or directory specified by this path, which is either absolute or relative to `starting_dir`.
+    /// 
+    /// Symbolic links are followed, except for one in the trailing component if `follow_final_link` is `false`.
+    /// A symbolic link's target is resolved relative to the directory that contains the link.
+    /// 
+    /// Returns an error if a component of this path doesn't exist, 
+    /// if a symbolic link refers (directly or indirectly) to itself, 
+    /// or if more than [`MAX_SYMLINK_DEPTH`](constant.MAX_SYMLINK_DEPTH.html) symbolic links are nested within each other
+    /// or more than [`MAX_SYMLINK_EXPANSIONS`](constant.MAX_SYMLINK_EXPANSIONS.html) are followed in total.
+    pub fn resolve(&self, starting_dir: &DirRef, follow_final_link: bool) -> Result<FileOrDir, &'static str> {
+        let mut state = SymlinkState { active: Vec::new(), expansions: 0 };
+        self.resolve_internal(starting_dir, follow_final_link, &mut state)
+    }
+
+    fn resolve_internal(&self, starting_dir: &DirRef, follow_final_link: bool, state: &mut SymlinkState) -> Result<FileOrDir, &'static str> {
         // let current_path = { Path::new(starting_dir.lock().get_absolute_path()) };
         let mut curr_dir = {
             if self.is_absolute() {
-                Arc::clone(root::get_root())
+                mount_table::resolve(Arc::clone(root::get_root()))
             }
             else {
-                Arc::clone(&starting_dir)
+                mount_table::resolve(Arc::clone(&starting_dir))
             }
         };
 
-        for component in self.components() {
-            match component {
+        let components: Vec<&str> = self.components().collect();
+        for (i, component) in components.iter().enumerate() {
+            let is_last = i == components.len() - 1;
+            match *component {
                 "." => { 
                     // stay in the current directory, do nothing. 
                 }
                 ".." => {
+                    // first cross out of any filesystems mounted on the current directory
+                    while let Some(mount_point) = mount_table::mount_point_of(&curr_dir) {
+                        curr_dir = mount_point;
+                    }
                     // navigate to parent directory
-                    let parent_dir = curr_dir.lock().get_parent_dir()?;
-                    curr_dir = parent_dir;
+                    let parent_dir = curr_dir.lock().get_parent_dir().ok_or("directory has no parent")?;
+                    curr_dir = mount_table::resolve(parent_dir);
                 }
                 cmpnt => {
                     // navigate to child directory, or return the child file
-                    let child_dir = match curr_dir.lock().get(cmpnt) {
-                        Some(FileOrDir::File(f)) => return Some(FileOrDir::File(f)),
-                        Some(FileOrDir::Dir(d)) => d,
-                        None => return None,
+                    let child = curr_dir.lock().get(cmpnt).ok_or("path does not exist")?;
+                    let child = match child {
+                        FileOrDir::File(f) => {
+                            let target = if is_last && !follow_final_link { None } else { f.lock().symlink_target() };
+                            match target {
+                                Some(target) => Path::new(target).follow_symlink(&f, &curr_dir, state)?,
+                                None => FileOrDir::File(f),
+                            }
+                        }
+                        dir => dir,
+                    };
+                    let child_dir = match child {
+                        FileOrDir::File(f) => return Ok(FileOrDir::File(f)),
+                        FileOrDir::Dir(d) => d,
                     };
-                    curr_dir = child_dir;
+                    curr_dir = mount_table::resolve(child_dir);
                 }
             }
         }
-        Some(FileOrDir::Dir(curr_dir))
+        Ok(FileOrDir::Dir(curr_dir))
+    }
+
+    /// Resolves this path, which is the target of the given `symlink` within the directory `link_dir`.
+    fn follow_symlink(&self, symlink: &FileRef, link_dir: &DirRef, state: &mut SymlinkState) -> Result<FileOrDir, &'static str> {
+        let id = &**symlink as *const Mutex<dyn File + Send> as *const u8 as usize;
+        if state.active.contains(&id) {
+            return Err("symbolic link loop detected");
+        }
+        if state.active.len() >= MAX_SYMLINK_DEPTH {
+            return Err("symbolic links are nested too deeply");
+        }
+        state.expansions += 1;
+        if state.expansions > MAX_SYMLINK_EXPANSIONS {
+            return Err("too many symbolic links were followed");
+        }
+        state.active.push(id);
+        let result = self.resolve_internal(link_dir, true, state);
+        state.active.pop();
+        result
     }
 
 
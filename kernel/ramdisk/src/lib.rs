@@ -0,0 +1,261 @@
+//! A storage device backed by memory rather than by disk hardware.
+//!
+//! A [`RamDisk`] behaves like any other `StorageDevice`, so it can be used to test filesystems,
+//! partition tables, and the `block_io` layer without attaching a real disk.
+//! Its contents can be empty (all zeros), or copied from a bootloader data module or from an existing file,
+//! such as a disk image in memfs.
+//!
+//! Read and write errors can be injected into a `RamDisk` to test how its users handle device failures,
+//! see [`RamDisk::inject_error()`](struct.RamDisk.html#method.inject_error).
+//!
+//! Once created, a `RamDisk` can be registered with the `storage_manager` using [`register()`](fn.register.html),
+//! after which it is found by tools and filesystems just like any other drive.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate memory;
+extern crate fs_node;
+extern crate mod_mgmt;
+extern crate storage_device;
+extern crate storage_manager;
+
+use core::ops::Range;
+use alloc::{
+    boxed::Box,
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use spin::Mutex;
+use memory::{EntryFlags, MappedPages, create_mapping};
+use fs_node::{File, FileRef};
+use storage_device::{StorageController, StorageDevice, StorageDeviceRef};
+
+
+/// The default sector size of a `RamDisk`, which matches most hard disks.
+pub const DEFAULT_SECTOR_SIZE_IN_BYTES: usize = 512;
+
+
+/// Which kind of access an injected error applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Only reads fail.
+    Read,
+    /// Only writes fail.
+    Write,
+    /// Both reads and writes fail.
+    ReadWrite,
+}
+
+/// An error that is injected into a `RamDisk`, causing accesses to certain sectors to fail.
+#[derive(Debug, Clone)]
+pub struct InjectedError {
+    /// Which kind of access fails.
+    pub kind: ErrorKind,
+    /// The range of sectors that fail when accessed.
+    /// An access fails if any of its sectors are in this range.
+    pub sectors: Range<usize>,
+    /// The number of accesses that will fail before this error is removed,
+    /// or `None` if the error should persist until it is explicitly cleared.
+    pub remaining: Option<usize>,
+}
+
+impl InjectedError {
+    fn applies_to(&self, write: bool, sectors: &Range<usize>) -> bool {
+        let kind_matches = match self.kind {
+            ErrorKind::Read => !write,
+            ErrorKind::Write => write,
+            ErrorKind::ReadWrite => true,
+        };
+        kind_matches && self.sectors.start < sectors.end && sectors.start < self.sectors.end
+    }
+}
+
+
+/// A storage device whose contents are kept in memory.
+pub struct RamDisk {
+    /// The memory that holds this disk's contents.
+    data: MappedPages,
+    sector_size: usize,
+    sector_count: usize,
+    /// The errors that are currently injected into this disk.
+    injected_errors: Vec<InjectedError>,
+}
+
+impl RamDisk {
+    /// Creates a new `RamDisk` with the given number of sectors of the given size, whose contents are all zeros.
+    pub fn new(sector_size: usize, sector_count: usize) -> Result<RamDisk, &'static str> {
+        if sector_size == 0 || sector_count == 0 {
+            return Err("RamDisk: sector size and sector count must be nonzero");
+        }
+        let size_in_bytes = sector_size.checked_mul(sector_count).ok_or("RamDisk: size was too large")?;
+        let mut data = create_mapping(size_in_bytes, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE)?;
+        // Newly-mapped memory may contain stale data.
+        for b in data.as_slice_mut::<u8>(0, size_in_bytes)?.iter_mut() {
+            *b = 0;
+        }
+        Ok(RamDisk {
+            data,
+            sector_size,
+            sector_count,
+            injected_errors: Vec::new(),
+        })
+    }
+
+    /// Creates a new `RamDisk` with sectors of the given size whose contents are a copy of the given `bytes`.
+    ///
+    /// The disk is just large enough to hold all of the `bytes`; if their length is not a multiple of the sector size,
+    /// the remainder of the last sector is filled with zeros.
+    pub fn from_bytes(sector_size: usize, bytes: &[u8]) -> Result<RamDisk, &'static str> {
+        if sector_size == 0 {
+            return Err("RamDisk: sector size must be nonzero");
+        }
+        let sector_count = (bytes.len() + sector_size - 1) / sector_size;
+        let mut disk = RamDisk::new(sector_size, sector_count)?;
+        disk.data.as_slice_mut::<u8>(0, bytes.len())?.copy_from_slice(bytes);
+        Ok(disk)
+    }
+
+    /// Creates a new `RamDisk` with sectors of the given size whose contents are a copy of the given `file`,
+    /// e.g., a disk image in memfs.
+    pub fn from_file(sector_size: usize, file: &FileRef) -> Result<RamDisk, &'static str> {
+        let locked_file = file.lock();
+        let size = locked_file.size();
+        if size == 0 {
+            return Err("RamDisk: file was empty");
+        }
+        if sector_size == 0 {
+            return Err("RamDisk: sector size must be nonzero");
+        }
+        let sector_count = (size + sector_size - 1) / sector_size;
+        let mut disk = RamDisk::new(sector_size, sector_count)?;
+        let bytes_read = locked_file.read(disk.data.as_slice_mut::<u8>(0, size)?, 0)?;
+        if bytes_read != size {
+            return Err("RamDisk: couldn't read the entire file");
+        }
+        Ok(disk)
+    }
+
+    /// Creates a new `RamDisk` with sectors of the given size whose contents are a copy of
+    /// the bootloader data module with the given `module_name`, e.g., `disk.img` for a module loaded as `d#disk.img`.
+    ///
+    /// See `mod_mgmt::get_data_module()`.
+    pub fn from_module(sector_size: usize, module_name: &str) -> Result<RamDisk, &'static str> {
+        let file = mod_mgmt::get_data_module(module_name).ok_or("RamDisk: couldn't find the given data module")?;
+        RamDisk::from_file(sector_size, &file)
+    }
+
+    /// Injects the given error into this disk, such that matching accesses will fail.
+    pub fn inject_error(&mut self, error: InjectedError) {
+        debug!("RamDisk: injecting error {:?}", error);
+        self.injected_errors.push(error);
+    }
+
+    /// Removes all errors that have been injected into this disk.
+    pub fn clear_injected_errors(&mut self) {
+        self.injected_errors.clear();
+    }
+
+    /// Returns the errors that are currently injected into this disk.
+    pub fn injected_errors(&self) -> &[InjectedError] {
+        &self.injected_errors
+    }
+
+    /// Returns the entire contents of this disk.
+    pub fn as_slice(&self) -> Result<&[u8], &'static str> {
+        self.data.as_slice(0, self.size_in_bytes())
+    }
+
+    /// Checks that an access of `buffer_length` bytes starting at `offset_in_sectors` is within this disk's bounds
+    /// and doesn't hit an injected error, and returns the byte range of the access within this disk.
+    fn check_access(&mut self, buffer_length: usize, offset_in_sectors: usize, write: bool) -> Result<Range<usize>, &'static str> {
+        if buffer_length % self.sector_size != 0 {
+            return Err("RamDisk: the buffer length must be a multiple of the sector size");
+        }
+        let sectors = offset_in_sectors .. offset_in_sectors + buffer_length / self.sector_size;
+        if sectors.end > self.sector_count {
+            return Err("RamDisk: access extended beyond the end of the disk");
+        }
+
+        if let Some(index) = self.injected_errors.iter().position(|e| e.applies_to(write, &sectors)) {
+            let exhausted = match self.injected_errors[index].remaining {
+                Some(ref mut remaining) => {
+                    *remaining = remaining.saturating_sub(1);
+                    *remaining == 0
+                }
+                None => false,
+            };
+            if exhausted {
+                self.injected_errors.remove(index);
+            }
+            return Err(if write { "RamDisk: injected write error" } else { "RamDisk: injected read error" });
+        }
+
+        Ok(sectors.start * self.sector_size .. sectors.end * self.sector_size)
+    }
+}
+
+impl StorageDevice for RamDisk {
+    fn read_sectors(&mut self, buffer: &mut [u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+        let range = self.check_access(buffer.len(), offset_in_sectors, false)?;
+        buffer.copy_from_slice(self.data.as_slice(range.start, range.end - range.start)?);
+        Ok(buffer.len() / self.sector_size)
+    }
+
+    fn write_sectors(&mut self, buffer: &[u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+        let range = self.check_access(buffer.len(), offset_in_sectors, true)?;
+        self.data.as_slice_mut::<u8>(range.start, range.end - range.start)?.copy_from_slice(buffer);
+        Ok(buffer.len() / self.sector_size)
+    }
+
+    fn sector_size_in_bytes(&self) -> usize {
+        self.sector_size
+    }
+
+    fn size_in_sectors(&self) -> usize {
+        self.sector_count
+    }
+}
+
+/// A `RamDisk` wrapped in an Arc and Mutex, which can be shared in a thread-safe manner.
+pub type RamDiskRef = Arc<Mutex<RamDisk>>;
+
+
+/// A pseudo storage controller that holds a single `RamDisk`,
+/// which allows the disk to be added to the `storage_manager`'s list of storage controllers.
+pub struct RamDiskController {
+    disk: RamDiskRef,
+}
+
+impl StorageController for RamDiskController {
+    fn devices<'c>(&'c self) -> Box<(dyn Iterator<Item = StorageDeviceRef> + 'c)> {
+        Box::new(
+            Some(Arc::clone(&self.disk) as StorageDeviceRef).into_iter()
+        )
+    }
+}
+
+
+/// Registers the given `disk` with the `storage_manager` so that it can be found like any other storage device,
+/// and reads its partition table, if it has one.
+///
+/// Returns a reference to the registered disk, which can be used to inject errors into it,
+/// and the name of the disk within the `storage_manager`, e.g., `sd2`.
+pub fn register(disk: RamDisk) -> (RamDiskRef, String) {
+    let disk = Arc::new(Mutex::new(disk));
+    let controller = RamDiskController { disk: Arc::clone(&disk) };
+    let index = storage_manager::add_controller(Arc::new(Mutex::new(controller)));
+    let name = storage_manager::storage_device_name(index);
+    info!("Registered RAM disk {} with {} sectors of {} bytes", name, disk.lock().size_in_sectors(), disk.lock().sector_size_in_bytes());
+    (disk, name)
+}
+
+/// Convenience function that creates a new zeroed `RamDisk` and registers it with the `storage_manager`.
+///
+/// See [`RamDisk::new()`](struct.RamDisk.html#method.new) and [`register()`](fn.register.html).
+pub fn create(sector_size: usize, sector_count: usize) -> Result<(RamDiskRef, String), &'static str> {
+    RamDisk::new(sector_size, sector_count).map(register)
+}
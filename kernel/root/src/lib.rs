@@ -13,8 +13,8 @@ use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use spin::Mutex;
 use alloc::sync::{Arc, Weak};
-use alloc::collections::BTreeMap;
-use fs_node::{DirRef, Directory, FileOrDir, FsNode, WeakDirRef};
+use alloc::collections::{BTreeMap, BTreeSet};
+use fs_node::{DirRef, Directory, FileOrDir, FileRef, FilesystemId, FsNode, WeakDirRef, Metadata, NodeAttributes, NodeKind, Permissions};
 
 
 pub const ROOT_DIRECTORY_NAME: &'static str = "";
@@ -24,7 +24,9 @@ lazy_static! {
     /// Returns a tuple for easy access to the name of the root so we don't have to lock it
     pub static ref ROOT: (String, DirRef) = {
         let root_dir = RootDirectory {
-            children: BTreeMap::new() 
+            children: BTreeMap::new(),
+            links: BTreeSet::new(),
+            attributes: NodeAttributes::new(Permissions::all()),
         };
         let strong_root = Arc::new(Mutex::new(root_dir)) as DirRef;
         (ROOT_DIRECTORY_NAME.to_string(), strong_root)
@@ -40,17 +42,48 @@ pub fn get_root() -> &'static DirRef {
 pub struct RootDirectory {
     /// A list of DirRefs or pointers to the child directories   
     children: BTreeMap<String, FileOrDir>,
+    /// The names of the children that are hard links, i.e., not the original entries of their nodes
+    links: BTreeSet<String>,
+    /// The permissions and timestamps of the root directory
+    attributes: NodeAttributes,
+}
+
+impl RootDirectory {
+    /// Removes the child called `name`, and clears its parent directory unless that child was a hard link.
+    fn remove_entry(&mut self, name: &str) -> Option<FileOrDir> {
+        let mut old_node = self.children.remove(name)?;
+        if !self.links.remove(name) {
+            old_node.set_parent_dir(Weak::<Mutex<RootDirectory>>::new());
+        }
+        self.attributes.mark_modified();
+        Some(old_node)
+    }
 }
 
 impl Directory for RootDirectory {
     fn insert(&mut self, node: FileOrDir) -> Result<Option<FileOrDir>, &'static str> {
+        self.attributes.check(Permissions::WRITE)?;
         let name = node.get_name();
-        if let Some(mut old_node) = self.children.insert(name, node) {
-            old_node.set_parent_dir(Weak::<Mutex<RootDirectory>>::new());
-            Ok(Some(old_node))
-        } else {
-            Ok(None)
+        let old_node = self.remove_entry(&name);
+        self.children.insert(name, node);
+        self.attributes.mark_modified();
+        Ok(old_node)
+    }
+
+    fn insert_link(&mut self, name: &str, file: FileRef) -> Result<Option<FileOrDir>, &'static str> {
+        self.attributes.check(Permissions::WRITE)?;
+        if let Some(FileOrDir::Dir(_)) = self.children.get(name) {
+            return Err("a directory with the given name already exists");
         }
+        let old_node = self.remove_entry(name);
+        self.children.insert(String::from(name), FileOrDir::File(file));
+        self.links.insert(String::from(name));
+        self.attributes.mark_modified();
+        Ok(old_node)
+    }
+
+    fn is_link(&self, name: &str) -> bool {
+        self.links.contains(name)
     }
 
     fn get(&self, name: &str) -> Option<FileOrDir> {
@@ -73,12 +106,23 @@ impl Directory for RootDirectory {
             _ => {}
         }
         
-        if let Some(mut old_node) = self.children.remove(&node.get_name()) {
-            old_node.set_parent_dir(Weak::<Mutex<RootDirectory>>::new());
-            Some(old_node)
-        } else {
-            None
-        }
+        let name = node.get_name();
+        // If the entry with the node's own name holds a different node, the node may be held here under a hard link.
+        let key = match self.children.get(&name) {
+            Some(n) if n.is_same_node(node) => name,
+            _ => self.children.iter().find(|&(_, n)| n.is_same_node(node)).map(|(k, _)| k.clone()).unwrap_or(name),
+        };
+        self.remove_entry(&key)
+    }
+
+    fn unlink(&mut self, name: &str) -> Option<FileOrDir> {
+        self.remove_entry(name)
+    }
+
+    /// The root directory holds its children the same way a `VFSDirectory` does,
+    /// so nodes can be moved freely between it and the in-memory directories below it.
+    fn filesystem_id(&self) -> FilesystemId {
+        FilesystemId::IN_MEMORY
     }
 }
 
@@ -100,4 +144,13 @@ impl FsNode for RootDirectory {
     fn set_parent_dir(&mut self, _: WeakDirRef) {
         // do nothing
     }
+
+    fn metadata(&self) -> Metadata {
+        self.attributes.metadata(NodeKind::Directory, 0)
+    }
+
+    fn set_permissions(&mut self, permissions: Permissions) -> Result<(), &'static str> {
+        self.attributes.set_permissions(permissions);
+        Ok(())
+    }
 }
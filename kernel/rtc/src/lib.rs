@@ -96,7 +96,7 @@ fn read_register(register: u8) -> u8{
 }
 
 /// A timestamp obtained from the real-time clock.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct RtcTime {
     pub seconds: u8,
     pub minutes: u8,
@@ -0,0 +1,263 @@
+//! Named regions of memory that can be shared among tasks, e.g., a frame of video or a ring of packets,
+//! without copying or moving their contents the way `async_channel` and `rendezvous` do.
+//!
+//! A shared memory region is created with a name and a size by [`create()`], which allocates zeroed frames for it.
+//! Tasks attach to an existing region by its name with [`attach()`], either [`AccessMode::ReadOnly`] or [`AccessMode::ReadWrite`].
+//! Each [`SharedMemoryAttachment`] maps new pages to the region's frames with the flags of its access mode,
+//! so a read-only attachment can never be written, and offers typed views of the region through `as_type()` and `as_slice()`.
+//!
+//! Attachments are reference counted: a region lives as long as any task is attached to it.
+//! Once the last attachment is detached (dropped), the region's frames are deallocated and its name can be used again.
+//!
+//! # Permissions
+//! The task that creates a region can always attach to it in either mode.
+//! The `others` access mode given when the region is created determines how other tasks can attach to it, if at all.
+//!
+//! # Synchronization
+//! Tasks attached to the same region may read and write it concurrently,
+//! so data in shared memory should be accessed through atomic types or be protected by some other means of synchronization.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate lazy_static;
+extern crate spin;
+extern crate memory;
+extern crate task;
+
+use core::mem;
+use core::ops::DerefMut;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use memory::{MappedPages, EntryFlags, Frame, VirtualAddress, get_kernel_mmi_ref, get_frame_allocator_ref, create_mapping, allocate_pages};
+use spin::Mutex;
+
+
+/// The ways in which a task can attach to a shared memory region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessMode {
+    /// The region can only be read.
+    ReadOnly,
+    /// The region can be read and written.
+    ReadWrite,
+}
+
+impl AccessMode {
+    /// Returns the page table entry flags of an attachment in this mode.
+    fn entry_flags(&self) -> EntryFlags {
+        match self {
+            AccessMode::ReadOnly => EntryFlags::NO_EXECUTE,
+            AccessMode::ReadWrite => EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+        }
+    }
+}
+
+
+/// A shared memory region, which is kept alive by the attachments to it.
+struct Region {
+    name: String,
+    /// The size of the region in bytes as requested by its creator, which may be less than the size of its frames.
+    size_in_bytes: usize,
+    /// The id of the task that created this region, which can always attach to it in either mode.
+    creator_task_id: Option<usize>,
+    /// How tasks other than the creator can attach to this region, or `None` if they can't.
+    others: Option<AccessMode>,
+    /// The frames that hold the region's contents, in order.
+    frames: Vec<Frame>,
+    /// The mapping for which the region's frames were allocated.
+    /// The frames are deallocated once both this and every attachment's mapping of them have been unmapped.
+    _backing: MappedPages,
+}
+
+impl Region {
+    /// Returns an error if the current task isn't permitted to attach to this region in the given `mode`.
+    fn check_permission(&self, mode: AccessMode) -> Result<(), &'static str> {
+        let current_task_id = task::get_my_current_task_id();
+        if current_task_id.is_some() && current_task_id == self.creator_task_id {
+            return Ok(());
+        }
+        match (self.others, mode) {
+            (Some(AccessMode::ReadWrite), _) | (Some(AccessMode::ReadOnly), AccessMode::ReadOnly) => Ok(()),
+            (Some(AccessMode::ReadOnly), AccessMode::ReadWrite) => Err("shared memory region can only be attached read-only by tasks other than its creator"),
+            (None, _) => Err("shared memory region can only be attached by its creator"),
+        }
+    }
+}
+
+lazy_static! {
+    /// The shared memory regions, keyed by their names.
+    /// Entries of regions that no longer exist are removed when a new region is created.
+    static ref REGIONS: Mutex<BTreeMap<String, Weak<Region>>> = Mutex::new(BTreeMap::new());
+}
+
+
+/// Creates a new shared memory region with the given `name` that is at least `size_in_bytes` bytes large,
+/// and attaches the current task to it read-write.
+///
+/// # Arguments
+/// * `name`: the name by which other tasks can attach to the region, which must not be used by an existing region.
+/// * `size_in_bytes`: the size of the region, which is rounded up to a multiple of the page size.
+/// * `others`: how tasks other than the current one can attach to the region, or `None` if they can't at all.
+///
+/// The region's contents are initially zeroed.
+pub fn create(name: &str, size_in_bytes: usize, others: Option<AccessMode>) -> Result<SharedMemoryAttachment, &'static str> {
+    if size_in_bytes == 0 {
+        return Err("shared_memory::create(): the size of a shared memory region must not be zero");
+    }
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("shared_memory::create(): KERNEL_MMI was not yet initialized!")?;
+
+    let mut backing = create_mapping(size_in_bytes, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE)?;
+    {
+        let contents = backing.as_slice_mut::<u8>(0, backing.size_in_bytes())?;
+        for byte in contents.iter_mut() {
+            *byte = 0;
+        }
+    }
+    let frames = {
+        let kernel_mmi = kernel_mmi_ref.lock();
+        (0 .. backing.size_in_pages())
+            .map(|i| kernel_mmi.page_table.translate_page(*backing.start() + i).ok_or("shared_memory::create(): the region's page was not mapped"))
+            .collect::<Result<Vec<Frame>, &'static str>>()?
+    };
+
+    let region = Arc::new(Region {
+        name: String::from(name),
+        size_in_bytes,
+        creator_task_id: task::get_my_current_task_id(),
+        others,
+        frames,
+        _backing: backing,
+    });
+
+    {
+        let mut regions = REGIONS.lock();
+        let dead_regions: Vec<String> = regions.iter()
+            .filter(|(_, region)| region.upgrade().is_none())
+            .map(|(name, _)| name.clone())
+            .collect();
+        for dead_region in dead_regions {
+            regions.remove(&dead_region);
+        }
+        if regions.contains_key(name) {
+            return Err("shared_memory::create(): a shared memory region with that name already exists");
+        }
+        regions.insert(String::from(name), Arc::downgrade(&region));
+    }
+
+    SharedMemoryAttachment::new(region, AccessMode::ReadWrite)
+}
+
+/// Attaches the current task to the existing shared memory region with the given `name` in the given `mode`.
+///
+/// Returns an error if there is no such region, or if the current task isn't permitted to attach to it in that mode.
+pub fn attach(name: &str, mode: AccessMode) -> Result<SharedMemoryAttachment, &'static str> {
+    let region = REGIONS.lock().get(name).and_then(|region| region.upgrade())
+        .ok_or("shared_memory::attach(): there is no shared memory region with that name")?;
+    region.check_permission(mode)?;
+    SharedMemoryAttachment::new(region, mode)
+}
+
+
+/// A task's attachment to a shared memory region, through which it accesses the region's contents.
+///
+/// The region is detached when this is dropped, see [`detach()`](#method.detach).
+pub struct SharedMemoryAttachment {
+    /// The new pages that are mapped to the region's frames for this attachment.
+    /// This is declared before `region` such that it's unmapped before the region may be dropped.
+    pages: MappedPages,
+    mode: AccessMode,
+    region: Arc<Region>,
+}
+
+impl SharedMemoryAttachment {
+    /// Maps new pages to the frames of the given `region` with the flags of the given `mode`.
+    fn new(region: Arc<Region>, mode: AccessMode) -> Result<SharedMemoryAttachment, &'static str> {
+        let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("shared_memory: KERNEL_MMI was not yet initialized!")?;
+        let allocator = get_frame_allocator_ref().ok_or("shared_memory: couldn't get frame allocator")?;
+        let pages = allocate_pages(region.frames.len()).ok_or("shared_memory: couldn't allocate pages")?;
+        let pages = kernel_mmi_ref.lock().page_table.map_allocated_pages_to_frames(pages, &region.frames, mode.entry_flags(), allocator.lock().deref_mut())?;
+        Ok(SharedMemoryAttachment { pages, mode, region })
+    }
+
+    /// Returns the name of the shared memory region.
+    pub fn name(&self) -> &str {
+        &self.region.name
+    }
+
+    /// Returns the size in bytes of the shared memory region, as it was requested when the region was created.
+    pub fn size_in_bytes(&self) -> usize {
+        self.region.size_in_bytes
+    }
+
+    /// Returns the mode in which the region is attached.
+    pub fn mode(&self) -> AccessMode {
+        self.mode
+    }
+
+    /// Returns the virtual address at which the region is mapped for this attachment.
+    /// Other attachments to the same region map it at different addresses.
+    pub fn start_address(&self) -> VirtualAddress {
+        self.pages.start_address()
+    }
+
+    /// Attaches to the same region again in the given `mode`, e.g., to give a read-only attachment to another task.
+    /// The current task must be permitted to attach to the region in that mode.
+    pub fn attach_again(&self, mode: AccessMode) -> Result<SharedMemoryAttachment, &'static str> {
+        self.region.check_permission(mode)?;
+        SharedMemoryAttachment::new(Arc::clone(&self.region), mode)
+    }
+
+    /// Detaches from the region, which unmaps it from this attachment.
+    /// If this was the last attachment to the region, the region's frames are deallocated.
+    ///
+    /// This is equivalent to dropping this attachment.
+    pub fn detach(self) { }
+
+    /// Reinterprets the region's contents at the given `offset` as a struct of the given type.
+    pub fn as_type<T>(&self, offset: usize) -> Result<&T, &'static str> {
+        self.check_bounds(offset, mem::size_of::<T>())?;
+        self.pages.as_type(offset)
+    }
+
+    /// Reinterprets the region's contents at the given `offset` as a mutable struct of the given type.
+    ///
+    /// Returns an error if the region is attached read-only.
+    pub fn as_type_mut<T>(&mut self, offset: usize) -> Result<&mut T, &'static str> {
+        self.check_writable()?;
+        self.check_bounds(offset, mem::size_of::<T>())?;
+        self.pages.as_type_mut(offset)
+    }
+
+    /// Reinterprets the region's contents at the given `byte_offset` as a slice of `length` elements of the given type.
+    pub fn as_slice<T>(&self, byte_offset: usize, length: usize) -> Result<&[T], &'static str> {
+        self.check_bounds(byte_offset, length.saturating_mul(mem::size_of::<T>()))?;
+        self.pages.as_slice(byte_offset, length)
+    }
+
+    /// Reinterprets the region's contents at the given `byte_offset` as a mutable slice of `length` elements of the given type.
+    ///
+    /// Returns an error if the region is attached read-only.
+    pub fn as_slice_mut<T>(&mut self, byte_offset: usize, length: usize) -> Result<&mut [T], &'static str> {
+        self.check_writable()?;
+        self.check_bounds(byte_offset, length.saturating_mul(mem::size_of::<T>()))?;
+        self.pages.as_slice_mut(byte_offset, length)
+    }
+
+    /// Returns an error if `size` bytes starting at `offset` would extend past the end of the region.
+    fn check_bounds(&self, offset: usize, size: usize) -> Result<(), &'static str> {
+        match offset.checked_add(size) {
+            Some(end) if end <= self.region.size_in_bytes => Ok(()),
+            _ => Err("shared memory access would be out of the region's bounds"),
+        }
+    }
+
+    /// Returns an error if the region is attached read-only.
+    fn check_writable(&self) -> Result<(), &'static str> {
+        match self.mode {
+            AccessMode::ReadWrite => Ok(()),
+            AccessMode::ReadOnly => Err("shared memory region is attached read-only"),
+        }
+    }
+}
@@ -157,6 +157,32 @@ impl ZoneAllocator {
         empty_pages
     }
 
+    /// The number of empty pages in each SCAllocator, in order of size class (see `BASE_ALLOC_SIZES`).
+    pub fn empty_pages_per_size_class(&self) -> [usize; ZoneAllocator::MAX_BASE_SIZE_CLASSES] {
+        let mut empty_pages = [0; ZoneAllocator::MAX_BASE_SIZE_CLASSES];
+        for (i, sca) in self.small_slabs.iter().enumerate() {
+            empty_pages[i] = sca.empty_count;
+        }
+        empty_pages
+    }
+
+    /// The number of empty pages in the SCAllocator that serves allocations of the given `layout`.
+    pub fn empty_pages_for(&self, layout: Layout) -> usize {
+        match ZoneAllocator::get_slab(layout.size()) {
+            Slab::Base(idx) => self.small_slabs[idx].empty_count,
+            _ => 0,
+        }
+    }
+
+    /// Returns a MappedPages8k from the SCAllocator that serves allocations of the given `layout`,
+    /// if that SCAllocator has more than `watermark` empty pages.
+    pub fn retrieve_empty_page_above_watermark(&mut self, layout: Layout, watermark: usize) -> Option<MappedPages8k> {
+        match ZoneAllocator::get_slab(layout.size()) {
+            Slab::Base(idx) if self.small_slabs[idx].empty_count > watermark => self.small_slabs[idx].retrieve_empty_page(),
+            _ => None,
+        }
+    }
+
     /// Allocate a pointer to a block of memory described by `layout`.
     pub fn allocate(&mut self, layout: Layout) -> Result<NonNull<u8>, &'static str> {
         match ZoneAllocator::get_slab(layout.size()) {
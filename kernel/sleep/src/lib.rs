@@ -0,0 +1,88 @@
+//! Puts tasks to sleep for a given duration.
+//!
+//! A sleeping task is blocked, so it isn't scheduled in again until its duration has elapsed,
+//! rather than repeatedly yielding and checking the time.
+//! Each sleeping task is recorded along with the HPET counter value at which it should be woken up,
+//! and the local APIC timer interrupt handler invokes [`unblock_sleeping_tasks()`] to wake up the tasks whose time has come.
+//! Thus, a task sleeps for at least the given duration, which is effectively rounded up to the next timer interrupt.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate lazy_static;
+extern crate irq_safety;
+extern crate task;
+extern crate scheduler;
+extern crate hpet;
+
+use alloc::collections::BTreeMap;
+use irq_safety::MutexIrqSafe;
+use task::TaskRef;
+
+
+lazy_static! {
+    /// The sleeping tasks, keyed by the HPET counter value at which they should be woken up and by their task id.
+    static ref SLEEPING_TASKS: MutexIrqSafe<BTreeMap<(u64, usize), TaskRef>> = MutexIrqSafe::new(BTreeMap::new());
+}
+
+
+/// Puts the current task to sleep until at least `ms` milliseconds have elapsed, according to the HPET.
+pub fn sleep_ms(ms: u64) -> Result<(), &'static str> {
+    const FEMTOSECONDS_PER_MILLISECOND: u64 = 1_000_000_000_000;
+    let (now, period_femtoseconds) = {
+        let hpet = hpet::get_hpet();
+        let hpet = hpet.as_ref().ok_or("sleep_ms(): couldn't get HPET timer")?;
+        (hpet.get_counter(), hpet.counter_period_femtoseconds() as u64)
+    };
+    let ticks = ms.checked_mul(FEMTOSECONDS_PER_MILLISECOND).ok_or("sleep_ms(): duration is too long")? / period_femtoseconds;
+    sleep_until(now.saturating_add(ticks))
+}
+
+
+/// Puts the current task to sleep until the HPET counter has reached `wakeup_counter`.
+fn sleep_until(wakeup_counter: u64) -> Result<(), &'static str> {
+    let curr_task = task::get_my_current_task().ok_or("sleep_ms(): couldn't get current task")?;
+    let task_id = task::get_my_current_task_id().ok_or("sleep_ms(): couldn't get current task id")?;
+    let key = (wakeup_counter, task_id);
+    loop {
+        let now = hpet::get_hpet().as_ref().ok_or("sleep_ms(): couldn't get HPET timer")?.get_counter();
+        if now >= wakeup_counter {
+            return Ok(());
+        }
+        {
+            // The lock disables interrupts, and the task is blocked before it's released,
+            // such that the task can't be woken up before it's blocked.
+            let mut sleeping_tasks = SLEEPING_TASKS.lock();
+            sleeping_tasks.insert(key, curr_task.clone());
+            curr_task.block();
+        }
+        scheduler::schedule();
+        // The task may have been unblocked by something else before its time came,
+        // in which case it must no longer be recorded here before it sleeps again or returns.
+        SLEEPING_TASKS.lock().remove(&key);
+    }
+}
+
+
+/// Wakes up the sleeping tasks whose wakeup time has passed.
+///
+/// This is invoked by the local APIC timer interrupt handler on every core, so it doesn't wait for any lock:
+/// if another core is waking up tasks or a task is going to sleep, the tasks are woken up by a later timer interrupt.
+pub fn unblock_sleeping_tasks() {
+    let now = match hpet::get_hpet() {
+        Some(hpet) => hpet.get_counter(),
+        None => return,
+    };
+    let mut sleeping_tasks = match SLEEPING_TASKS.try_lock() {
+        Some(tasks) => tasks,
+        None => return,
+    };
+    while let Some(&key) = sleeping_tasks.keys().next() {
+        if key.0 > now {
+            break;
+        }
+        if let Some(sleeping_task) = sleeping_tasks.remove(&key) {
+            sleeping_task.unblock();
+        }
+    }
+}
@@ -17,6 +17,7 @@
 #[macro_use] extern crate debugit;
 extern crate irq_safety;
 extern crate memory;
+extern crate kernel_config;
 extern crate task;
 extern crate runqueue;
 extern crate scheduler;
@@ -43,6 +44,7 @@ use alloc::{
 };
 use irq_safety::{MutexIrqSafe, hold_interrupts, enable_interrupts};
 use memory::{get_kernel_mmi_ref, MemoryManagementInfo, VirtualAddress};
+use kernel_config::memory::{PAGE_SIZE, KERNEL_STACK_SIZE_IN_PAGES};
 use task::{Task, TaskRef, get_my_current_task, RunState, RestartInfo, TASKLIST};
 use mod_mgmt::{CrateNamespace, SectionType, SECTION_HASH_DELIMITER};
 use path::Path;
@@ -204,6 +206,8 @@ pub struct TaskBuilder<F, A, R> {
     _return_type: PhantomData<R>,
     name: Option<String>,
     pin_on_core: Option<u8>,
+    stack_size: Option<usize>,
+    growable_stack: bool,
     blocked: bool,
     idle: bool,
     post_build_function: Option<Box< dyn FnOnce(&mut Task) -> Result<(), &'static str> >>,
@@ -226,6 +230,8 @@ impl<F, A, R> TaskBuilder<F, A, R>
             _return_type: PhantomData,
             name: None,
             pin_on_core: None,
+            stack_size: None,
+            growable_stack: false,
             blocked: false,
             idle: false,
             post_build_function: None,
@@ -253,6 +259,23 @@ impl<F, A, R> TaskBuilder<F, A, R>
         self
     }
 
+    /// Set the size in bytes of the new Task's kernel stack, which is rounded up to a multiple of the page size.
+    /// If not set, a stack of the default size (`KERNEL_STACK_SIZE_IN_PAGES`) is used.
+    /// 
+    /// Like every task stack, the new stack has an unmapped guard page below it,
+    /// such that the new Task is killed if it overflows its stack.
+    pub fn stack_size(mut self, size_in_bytes: usize) -> TaskBuilder<F, A, R> {
+        self.stack_size = Some(size_in_bytes);
+        self
+    }
+
+    /// Give the new Task a growable kernel stack, whose pages are only backed by frames once the stack grows into them.
+    /// This allows a task to reserve a large stack (see [`stack_size()`](#method.stack_size)) without using memory for it up front.
+    pub fn growable_stack(mut self) -> TaskBuilder<F, A, R> {
+        self.growable_stack = true;
+        self
+    }
+
     /// Mark this new Task as a SIMD-enabled Task 
     /// that can run SIMD instructions and use SIMD registers.
     #[cfg(simd_personality)]
@@ -276,8 +299,23 @@ impl<F, A, R> TaskBuilder<F, A, R>
     /// This merely makes the new task Runnable, it does not switch to it immediately; that will happen on the next scheduler invocation.
     #[inline(never)]
     pub fn spawn(self) -> Result<TaskRef, &'static str> {
+        let kstack = match (self.stack_size, self.growable_stack) {
+            (Some(0), _) => return Err("TaskBuilder::spawn(): stack size must not be zero"),
+            (None, false) => None,
+            (size_in_bytes, growable) => {
+                let size_in_pages = size_in_bytes.map_or(KERNEL_STACK_SIZE_IN_PAGES, |size| (size + PAGE_SIZE - 1) / PAGE_SIZE);
+                let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("TaskBuilder::spawn(): KERNEL_MMI was not yet initialized!")?;
+                let mut kernel_mmi = kernel_mmi_ref.lock();
+                let kstack = if growable {
+                    kernel_mmi.alloc_growable_stack(size_in_pages)
+                } else {
+                    kernel_mmi.alloc_stack(size_in_pages)
+                };
+                Some(kstack.ok_or("TaskBuilder::spawn(): couldn't allocate kernel stack of the requested size")?)
+            }
+        };
         let mut new_task = Task::new(
-            None,
+            kstack,
             task_cleanup_failure::<F, A, R>,
         )?;
         // If a Task name wasn't provided, then just use the function's name.
@@ -1,24 +1,34 @@
 //! Manages and handles initialization of all storage devices
 //! and storage controllers in the system.
+//!
+//! When a storage controller is initialized, the partition table on each of its storage devices is read,
+//! and each partition is recorded such that it can be accessed as its own `StorageDevice`.
+//! Storage devices are named `sd0`, `sd1`, etc., and their partitions are named by appending
+//! the partition number, e.g., `sd0p1` for the first partition on `sd0`.
 
 #![no_std]
 
-extern crate alloc;
+#[macro_use] extern crate alloc;
 #[macro_use] extern crate log;
 #[macro_use] extern crate lazy_static;
 extern crate spin;
 extern crate owning_ref;
 extern crate pci;
 extern crate ata;
+extern crate ahci;
+extern crate virtio_blk;
 extern crate storage_device;
+extern crate partitions;
 
 use alloc::{
+    string::String,
     vec::Vec,
     sync::Arc,
 };
 use spin::Mutex;
 use pci::PciDevice;
-use storage_device::StorageControllerRef;
+use storage_device::{StorageControllerRef, StorageDeviceRef};
+use partitions::PartitionRef;
 
 pub use storage_device::*;
 
@@ -26,6 +36,106 @@ pub use storage_device::*;
 lazy_static! {
     /// A list of all of the available and initialized storage controllers that exist on this system.
     pub static ref STORAGE_CONTROLLERS: Mutex<Vec<StorageControllerRef>> = Mutex::new(Vec::new());
+
+    /// A list of all of the partitions found on the storage devices that exist on this system,
+    /// along with their names, e.g., `sd0p1`.
+    static ref PARTITIONS: Mutex<Vec<(String, PartitionRef)>> = Mutex::new(Vec::new());
+}
+
+
+/// Returns a list of all of the storage devices attached to all of the storage controllers on this system.
+///
+/// The index of a device in this list is used to refer to it by name, e.g., `sd0` for the first device,
+/// as returned by [`storage_device_name()`](fn.storage_device_name.html).
+pub fn storage_devices() -> Vec<StorageDeviceRef> {
+    let mut devices = Vec::new();
+    for controller in STORAGE_CONTROLLERS.lock().iter() {
+        devices.extend(controller.lock().devices());
+    }
+    devices
+}
+
+/// Returns the name of the storage device at the given `index` in the list returned by
+/// [`storage_devices()`](fn.storage_devices.html), e.g., `sd0`.
+pub fn storage_device_name(index: usize) -> String {
+    format!("sd{}", index)
+}
+
+/// Returns the name of the partition with the given `partition_number` on the storage device at the given `device_index`,
+/// e.g., `sd0p1`.
+pub fn partition_name(device_index: usize, partition_number: usize) -> String {
+    format!("{}p{}", storage_device_name(device_index), partition_number)
+}
+
+/// Returns a list of all of the partitions on all of the storage devices on this system, along with their names.
+pub fn partitions() -> Vec<(String, PartitionRef)> {
+    PARTITIONS.lock().clone()
+}
+
+/// Returns every volume on this system that may hold a filesystem, along with its name:
+/// each partition on a partitioned storage device, and each storage device that has no partition table.
+pub fn volumes() -> Vec<(String, StorageDeviceRef)> {
+    let partitions = PARTITIONS.lock();
+    let mut volumes = Vec::new();
+    for (index, device) in storage_devices().into_iter().enumerate() {
+        let name = storage_device_name(index);
+        let prefix = format!("{}p", name);
+        let mut has_partitions = false;
+        for (partition_name, partition) in partitions.iter().filter(|(n, _)| n.starts_with(&prefix)) {
+            volumes.push((partition_name.clone(), Arc::clone(partition) as StorageDeviceRef));
+            has_partitions = true;
+        }
+        if !has_partitions {
+            volumes.push((name, device));
+        }
+    }
+    volumes
+}
+
+/// Returns the storage device or partition with the given `name`, 
+/// as returned by [`storage_device_name()`](fn.storage_device_name.html) 
+/// or [`partition_name()`](fn.partition_name.html).
+pub fn storage_device_by_name(name: &str) -> Option<StorageDeviceRef> {
+    if !name.starts_with("sd") {
+        return None;
+    }
+    if name.contains('p') {
+        return PARTITIONS.lock().iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, p)| Arc::clone(p) as StorageDeviceRef);
+    }
+    let index = name[2..].parse::<usize>().ok()?;
+    storage_devices().into_iter().nth(index)
+}
+
+/// Reads the partition tables of the storage devices attached to the given `controller`,
+/// whose first device has the given `first_device_index` in the list returned by [`storage_devices()`](fn.storage_devices.html).
+fn scan_partitions(controller: &StorageControllerRef, first_device_index: usize) {
+    let devices: Vec<StorageDeviceRef> = controller.lock().devices().collect();
+    for (i, device) in devices.iter().enumerate() {
+        let device_index = first_device_index + i;
+        match partitions::read_partitions(device) {
+            Ok(found) => {
+                for partition in found {
+                    let name = partition_name(device_index, partition.number());
+                    info!("Found partition {}: {:?}", name, partition);
+                    PARTITIONS.lock().push((name, Arc::new(Mutex::new(partition))));
+                }
+            }
+            Err(e) => warn!("Couldn't read partition table of storage device {}: {}", storage_device_name(device_index), e),
+        }
+    }
+}
+
+/// Adds the given `controller` to the list of storage controllers and reads the partition tables of its devices.
+///
+/// This is used for controllers that are not PCI devices, e.g., RAM disks created at runtime.
+/// Returns the index of the controller's first device in the list returned by [`storage_devices()`](fn.storage_devices.html).
+pub fn add_controller(controller: StorageControllerRef) -> usize {
+    let first_device_index = storage_devices().len();
+    STORAGE_CONTROLLERS.lock().push(Arc::clone(&controller));
+    scan_partitions(&controller, first_device_index);
+    first_device_index
 }
 
 
@@ -36,11 +146,27 @@ lazy_static! {
 /// `Ok(false)` if the given `PciDevice` isn't a supported storage device,
 /// and an error if it fails to initialize a supported storage device.
 pub fn init_device(pci_device: &PciDevice) -> Result<bool, &'static str> {
-    // We currently only support IDE controllers for ATA drives (aka PATA).
+    // IDE controllers for ATA drives (aka PATA), or SATA drives in legacy IDE emulation mode.
     if pci_device.class == 0x01 && pci_device.subclass == 0x01 {
         info!("IDE controller PCI device found at: {:?}", pci_device.location);
         let ide_controller = ata::IdeController::new(pci_device)?;
-        STORAGE_CONTROLLERS.lock().push(Arc::new(Mutex::new(ide_controller)));
+        add_controller(Arc::new(Mutex::new(ide_controller)));
+        return Ok(true);
+    }
+
+    // AHCI controllers for SATA drives, which have a programming interface of 0x01.
+    if pci_device.class == 0x01 && pci_device.subclass == 0x06 && pci_device.prog_if == 0x01 {
+        info!("AHCI controller PCI device found at: {:?}", pci_device.location);
+        let ahci_controller = ahci::AhciController::new(pci_device)?;
+        add_controller(Arc::new(Mutex::new(ahci_controller)));
+        return Ok(true);
+    }
+
+    // Virtio block devices, which are identified by their vendor and device IDs rather than their class.
+    if virtio_blk::is_virtio_blk(pci_device) {
+        info!("virtio-blk PCI device found at: {:?}", pci_device.location);
+        let virtio_blk_controller = virtio_blk::VirtioBlkController::new(pci_device)?;
+        add_controller(Arc::new(Mutex::new(virtio_blk_controller)));
         return Ok(true);
     }
 
@@ -0,0 +1,111 @@
+//! Symbolic links, which are files that refer to another file or directory by its path.
+//!
+//! The path crate follows symbolic links when resolving a path, so a symbolic link to a directory
+//! can be used as an alias for that directory, e.g., `/namespaces/_applications/current -> /namespaces/_applications/v2`.
+//! The target path is stored as given, so it may refer to a node that doesn't (yet) exist.
+//! Reading a symbolic link as a file yields its target path.
+
+#![no_std]
+
+extern crate alloc;
+extern crate spin;
+extern crate fs_node;
+extern crate memory;
+
+use alloc::{
+    string::String,
+    sync::Arc,
+};
+use spin::Mutex;
+use fs_node::{DirRef, WeakDirRef, File, FileOrDir, FileRef, FsNode, Metadata, NodeAttributes, NodeKind, Permissions};
+use memory::MappedPages;
+
+
+/// A symbolic link, which refers to the node at its target path.
+pub struct Symlink {
+    /// The name of the symbolic link itself.
+    name: String,
+    /// The path that this symbolic link refers to,
+    /// which is either absolute or relative to the directory that contains this link.
+    target: String,
+    /// The parent directory that contains this symbolic link.
+    parent: WeakDirRef,
+    /// The timestamps of this symbolic link.
+    attributes: NodeAttributes,
+}
+
+impl Symlink {
+    /// Creates a new symbolic link called `name` in the given `parent` directory that refers to the given `target` path.
+    pub fn new(name: String, target: String, parent: &DirRef) -> Result<FileRef, &'static str> {
+        if target.is_empty() {
+            return Err("a symbolic link's target cannot be empty");
+        }
+        let symlink = Symlink {
+            name,
+            target,
+            parent: Arc::downgrade(parent),
+            // The permissions of the target apply, not those of the link.
+            attributes: NodeAttributes::new(Permissions::all()),
+        };
+        let file_ref = Arc::new(Mutex::new(symlink)) as FileRef;
+        parent.lock().insert(FileOrDir::File(file_ref.clone()))?;
+        Ok(file_ref)
+    }
+
+    /// Returns the path that this symbolic link refers to.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+}
+
+impl File for Symlink {
+    fn read(&self, buffer: &mut [u8], offset: usize) -> Result<usize, &'static str> {
+        let bytes = self.target.as_bytes();
+        if offset > bytes.len() {
+            return Err("read offset exceeds file size");
+        }
+        let read_bytes = core::cmp::min(bytes.len() - offset, buffer.len());
+        buffer[..read_bytes].copy_from_slice(&bytes[offset .. offset + read_bytes]);
+        self.attributes.mark_accessed();
+        Ok(read_bytes)
+    }
+
+    fn write(&mut self, _buffer: &[u8], _offset: usize) -> Result<usize, &'static str> {
+        Err("cannot write to a symbolic link")
+    }
+
+    fn size(&self) -> usize {
+        self.target.len()
+    }
+
+    fn as_mapping(&self) -> Result<&MappedPages, &'static str> {
+        Err("cannot treat a symbolic link as a memory mapped region")
+    }
+
+    fn symlink_target(&self) -> Option<String> {
+        Some(self.target.clone())
+    }
+}
+
+impl FsNode for Symlink {
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_parent_dir(&self) -> Option<DirRef> {
+        self.parent.upgrade()
+    }
+
+    fn set_parent_dir(&mut self, new_parent: WeakDirRef) {
+        self.parent = new_parent;
+    }
+
+    fn set_name(&mut self, new_name: String) -> Result<(), &'static str> {
+        self.name = new_name;
+        Ok(())
+    }
+
+    fn metadata(&self) -> Metadata {
+        self.attributes.metadata(NodeKind::Symlink, self.target.len())
+    }
+}
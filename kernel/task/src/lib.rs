@@ -37,6 +37,7 @@ extern crate tss;
 extern crate mod_mgmt;
 extern crate context_switch;
 extern crate environment;
+extern crate file_handle;
 extern crate root;
 extern crate x86_64;
 extern crate spin;
@@ -55,7 +56,7 @@ use alloc::{
     sync::Arc,
 };
 use irq_safety::{MutexIrqSafe, MutexIrqSafeGuardRef, MutexIrqSafeGuardRefMut, interrupts_enabled};
-use memory::{Stack, MappedPages, PageRange, EntryFlags, MmiRef, VirtualAddress};
+use memory::{Stack, MappedPages, PageRange, EntryFlags, MmiRef, VirtualAddress, MemoryUsage};
 use kernel_config::memory::KERNEL_STACK_SIZE_IN_PAGES;
 // use tss::tss_set_rsp0;
 use mod_mgmt::{
@@ -63,6 +64,7 @@ use mod_mgmt::{
     AppCrateRef,
 };
 use environment::Environment;
+use file_handle::FdTable;
 use spin::Mutex;
 use x86_64::registers::msr::{rdmsr, wrmsr, IA32_FS_BASE};
 
@@ -254,6 +256,9 @@ pub struct Task {
     pub kill_handler: Option<KillHandler>,
     /// The environment of the task, Wrapped in an Arc & Mutex because it is shared among child and parent tasks
     pub env: Arc<Mutex<Environment>>,
+    /// The table of files that this task has open. Unlike the `env`, this is not shared with child tasks,
+    /// and all of its file descriptors are closed when this task exits.
+    pub fd_table: Arc<Mutex<FdTable>>,
     /// The function that should be run as a last-ditch attempt to recover from this task's failure,
     /// e.g., this can be called when unwinding itself fails. 
     /// Typically, it will point to this Task's specific instance of `spawn::task_cleanup_failure()`,
@@ -262,6 +267,9 @@ pub struct Task {
     /// Stores the restartable information of the task. 
     /// `Some(RestartInfo)` indicates that the task is restartable.
     pub restart_info: Option<RestartInfo>,
+    /// The frames and heap memory that this `Task` has allocated and deallocated so far.
+    /// Unlike the `mmi`, this is not shared with any other task.
+    pub memory_usage: Arc<MemoryUsage>,
     
     #[cfg(simd_personality)]
     /// Whether this Task is SIMD enabled and what level of SIMD extensions it uses.
@@ -343,8 +351,10 @@ impl Task {
             namespace,
             kill_handler: None,
             env,
+            fd_table: Arc::new(Mutex::new(FdTable::new())),
             failure_cleanup_function,
             restart_info: None,
+            memory_usage: Arc::new(MemoryUsage::new()),
             
             #[cfg(simd_personality)]
             simd: SimdExt::None,
@@ -679,10 +689,16 @@ impl TaskRef {
     /// to determine the current `Task` on each processor core.
     pub fn new(task: Task) -> TaskRef {
         let task_id = task.id;
+        let name = task.name.clone();
+        let kstack_bottom = task.kstack.bottom();
+        let memory_usage = Arc::clone(&task.memory_usage);
         let taskref = TaskRef(Arc::new((MutexIrqSafe::new(task), AtomicBool::new(false))));
         let tld = TaskLocalData {
             current_taskref: taskref.clone(),
             current_task_id: task_id,
+            name,
+            kstack_bottom,
+            memory_usage,
         };
         let tld_ptr = Box::into_raw(Box::new(tld));
         taskref.0.deref().0.lock().task_local_data_ptr = VirtualAddress::new_canonical(tld_ptr as usize);
@@ -727,9 +743,10 @@ impl TaskRef {
 
 
     /// The internal routine that actually exits or kills a Task.
-    /// It also performs select cleanup routines, e.g., removing the task from the task list.
+    /// It also performs select cleanup routines, e.g., removing the task from the task list
+    /// and closing its open files.
     fn internal_exit(&self, val: ExitValue) -> Result<(), &'static str> {
-        {
+        let (task_id, fd_table) = {
             let mut task = self.0.deref().0.lock();
             if let RunState::Exited(_) = task.runstate {
                 return Err("task was already exited! (did not overwrite its existing exit value)");
@@ -743,6 +760,14 @@ impl TaskRef {
                 // trace!("internal_exit(): dropping TaskLocalData for non-running task {}", &*task);
                 let _tld = task.take_task_local_data();
             }
+            (task.id, Arc::clone(&task.fd_table))
+        };
+
+        // Close the task's files outside of the task lock. If a killed task was holding its fd table's lock,
+        // the files are instead closed when the task itself is dropped.
+        match fd_table.try_lock() {
+            Some(mut table) => table.close_all(),
+            None => warn!("internal_exit(): couldn't lock the fd table of task {} to close its files", task_id),
         }
 
         #[cfg(runqueue_spillful)] 
@@ -889,6 +914,11 @@ impl TaskRef {
         Arc::clone(&self.0.deref().0.lock().env)
     }
 
+    /// Gets a reference to this task's table of open files.
+    pub fn get_fd_table(&self) -> Arc<Mutex<FdTable>> {
+        Arc::clone(&self.0.deref().0.lock().fd_table)
+    }
+
     /// Gets a reference to this task's `CrateNamespace`.
     pub fn get_namespace(&self) -> Arc<CrateNamespace> {
         Arc::clone(&self.0.deref().0.lock().namespace)
@@ -955,6 +985,8 @@ pub fn bootstrap_task(
         error!("BUG: bootstrap_task(): failed to properly set the new idle task as the current task on AP {}", apic_id);
         return Err("BUG: bootstrap_task(): failed to properly set the new idle task as the current task");
     }
+    // from now on, memory allocated on this core is charged to the current task
+    memory::set_current_memory_usage_cb(get_my_memory_usage);
 
     // insert the new task into the task list
     let old_task = TASKLIST.lock().insert(bootstrap_task_id, task_ref.clone());
@@ -992,6 +1024,12 @@ fn bootstrap_task_cleanup_failure(current_task: TaskRef, kill_reason: KillReason
 struct TaskLocalData {
     current_taskref: TaskRef,
     current_task_id: usize,
+    /// The current task's name, which can be accessed here without locking the task.
+    name: String,
+    /// The bottom of the current task's `kstack`, which can be accessed here without locking the task.
+    kstack_bottom: VirtualAddress,
+    /// The same as the current task's `memory_usage`, which can be accessed here without locking the task.
+    memory_usage: Arc<MemoryUsage>,
 }
 
 /// Returns a reference to the current task's `TaskLocalData` 
@@ -1017,6 +1055,35 @@ pub fn get_my_current_task() -> Option<&'static TaskRef> {
 
 /// Returns the current Task's id by using the `TaskLocalData` pointer
 /// stored in the thread-local storage (FS base model-specific register).
+/// 
+/// This must not lock or allocate anything, because the `tracing_allocator` calls it for every heap allocation.
 pub fn get_my_current_task_id() -> Option<usize> {
     get_task_local_data().map(|tld| tld.current_task_id)
 }
+
+/// Returns the current Task's name by using the `TaskLocalData` pointer
+/// stored in the thread-local storage (FS base model-specific register).
+/// 
+/// This doesn't lock the current task, so it can be used by exception handlers
+/// that may have interrupted the current task while it held its own lock.
+pub fn get_my_current_task_name() -> Option<&'static str> {
+    get_task_local_data().map(|tld| tld.name.as_str())
+}
+
+/// Returns the bottom (lowest address) of the current Task's kernel stack by using the `TaskLocalData` pointer
+/// stored in the thread-local storage (FS base model-specific register).
+/// 
+/// This doesn't lock the current task, so it can be used by exception handlers
+/// that may have interrupted the current task while it held its own lock.
+pub fn get_my_current_task_stack_bottom() -> Option<VirtualAddress> {
+    get_task_local_data().map(|tld| tld.kstack_bottom)
+}
+
+/// Returns the current Task's `MemoryUsage` by using the `TaskLocalData` pointer
+/// stored in the thread-local storage (FS base model-specific register).
+/// 
+/// This is registered with the memory subsystem, which invokes it on every allocation,
+/// so it must neither allocate memory nor lock the current task.
+fn get_my_memory_usage() -> Option<&'static MemoryUsage> {
+    get_task_local_data().map(|tld| tld.memory_usage.deref())
+}
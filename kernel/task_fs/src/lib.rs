@@ -9,7 +9,9 @@
 //! 4) MmiDir: lazily computed directory that holds subdirectories and files
 //!     about the task's memory management information
 //! 5) MmiFile: lazily computed file that contains information about the task's
-//!     memory management information
+//!     memory management information and the memory it has allocated
+//! 6) MemInfoFile: the `/meminfo` file in the root directory, which contains 
+//!     lazily computed information about the memory usage of the whole system
 //! 
 //! * Note that all the structs here are NOT persistent in the filesystem EXCEPT
 //! for the TaskFs struct, which contains all the individual TaskDirs. This means 
@@ -33,14 +35,17 @@ extern crate memory;
 extern crate task;
 extern crate path;
 extern crate root;
+extern crate multiple_heaps;
+extern crate kernel_config;
 
 
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use spin::Mutex;
 use alloc::sync::Arc;
-use fs_node::{DirRef, WeakDirRef, Directory, FileOrDir, File, FileRef, FsNode};
-use memory::MappedPages;
+use fs_node::{DirRef, WeakDirRef, Directory, FileOrDir, File, FileRef, FilesystemId, FsNode, Metadata, NodeKind, Permissions};
+use memory::{MappedPages, MemoryUsageStats};
+use kernel_config::memory::{PAGE_SIZE, KERNEL_HEAP_INITIAL_SIZE};
 use task::{TaskRef, TASKLIST, RunState};
 use path::Path;
 
@@ -49,15 +54,61 @@ use path::Path;
 pub const TASKS_DIRECTORY_NAME: &str = "tasks";
 /// The absolute path of the tasks directory, which is currently below the root
 pub const TASKS_DIRECTORY_PATH: &str = "/tasks"; 
+/// The name of the file in the root that exposes the system-wide memory usage.
+pub const MEMINFO_FILE_NAME: &str = "meminfo";
+/// The absolute path of the meminfo file, which is currently below the root
+pub const MEMINFO_FILE_PATH: &str = "/meminfo";
+
+/// Only its address is used, which identifies the task filesystem, see [`task_fs_id()`](fn.task_fs_id.html).
+static TASK_FS_ID_MARKER: u8 = 0;
+
+
+/// Returns the metadata of a lazily computed node, which is read-only and doesn't keep track of any timestamps.
+fn virtual_metadata(kind: NodeKind, size: usize) -> Metadata {
+    let permissions = match kind {
+        NodeKind::Directory => Permissions::READ | Permissions::EXECUTE,
+        _ => Permissions::READ,
+    };
+    Metadata {
+        kind,
+        size,
+        permissions,
+        created: None,
+        modified: None,
+        accessed: None,
+    }
+}
+
+
+/// Returns the filesystem ID shared by all directories in the task filesystem.
+/// 
+/// Its nodes are computed on demand from the task list, so they can't be moved into or out of it.
+fn task_fs_id() -> FilesystemId {
+    FilesystemId(&TASK_FS_ID_MARKER as *const u8 as usize)
+}
 
 
-/// Initializes the tasks virtual filesystem directory within the root directory.
+/// Initializes the tasks virtual filesystem directory and the meminfo file within the root directory.
 pub fn init() -> Result<(), &'static str> {
     TaskFs::new()?;
+    MemInfoFile::new()?;
     Ok(())
 }
 
 
+/// Returns the lines that describe the given frame and heap usage of a task or of the whole system.
+fn format_memory_usage(usage: &MemoryUsageStats) -> String {
+    format!("{0:<24} {1:>12}\n{2:<24} {3:>12}\n{4:<24} {5:>12} ({6} KiB)\n{7:<24} {8:>12}\n{9:<24} {10:>12}\n{11:<24} {12:>12} ({13} KiB)\n",
+        "frames allocated", usage.frames_allocated,
+        "frames deallocated", usage.frames_deallocated,
+        "frames in use", usage.frames_in_use(), usage.frames_in_use() * PAGE_SIZE / 1024,
+        "heap bytes allocated", usage.heap_bytes_allocated,
+        "heap bytes deallocated", usage.heap_bytes_deallocated,
+        "heap bytes in use", usage.heap_bytes_in_use(), usage.heap_bytes_in_use() / 1024,
+    )
+}
+
+
 /// The top level directory that includes a dynamically-generated list of all `Task`s,
 /// each comprising a `TaskDir`.
 /// This directory exists in the root directory.
@@ -103,6 +154,10 @@ impl FsNode for TaskFs {
     fn set_parent_dir(&mut self, _new_parent: WeakDirRef) {
         // do nothing
     }
+
+    fn metadata(&self) -> Metadata {
+        virtual_metadata(NodeKind::Directory, 0)
+    }
 }
 
 impl Directory for TaskFs {
@@ -134,6 +189,9 @@ impl Directory for TaskFs {
         None
     }
 
+    fn filesystem_id(&self) -> FilesystemId {
+        task_fs_id()
+    }
 }
 
 
@@ -195,6 +253,10 @@ impl Directory for TaskDir {
     fn remove(&mut self, _: &FileOrDir) -> Option<FileOrDir> { 
         None
     }
+
+    fn filesystem_id(&self) -> FilesystemId {
+        task_fs_id()
+    }
 }
 
 impl FsNode for TaskDir {
@@ -213,6 +275,10 @@ impl FsNode for TaskDir {
     fn set_parent_dir(&mut self, _: WeakDirRef) {
         // do nothing
     }
+
+    fn metadata(&self) -> Metadata {
+        virtual_metadata(NodeKind::Directory, 0)
+    }
 }
 
 
@@ -287,6 +353,10 @@ impl FsNode for TaskFile {
     fn set_parent_dir(&mut self, _: WeakDirRef) {
         // do nothing
     }
+
+    fn metadata(&self) -> Metadata {
+        virtual_metadata(NodeKind::File, self.size())
+    }
 }
 
 impl File for TaskFile {
@@ -363,6 +433,10 @@ impl Directory for MmiDir {
     fn remove(&mut self, _: &FileOrDir) -> Option<FileOrDir> {
         None
     }
+
+    fn filesystem_id(&self) -> FilesystemId {
+        task_fs_id()
+    }
 }
 
 impl FsNode for MmiDir {
@@ -385,6 +459,10 @@ impl FsNode for MmiDir {
     fn set_parent_dir(&mut self, _: WeakDirRef) {
         // do nothing
     }
+
+    fn metadata(&self) -> Metadata {
+        virtual_metadata(NodeKind::Directory, 0)
+    }
 }
 
 
@@ -407,11 +485,27 @@ impl MmiFile {
         }
     }
 
-    /// Generates the mmi info string.
+    /// Generates the mmi info string, which includes the memory allocated by this task 
+    /// and, for application tasks, the memory used by its application crate. 
     fn generate(&self) -> String {
         let task = self.taskref.lock();
-        let mmi = task.mmi.lock();
-        format!("Page table: {:?}\n", mmi.page_table)
+        let mut output = {
+            let mmi = task.mmi.lock();
+            format!("Page table: {:?}\n", mmi.page_table)
+        };
+        output.push_str(&format_memory_usage(&task.memory_usage.stats()));
+        if let Some(ref app_crate) = task.app_crate {
+            let app_crate = app_crate.lock_as_ref();
+            let usage = app_crate.memory_usage();
+            output.push_str(&format!("{0:<24} {1}\n{2:<24} {3:>12}\n{4:<24} {5:>12}\n{6:<24} {7:>12}\n{8:<24} {9:>12} ({10} KiB)\n",
+                "app crate", app_crate.crate_name,
+                "app text bytes", usage.text_bytes,
+                "app rodata bytes", usage.rodata_bytes,
+                "app data bytes", usage.data_bytes,
+                "app mapped pages", usage.mapped_pages, usage.mapped_pages * PAGE_SIZE / 1024,
+            ));
+        }
+        output
     }
 }
 
@@ -435,6 +529,10 @@ impl FsNode for MmiFile {
     fn set_parent_dir(&mut self, _: WeakDirRef) {
         // do nothing
     }
+
+    fn metadata(&self) -> Metadata {
+        virtual_metadata(NodeKind::File, self.size())
+    }
 }
 
 impl File for MmiFile {
@@ -461,3 +559,99 @@ impl File for MmiFile {
     }
 }
 
+
+
+
+/// The `/meminfo` file, which contains lazily computed information
+/// about the memory usage of the whole system, similar to `/proc/meminfo` in Linux.
+pub struct MemInfoFile { }
+
+impl MemInfoFile {
+    fn new() -> Result<FileRef, &'static str> {
+        let root = root::get_root();
+        let file_ref = Arc::new(Mutex::new(MemInfoFile { })) as FileRef;
+        root.lock().insert(FileOrDir::File(file_ref.clone()))?;
+        Ok(file_ref)
+    }
+
+    /// Generates the meminfo string.
+    fn generate(&self) -> String {
+        let mut output = String::new();
+        if let Some(frames) = memory::frame_stats() {
+            output.push_str(&format!("{0:<16} {1:>12} kB\n{2:<16} {3:>12} kB\n{4:<16} {5:>12} kB\n",
+                "MemTotal:", frames.total_frames * PAGE_SIZE / 1024,
+                "MemFree:", frames.free_frames * PAGE_SIZE / 1024,
+                "MemUsed:", frames.used_frames() * PAGE_SIZE / 1024,
+            ));
+        }
+
+        let heaps = multiple_heaps::stats();
+        let heap_size = KERNEL_HEAP_INITIAL_SIZE + heaps.as_ref().map(|h| h.size_in_bytes()).unwrap_or(0);
+        let usage = memory::system_memory_usage();
+        output.push_str(&format!("{0:<16} {1:>12} kB\n{2:<16} {3:>12} kB\n",
+            "HeapSize:", heap_size / 1024,
+            "HeapUsed:", usage.heap_bytes_in_use() / 1024,
+        ));
+        if let Some(heaps) = heaps {
+            for heap in &heaps.per_core {
+                output.push_str(&format!("{0:<16} {1:>12} kB {2:>12} kB used\n",
+                    format!("Heap{}:", heap.heap_id), heap.size_in_bytes / 1024, heap.allocated_bytes / 1024,
+                ));
+            }
+            output.push_str(&format!("{0:<16} {1:>12} kB\n", "HeapLarge:", heaps.large_allocation_bytes / 1024));
+        }
+
+        let mapped_pages = memory::mapped_pages_stats();
+        output.push_str(&format!("{0:<16} {1:>12}\n{2:<16} {3:>12} kB\n",
+            "MappedPages:", mapped_pages.count,
+            "MappedSize:", mapped_pages.pages * PAGE_SIZE / 1024,
+        ));
+        output
+    }
+}
+
+impl FsNode for MemInfoFile {
+    fn get_absolute_path(&self) -> String {
+        String::from(MEMINFO_FILE_PATH)
+    }
+
+    fn get_name(&self) -> String {
+        String::from(MEMINFO_FILE_NAME)
+    }
+
+    fn get_parent_dir(&self) -> Option<DirRef> {
+        Some(root::get_root().clone())
+    }
+
+    fn set_parent_dir(&mut self, _: WeakDirRef) {
+        // do nothing
+    }
+
+    fn metadata(&self) -> Metadata {
+        virtual_metadata(NodeKind::File, self.size())
+    }
+}
+
+impl File for MemInfoFile {
+    fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, &'static str> { 
+        let output = self.generate();
+        if offset > output.len() {
+            return Err("read offset exceeds file size");
+        }
+        let count = core::cmp::min(buf.len(), output.len() - offset);
+        buf[..count].copy_from_slice(&output.as_bytes()[offset..offset + count]);
+        Ok(count)
+    }
+
+    fn write(&mut self, _buf: &[u8], _offset: usize) -> Result<usize, &'static str> { 
+        Err("not permitted to write to the meminfo file") 
+    } 
+
+    fn size(&self) -> usize { 
+        self.generate().len() 
+    }
+
+    fn as_mapping(&self) -> Result<&MappedPages, &'static str> {
+        Err("the meminfo file is autogenerated, cannot be memory mapped")
+    }
+}
@@ -0,0 +1,349 @@
+//! A heap allocator wrapper that traces live allocations, for finding memory leaks.
+//!
+//! A [`TracingAllocator`] wraps another allocator and records every allocation made through it
+//! until that allocation is deallocated: its address, size, and alignment, the task that made it,
+//! and the return addresses of the call stack that made it.
+//! It is used as the system's default allocator if the `multiple_heaps` crate is built with its `tracing_allocator` feature,
+//! e.g., with `make trace_heap=yes`, which also enables frame pointers.
+//!
+//! The live allocations can be dumped at any time with [`dump_live_allocations()`].
+//! To find leaks in a long-running application, take a [`Snapshot`] of the live allocations,
+//! let the application run for a while, take another one, and then use [`Snapshot::allocated_since()`]
+//! to get the allocations that were made in the meantime and are still live.
+//! Snapshots can be kept across application runs with [`save_snapshot()`].
+//!
+//! Call stacks are found by following the frame pointers, so they are only recorded if the compiler
+//! was configured to emit frame pointers via the `-C force-frame-pointers=yes` rust flags option,
+//! which `make trace_heap=yes` does.
+//! Return addresses are only symbolized when allocations are written out, see [`write_allocations()`].
+//!
+//! Allocations made while the tracer itself is recording another allocation,
+//! e.g., by the map that holds the records, are not traced.
+//!
+//! Because every allocation passes through the tracer, the functions it calls to record an allocation
+//! must not allocate or take a lock that the code that is allocating may hold.
+//! Besides its own records, it only uses `apic::get_my_apic_id()` and `task::get_my_current_task_id()`,
+//! both of which just read a model-specific register (and the current task's `TaskLocalData`).
+
+#![no_std]
+#![feature(asm)]
+
+extern crate alloc;
+#[macro_use] extern crate lazy_static;
+extern crate spin;
+extern crate irq_safety;
+extern crate memory;
+extern crate apic;
+extern crate task;
+extern crate mod_mgmt;
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use alloc::alloc::{GlobalAlloc, Layout};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use irq_safety::{MutexIrqSafe, hold_interrupts};
+use memory::VirtualAddress;
+use mod_mgmt::CrateNamespace;
+use spin::Mutex;
+
+
+/// The maximum number of return addresses recorded for each allocation.
+pub const MAX_CALL_SITES: usize = 8;
+
+/// The prefixes of the symbols of the allocator functions that every allocation passes through,
+/// which are skipped when writing out the call stack of an allocation.
+const ALLOCATOR_SYMBOL_PREFIXES: [&'static str; 6] = [
+    "tracing_allocator::",
+    "multiple_heaps::",
+    "heap::",
+    "__rust_alloc",
+    "__rg_alloc",
+    "alloc::alloc::",
+];
+
+
+/// A live allocation recorded by a [`TracingAllocator`].
+#[derive(Debug, Clone)]
+pub struct AllocationRecord {
+    /// The order in which this allocation was made; a larger number means a later allocation.
+    pub sequence: usize,
+    /// The starting address of the allocated memory.
+    pub address: usize,
+    /// The size of the allocation in bytes.
+    pub size: usize,
+    /// The alignment of the allocation in bytes.
+    pub align: usize,
+    /// The id of the task that made this allocation, or `None` if it was made before tasking was set up.
+    pub task_id: Option<usize>,
+    /// The return addresses of the call stack that made this allocation, innermost first.
+    /// Unused entries are zero.
+    call_sites: [usize; MAX_CALL_SITES],
+}
+
+impl AllocationRecord {
+    /// Returns the return addresses of the call stack that made this allocation, innermost first.
+    /// This is empty if the compiler wasn't configured to emit frame pointers.
+    pub fn call_sites(&self) -> &[usize] {
+        let len = self.call_sites.iter().position(|&addr| addr == 0).unwrap_or(MAX_CALL_SITES);
+        &self.call_sites[..len]
+    }
+}
+
+
+lazy_static! {
+    /// The records of all live allocations, keyed by their starting address.
+    static ref RECORDS: MutexIrqSafe<BTreeMap<usize, AllocationRecord>> = MutexIrqSafe::new(BTreeMap::new());
+
+    /// The snapshots saved by `save_snapshot()`, in which the index of each snapshot is its id.
+    static ref SAVED_SNAPSHOTS: Mutex<Vec<Arc<Snapshot>>> = Mutex::new(Vec::new());
+}
+
+/// The apic id (plus one) of the core that is currently accessing `RECORDS`, or zero if no core is.
+/// Allocations made on that core while it does so are made by the tracer itself, and are not traced.
+static RECORDS_OWNER: AtomicUsize = AtomicUsize::new(0);
+
+/// The sequence number of the next allocation.
+static NEXT_SEQUENCE: AtomicUsize = AtomicUsize::new(1);
+
+/// Whether a `TracingAllocator` has been created, i.e., whether allocations are being traced.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+
+/// Invokes the given function `f` on the records of all live allocations, and returns its result.
+///
+/// Returns `None` without invoking `f` if this core is already accessing the records,
+/// i.e., if this was invoked by an allocation that the tracer itself made.
+fn with_records<R, F: FnOnce(&mut BTreeMap<usize, AllocationRecord>) -> R>(f: F) -> Option<R> {
+    // Interrupts are held such that this core can't switch to another task while it owns the records.
+    let _held_interrupts = hold_interrupts();
+    let me = apic::get_my_apic_id() as usize + 1;
+    if RECORDS_OWNER.load(Ordering::Acquire) == me {
+        return None;
+    }
+
+    let mut records = RECORDS.lock();
+    RECORDS_OWNER.store(me, Ordering::Release);
+    let result = f(&mut records);
+    RECORDS_OWNER.store(0, Ordering::Release);
+    Some(result)
+}
+
+
+/// An allocator that forwards all requests to the allocator it wraps,
+/// and records each allocation until it is deallocated.
+pub struct TracingAllocator<A: GlobalAlloc> {
+    inner: A,
+}
+
+impl<A: GlobalAlloc> TracingAllocator<A> {
+    /// Wraps the given allocator such that all allocations made through the returned `TracingAllocator` are traced.
+    pub fn new(inner: A) -> TracingAllocator<A> {
+        ENABLED.store(true, Ordering::Release);
+        TracingAllocator { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TracingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            record_allocation(ptr as usize, layout);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // The record is removed before the memory is deallocated,
+        // such that it can't remove the record of another allocation that reuses this memory.
+        with_records(|records| records.remove(&(ptr as usize)));
+        self.inner.dealloc(ptr, layout);
+    }
+}
+
+/// Records a new allocation of the given `layout` at the given `address`.
+#[inline(never)]
+fn record_allocation(address: usize, layout: Layout) {
+    #[allow(unused_mut)]
+    let mut call_sites = [0; MAX_CALL_SITES];
+    #[cfg(frame_pointers)]
+    collect_call_sites(&mut call_sites);
+
+    let record = AllocationRecord {
+        sequence: NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed),
+        address,
+        size: layout.size(),
+        align: layout.align(),
+        task_id: task::get_my_current_task_id(),
+        call_sites,
+    };
+    with_records(|records| records.insert(address, record));
+}
+
+/// Fills `call_sites` with the return addresses of the current call stack by following the frame pointers.
+///
+/// Each frame pointer is checked with a walk of the current page table before it's dereferenced.
+/// Unlike `stack_trace_frame_pointers`, this doesn't need the lock on the kernel's `MemoryManagementInfo`,
+/// which may be held by the code that is allocating memory.
+#[cfg(frame_pointers)]
+#[inline(never)]
+fn collect_call_sites(call_sites: &mut [usize; MAX_CALL_SITES]) {
+    let mapper = memory::Mapper::from_current();
+    let mut rbp: usize;
+    // SAFE: just reading current register value
+    unsafe {
+        asm!("" : "={rbp}"(rbp) : : "memory" : "intel", "volatile");
+    }
+
+    // The first return address is in `record_allocation()`, which called this function.
+    for call_site in call_sites.iter_mut() {
+        let rip_ptr = match rbp.checked_add(core::mem::size_of::<usize>()) {
+            Some(rip_ptr) => rip_ptr,
+            None => return,
+        };
+        match (VirtualAddress::new(rbp), VirtualAddress::new(rip_ptr)) {
+            (Ok(rbp_vaddr), Ok(rip_vaddr)) if mapper.translate(rbp_vaddr).is_some() && mapper.translate(rip_vaddr).is_some() => { }
+            _ => return,
+        }
+
+        // SAFE: both addresses were checked above using page table walks
+        let (rip, next_rbp) = unsafe { (*(rip_ptr as *const usize), *(rbp as *const usize)) };
+        if rip == 0 {
+            return;
+        }
+        *call_site = rip;
+        // the stack grows downwards, so the caller's frame must be above this one
+        if next_rbp <= rbp {
+            return;
+        }
+        rbp = next_rbp;
+    }
+}
+
+
+/// Returns `true` if allocations are being traced, i.e., if a `TracingAllocator` is in use.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+
+/// The live allocations at one point in time.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// The sequence number of the first allocation made after this snapshot was taken.
+    next_sequence: usize,
+    /// The records of the live allocations, in the order in which they were made.
+    records: Vec<AllocationRecord>,
+}
+
+impl Snapshot {
+    /// Returns the records of the allocations that were live when this snapshot was taken,
+    /// in the order in which they were made.
+    pub fn records(&self) -> &[AllocationRecord] {
+        &self.records
+    }
+
+    /// Returns the total number of bytes of the allocations that were live when this snapshot was taken.
+    pub fn total_bytes(&self) -> usize {
+        self.records.iter().map(|r| r.size).sum()
+    }
+
+    /// Returns the records of the allocations in this snapshot that were made after the given `earlier` snapshot was taken.
+    ///
+    /// These were allocated since then and had not been deallocated yet when this snapshot was taken,
+    /// so allocations that keep showing up here across several snapshots are likely leaks.
+    pub fn allocated_since(&self, earlier: &Snapshot) -> &[AllocationRecord] {
+        let start = self.records.iter().position(|r| r.sequence >= earlier.next_sequence).unwrap_or(self.records.len());
+        &self.records[start..]
+    }
+
+    /// Returns the records of the allocations in the given `earlier` snapshot that were deallocated
+    /// by the time this snapshot was taken.
+    pub fn deallocated_since<'e>(&self, earlier: &'e Snapshot) -> Vec<&'e AllocationRecord> {
+        earlier.records.iter()
+            .filter(|r| self.records.binary_search_by_key(&r.sequence, |s| s.sequence).is_err())
+            .collect()
+    }
+}
+
+/// Takes a snapshot of the live allocations.
+///
+/// Returns an error if allocations are not being traced.
+pub fn take_snapshot() -> Result<Snapshot, &'static str> {
+    if !is_enabled() {
+        return Err("heap allocations are not being traced; build the multiple_heaps crate with its `tracing_allocator` feature, e.g., with `make trace_heap=yes`");
+    }
+    // The snapshot's vector is allocated while accessing the records, so it isn't traced itself.
+    let (next_sequence, mut records) = with_records(|records| {
+        (NEXT_SEQUENCE.load(Ordering::Relaxed), records.values().cloned().collect::<Vec<_>>())
+    }).ok_or("take_snapshot(): called while recording an allocation")?;
+    records.sort_unstable_by_key(|r| r.sequence);
+    Ok(Snapshot { next_sequence, records })
+}
+
+/// Takes a snapshot of the live allocations and saves it, such that it can later be retrieved with `get_saved_snapshot()`.
+///
+/// Returns the id of the saved snapshot.
+pub fn save_snapshot() -> Result<usize, &'static str> {
+    let snapshot = Arc::new(take_snapshot()?);
+    let mut saved = SAVED_SNAPSHOTS.lock();
+    saved.push(snapshot);
+    Ok(saved.len() - 1)
+}
+
+/// Returns the snapshot with the given `id` that was saved by `save_snapshot()`.
+pub fn get_saved_snapshot(id: usize) -> Option<Arc<Snapshot>> {
+    SAVED_SNAPSHOTS.lock().get(id).cloned()
+}
+
+
+/// Writes a description of each of the given allocation `records` to `out`,
+/// including their call stacks with each return address symbolized using the given `namespace`.
+///
+/// The frames of the allocator functions that every allocation passes through are left out of the call stacks.
+pub fn write_allocations<'r, W, I>(out: &mut W, records: I, namespace: &CrateNamespace) -> fmt::Result
+    where W: Write, I: IntoIterator<Item = &'r AllocationRecord>
+{
+    for record in records {
+        write!(out, "#{} at {:#X}: {} bytes (align {})", record.sequence, record.address, record.size, record.align)?;
+        match record.task_id {
+            Some(id) => writeln!(out, ", task {}", id)?,
+            None => writeln!(out, ", before tasking")?,
+        }
+
+        let mut in_allocator = true;
+        for &call_site in record.call_sites() {
+            let symbol = VirtualAddress::new(call_site).ok()
+                .and_then(|vaddr| namespace.get_section_containing_address(vaddr, false));
+            match symbol {
+                Some((sec, offset)) => {
+                    if in_allocator && ALLOCATOR_SYMBOL_PREFIXES.iter().any(|prefix| sec.name.starts_with(prefix)) {
+                        continue;
+                    }
+                    in_allocator = false;
+                    writeln!(out, "    {:#X} in {} + {:#X}", call_site, sec.name, offset)?;
+                }
+                None => {
+                    in_allocator = false;
+                    writeln!(out, "    {:#X} in ??", call_site)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes a description of every live allocation to `out`, symbolized using the current task's namespace.
+///
+/// Returns an error if allocations are not being traced.
+pub fn dump_live_allocations<W: Write>(out: &mut W) -> Result<(), &'static str> {
+    let snapshot = take_snapshot()?;
+    let namespace = task::get_my_current_task().map(|t| t.get_namespace())
+        .or_else(|| mod_mgmt::get_initial_kernel_namespace().cloned())
+        .ok_or("dump_live_allocations(): couldn't get the current namespace")?;
+    write_allocations(out, snapshot.records(), &namespace)
+        .map_err(|_e| "dump_live_allocations(): formatting error")?;
+    writeln!(out, "{} live allocations, {} bytes in total", snapshot.records().len(), snapshot.total_bytes())
+        .map_err(|_e| "dump_live_allocations(): formatting error")
+}
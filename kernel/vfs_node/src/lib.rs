@@ -16,19 +16,28 @@ use alloc::string::String;
 use alloc::vec::Vec;
 use spin::Mutex;
 use alloc::sync::{Arc, Weak};
-use alloc::collections::BTreeMap;
-use fs_node::{DirRef, FileRef, WeakDirRef, Directory, FileOrDir, File, FsNode};
+use alloc::collections::{BTreeMap, BTreeSet};
+use fs_node::{DirRef, FileRef, WeakDirRef, Directory, FileOrDir, File, FsNode, Metadata, NodeAttributes, NodeKind, Permissions, Watchers, WatchEventKind};
 use memory::MappedPages;
 
 
 /// A struct that represents a node in the VFS 
+/// 
+/// A `VFSDirectory` notifies its watchers whenever an entry is created, replaced, removed, or renamed.
+/// Moving a node to another directory appears as a `Deleted` event here and a `Created` event in the destination.
 pub struct VFSDirectory {
     /// The name of the directory
     pub name: String,
     /// A list of child filesystem nodes
     pub children: BTreeMap<String, FileOrDir>,
+    /// The names of the children that are hard links, i.e., not the original entries of their nodes
+    pub links: BTreeSet<String>,
     /// A weak reference to the parent directory
     pub parent: WeakDirRef,
+    /// The permissions and timestamps of the directory
+    pub attributes: NodeAttributes,
+    /// The watchers that are notified of changes to the directory's entries
+    pub watchers: Watchers,
 }
 
 impl VFSDirectory {
@@ -38,23 +47,57 @@ impl VFSDirectory {
         let directory = VFSDirectory {
             name: name,
             children: BTreeMap::new(),
+            links: BTreeSet::new(),
             parent: Arc::downgrade(parent),
+            attributes: NodeAttributes::new(Permissions::all()),
+            watchers: Watchers::new(),
         };
         let dir_ref = Arc::new(Mutex::new(directory)) as DirRef;
         parent.lock().insert(FileOrDir::Dir(dir_ref.clone()))?;
         Ok(dir_ref)
     }
+
+    /// Removes the child called `name`, and clears its parent directory unless that child was a hard link.
+    /// In the latter case, the child's own watchers are told that it was deleted.
+    fn remove_entry(&mut self, name: &str) -> Option<FileOrDir> {
+        let mut old_node = self.children.remove(name)?;
+        if !self.links.remove(name) {
+            old_node.set_parent_dir(Weak::<Mutex<VFSDirectory>>::new());
+            old_node.notify_watchers(WatchEventKind::Deleted);
+        }
+        self.attributes.mark_modified();
+        Some(old_node)
+    }
 }
 
 impl Directory for VFSDirectory {
     fn insert(&mut self, node: FileOrDir) -> Result<Option<FileOrDir>, &'static str> {
+        self.attributes.check(Permissions::WRITE)?;
         let name = node.get_name();
-        if let Some(mut old_node) = self.children.insert(name, node) {
-            old_node.set_parent_dir(Weak::<Mutex<VFSDirectory>>::new());
-            Ok(Some(old_node))
-        } else {
-            Ok(None)
+        let old_node = self.remove_entry(&name);
+        let kind = if old_node.is_some() { WatchEventKind::Modified } else { WatchEventKind::Created };
+        self.watchers.notify_kind(kind, &name);
+        self.children.insert(name, node);
+        self.attributes.mark_modified();
+        Ok(old_node)
+    }
+
+    fn insert_link(&mut self, name: &str, file: FileRef) -> Result<Option<FileOrDir>, &'static str> {
+        self.attributes.check(Permissions::WRITE)?;
+        if let Some(FileOrDir::Dir(_)) = self.children.get(name) {
+            return Err("a directory with the given name already exists");
         }
+        let old_node = self.remove_entry(name);
+        self.children.insert(String::from(name), FileOrDir::File(file));
+        self.links.insert(String::from(name));
+        self.attributes.mark_modified();
+        let kind = if old_node.is_some() { WatchEventKind::Modified } else { WatchEventKind::Created };
+        self.watchers.notify_kind(kind, name);
+        Ok(old_node)
+    }
+
+    fn is_link(&self, name: &str) -> bool {
+        self.links.contains(name)
     }
 
     fn get(&self, name: &str) -> Option<FileOrDir> {
@@ -67,12 +110,82 @@ impl Directory for VFSDirectory {
     }
 
     fn remove(&mut self, node: &FileOrDir) -> Option<FileOrDir> {
-        if let Some(mut old_node) = self.children.remove(&node.get_name()) {
-            old_node.set_parent_dir(Weak::<Mutex<VFSDirectory>>::new());
-            Some(old_node)
-        } else {
-            None
+        let name = node.get_name();
+        // If the entry with the node's own name holds a different node, the node may be held here under a hard link.
+        let key = match self.children.get(&name) {
+            Some(n) if n.is_same_node(node) => name,
+            _ => self.children.iter().find(|&(_, n)| n.is_same_node(node)).map(|(k, _)| k.clone()).unwrap_or(name),
+        };
+        self.unlink(&key)
+    }
+
+    fn unlink(&mut self, name: &str) -> Option<FileOrDir> {
+        let old_node = self.remove_entry(name)?;
+        self.watchers.notify_kind(WatchEventKind::Deleted, name);
+        Some(old_node)
+    }
+
+    /// Renames the entry in place, so that watchers see a single `Renamed` event 
+    /// rather than the removal and re-insertion done by the default implementation.
+    fn rename(&mut self, old_name: &str, new_name: &str) -> Result<(), &'static str> {
+        self.attributes.check(Permissions::WRITE)?;
+        if self.links.contains(old_name) {
+            return Err("cannot rename a hard link; remove it and create a new link instead");
+        }
+        if !self.children.contains_key(old_name) {
+            return Err("source node does not exist");
+        }
+        if old_name == new_name {
+            return Ok(());
+        }
+        if let Some(FileOrDir::Dir(_)) = self.children.get(new_name) {
+            return Err("a directory with the new name already exists");
         }
+        let mut node = self.children.remove(old_name).ok_or("source node does not exist")?;
+        if let Err(e) = node.set_name(String::from(new_name)) {
+            self.children.insert(String::from(old_name), node);
+            return Err(e);
+        }
+        self.remove_entry(new_name);
+        self.children.insert(String::from(new_name), node);
+        self.attributes.mark_modified();
+        self.watchers.notify_kind(WatchEventKind::Renamed { old_name: String::from(old_name) }, new_name);
+        Ok(())
+    }
+
+    /// Like the default implementation, but the moved node's own watchers aren't told that it was deleted.
+    fn move_child(
+        &mut self,
+        name: &str,
+        destination: &mut dyn Directory,
+        new_parent: WeakDirRef,
+        new_name: &str,
+    ) -> Result<(), &'static str> {
+        self.attributes.check(Permissions::WRITE)?;
+        if self.links.contains(name) {
+            return Err("cannot move a hard link; remove it and create a new link instead");
+        }
+        if let Some(FileOrDir::Dir(_)) = destination.get(new_name) {
+            return Err("a directory with the new name already exists in the destination");
+        }
+        let mut node = self.children.remove(name).ok_or("source node does not exist")?;
+        let old_parent = node.get_parent_dir();
+        let result = node.set_name(String::from(new_name)).and_then(|_| {
+            node.set_parent_dir(new_parent);
+            destination.insert(node.clone())
+        });
+        if let Err(e) = result {
+            // Put the node back where it was.
+            let _ = node.set_name(String::from(name));
+            if let Some(ref p) = old_parent {
+                node.set_parent_dir(Arc::downgrade(p));
+            }
+            self.children.insert(String::from(name), node);
+            return Err(e);
+        }
+        self.attributes.mark_modified();
+        self.watchers.notify_kind(WatchEventKind::Deleted, name);
+        Ok(())
     }
 }
 
@@ -89,6 +202,24 @@ impl FsNode for VFSDirectory {
     fn set_parent_dir(&mut self, new_parent: WeakDirRef) {
         self.parent = new_parent;
     }
+
+    fn set_name(&mut self, new_name: String) -> Result<(), &'static str> {
+        self.name = new_name;
+        Ok(())
+    }
+
+    fn metadata(&self) -> Metadata {
+        self.attributes.metadata(NodeKind::Directory, 0)
+    }
+
+    fn set_permissions(&mut self, permissions: Permissions) -> Result<(), &'static str> {
+        self.attributes.set_permissions(permissions);
+        Ok(())
+    }
+
+    fn watchers(&self) -> Option<&Watchers> {
+        Some(&self.watchers)
+    }
 }
 
 pub struct VFSFile {
@@ -100,6 +231,8 @@ pub struct VFSFile {
     _contents: String,
     /// A weak reference to the parent directory
     parent: WeakDirRef,
+    /// The permissions and timestamps of the file
+    attributes: NodeAttributes,
 }
 
 impl VFSFile {
@@ -109,6 +242,7 @@ impl VFSFile {
             size: size, 
             _contents: contents,
             parent: Arc::downgrade(parent),
+            attributes: NodeAttributes::new(Permissions::READ | Permissions::WRITE),
         };
         let file_ref = Arc::new(Mutex::new(file)) as FileRef;
         parent.lock().insert(FileOrDir::File(file_ref.clone()))?;
@@ -146,4 +280,17 @@ impl FsNode for VFSFile {
     fn set_parent_dir(&mut self, new_parent: WeakDirRef) {
         self.parent = new_parent;
     }
+
+    fn set_name(&mut self, new_name: String) -> Result<(), &'static str> {
+        self.name = new_name;
+        Ok(())
+    }
+    fn metadata(&self) -> Metadata {
+        self.attributes.metadata(NodeKind::File, self.size)
+    }
+
+    fn set_permissions(&mut self, permissions: Permissions) -> Result<(), &'static str> {
+        self.attributes.set_permissions(permissions);
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,139 @@
+//! The legacy virtio-pci transport, in which a device's common registers
+//! and its device-specific configuration are accessed through the I/O ports in BAR0.
+//!
+//! Legacy devices only support the lower 32 feature bits, don't allow the driver to choose the size of a virtqueue,
+//! and require each virtqueue to be laid out contiguously with its used ring aligned to a 4096-byte boundary.
+
+use memory::PhysicalAddress;
+use port_io::{Port, PortIn, PortOut};
+use pci::PciDevice;
+use super::{DeviceStatus, Transport};
+
+
+/// The offsets of the legacy registers within the I/O space.
+const DEVICE_FEATURES: u16 = 0;
+const DRIVER_FEATURES: u16 = 4;
+const QUEUE_ADDRESS:   u16 = 8;
+const QUEUE_SIZE:      u16 = 12;
+const QUEUE_SELECT:    u16 = 14;
+const QUEUE_NOTIFY:    u16 = 16;
+const DEVICE_STATUS:   u16 = 18;
+const ISR_STATUS:      u16 = 19;
+/// The offset of the device-specific configuration, which follows the common registers when MSI-X is disabled.
+const DEVICE_CONFIG:   u16 = 20;
+
+/// The queue address register holds a page frame number, in units of this size.
+const QUEUE_ADDRESS_SHIFT: usize = 12;
+/// The alignment of a legacy virtqueue's used ring.
+pub const LEGACY_QUEUE_ALIGNMENT: usize = 4096;
+
+
+/// A virtio device accessed through the legacy I/O port interface.
+pub struct LegacyTransport {
+    /// The base of the device's I/O port range.
+    io_base: u16,
+}
+
+impl LegacyTransport {
+    /// Creates a legacy transport for the given `pci_device`, whose BAR0 must be an I/O space BAR.
+    pub fn new(pci_device: &PciDevice) -> Result<LegacyTransport, &'static str> {
+        let bar0 = pci_device.bars[0];
+        // Bit 0 of a BAR is set if it describes I/O space rather than memory space.
+        if bar0 & 0x1 == 0 {
+            return Err("virtio: legacy device's BAR0 was not an I/O space BAR");
+        }
+        Ok(LegacyTransport {
+            io_base: (bar0 & 0xFFFC) as u16,
+        })
+    }
+
+    fn read<T: PortIn + PortOut>(&self, offset: u16) -> T {
+        Port::<T>::new(self.io_base + offset).read()
+    }
+
+    fn write<T: PortIn + PortOut>(&self, offset: u16, value: T) {
+        unsafe { Port::<T>::new(self.io_base + offset).write(value) }
+    }
+}
+
+impl Transport for LegacyTransport {
+    fn is_legacy(&self) -> bool {
+        true
+    }
+
+    fn device_features(&mut self) -> u64 {
+        self.read::<u32>(DEVICE_FEATURES) as u64
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        self.write::<u32>(DRIVER_FEATURES, features as u32);
+    }
+
+    fn status(&mut self) -> DeviceStatus {
+        DeviceStatus::from_bits_truncate(self.read::<u8>(DEVICE_STATUS))
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.write::<u8>(DEVICE_STATUS, status.bits());
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        self.write::<u16>(QUEUE_SELECT, queue);
+        self.read::<u16>(QUEUE_SIZE)
+    }
+
+    fn setup_queue(
+        &mut self,
+        queue: u16,
+        size: u16,
+        descriptors: PhysicalAddress,
+        available_ring: PhysicalAddress,
+        used_ring: PhysicalAddress,
+    ) -> Result<(), &'static str> {
+        if size != self.max_queue_size(queue) {
+            return Err("virtio: legacy devices don't support changing the size of a virtqueue");
+        }
+        // A legacy device derives the location of the rings from the location of the descriptor table.
+        let size = size as usize;
+        let expected_available_ring = descriptors + 16 * size;
+        let expected_used_ring = descriptors + align_up(16 * size + 6 + 2 * size, LEGACY_QUEUE_ALIGNMENT);
+        if available_ring != expected_available_ring || used_ring != expected_used_ring {
+            return Err("virtio: virtqueue was not laid out as required by the legacy transport");
+        }
+        if descriptors.value() % LEGACY_QUEUE_ALIGNMENT != 0 {
+            return Err("virtio: legacy virtqueue was not page-aligned");
+        }
+        let pfn = descriptors.value() >> QUEUE_ADDRESS_SHIFT;
+        if pfn > core::u32::MAX as usize {
+            return Err("virtio: legacy virtqueue must be located below 16 TiB");
+        }
+        self.write::<u32>(QUEUE_ADDRESS, pfn as u32);
+        Ok(())
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.write::<u16>(QUEUE_NOTIFY, queue);
+    }
+
+    fn read_isr(&mut self) -> u8 {
+        self.read::<u8>(ISR_STATUS)
+    }
+
+    fn config_generation(&mut self) -> u8 {
+        // Legacy devices have no generation counter.
+        0
+    }
+
+    fn read_config_u8(&mut self, offset: usize) -> u8 {
+        self.read::<u8>(DEVICE_CONFIG + offset as u16)
+    }
+
+    fn read_config_u32(&mut self, offset: usize) -> u32 {
+        self.read::<u32>(DEVICE_CONFIG + offset as u16)
+    }
+}
+
+/// Rounds the given `value` up to the nearest multiple of `alignment`, which must be a power of two.
+pub(crate) fn align_up(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) & !(alignment - 1)
+}
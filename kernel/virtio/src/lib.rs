@@ -0,0 +1,257 @@
+//! Common support for virtio devices, i.e., the paravirtualized devices offered by hypervisors like QEMU and KVM.
+//!
+//! This crate contains the parts of a virtio driver that don't depend on the type of device:
+//! * discovery of virtio devices on the PCI bus and the two virtio-pci transports through which they are accessed,
+//!   the [`legacy`](legacy/index.html) transport based on I/O ports and the [`modern`](modern/index.html) transport
+//!   based on memory-mapped regions described by PCI capabilities,
+//! * the device initialization sequence and feature negotiation, see [`negotiate_features()`](fn.negotiate_features.html),
+//! * the split virtqueue through which a driver exchanges buffers with a device, see [`Virtqueue`](virtqueue/struct.Virtqueue.html).
+//!
+//! A driver for a specific type of device (e.g., `virtio_blk`) uses [`transport_for()`](fn.transport_for.html)
+//! to obtain a [`Transport`](trait.Transport.html) for its PCI device, negotiates features, sets up its virtqueues,
+//! and then marks itself as ready with [`driver_ok()`](fn.driver_ok.html).
+//!
+//! The virtio specification is available here:
+//! <https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html>
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+#[macro_use] extern crate bitflags;
+extern crate volatile;
+extern crate kernel_config;
+extern crate memory;
+extern crate pci;
+extern crate port_io;
+
+pub mod legacy;
+pub mod modern;
+pub mod virtqueue;
+
+pub use virtqueue::{Buffer, UsedBuffer, Virtqueue};
+
+use alloc::boxed::Box;
+use memory::PhysicalAddress;
+use pci::PciDevice;
+
+
+/// The PCI vendor ID shared by all virtio devices.
+pub const VIRTIO_PCI_VENDOR_ID: u16 = 0x1AF4;
+/// Transitional devices, which support both the legacy and modern transports,
+/// have PCI device IDs in this range, ordered by their device type (e.g., `0x1001` for a block device).
+const TRANSITIONAL_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1000 ..= 0x103F;
+/// Modern-only devices have a PCI device ID of this base plus their virtio device type.
+const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+
+/// The feature bit that indicates compliance with version 1 of the virtio specification,
+/// which is required by the modern transport.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+
+/// The types of virtio devices, as given in the virtio specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Network,
+    Block,
+    Console,
+    Entropy,
+    Balloon,
+    Scsi,
+    Gpu,
+    Input,
+    Other(u16),
+}
+
+impl DeviceType {
+    fn from_id(id: u16) -> DeviceType {
+        match id {
+            1  => DeviceType::Network,
+            2  => DeviceType::Block,
+            3  => DeviceType::Console,
+            4  => DeviceType::Entropy,
+            5  => DeviceType::Balloon,
+            8  => DeviceType::Scsi,
+            16 => DeviceType::Gpu,
+            18 => DeviceType::Input,
+            id => DeviceType::Other(id),
+        }
+    }
+}
+
+/// Returns the type of virtio device that the given `pci_device` is,
+/// or `None` if it isn't a virtio device.
+pub fn device_type(pci_device: &PciDevice) -> Option<DeviceType> {
+    if pci_device.vendor_id != VIRTIO_PCI_VENDOR_ID {
+        return None;
+    }
+    if TRANSITIONAL_DEVICE_IDS.contains(&pci_device.device_id) {
+        // Transitional devices store their device type in the PCI subsystem ID.
+        let subsystem_id = pci_device.location.pci_read_16(pci::PCI_SUBSYSTEM_ID);
+        Some(DeviceType::from_id(subsystem_id))
+    } else if pci_device.device_id >= MODERN_DEVICE_ID_BASE {
+        Some(DeviceType::from_id(pci_device.device_id - MODERN_DEVICE_ID_BASE))
+    } else {
+        None
+    }
+}
+
+
+bitflags! {
+    /// The bits of the device status field, which reflect the progress of the device initialization sequence.
+    pub struct DeviceStatus: u8 {
+        /// The driver has noticed the device.
+        const ACKNOWLEDGE = 1 << 0;
+        /// The driver knows how to drive the device.
+        const DRIVER = 1 << 1;
+        /// The driver is set up and ready to drive the device.
+        const DRIVER_OK = 1 << 2;
+        /// The driver has acknowledged the features it understands, and feature negotiation is complete.
+        const FEATURES_OK = 1 << 3;
+        /// The device has experienced an error from which it can't recover.
+        const DEVICE_NEEDS_RESET = 1 << 6;
+        /// The driver has given up on the device.
+        const FAILED = 1 << 7;
+    }
+}
+
+
+/// The interface through which a driver accesses a virtio device's common registers,
+/// independent of how those registers are exposed by the underlying transport.
+pub trait Transport: Send {
+    /// Returns `true` if this is the legacy transport,
+    /// in which case only the lower 32 feature bits are available
+    /// and queue sizes are fixed by the device.
+    fn is_legacy(&self) -> bool;
+
+    /// Returns the feature bits offered by the device.
+    fn device_features(&mut self) -> u64;
+
+    /// Writes the feature bits that the driver accepts.
+    fn set_driver_features(&mut self, features: u64);
+
+    /// Returns the current device status.
+    fn status(&mut self) -> DeviceStatus;
+
+    /// Sets the device status, which resets the device if the status is empty.
+    fn set_status(&mut self, status: DeviceStatus);
+
+    /// Returns the maximum size of the given virtqueue, or `0` if that virtqueue doesn't exist.
+    fn max_queue_size(&mut self, queue: u16) -> u16;
+
+    /// Tells the device where the given virtqueue of the given `size` resides in memory and enables it.
+    ///
+    /// The descriptor table, available ring, and used ring are given by their physical addresses.
+    fn setup_queue(
+        &mut self,
+        queue: u16,
+        size: u16,
+        descriptors: PhysicalAddress,
+        available_ring: PhysicalAddress,
+        used_ring: PhysicalAddress,
+    ) -> Result<(), &'static str>;
+
+    /// Notifies the device that new buffers are available in the given virtqueue.
+    fn notify(&mut self, queue: u16);
+
+    /// Reads and clears the interrupt status register.
+    fn read_isr(&mut self) -> u8;
+
+    /// Returns the generation counter of the device-specific configuration,
+    /// which changes whenever the device changes its configuration.
+    fn config_generation(&mut self) -> u8;
+
+    /// Reads a byte from the device-specific configuration at the given `offset`.
+    fn read_config_u8(&mut self, offset: usize) -> u8;
+
+    /// Reads a `u32` from the device-specific configuration at the given `offset`.
+    fn read_config_u32(&mut self, offset: usize) -> u32;
+
+    /// Reads a `u64` from the device-specific configuration at the given `offset`,
+    /// retrying if the configuration changed between reading its two halves.
+    fn read_config_u64(&mut self, offset: usize) -> u64 {
+        loop {
+            let generation = self.config_generation();
+            let low = self.read_config_u32(offset) as u64;
+            let high = self.read_config_u32(offset + 4) as u64;
+            if self.config_generation() == generation {
+                return low | (high << 32);
+            }
+        }
+    }
+}
+
+/// Returns the transport through which the given virtio `pci_device` can be accessed.
+///
+/// The modern transport is preferred if the device offers it, otherwise the legacy transport is used.
+pub fn transport_for(pci_device: &PciDevice) -> Result<Box<dyn Transport>, &'static str> {
+    if device_type(pci_device).is_none() {
+        return Err("virtio: PCI device was not a virtio device");
+    }
+    // Allow the device to access the virtqueues via DMA.
+    pci_device.pci_set_command_bus_master_bit();
+
+    match modern::ModernTransport::new(pci_device) {
+        Ok(transport) => {
+            debug!("virtio: using the modern transport for device at {:?}", pci_device.location);
+            return Ok(Box::new(transport));
+        }
+        Err(e) => debug!("virtio: modern transport was unavailable ({}), trying the legacy transport", e),
+    }
+    let transport = legacy::LegacyTransport::new(pci_device)?;
+    debug!("virtio: using the legacy transport for device at {:?}", pci_device.location);
+    Ok(Box::new(transport))
+}
+
+
+/// Resets the device and negotiates the features that both the device and the driver support.
+///
+/// `driver_features` are the device-specific features that the driver understands;
+/// `VIRTIO_F_VERSION_1` is added automatically when using the modern transport.
+/// Returns the accepted features, which the driver must respect.
+///
+/// After this returns, the driver should set up its virtqueues and then call [`driver_ok()`](fn.driver_ok.html).
+pub fn negotiate_features(transport: &mut dyn Transport, driver_features: u64) -> Result<u64, &'static str> {
+    transport.set_status(DeviceStatus::empty());
+    while !transport.status().is_empty() { }
+
+    let mut status = DeviceStatus::ACKNOWLEDGE;
+    transport.set_status(status);
+    status |= DeviceStatus::DRIVER;
+    transport.set_status(status);
+
+    let device_features = transport.device_features();
+    let mut accepted = device_features & driver_features;
+    if !transport.is_legacy() {
+        if device_features & VIRTIO_F_VERSION_1 == 0 {
+            transport.set_status(status | DeviceStatus::FAILED);
+            return Err("virtio: device did not offer VIRTIO_F_VERSION_1 over the modern transport");
+        }
+        accepted |= VIRTIO_F_VERSION_1;
+    }
+    transport.set_driver_features(accepted);
+    debug!("virtio: device features {:#X}, accepted features {:#X}", device_features, accepted);
+
+    // Legacy devices don't have the FEATURES_OK step.
+    if !transport.is_legacy() {
+        status |= DeviceStatus::FEATURES_OK;
+        transport.set_status(status);
+        if !transport.status().contains(DeviceStatus::FEATURES_OK) {
+            transport.set_status(status | DeviceStatus::FAILED);
+            return Err("virtio: device did not accept the negotiated features");
+        }
+    }
+    Ok(accepted)
+}
+
+/// Tells the device that the driver is ready to drive it, which completes the device initialization sequence.
+pub fn driver_ok(transport: &mut dyn Transport) {
+    let status = transport.status();
+    transport.set_status(status | DeviceStatus::DRIVER_OK);
+}
+
+/// Tells the device that the driver has given up on it, e.g., because its initialization failed.
+pub fn mark_failed(transport: &mut dyn Transport) {
+    let status = transport.status();
+    transport.set_status(status | DeviceStatus::FAILED);
+}
@@ -0,0 +1,354 @@
+//! The modern virtio-pci transport, in which a device's registers reside in memory-mapped regions of its BARs.
+//!
+//! The location of each region is described by a vendor-specific PCI capability:
+//! the common configuration, the notification area, the interrupt status register,
+//! and the device-specific configuration.
+
+use core::{
+    mem::size_of,
+    ops::DerefMut,
+};
+use alloc::vec::Vec;
+use volatile::{Volatile, ReadOnly};
+use kernel_config::memory::PAGE_SIZE;
+use memory::{EntryFlags, FrameRange, MappedPages, PhysicalAddress, PhysicalMemoryArea,
+    allocate_pages_by_bytes, get_frame_allocator_ref, get_kernel_mmi_ref};
+use pci::{PciDevice, PCI_CAPABILITIES, PCI_STATUS};
+use super::{DeviceStatus, Transport};
+
+
+/// The PCI capability ID of vendor-specific capabilities, which virtio uses to describe its regions.
+const VENDOR_SPECIFIC_CAPABILITY: u8 = 0x09;
+
+/// The types of regions described by virtio's vendor-specific capabilities.
+const COMMON_CONFIG_TYPE: u8 = 1;
+const NOTIFY_CONFIG_TYPE: u8 = 2;
+const ISR_CONFIG_TYPE:    u8 = 3;
+const DEVICE_CONFIG_TYPE: u8 = 4;
+
+/// Bit 4 of the PCI status register is set if the device has a capabilities list.
+const CAPABILITIES_LIST_VALID: u16 = 1 << 4;
+/// The maximum number of capabilities we walk, which guards against a malformed list that loops.
+const MAX_CAPABILITIES: usize = 48;
+
+
+/// The layout of the common configuration region.
+#[repr(C)]
+struct CommonConfig {
+    device_feature_select: Volatile<u32>,  // 0x00
+    device_feature:        ReadOnly<u32>,  // 0x04
+    driver_feature_select: Volatile<u32>,  // 0x08
+    driver_feature:        Volatile<u32>,  // 0x0C
+    _msix_config:          Volatile<u16>,  // 0x10
+    _num_queues:           ReadOnly<u16>,  // 0x12
+    device_status:         Volatile<u8>,   // 0x14
+    config_generation:     ReadOnly<u8>,   // 0x15
+    queue_select:          Volatile<u16>,  // 0x16
+    queue_size:            Volatile<u16>,  // 0x18
+    _queue_msix_vector:    Volatile<u16>,  // 0x1A
+    queue_enable:          Volatile<u16>,  // 0x1C
+    queue_notify_off:      ReadOnly<u16>,  // 0x1E
+    // The 64-bit queue addresses are written as two 32-bit halves, which all devices must support.
+    queue_desc_low:        Volatile<u32>,  // 0x20
+    queue_desc_high:       Volatile<u32>,  // 0x24
+    queue_driver_low:      Volatile<u32>,  // 0x28
+    queue_driver_high:     Volatile<u32>,  // 0x2C
+    queue_device_low:      Volatile<u32>,  // 0x30
+    queue_device_high:     Volatile<u32>,  // 0x34
+}
+
+
+/// A region described by a virtio capability, located within one of the mapped BARs.
+#[derive(Clone, Copy)]
+struct Region {
+    /// The index of the mapping that holds this region within `ModernTransport::mappings`.
+    mapping: usize,
+    /// The offset of this region within that mapping.
+    offset: usize,
+    length: usize,
+}
+
+/// A mapping of the part of a BAR that holds one or more regions.
+struct BarMapping {
+    bar: u8,
+    /// The offset within the BAR where the mapped part begins.
+    start: usize,
+    /// The offset of `start` within the first mapped page.
+    offset_in_page: usize,
+    pages: MappedPages,
+}
+
+/// A virtio device accessed through the modern, memory-mapped interface.
+pub struct ModernTransport {
+    mappings: Vec<BarMapping>,
+    common: Region,
+    notify: Region,
+    /// The queue's notification offset is multiplied by this value to obtain its offset in the notification region.
+    notify_offset_multiplier: u32,
+    isr: Region,
+    device: Option<Region>,
+}
+
+impl ModernTransport {
+    /// Creates a modern transport for the given `pci_device` by finding and mapping the regions described by its capabilities.
+    pub fn new(pci_device: &PciDevice) -> Result<ModernTransport, &'static str> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device = None;
+        let mut notify_offset_multiplier = 0;
+
+        // Find the first capability of each type, as recommended by the specification.
+        for cap in vendor_capabilities(pci_device) {
+            let cfg_type = pci_device.pci_read_8(cap + 3);
+            let bar = pci_device.pci_read_8(cap + 4);
+            let offset = pci_device.pci_read_32(cap + 8) as usize;
+            let length = pci_device.pci_read_32(cap + 12) as usize;
+            if bar > 5 || length == 0 {
+                continue;
+            }
+            let location = Some((bar, offset, length));
+            match cfg_type {
+                COMMON_CONFIG_TYPE if common.is_none() => common = location,
+                NOTIFY_CONFIG_TYPE if notify.is_none() => {
+                    notify = location;
+                    notify_offset_multiplier = pci_device.pci_read_32(cap + 16);
+                }
+                ISR_CONFIG_TYPE if isr.is_none() => isr = location,
+                DEVICE_CONFIG_TYPE if device.is_none() => device = location,
+                _ => { }
+            }
+        }
+
+        let common = common.ok_or("virtio: device had no common configuration capability")?;
+        let notify = notify.ok_or("virtio: device had no notification capability")?;
+        let isr = isr.ok_or("virtio: device had no ISR capability")?;
+        if common.2 < size_of::<CommonConfig>() {
+            return Err("virtio: device's common configuration region was too small");
+        }
+
+        let regions = [Some(common), Some(notify), Some(isr), device];
+        let mappings = map_bars(pci_device, &regions)?;
+        let find_region = |(bar, offset, length): (u8, usize, usize)| -> Result<Region, &'static str> {
+            let mapping = mappings.iter().position(|m| m.bar == bar).ok_or("virtio: region's BAR was not mapped")?;
+            let m = &mappings[mapping];
+            Ok(Region { mapping, offset: m.offset_in_page + offset - m.start, length })
+        };
+        let common = find_region(common)?;
+        let notify = find_region(notify)?;
+        let isr = find_region(isr)?;
+        let device = match device {
+            Some(d) => Some(find_region(d)?),
+            None => None,
+        };
+
+        Ok(ModernTransport {
+            mappings,
+            common,
+            notify,
+            notify_offset_multiplier,
+            isr,
+            device,
+        })
+    }
+
+    fn common_config(&mut self) -> &mut CommonConfig {
+        let region = self.common;
+        self.mappings[region.mapping].pages.as_type_mut::<CommonConfig>(region.offset)
+            .expect("virtio: common configuration region was not mapped")
+    }
+
+    /// Returns a volatile reference to the value of type `T` at the given `offset` into the given `region`.
+    fn region_value<T>(&mut self, region: Region, offset: usize) -> Result<&mut Volatile<T>, &'static str>
+        where T: Copy
+    {
+        if offset + size_of::<T>() > region.length {
+            return Err("virtio: access extended beyond the end of the region");
+        }
+        self.mappings[region.mapping].pages.as_type_mut::<Volatile<T>>(region.offset + offset)
+    }
+
+    fn read_device_config<T: Copy + Default>(&mut self, offset: usize) -> T {
+        let value = self.device
+            .ok_or("virtio: device had no device-specific configuration")
+            .and_then(|region| self.region_value::<T>(region, offset).map(|v| v.read()));
+        value.unwrap_or_else(|e| {
+            error!("{}", e);
+            T::default()
+        })
+    }
+}
+
+impl Transport for ModernTransport {
+    fn is_legacy(&self) -> bool {
+        false
+    }
+
+    fn device_features(&mut self) -> u64 {
+        let common = self.common_config();
+        common.device_feature_select.write(0);
+        let low = common.device_feature.read() as u64;
+        common.device_feature_select.write(1);
+        let high = common.device_feature.read() as u64;
+        low | (high << 32)
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        let common = self.common_config();
+        common.driver_feature_select.write(0);
+        common.driver_feature.write(features as u32);
+        common.driver_feature_select.write(1);
+        common.driver_feature.write((features >> 32) as u32);
+    }
+
+    fn status(&mut self) -> DeviceStatus {
+        DeviceStatus::from_bits_truncate(self.common_config().device_status.read())
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.common_config().device_status.write(status.bits());
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        let common = self.common_config();
+        common.queue_select.write(queue);
+        common.queue_size.read()
+    }
+
+    fn setup_queue(
+        &mut self,
+        queue: u16,
+        size: u16,
+        descriptors: PhysicalAddress,
+        available_ring: PhysicalAddress,
+        used_ring: PhysicalAddress,
+    ) -> Result<(), &'static str> {
+        let common = self.common_config();
+        common.queue_select.write(queue);
+        if size == 0 || size > common.queue_size.read() {
+            return Err("virtio: invalid virtqueue size");
+        }
+        common.queue_size.write(size);
+        common.queue_desc_low.write(descriptors.value() as u32);
+        common.queue_desc_high.write((descriptors.value() as u64 >> 32) as u32);
+        common.queue_driver_low.write(available_ring.value() as u32);
+        common.queue_driver_high.write((available_ring.value() as u64 >> 32) as u32);
+        common.queue_device_low.write(used_ring.value() as u32);
+        common.queue_device_high.write((used_ring.value() as u64 >> 32) as u32);
+        common.queue_enable.write(1);
+        Ok(())
+    }
+
+    fn notify(&mut self, queue: u16) {
+        let notify_off = {
+            let common = self.common_config();
+            common.queue_select.write(queue);
+            common.queue_notify_off.read() as usize
+        };
+        let offset = notify_off * self.notify_offset_multiplier as usize;
+        let region = self.notify;
+        match self.region_value::<u16>(region, offset) {
+            Ok(register) => register.write(queue),
+            Err(e) => error!("virtio: couldn't notify queue {}: {}", queue, e),
+        }
+    }
+
+    fn read_isr(&mut self) -> u8 {
+        let region = self.isr;
+        self.region_value::<u8>(region, 0).map(|v| v.read()).unwrap_or(0)
+    }
+
+    fn config_generation(&mut self) -> u8 {
+        self.common_config().config_generation.read()
+    }
+
+    fn read_config_u8(&mut self, offset: usize) -> u8 {
+        self.read_device_config::<u8>(offset)
+    }
+
+    fn read_config_u32(&mut self, offset: usize) -> u32 {
+        self.read_device_config::<u32>(offset)
+    }
+}
+
+
+/// Returns the config space offsets of all of the given `pci_device`'s vendor-specific capabilities.
+fn vendor_capabilities(pci_device: &PciDevice) -> Vec<u16> {
+    let mut capabilities = Vec::new();
+    if pci_device.pci_read_16(PCI_STATUS) & CAPABILITIES_LIST_VALID == 0 {
+        return capabilities;
+    }
+    let mut cap = (pci_device.pci_read_8(PCI_CAPABILITIES) & 0xFC) as u16;
+    for _ in 0 .. MAX_CAPABILITIES {
+        if cap == 0 {
+            break;
+        }
+        let header = pci_device.pci_read_16(cap);
+        if (header & 0xFF) as u8 == VENDOR_SPECIFIC_CAPABILITY {
+            capabilities.push(cap);
+        }
+        cap = (header >> 8) & 0xFC;
+    }
+    capabilities
+}
+
+/// Returns the range of offsets within the given `bar` that covers all of the given `regions` in that BAR.
+fn region_span(regions: &[Option<(u8, usize, usize)>], bar: u8) -> (usize, usize) {
+    let mut start = core::usize::MAX;
+    let mut end = 0;
+    for &(_, offset, length) in regions.iter().filter_map(|r| r.as_ref()).filter(|r| r.0 == bar) {
+        start = core::cmp::min(start, offset);
+        end = core::cmp::max(end, offset + length);
+    }
+    (start, end)
+}
+
+/// Maps the parts of the given `pci_device`'s BARs that hold the given `regions`, mapping each BAR only once.
+fn map_bars(pci_device: &PciDevice, regions: &[Option<(u8, usize, usize)>]) -> Result<Vec<BarMapping>, &'static str> {
+    let mut mappings: Vec<BarMapping> = Vec::new();
+    for &(bar, _, _) in regions.iter().filter_map(|r| r.as_ref()) {
+        if mappings.iter().any(|m| m.bar == bar) {
+            continue;
+        }
+        let (start, end) = region_span(regions, bar);
+        let base = bar_address(pci_device, bar)?;
+        let (pages, offset_in_page) = map_mmio(base + start, end - start)?;
+        mappings.push(BarMapping { bar, start, offset_in_page, pages });
+    }
+    Ok(mappings)
+}
+
+/// Returns the physical address described by the given memory space `bar`, which may be a 64-bit BAR.
+fn bar_address(pci_device: &PciDevice, bar: u8) -> Result<PhysicalAddress, &'static str> {
+    let value = pci_device.bars[bar as usize];
+    if value & 0x1 != 0 {
+        return Err("virtio: region was in an I/O space BAR, which is not supported");
+    }
+    // Bits 1-2 of a memory space BAR are `0b10` if it is a 64-bit BAR, whose upper half is in the next BAR.
+    let high = if (value >> 1) & 0x3 == 0x2 {
+        let next = pci_device.bars.get(bar as usize + 1).ok_or("virtio: 64-bit BAR5 is invalid")?;
+        (*next as usize) << 32
+    } else {
+        0
+    };
+    PhysicalAddress::new(high | (value & 0xFFFF_FFF0) as usize)
+}
+
+/// Maps the given physical range of device memory as uncacheable.
+///
+/// Returns the mapping and the offset of the given `address` within its first page.
+fn map_mmio(address: PhysicalAddress, size_in_bytes: usize) -> Result<(MappedPages, usize), &'static str> {
+    let offset_in_page = address.value() % PAGE_SIZE;
+    let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE;
+
+    // Inform the frame allocator that these frames of device memory are off-limits.
+    let frame_allocator = get_frame_allocator_ref().ok_or("virtio: couldn't get the frame allocator")?;
+    let area = PhysicalMemoryArea::new(address, size_in_bytes, 1, 0);
+    frame_allocator.lock().add_area(area, false)?;
+
+    let pages = allocate_pages_by_bytes(offset_in_page + size_in_bytes).ok_or("virtio: couldn't allocate pages for device memory")?;
+    let frames = FrameRange::from_phys_addr(address, size_in_bytes);
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("virtio: KERNEL_MMI was not yet initialized!")?;
+    let mapped_pages = kernel_mmi_ref.lock().page_table.map_allocated_pages_to(pages, frames, flags, frame_allocator.lock().deref_mut())?;
+    Ok((mapped_pages, offset_in_page))
+}
@@ -0,0 +1,301 @@
+//! The split virtqueue, through which a driver passes buffers to a virtio device and gets them back once they've been used.
+//!
+//! A split virtqueue consists of three parts that reside in memory shared with the device:
+//! * the descriptor table, in which each descriptor describes one buffer and may be chained to a next descriptor,
+//! * the available ring, in which the driver places the head descriptors of the chains it offers to the device,
+//! * the used ring, in which the device places the head descriptors of the chains it has finished with.
+//!
+//! The three parts are allocated contiguously using the layout required by the legacy transport,
+//! which the modern transport also accepts, so the same `Virtqueue` works with any transport.
+//!
+//! A `Virtqueue` doesn't know anything about the meaning of the buffers it carries,
+//! so it can be used by any virtio driver.
+
+use core::{
+    mem::size_of,
+    sync::atomic::{fence, Ordering},
+};
+use volatile::Volatile;
+use memory::{EntryFlags, MappedPages, PhysicalAddress, create_contiguous_mapping};
+use super::Transport;
+use super::legacy::{LEGACY_QUEUE_ALIGNMENT, align_up};
+
+
+/// The descriptor continues via its `next` field.
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// The buffer is written by the device rather than read by it.
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+/// Set in the available ring's flags to ask the device not to interrupt the driver when it uses a buffer.
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+/// Set in the used ring's flags by the device to tell the driver that it doesn't need to be notified.
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
+/// The maximum size of a virtqueue, as defined by the specification.
+pub const MAX_QUEUE_SIZE: u16 = 32768;
+
+
+/// An entry in the descriptor table.
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct Descriptor {
+    /// The physical address of the buffer.
+    address: u64,
+    length: u32,
+    flags: u16,
+    /// The index of the next descriptor in this chain, if `VIRTQ_DESC_F_NEXT` is set.
+    next: u16,
+}
+
+/// An entry in the used ring.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct UsedElement {
+    /// The index of the head descriptor of the used chain.
+    id: u32,
+    /// The number of bytes that the device wrote into the chain's device-writable buffers.
+    length: u32,
+}
+
+
+/// A buffer to be passed to the device, described by its physical address and length.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: PhysicalAddress,
+    pub length: u32,
+    /// Whether the device writes into this buffer, rather than reading from it.
+    pub device_writable: bool,
+}
+
+/// A chain of buffers that the device has finished using.
+#[derive(Debug, Clone, Copy)]
+pub struct UsedBuffer {
+    /// The token that was returned by [`Virtqueue::add_buffers()`](struct.Virtqueue.html#method.add_buffers) for this chain.
+    pub token: u16,
+    /// The number of bytes that the device wrote into the chain's device-writable buffers.
+    pub length: u32,
+}
+
+
+/// A split virtqueue.
+pub struct Virtqueue {
+    /// The index of this queue within its device.
+    index: u16,
+    /// The number of descriptors, which is also the number of entries in each ring.
+    size: u16,
+    /// The memory that holds the descriptor table and both rings.
+    memory: MappedPages,
+    available_ring_offset: usize,
+    used_ring_offset: usize,
+    /// The head of the list of free descriptors, which are chained through their `next` fields.
+    free_head: u16,
+    free_count: u16,
+    /// Our copy of the available ring's index, which is the next available ring entry that we will fill.
+    available_index: u16,
+    /// The index of the next entry in the used ring that we haven't yet consumed.
+    last_used_index: u16,
+}
+
+impl Virtqueue {
+    /// Allocates the virtqueue with the given `index` and tells the device about it via the given `transport`.
+    ///
+    /// The queue will have at most `max_size` descriptors, which must be a power of two;
+    /// the device may support fewer, and legacy devices always use the size they report.
+    pub fn new(transport: &mut dyn Transport, index: u16, max_size: u16) -> Result<Virtqueue, &'static str> {
+        let device_size = transport.max_queue_size(index);
+        if device_size == 0 {
+            return Err("virtio: device doesn't have a virtqueue with the given index");
+        }
+        let size = if transport.is_legacy() {
+            device_size
+        } else {
+            core::cmp::min(device_size, max_size)
+        };
+        if size == 0 || size > MAX_QUEUE_SIZE || !size.is_power_of_two() {
+            return Err("virtio: virtqueue size must be a power of two no larger than 32768");
+        }
+
+        let n = size as usize;
+        let available_ring_offset = n * size_of::<Descriptor>();
+        // flags, idx, ring[n], used_event
+        let available_ring_size = 2 + 2 + 2 * n + 2;
+        let used_ring_offset = align_up(available_ring_offset + available_ring_size, LEGACY_QUEUE_ALIGNMENT);
+        // flags, idx, ring[n], avail_event
+        let used_ring_size = 2 + 2 + n * size_of::<UsedElement>() + 2;
+        let total_size = used_ring_offset + used_ring_size;
+
+        let (mut memory, physical_address) = create_contiguous_mapping(total_size, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE)?;
+        for b in memory.as_slice_mut::<u8>(0, total_size)?.iter_mut() {
+            *b = 0;
+        }
+
+        // Initially, all descriptors are free and chained together in order.
+        {
+            let descriptors = memory.as_slice_mut::<Descriptor>(0, n)?;
+            for (i, descriptor) in descriptors.iter_mut().enumerate() {
+                descriptor.next = (i + 1) as u16;
+            }
+        }
+
+        transport.setup_queue(
+            index,
+            size,
+            physical_address,
+            physical_address + available_ring_offset,
+            physical_address + used_ring_offset,
+        )?;
+        debug!("virtio: set up virtqueue {} with {} descriptors at {:?}", index, size, physical_address);
+
+        Ok(Virtqueue {
+            index,
+            size,
+            memory,
+            available_ring_offset,
+            used_ring_offset,
+            free_head: 0,
+            free_count: size,
+            available_index: 0,
+            last_used_index: 0,
+        })
+    }
+
+    /// Returns the index of this queue within its device.
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Returns the number of descriptors in this queue.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns the number of descriptors that are not currently in use.
+    pub fn free_descriptors(&self) -> usize {
+        self.free_count as usize
+    }
+
+    /// Offers the given chain of `buffers` to the device.
+    ///
+    /// The device reads from the buffers that aren't device-writable and writes into the ones that are;
+    /// a chain must list all device-readable buffers before any device-writable ones.
+    /// The device isn't told about the new buffers until [`notify()`](#method.notify) is called.
+    ///
+    /// Returns a token that identifies this chain once it has been used, see [`pop_used()`](#method.pop_used).
+    pub fn add_buffers(&mut self, buffers: &[Buffer]) -> Result<u16, &'static str> {
+        if buffers.is_empty() {
+            return Err("virtio: cannot add an empty chain of buffers");
+        }
+        if buffers.len() > self.free_count as usize {
+            return Err("virtio: not enough free descriptors in the virtqueue");
+        }
+
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let last = i == buffers.len() - 1;
+            let descriptor = self.descriptor_mut(index)?;
+            descriptor.address = buffer.address.value() as u64;
+            descriptor.length = buffer.length;
+            descriptor.flags = if buffer.device_writable { VIRTQ_DESC_F_WRITE } else { 0 };
+            if !last {
+                descriptor.flags |= VIRTQ_DESC_F_NEXT;
+            }
+            let next = descriptor.next;
+            if !last {
+                index = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let slot = self.available_ring_offset + 4 + 2 * (self.available_index % self.size) as usize;
+        self.memory.as_type_mut::<Volatile<u16>>(slot)?.write(head);
+        self.available_index = self.available_index.wrapping_add(1);
+        // The device must see the descriptors and ring entry before it sees the new index.
+        fence(Ordering::SeqCst);
+        let index_offset = self.available_ring_offset + 2;
+        self.memory.as_type_mut::<Volatile<u16>>(index_offset)?.write(self.available_index);
+        Ok(head)
+    }
+
+    /// Notifies the device that new buffers are available, unless it has asked not to be notified.
+    pub fn notify(&mut self, transport: &mut dyn Transport) {
+        // Make sure the device sees the new available index before we read its flags.
+        fence(Ordering::SeqCst);
+        let used_flags = self.memory.as_type::<Volatile<u16>>(self.used_ring_offset).map(|f| f.read()).unwrap_or(0);
+        if used_flags & VIRTQ_USED_F_NO_NOTIFY == 0 {
+            transport.notify(self.index);
+        }
+    }
+
+    /// Asks the device to interrupt (or not interrupt) the driver whenever it uses a chain of buffers.
+    ///
+    /// This is only a hint, so drivers that poll [`pop_used()`](#method.pop_used) must still tolerate interrupts.
+    pub fn set_interrupts_enabled(&mut self, enabled: bool) -> Result<(), &'static str> {
+        let flags = if enabled { 0 } else { VIRTQ_AVAIL_F_NO_INTERRUPT };
+        let offset = self.available_ring_offset;
+        self.memory.as_type_mut::<Volatile<u16>>(offset)?.write(flags);
+        Ok(())
+    }
+
+    /// Returns `true` if the device has used a chain of buffers that hasn't yet been retrieved with `pop_used()`.
+    pub fn has_used(&self) -> bool {
+        fence(Ordering::SeqCst);
+        self.used_index() != self.last_used_index
+    }
+
+    /// Retrieves the next chain of buffers that the device has finished using, if any,
+    /// and returns its descriptors to the free list.
+    pub fn pop_used(&mut self) -> Option<UsedBuffer> {
+        if !self.has_used() {
+            return None;
+        }
+        let slot = self.used_ring_offset + 4 + size_of::<UsedElement>() * (self.last_used_index % self.size) as usize;
+        let element = *self.memory.as_type::<UsedElement>(slot).ok()?;
+        self.last_used_index = self.last_used_index.wrapping_add(1);
+
+        let token = element.id as u16;
+        if let Err(e) = self.free_chain(token) {
+            error!("virtio: device returned an invalid descriptor chain {} on queue {}: {}", token, self.index, e);
+        }
+        Some(UsedBuffer { token, length: element.length })
+    }
+
+    /// Returns the used ring's index, which the device increments each time it uses a chain of buffers.
+    fn used_index(&self) -> u16 {
+        self.memory.as_type::<Volatile<u16>>(self.used_ring_offset + 2).map(|i| i.read()).unwrap_or(self.last_used_index)
+    }
+
+    /// Returns the chain of descriptors starting at `head` to the free list.
+    fn free_chain(&mut self, head: u16) -> Result<(), &'static str> {
+        let mut index = head;
+        let mut count = 1;
+        loop {
+            let (flags, next) = {
+                let descriptor = self.descriptor_mut(index)?;
+                (descriptor.flags, descriptor.next)
+            };
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next;
+            count += 1;
+            if count > self.size {
+                return Err("descriptor chain looped");
+            }
+        }
+        // Prepend the whole chain to the free list.
+        let free_head = self.free_head;
+        self.descriptor_mut(index)?.next = free_head;
+        self.free_head = head;
+        self.free_count += count;
+        Ok(())
+    }
+
+    fn descriptor_mut(&mut self, index: u16) -> Result<&mut Descriptor, &'static str> {
+        if index >= self.size {
+            return Err("descriptor index was out of bounds");
+        }
+        self.memory.as_type_mut::<Descriptor>(index as usize * size_of::<Descriptor>())
+    }
+}
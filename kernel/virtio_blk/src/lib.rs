@@ -0,0 +1,292 @@
+//! A driver for virtio block devices, the paravirtualized disks offered by hypervisors like QEMU and KVM,
+//! e.g., with `-drive file=disk.img,if=virtio`.
+//!
+//! The primary structs of interest are [`VirtioBlkController`](struct.VirtioBlkController.html),
+//! which initializes a virtio block device found on the PCI bus,
+//! and [`VirtioBlkDevice`](struct.VirtioBlkDevice.html), which implements the `StorageDevice` trait.
+//!
+//! The device is accessed through whichever virtio-pci transport it supports (see the `virtio` crate),
+//! using a single virtqueue. Each request is a chain of three buffers:
+//! a header that specifies the request type and starting sector, the data being transferred, and a status byte.
+//! Requests are issued one at a time and their completion is detected by polling the virtqueue.
+
+#![no_std]
+
+#[macro_use] extern crate log;
+extern crate alloc;
+extern crate spin;
+extern crate kernel_config;
+extern crate memory;
+extern crate pci;
+extern crate virtio;
+extern crate storage_device;
+
+use alloc::{
+    boxed::Box,
+    sync::Arc,
+};
+use spin::Mutex;
+use kernel_config::memory::PAGE_SIZE;
+use memory::{EntryFlags, MappedPages, PhysicalAddress, create_contiguous_mapping};
+use pci::PciDevice;
+use virtio::{Buffer, DeviceType, Transport, Virtqueue};
+use storage_device::{StorageDevice, StorageDeviceRef, StorageController};
+
+
+/// Virtio block devices always address sectors in units of 512 bytes,
+/// regardless of the block size that the device reports as optimal.
+const SECTOR_SIZE_IN_BYTES: usize = 512;
+
+/// The device is read-only.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// The device reports its optimal block size in its configuration.
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+/// The device supports the flush command, which is required to make writes durable if the device has a write cache.
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+/// The features that this driver understands.
+const SUPPORTED_FEATURES: u64 = VIRTIO_BLK_F_RO | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH;
+
+/// The offsets of fields in the device-specific configuration.
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_BLK_SIZE: usize = 20;
+
+/// The request types.
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+/// The values of the status byte that the device writes when it completes a request.
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// The index of the request queue, which is the only queue we use.
+const REQUEST_QUEUE: u16 = 0;
+/// The maximum number of descriptors in the request queue; each request uses three.
+const MAX_QUEUE_SIZE: u16 = 128;
+
+/// The size of a request header: the type, a reserved field, and the starting sector.
+const REQUEST_HEADER_SIZE: usize = 16;
+/// The offset of the status byte within the request buffer, right after the header.
+const STATUS_OFFSET: usize = REQUEST_HEADER_SIZE;
+/// The offset of the data buffer within the request buffer, which starts on its own page.
+const DATA_OFFSET: usize = PAGE_SIZE;
+/// The number of bytes transferred by a single request, which is the size of the data buffer.
+const BYTES_PER_REQUEST: usize = 64 * 1024;
+
+
+/// A virtio block device.
+pub struct VirtioBlkDevice {
+    transport: Box<dyn Transport>,
+    queue: Virtqueue,
+    /// The memory that holds the request header, the status byte, and the data buffer, which the device accesses via DMA.
+    request_buffer: MappedPages,
+    request_buffer_physical_address: PhysicalAddress,
+    size_in_sectors: usize,
+    /// The block size that the device reports as optimal, if it reports one.
+    block_size: Option<usize>,
+    read_only: bool,
+    flush_supported: bool,
+}
+
+impl VirtioBlkDevice {
+    /// Initializes the virtio block device described by the given `pci_device`.
+    pub fn new(pci_device: &PciDevice) -> Result<VirtioBlkDevice, &'static str> {
+        let mut transport = virtio::transport_for(pci_device)?;
+        match VirtioBlkDevice::init(transport.as_mut()) {
+            Ok((features, queue)) => {
+                let size_in_sectors = transport.read_config_u64(CONFIG_CAPACITY) as usize;
+                let block_size = if features & VIRTIO_BLK_F_BLK_SIZE != 0 {
+                    Some(transport.read_config_u32(CONFIG_BLK_SIZE) as usize)
+                } else {
+                    None
+                };
+                let (request_buffer, request_buffer_physical_address) = create_contiguous_mapping(
+                    DATA_OFFSET + BYTES_PER_REQUEST,
+                    EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+                ).map_err(|e| {
+                    virtio::mark_failed(transport.as_mut());
+                    e
+                })?;
+                virtio::driver_ok(transport.as_mut());
+
+                let device = VirtioBlkDevice {
+                    transport,
+                    queue,
+                    request_buffer,
+                    request_buffer_physical_address,
+                    size_in_sectors,
+                    block_size,
+                    read_only: features & VIRTIO_BLK_F_RO != 0,
+                    flush_supported: features & VIRTIO_BLK_F_FLUSH != 0,
+                };
+                info!("virtio-blk device at {:?}: {} sectors, block size {:?}, read-only: {}, flush: {}",
+                    pci_device.location, device.size_in_sectors, device.block_size, device.read_only, device.flush_supported
+                );
+                Ok(device)
+            }
+            Err(e) => {
+                virtio::mark_failed(transport.as_mut());
+                Err(e)
+            }
+        }
+    }
+
+    /// Negotiates features and sets up the request queue.
+    fn init(transport: &mut dyn Transport) -> Result<(u64, Virtqueue), &'static str> {
+        let features = virtio::negotiate_features(transport, SUPPORTED_FEATURES)?;
+        let mut queue = Virtqueue::new(transport, REQUEST_QUEUE, MAX_QUEUE_SIZE)?;
+        // We poll for completions, so we don't need interrupts.
+        queue.set_interrupts_enabled(false)?;
+        Ok((features, queue))
+    }
+
+    /// Returns `true` if this device doesn't allow writes.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Returns the block size that the device reports as optimal, if it reports one.
+    pub fn block_size(&self) -> Option<usize> {
+        self.block_size
+    }
+
+    /// Asks the device to write any data in its write cache to its backing storage.
+    ///
+    /// Does nothing if the device doesn't support flushing, in which case its writes are always durable.
+    pub fn flush(&mut self) -> Result<(), &'static str> {
+        if !self.flush_supported {
+            return Ok(());
+        }
+        self.submit_request(VIRTIO_BLK_T_FLUSH, 0, 0)
+    }
+
+    /// Checks that a transfer of `buffer_length` bytes starting at `offset_in_sectors` lies within this device.
+    fn check_transfer(&self, buffer_length: usize, offset_in_sectors: usize) -> Result<(), &'static str> {
+        if buffer_length % SECTOR_SIZE_IN_BYTES != 0 {
+            return Err("virtio-blk: the buffer length must be a multiple of the sector size");
+        }
+        let end = offset_in_sectors.checked_add(buffer_length / SECTOR_SIZE_IN_BYTES)
+            .ok_or("virtio-blk: offset_in_sectors was out of bounds")?;
+        if end > self.size_in_sectors {
+            return Err("virtio-blk: transfer extended beyond the end of the device");
+        }
+        Ok(())
+    }
+
+    /// Issues a single request of the given type for `data_length` bytes of the data buffer
+    /// starting at the given `sector`, and waits for it to complete.
+    fn submit_request(&mut self, request_type: u32, sector: usize, data_length: usize) -> Result<(), &'static str> {
+        {
+            let header = self.request_buffer.as_slice_mut::<u32>(0, REQUEST_HEADER_SIZE / 4)?;
+            header[0] = request_type;
+            header[1] = 0;
+            header[2] = sector as u32;
+            header[3] = ((sector as u64) >> 32) as u32;
+        }
+        // Set the status to a value that the device never writes, so we can tell if it didn't write one.
+        *self.request_buffer.as_type_mut::<u8>(STATUS_OFFSET)? = 0xFF;
+
+        let header = Buffer {
+            address: self.request_buffer_physical_address,
+            length: REQUEST_HEADER_SIZE as u32,
+            device_writable: false,
+        };
+        let data = Buffer {
+            address: self.request_buffer_physical_address + DATA_OFFSET,
+            length: data_length as u32,
+            device_writable: request_type == VIRTIO_BLK_T_IN,
+        };
+        let status = Buffer {
+            address: self.request_buffer_physical_address + STATUS_OFFSET,
+            length: 1,
+            device_writable: true,
+        };
+        let token = if data_length == 0 {
+            self.queue.add_buffers(&[header, status])?
+        } else {
+            self.queue.add_buffers(&[header, data, status])?
+        };
+        self.queue.notify(self.transport.as_mut());
+
+        loop {
+            match self.queue.pop_used() {
+                Some(used) if used.token == token => break,
+                Some(used) => warn!("virtio-blk: ignoring unexpected completion of request {}", used.token),
+                None => core::sync::atomic::spin_loop_hint(),
+            }
+        }
+
+        match *self.request_buffer.as_type::<u8>(STATUS_OFFSET)? {
+            VIRTIO_BLK_S_OK => Ok(()),
+            VIRTIO_BLK_S_IOERR => Err("virtio-blk: device reported an I/O error"),
+            VIRTIO_BLK_S_UNSUPP => Err("virtio-blk: device didn't support the request"),
+            _ => Err("virtio-blk: device returned an invalid status"),
+        }
+    }
+}
+
+impl StorageDevice for VirtioBlkDevice {
+    fn read_sectors(&mut self, buffer: &mut [u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+        self.check_transfer(buffer.len(), offset_in_sectors)?;
+        for (i, chunk) in buffer.chunks_mut(BYTES_PER_REQUEST).enumerate() {
+            let sector = offset_in_sectors + i * BYTES_PER_REQUEST / SECTOR_SIZE_IN_BYTES;
+            self.submit_request(VIRTIO_BLK_T_IN, sector, chunk.len())?;
+            chunk.copy_from_slice(self.request_buffer.as_slice(DATA_OFFSET, chunk.len())?);
+        }
+        Ok(buffer.len() / SECTOR_SIZE_IN_BYTES)
+    }
+
+    fn write_sectors(&mut self, buffer: &[u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+        if self.read_only {
+            return Err("virtio-blk: device is read-only");
+        }
+        self.check_transfer(buffer.len(), offset_in_sectors)?;
+        for (i, chunk) in buffer.chunks(BYTES_PER_REQUEST).enumerate() {
+            let sector = offset_in_sectors + i * BYTES_PER_REQUEST / SECTOR_SIZE_IN_BYTES;
+            self.request_buffer.as_slice_mut::<u8>(DATA_OFFSET, chunk.len())?.copy_from_slice(chunk);
+            self.submit_request(VIRTIO_BLK_T_OUT, sector, chunk.len())?;
+        }
+        // Like the `ata` driver, make each write durable before returning.
+        self.flush()?;
+        Ok(buffer.len() / SECTOR_SIZE_IN_BYTES)
+    }
+
+    fn sector_size_in_bytes(&self) -> usize {
+        SECTOR_SIZE_IN_BYTES
+    }
+
+    fn size_in_sectors(&self) -> usize {
+        self.size_in_sectors
+    }
+}
+
+
+/// A pseudo storage controller for a single virtio block device,
+/// since each virtio block device is its own PCI device.
+pub struct VirtioBlkController {
+    device: Arc<Mutex<VirtioBlkDevice>>,
+}
+
+impl VirtioBlkController {
+    /// Initializes the virtio block device described by the given `pci_device`.
+    pub fn new(pci_device: &PciDevice) -> Result<VirtioBlkController, &'static str> {
+        Ok(VirtioBlkController {
+            device: Arc::new(Mutex::new(VirtioBlkDevice::new(pci_device)?)),
+        })
+    }
+}
+
+impl StorageController for VirtioBlkController {
+    fn devices<'c>(&'c self) -> Box<(dyn Iterator<Item = StorageDeviceRef> + 'c)> {
+        Box::new(
+            Some(Arc::clone(&self.device) as StorageDeviceRef).into_iter()
+        )
+    }
+}
+
+
+/// Returns `true` if the given `pci_device` is a virtio block device.
+pub fn is_virtio_blk(pci_device: &PciDevice) -> bool {
+    virtio::device_type(pci_device) == Some(DeviceType::Block)
+}